@@ -0,0 +1,192 @@
+//! Fetches and decodes the program's accounts over RPC.
+
+use std::collections::HashMap;
+
+use borsh::BorshDeserialize;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::RpcFilterType,
+};
+use solana_program::{clock::Clock, program_pack::Pack, pubkey::Pubkey, sysvar};
+use solana_sdk::account::{from_account, Account};
+use spl_perpetual::{
+    math,
+    state::{CollateralConfig, PerpetualSwap},
+};
+use spl_token::state::Account as TokenAccount;
+
+/// Most accounts a single `getMultipleAccounts` request returns.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+/// A swap with both sides open, along with the accounts needed to judge and liquidate it.
+#[derive(Debug, Clone)]
+pub struct SwapAccounts {
+    pub pubkey: Pubkey,
+    pub perpetual_swap: PerpetualSwap,
+    pub long_margin: TokenAccount,
+    pub short_margin: TokenAccount,
+    pub collateral_config: Option<CollateralConfig>,
+    /// Long and short vault balances for every collateral asset, in order
+    pub collateral_balances: Vec<(u64, u64)>,
+}
+
+impl SwapAccounts {
+    pub fn margin(&self, is_long: bool) -> &TokenAccount {
+        if is_long {
+            &self.long_margin
+        } else {
+            &self.short_margin
+        }
+    }
+
+    /// The side's margin with its non-primary collateral counted at the haircut value, which is
+    /// what the program sees once a liquidator has bought the collateral.
+    pub fn margin_value(&self, is_long: bool) -> u64 {
+        let collateral: Vec<(u64, f64, f64)> = match &self.collateral_config {
+            Some(collateral_config) => collateral_config
+                .assets()
+                .iter()
+                .zip(&self.collateral_balances)
+                .map(|(asset, (long, short))| {
+                    let amount = if is_long { *long } else { *short };
+                    (amount, asset.price, asset.haircut)
+                })
+                .collect(),
+            None => vec![],
+        };
+        math::margin_value(self.margin(is_long).amount, &collateral)
+    }
+}
+
+/// Every swap owned by `program_id` with both sides open.
+pub fn fetch_perpetual_swaps(
+    rpc_client: &RpcClient,
+    program_id: &Pubkey,
+) -> Result<Vec<(Pubkey, PerpetualSwap)>, ClientError> {
+    let accounts = rpc_client.get_program_accounts_with_config(
+        program_id,
+        RpcProgramAccountsConfig {
+            filters: Some(vec![RpcFilterType::DataSize(PerpetualSwap::LEN as u64)]),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                ..RpcAccountInfoConfig::default()
+            },
+        },
+    )?;
+    Ok(accounts
+        .into_iter()
+        .filter_map(|(pubkey, account)| {
+            PerpetualSwap::try_from_slice(&account.data)
+                .ok()
+                .filter(|perpetual_swap| perpetual_swap.is_initialized())
+                .map(|perpetual_swap| (pubkey, perpetual_swap))
+        })
+        .collect())
+}
+
+/// Loads the margin accounts and collateral of each swap. Swaps whose accounts are missing or
+/// can't be decoded are skipped.
+pub fn fetch_swap_accounts(
+    rpc_client: &RpcClient,
+    swaps: Vec<(Pubkey, PerpetualSwap)>,
+) -> Result<Vec<SwapAccounts>, ClientError> {
+    let mut pubkeys = vec![];
+    for (_, perpetual_swap) in swaps.iter() {
+        pubkeys.push(perpetual_swap.long_margin_pubkey);
+        pubkeys.push(perpetual_swap.short_margin_pubkey);
+        if perpetual_swap.has_collateral_config() {
+            pubkeys.push(perpetual_swap.collateral_config_pubkey);
+        }
+    }
+    let mut accounts = fetch_multiple_accounts(rpc_client, &pubkeys)?;
+
+    // Collateral vaults are only known once the configs are decoded
+    let mut collateral_configs = HashMap::new();
+    for (_, perpetual_swap) in swaps.iter() {
+        if !perpetual_swap.has_collateral_config() {
+            continue;
+        }
+        if let Some(collateral_config) = accounts
+            .get(&perpetual_swap.collateral_config_pubkey)
+            .and_then(|account| CollateralConfig::try_from_slice(&account.data).ok())
+            .filter(|collateral_config| collateral_config.is_initialized)
+        {
+            collateral_configs.insert(perpetual_swap.collateral_config_pubkey, collateral_config);
+        }
+    }
+    let vault_pubkeys: Vec<Pubkey> = collateral_configs
+        .values()
+        .flat_map(|collateral_config| {
+            collateral_config
+                .assets()
+                .iter()
+                .flat_map(|asset| vec![asset.long_vault_pubkey, asset.short_vault_pubkey])
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    accounts.extend(fetch_multiple_accounts(rpc_client, &vault_pubkeys)?);
+
+    let unpack_token_account = |pubkey: &Pubkey| {
+        accounts
+            .get(pubkey)
+            .and_then(|account| TokenAccount::unpack(&account.data).ok())
+    };
+    Ok(swaps
+        .into_iter()
+        .filter_map(|(pubkey, perpetual_swap)| {
+            let long_margin = unpack_token_account(&perpetual_swap.long_margin_pubkey)?;
+            let short_margin = unpack_token_account(&perpetual_swap.short_margin_pubkey)?;
+            let (collateral_config, collateral_balances) = if perpetual_swap.has_collateral_config()
+            {
+                let collateral_config =
+                    collateral_configs.get(&perpetual_swap.collateral_config_pubkey)?;
+                let collateral_balances = collateral_config
+                    .assets()
+                    .iter()
+                    .map(|asset| {
+                        Some((
+                            unpack_token_account(&asset.long_vault_pubkey)?.amount,
+                            unpack_token_account(&asset.short_vault_pubkey)?.amount,
+                        ))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                (Some(collateral_config.clone()), collateral_balances)
+            } else {
+                (None, vec![])
+            };
+            Some(SwapAccounts {
+                pubkey,
+                perpetual_swap,
+                long_margin,
+                short_margin,
+                collateral_config,
+                collateral_balances,
+            })
+        })
+        .collect())
+}
+
+/// The cluster's time in milliseconds since the epoch, as the program reads it from the Clock sysvar.
+pub fn fetch_unix_timestamp_ms(rpc_client: &RpcClient) -> Result<u128, ClientError> {
+    let clock: Clock = from_account(&rpc_client.get_account(&sysvar::clock::id())?)
+        .ok_or_else(|| ClientErrorKind::Custom("Invalid clock sysvar".to_string()))?;
+    Ok(clock.unix_timestamp.max(0) as u128 * 1000)
+}
+
+fn fetch_multiple_accounts(
+    rpc_client: &RpcClient,
+    pubkeys: &[Pubkey],
+) -> Result<HashMap<Pubkey, Account>, ClientError> {
+    let mut accounts = HashMap::new();
+    for chunk in pubkeys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        for (pubkey, account) in chunk.iter().zip(rpc_client.get_multiple_accounts(chunk)?) {
+            if let Some(account) = account {
+                accounts.insert(*pubkey, account);
+            }
+        }
+    }
+    Ok(accounts)
+}
@@ -0,0 +1,101 @@
+//! Cranks `TransferFunds` on every perpetual swap of the program whose funding is due.
+
+use std::{error::Error, process::exit, thread};
+
+use clap::{crate_version, App, Arg};
+use solana_clap_utils::{input_parsers::pubkey_of, input_validators::is_valid_pubkey};
+use solana_sdk::{pubkey::Pubkey, signature::Signer, transaction::Transaction};
+use spl_perpetual_keeper::{
+    accounts::{fetch_perpetual_swaps, fetch_swap_accounts, fetch_unix_timestamp_ms},
+    cli::{with_common_args, Config},
+    crank::{crank_instruction, plan_cranks},
+};
+
+fn main() {
+    let matches = with_common_args(
+        App::new("perp-crank")
+            .version(crate_version!())
+            .about("Runs the funding payment of perpetual swaps once it is due"),
+    )
+    .arg(
+        Arg::with_name("reward_account")
+            .long("reward-account")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .validator(is_valid_pubkey)
+            .help("Token account receiving crank rewards [default: the keypair's associated token account for each swap's mint]"),
+    )
+    .get_matches();
+
+    let config = Config::from_matches(&matches);
+    let reward_account = pubkey_of(&matches, "reward_account");
+
+    loop {
+        if let Err(err) = scan(&config, reward_account.as_ref()) {
+            eprintln!("error: {}", err);
+            if config.once {
+                exit(1);
+            }
+        }
+        if config.once {
+            break;
+        }
+        thread::sleep(config.interval);
+    }
+}
+
+fn scan(config: &Config, reward_account: Option<&Pubkey>) -> Result<(), Box<dyn Error>> {
+    let now = fetch_unix_timestamp_ms(&config.rpc_client)?;
+    let swaps = fetch_swap_accounts(
+        &config.rpc_client,
+        fetch_perpetual_swaps(&config.rpc_client, &config.program_id)?,
+    )?;
+    let cranks = plan_cranks(&swaps, now);
+    println!(
+        "Scanned {} swaps, {} due for funding",
+        swaps.len(),
+        cranks.len()
+    );
+
+    for swap in cranks {
+        let swap_accounts = swaps
+            .iter()
+            .find(|swap_accounts| swap_accounts.pubkey == swap)
+            .unwrap();
+        let perpetual_swap = &swap_accounts.perpetual_swap;
+        println!(
+            "{}: funding due, mark {} index {}{}",
+            swap,
+            perpetual_swap.mark_price,
+            perpetual_swap.index_price,
+            if perpetual_swap.has_fee_vault() {
+                format!(", reward {}", perpetual_swap.crank_reward)
+            } else {
+                String::new()
+            }
+        );
+        if config.dry_run {
+            continue;
+        }
+
+        let instruction = crank_instruction(
+            &config.program_id,
+            swap_accounts,
+            &config.keypair.pubkey(),
+            reward_account,
+        )?;
+        let (recent_blockhash, _fee_calculator) = config.rpc_client.get_recent_blockhash()?;
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&config.keypair.pubkey()),
+            &[&config.keypair],
+            recent_blockhash,
+        );
+        // Another keeper may have cranked the swap first, so a failure only skips it
+        match config.rpc_client.send_and_confirm_transaction(&transaction) {
+            Ok(signature) => println!("Cranked {}: {}", swap, signature),
+            Err(err) => eprintln!("Failed to crank {}: {}", swap, err),
+        }
+    }
+    Ok(())
+}
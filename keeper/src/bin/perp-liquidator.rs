@@ -0,0 +1,119 @@
+//! Scans every perpetual swap of the program and liquidates the sides below maintenance margin.
+
+use std::{error::Error, process::exit, thread};
+
+use clap::{crate_version, App, Arg};
+use solana_clap_utils::{
+    input_parsers::{pubkey_of, value_of},
+    input_validators::{is_parsable, is_valid_pubkey},
+};
+use solana_sdk::{pubkey::Pubkey, signature::Signer, transaction::Transaction};
+use spl_perpetual_keeper::{
+    accounts::{fetch_perpetual_swaps, fetch_swap_accounts},
+    cli::{with_common_args, Config},
+    liquidator::{liquidate_instruction, plan_liquidations},
+};
+
+fn main() {
+    let matches = with_common_args(
+        App::new("perp-liquidator")
+            .version(crate_version!())
+            .about("Liquidates perpetual swap positions that are below maintenance margin"),
+    )
+    .arg(
+        Arg::with_name("liquidator_account")
+            .long("liquidator-account")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .validator(is_valid_pubkey)
+            .help("Token account posting collateral [default: the keypair's associated token account for each swap's mint]"),
+    )
+    .arg(
+        Arg::with_name("collateral_buffer")
+            .long("collateral-buffer")
+            .value_name("FRACTION")
+            .takes_value(true)
+            .default_value("0.05")
+            .validator(is_parsable::<f64>)
+            .help("Collateral posted on top of the minimum, as a fraction of it"),
+    )
+    .get_matches();
+
+    let config = Config::from_matches(&matches);
+    let liquidator_account = pubkey_of(&matches, "liquidator_account");
+    let collateral_buffer: f64 = value_of(&matches, "collateral_buffer").unwrap();
+
+    loop {
+        if let Err(err) = scan(&config, liquidator_account.as_ref(), collateral_buffer) {
+            eprintln!("error: {}", err);
+            if config.once {
+                exit(1);
+            }
+        }
+        if config.once {
+            break;
+        }
+        thread::sleep(config.interval);
+    }
+}
+
+fn scan(
+    config: &Config,
+    liquidator_account: Option<&Pubkey>,
+    collateral_buffer: f64,
+) -> Result<(), Box<dyn Error>> {
+    let swaps = fetch_swap_accounts(
+        &config.rpc_client,
+        fetch_perpetual_swaps(&config.rpc_client, &config.program_id)?,
+    )?;
+    let liquidations = plan_liquidations(&swaps, collateral_buffer);
+    println!(
+        "Scanned {} swaps, {} sides to liquidate",
+        swaps.len(),
+        liquidations.len()
+    );
+
+    for liquidation in liquidations {
+        let side = if liquidation.is_long { "long" } else { "short" };
+        println!(
+            "{} {}: {} below maintenance margin{}, posting {}",
+            liquidation.swap,
+            side,
+            liquidation.shortfall,
+            if liquidation.is_bankrupt {
+                ", bankrupt"
+            } else {
+                ""
+            },
+            liquidation.collateral
+        );
+        if config.dry_run {
+            continue;
+        }
+
+        let swap_accounts = swaps
+            .iter()
+            .find(|swap_accounts| swap_accounts.pubkey == liquidation.swap)
+            .unwrap();
+        let instruction = liquidate_instruction(
+            &config.program_id,
+            swap_accounts,
+            &liquidation,
+            &config.keypair.pubkey(),
+            liquidator_account,
+        )?;
+        let (recent_blockhash, _fee_calculator) = config.rpc_client.get_recent_blockhash()?;
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&config.keypair.pubkey()),
+            &[&config.keypair],
+            recent_blockhash,
+        );
+        // Another keeper may have got there first, so a failure only skips this side
+        match config.rpc_client.send_and_confirm_transaction(&transaction) {
+            Ok(signature) => println!("Liquidated {} {}: {}", liquidation.swap, side, signature),
+            Err(err) => eprintln!("Failed to liquidate {} {}: {}", liquidation.swap, side, err),
+        }
+    }
+    Ok(())
+}
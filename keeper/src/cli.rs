@@ -0,0 +1,121 @@
+//! Command line arguments shared by the keeper binaries.
+
+use std::{process::exit, time::Duration};
+
+use clap::{App, Arg, ArgMatches};
+use solana_clap_utils::{
+    input_parsers::{pubkey_of, value_of},
+    input_validators::{is_keypair, is_parsable, is_url, is_valid_pubkey},
+};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair},
+};
+
+/// Settings every keeper runs with.
+pub struct Config {
+    pub rpc_client: RpcClient,
+    pub keypair: Keypair,
+    pub program_id: Pubkey,
+    pub interval: Duration,
+    pub once: bool,
+    pub dry_run: bool,
+}
+
+/// Adds the arguments parsed by [`Config::from_matches`].
+pub fn with_common_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(
+        Arg::with_name("config_file")
+            .short("C")
+            .long("config")
+            .value_name("PATH")
+            .takes_value(true)
+            .global(true)
+            .help("Configuration file to use"),
+    )
+    .arg(
+        Arg::with_name("json_rpc_url")
+            .short("u")
+            .long("url")
+            .value_name("URL")
+            .takes_value(true)
+            .validator(is_url)
+            .help("JSON RPC URL for the cluster [default: value from configuration file]"),
+    )
+    .arg(
+        Arg::with_name("keypair")
+            .long("keypair")
+            .value_name("KEYPAIR")
+            .takes_value(true)
+            .validator(is_keypair)
+            .help("Keypair that signs and pays for transactions [default: value from configuration file]"),
+    )
+    .arg(
+        Arg::with_name("program_id")
+            .long("program-id")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .validator(is_valid_pubkey)
+            .help("Perpetual swap program id [default: the id declared by the program]"),
+    )
+    .arg(
+        Arg::with_name("interval")
+            .long("interval")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .default_value("10")
+            .validator(is_parsable::<u64>)
+            .help("Seconds to wait between scans"),
+    )
+    .arg(
+        Arg::with_name("once")
+            .long("once")
+            .takes_value(false)
+            .help("Scan once and exit"),
+    )
+    .arg(
+        Arg::with_name("dry_run")
+            .long("dry-run")
+            .takes_value(false)
+            .help("Log what would be sent without sending any transaction"),
+    )
+}
+
+impl Config {
+    /// Reads the common arguments, falling back to the Solana CLI configuration file.
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let cli_config = if let Some(config_file) = matches.value_of("config_file") {
+            solana_cli_config::Config::load(config_file).unwrap_or_else(|err| {
+                eprintln!("error: could not load config file {}: {}", config_file, err);
+                exit(1);
+            })
+        } else if let Some(config_file) = &*solana_cli_config::CONFIG_FILE {
+            solana_cli_config::Config::load(config_file).unwrap_or_default()
+        } else {
+            solana_cli_config::Config::default()
+        };
+
+        let json_rpc_url = matches
+            .value_of("json_rpc_url")
+            .unwrap_or(&cli_config.json_rpc_url)
+            .to_string();
+        let keypair_path = matches
+            .value_of("keypair")
+            .unwrap_or(&cli_config.keypair_path);
+        let keypair = read_keypair_file(keypair_path).unwrap_or_else(|err| {
+            eprintln!("error: could not read keypair {}: {}", keypair_path, err);
+            exit(1);
+        });
+
+        Self {
+            rpc_client: RpcClient::new_with_commitment(json_rpc_url, CommitmentConfig::confirmed()),
+            keypair,
+            program_id: pubkey_of(matches, "program_id").unwrap_or_else(spl_perpetual::id),
+            interval: Duration::from_secs(value_of(matches, "interval").unwrap()),
+            once: matches.is_present("once"),
+            dry_run: matches.is_present("dry_run"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Finds swaps whose funding is due and builds the transactions that crank them.
+
+use solana_program::{instruction::Instruction, program_error::ProgramError, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address;
+use spl_perpetual::{
+    instruction,
+    processor::Processor,
+    state::{MarketAction, PerpetualSwap},
+};
+
+use crate::accounts::SwapAccounts;
+
+/// Whether `TransferFunds` would accept the swap at `now`, in milliseconds since the epoch.
+pub fn is_funding_due(perpetual_swap: &PerpetualSwap, now: u128) -> bool {
+    let due_time = perpetual_swap
+        .reference_time
+        .saturating_add(perpetual_swap.minimum_funding_period);
+    perpetual_swap.has_funding()
+        && perpetual_swap.status.allows(MarketAction::Crank)
+        && now >= due_time
+}
+
+/// The swaps whose funding is due, longest overdue first.
+pub fn plan_cranks(swaps: &[SwapAccounts], now: u128) -> Vec<Pubkey> {
+    let mut due: Vec<&SwapAccounts> = swaps
+        .iter()
+        .filter(|swap_accounts| is_funding_due(&swap_accounts.perpetual_swap, now))
+        .collect();
+    due.sort_by_key(|swap_accounts| {
+        swap_accounts
+            .perpetual_swap
+            .reference_time
+            .saturating_add(swap_accounts.perpetual_swap.minimum_funding_period)
+    });
+    due.into_iter()
+        .map(|swap_accounts| swap_accounts.pubkey)
+        .collect()
+}
+
+/// Builds the `TransferFunds` instruction for a swap. If the swap pays crank rewards, they go to
+/// `reward_account`, or to `cranker`'s associated token account for the swap's mint.
+pub fn crank_instruction(
+    program_id: &Pubkey,
+    swap_accounts: &SwapAccounts,
+    cranker: &Pubkey,
+    reward_account: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let perpetual_swap = &swap_accounts.perpetual_swap;
+    let authority = Processor::authority_id(
+        program_id,
+        &swap_accounts.pubkey,
+        perpetual_swap.authority_bump,
+    )?;
+    let reward_account = reward_account
+        .copied()
+        .unwrap_or_else(|| get_associated_token_address(cranker, &perpetual_swap.collateral_mint));
+    instruction::transfer_funds(
+        program_id,
+        &swap_accounts.pubkey,
+        &authority,
+        &perpetual_swap.long_margin_pubkey,
+        &perpetual_swap.short_margin_pubkey,
+        &perpetual_swap.token_program_id,
+        &perpetual_swap.collateral_mint,
+        if perpetual_swap.has_fee_vault() {
+            Some((&perpetual_swap.fee_vault_pubkey, &reward_account))
+        } else {
+            None
+        },
+    )
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use borsh::BorshDeserialize;
+    #[cfg(test)]
+    use spl_perpetual::state::{MarketKind, MarketStatus};
+    #[cfg(test)]
+    use spl_token::state::Account as TokenAccount;
+
+    #[cfg(test)]
+    fn swap_accounts(
+        program_id: &Pubkey,
+        reference_time: u128,
+        minimum_funding_period: u128,
+    ) -> SwapAccounts {
+        let pubkey = Pubkey::new_unique();
+        let mut perpetual_swap = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        perpetual_swap.authority_bump = Processor::find_authority_address(program_id, &pubkey).1;
+        perpetual_swap.is_long_initialized = true;
+        perpetual_swap.is_short_initialized = true;
+        perpetual_swap.reference_time = reference_time;
+        perpetual_swap.minimum_funding_period = minimum_funding_period;
+        SwapAccounts {
+            pubkey,
+            perpetual_swap,
+            long_margin: TokenAccount::default(),
+            short_margin: TokenAccount::default(),
+            collateral_config: None,
+            collateral_balances: vec![],
+        }
+    }
+
+    #[test]
+    pub fn test_plan_cranks() {
+        let program_id = Pubkey::new_unique();
+        let not_due = swap_accounts(&program_id, 9_000, 2_000);
+        let due = swap_accounts(&program_id, 8_000, 1_000);
+        let overdue = swap_accounts(&program_id, 1_000, 1_000);
+
+        let cranks = plan_cranks(&[not_due.clone(), due.clone(), overdue.clone()], 10_000);
+        assert_eq!(cranks, vec![overdue.pubkey, due.pubkey]);
+
+        // Paused markets refuse funding cranks however overdue they are
+        let mut paused = overdue.clone();
+        paused.perpetual_swap.status = MarketStatus::Paused;
+        assert_eq!(plan_cranks(&[paused], 10_000), vec![]);
+        // Dated futures don't pay funding
+        let mut future = overdue.clone();
+        future.perpetual_swap.kind = MarketKind::DatedFuture;
+        assert_eq!(plan_cranks(&[future], 10_000), vec![]);
+
+        let mut rewarded = due;
+        rewarded.perpetual_swap.fee_vault_pubkey = Pubkey::new_unique();
+        let cranker = Pubkey::new_unique();
+        assert_eq!(
+            crank_instruction(&program_id, &not_due, &cranker, None)
+                .unwrap()
+                .accounts
+                .len(),
+            7
+        );
+        let instruction = crank_instruction(&program_id, &rewarded, &cranker, None).unwrap();
+        assert_eq!(instruction.accounts.len(), 9);
+        assert_eq!(
+            instruction.accounts[7].pubkey,
+            rewarded.perpetual_swap.fee_vault_pubkey
+        );
+    }
+}
@@ -0,0 +1,6 @@
+//! Off-chain keepers for the perpetual swap program.
+
+pub mod accounts;
+pub mod cli;
+pub mod crank;
+pub mod liquidator;
@@ -0,0 +1,218 @@
+//! Finds positions below maintenance margin and builds the transactions that liquidate them.
+
+use std::cmp::Ordering;
+
+use solana_program::{instruction::Instruction, program_error::ProgramError, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address;
+use spl_perpetual::{
+    instruction, math,
+    processor::Processor,
+    state::{LiquidationMode, MarketAction},
+};
+
+use crate::accounts::SwapAccounts;
+
+/// A side that `TryToLiquidate` would currently accept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Liquidation {
+    pub swap: Pubkey,
+    pub is_long: bool,
+    /// How far the side's equity is below its maintenance margin
+    pub shortfall: f64,
+    /// Whether the side's losses exceed its margin
+    pub is_bankrupt: bool,
+    /// Collateral the liquidator posts to take over the position
+    pub collateral: u64,
+}
+
+/// Every side that needs liquidating, largest shortfall first so the positions that put the
+/// insurance fund most at risk go out before the others. `collateral_buffer` is the fraction
+/// posted on top of the minimum so the new position isn't liquidatable straight away.
+pub fn plan_liquidations(swaps: &[SwapAccounts], collateral_buffer: f64) -> Vec<Liquidation> {
+    let mut liquidations = vec![];
+    for swap_accounts in swaps {
+        let perpetual_swap = &swap_accounts.perpetual_swap;
+        if !perpetual_swap.status.allows(MarketAction::Close) {
+            continue;
+        }
+        for &is_long in [true, false].iter() {
+            // The insurance fund stands behind the vAMM's side, and `TryToLiquidate` refuses it
+            if perpetual_swap.vamm_holds(is_long) {
+                continue;
+            }
+            let margin = swap_accounts.margin_value(is_long);
+            if !math::needs_liquidation(perpetual_swap, is_long, margin) {
+                continue;
+            }
+            let collateral = math::liquidation_collateral(perpetual_swap, is_long, margin);
+            liquidations.push(Liquidation {
+                swap: swap_accounts.pubkey,
+                is_long,
+                shortfall: math::maintenance_margin(perpetual_swap)
+                    - math::equity(perpetual_swap, is_long, margin),
+                is_bankrupt: math::negative_equity_deficit(perpetual_swap, is_long, margin) > 0,
+                collateral: (collateral as f64 * (1.0 + collateral_buffer)).ceil() as u64,
+            });
+        }
+    }
+    liquidations.sort_by(|a, b| {
+        b.shortfall
+            .partial_cmp(&a.shortfall)
+            .unwrap_or(Ordering::Equal)
+    });
+    liquidations
+}
+
+/// Builds the `TryToLiquidate` instruction for a planned liquidation, signed by `liquidator`.
+/// Collateral comes from `liquidator_account`, or the liquidator's associated token account for
+/// the swap's mint, and non-primary collateral lands in the liquidator's associated token accounts.
+pub fn liquidate_instruction(
+    program_id: &Pubkey,
+    swap_accounts: &SwapAccounts,
+    liquidation: &Liquidation,
+    liquidator: &Pubkey,
+    liquidator_account: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let perpetual_swap = &swap_accounts.perpetual_swap;
+    let authority =
+        Processor::authority_id(program_id, &swap_accounts.pubkey, perpetual_swap.authority_bump)?;
+    let liquidator_account = liquidator_account.copied().unwrap_or_else(|| {
+        get_associated_token_address(liquidator, &perpetual_swap.collateral_mint)
+    });
+    let (liquidated_margin, counterparty_margin, counterparty_account) = if liquidation.is_long {
+        (
+            perpetual_swap.long_margin_pubkey,
+            perpetual_swap.short_margin_pubkey,
+            perpetual_swap.short_account_pubkey,
+        )
+    } else {
+        (
+            perpetual_swap.short_margin_pubkey,
+            perpetual_swap.long_margin_pubkey,
+            perpetual_swap.long_account_pubkey,
+        )
+    };
+    let assets: Vec<_> = swap_accounts
+        .collateral_config
+        .iter()
+        .flat_map(|collateral_config| collateral_config.assets().to_vec())
+        .collect();
+    let collateral_vaults: Vec<(Pubkey, Pubkey, Pubkey)> = assets
+        .iter()
+        .map(|asset| {
+            (
+                asset.vault_pubkey(liquidation.is_long),
+                get_associated_token_address(liquidator, &asset.mint),
+                asset.mint,
+            )
+        })
+        .collect();
+    // A force-closed counterparty gets its collateral back in its owner's associated accounts
+    let counterparty_collateral_vaults: Vec<(Pubkey, Pubkey, Pubkey)> =
+        if perpetual_swap.liquidation_mode == LiquidationMode::AutoDeleverage {
+            let counterparty_owner = perpetual_swap.owner(!liquidation.is_long);
+            assets
+                .iter()
+                .map(|asset| {
+                    (
+                        asset.vault_pubkey(!liquidation.is_long),
+                        get_associated_token_address(counterparty_owner, &asset.mint),
+                        asset.mint,
+                    )
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+    instruction::try_to_liquidate(
+        program_id,
+        &swap_accounts.pubkey,
+        &authority,
+        liquidator,
+        &liquidated_margin,
+        &liquidator_account,
+        &perpetual_swap.insurance_fund_pubkey,
+        &counterparty_margin,
+        &counterparty_account,
+        &perpetual_swap.token_program_id,
+        &perpetual_swap.collateral_mint,
+        swap_accounts
+            .collateral_config
+            .as_ref()
+            .map(|_| &perpetual_swap.collateral_config_pubkey),
+        &collateral_vaults,
+        &counterparty_collateral_vaults,
+        liquidation.collateral,
+    )
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use borsh::BorshDeserialize;
+    #[cfg(test)]
+    use spl_perpetual::state::{MarginTier, PerpetualSwap, VammSide};
+    #[cfg(test)]
+    use spl_token::state::Account as TokenAccount;
+
+    #[cfg(test)]
+    fn swap_accounts(mark_price: f64, long_margin: u64, short_margin: u64) -> SwapAccounts {
+        let mut perpetual_swap = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        perpetual_swap.is_long_initialized = true;
+        perpetual_swap.is_short_initialized = true;
+        perpetual_swap.entry_price = 100.0;
+        perpetual_swap.mark_price = mark_price;
+        perpetual_swap.position_size = 1.0;
+        perpetual_swap.num_margin_tiers = 1;
+        perpetual_swap.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+        SwapAccounts {
+            pubkey: Pubkey::new_unique(),
+            perpetual_swap,
+            long_margin: TokenAccount {
+                amount: long_margin,
+                ..TokenAccount::default()
+            },
+            short_margin: TokenAccount {
+                amount: short_margin,
+                ..TokenAccount::default()
+            },
+            collateral_config: None,
+            collateral_balances: vec![],
+        }
+    }
+
+    #[test]
+    pub fn test_plan_liquidations() {
+        let healthy = swap_accounts(100.0, 20, 20);
+        // Long equity 3 against a 4.5 maintenance margin
+        let underwater = swap_accounts(90.0, 13, 20);
+        // Short equity -10
+        let bankrupt = swap_accounts(130.0, 40, 20);
+
+        let liquidations = plan_liquidations(&[healthy, underwater.clone(), bankrupt.clone()], 0.1);
+        assert_eq!(liquidations.len(), 2);
+
+        assert_eq!(liquidations[0].swap, bankrupt.pubkey);
+        assert!(!liquidations[0].is_long);
+        assert!(liquidations[0].is_bankrupt);
+        assert_eq!(liquidations[0].collateral, 15);
+
+        assert_eq!(liquidations[1].swap, underwater.pubkey);
+        assert!(liquidations[1].is_long);
+        assert!(!liquidations[1].is_bankrupt);
+        assert!((liquidations[1].shortfall - 1.5).abs() < 1e-9);
+        assert_eq!(liquidations[1].collateral, 21);
+
+        // The vAMM's side is never liquidated, however far under water
+        let mut vamm_short = bankrupt.clone();
+        vamm_short.perpetual_swap.vamm_side = VammSide::Short;
+        assert!(plan_liquidations(&[vamm_short], 0.1).is_empty());
+    }
+}
@@ -0,0 +1,379 @@
+#![cfg(feature = "test-validator")]
+
+//! Runs against a live `solana-test-validator` with the program deployed, e.g.
+//! `solana-test-validator --bpf-program Perp111111111111111111111111111111111111111 target/deploy/spl_perpetual.so`.
+//! The validator funds the Solana CLI keypair, which pays for the test. `SOLANA_URL` and
+//! `PERP_PROGRAM_ID` override the defaults.
+//!
+//! Swaps read their index from Pyth, so the validator also needs a Pyth product and its price
+//! account, e.g. with `--url mainnet-beta --clone <product> --clone <price>`. `PYTH_PRODUCT` and
+//! `PYTH_PRICE` name them.
+//!
+//! The payer is the swap's admin, so the test can move the mark price within the band around that
+//! index and push the long below maintenance margin before liquidating it.
+
+use std::{env, str::FromStr};
+
+use borsh::BorshDeserialize;
+use solana_client::rpc_client::RpcClient;
+use solana_program::{program_pack::Pack, pubkey::Pubkey, system_instruction};
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    signature::{read_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use spl_perpetual::{
+    instruction::{initialize_perpetual_swap, initialize_side, oracle_update_index, update_prices},
+    processor::Processor,
+    state::{ContractType, LiquidationMode, MarketKind, PerpetualSwap},
+};
+use spl_perpetual_keeper::{
+    accounts::{fetch_perpetual_swaps, fetch_swap_accounts, SwapAccounts},
+    liquidator::{liquidate_instruction, plan_liquidations},
+};
+use spl_token::state::{Account as TokenAccount, Mint};
+
+/// Tokens minted to each test account
+const TOKEN_SUPPLY: u64 = 1_000_000_000_000_000;
+/// Sized so that a 10% margin is many tokens whatever the oracle's price
+const POSITION_SIZE: f64 = 1_000.0;
+
+fn rpc_client() -> RpcClient {
+    let url = env::var("SOLANA_URL").unwrap_or_else(|_| "http://127.0.0.1:8899".to_string());
+    RpcClient::new_with_commitment(url, CommitmentConfig::confirmed())
+}
+
+fn program_id() -> Pubkey {
+    env::var("PERP_PROGRAM_ID")
+        .map(|program_id| Pubkey::from_str(&program_id).unwrap())
+        .unwrap_or_else(|_| spl_perpetual::id())
+}
+
+/// The Pyth product and price accounts the test swaps are bound to.
+fn pyth_oracle() -> (Pubkey, Pubkey) {
+    let pubkey = |name| {
+        Pubkey::from_str(&env::var(name).unwrap_or_else(|_| panic!("{} is not set", name))).unwrap()
+    };
+    (pubkey("PYTH_PRODUCT"), pubkey("PYTH_PRICE"))
+}
+
+fn payer() -> Keypair {
+    let config_file = solana_cli_config::CONFIG_FILE.as_ref().unwrap();
+    let cli_config = solana_cli_config::Config::load(config_file).unwrap_or_default();
+    read_keypair_file(&cli_config.keypair_path).unwrap()
+}
+
+fn send(
+    rpc_client: &RpcClient,
+    instructions: &[Instruction],
+    payer: &Keypair,
+    signers: &[&Keypair],
+) {
+    let (recent_blockhash, _fee_calculator) = rpc_client.get_recent_blockhash().unwrap();
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&payer.pubkey()),
+        &all_signers,
+        recent_blockhash,
+    );
+    rpc_client
+        .send_and_confirm_transaction(&transaction)
+        .unwrap();
+}
+
+fn create_account(
+    rpc_client: &RpcClient,
+    payer: &Keypair,
+    account: &Keypair,
+    len: usize,
+    owner: &Pubkey,
+) -> Instruction {
+    system_instruction::create_account(
+        &payer.pubkey(),
+        &account.pubkey(),
+        rpc_client
+            .get_minimum_balance_for_rent_exemption(len)
+            .unwrap(),
+        len as u64,
+        owner,
+    )
+}
+
+/// Decodes the swap as the program last wrote it.
+fn fetch_swap(rpc_client: &RpcClient, pubkey: &Pubkey) -> PerpetualSwap {
+    PerpetualSwap::try_from_slice(&rpc_client.get_account_data(pubkey).unwrap()).unwrap()
+}
+
+fn token_balance(rpc_client: &RpcClient, pubkey: &Pubkey) -> u64 {
+    TokenAccount::unpack(&rpc_client.get_account_data(pubkey).unwrap())
+        .unwrap()
+        .amount
+}
+
+/// Scans the program's swaps and returns the accounts of `swap`.
+fn scan(rpc_client: &RpcClient, program_id: &Pubkey, swap: &Pubkey) -> Vec<SwapAccounts> {
+    let swaps = fetch_swap_accounts(
+        rpc_client,
+        fetch_perpetual_swaps(rpc_client, program_id).unwrap(),
+    )
+    .unwrap();
+    assert!(
+        swaps
+            .iter()
+            .any(|swap_accounts| swap_accounts.pubkey == *swap),
+        "initialized swap is scanned"
+    );
+    swaps
+}
+
+#[test]
+fn test_scan_and_liquidate() {
+    let rpc_client = rpc_client();
+    let program_id = program_id();
+    let payer = payer();
+
+    // Mint and the funded token accounts of both users and the liquidator
+    let mint = Keypair::new();
+    let long_user = Keypair::new();
+    let short_user = Keypair::new();
+    let liquidator = Keypair::new();
+    let liquidator_account = Keypair::new();
+    let mut instructions = vec![
+        create_account(&rpc_client, &payer, &mint, Mint::LEN, &spl_token::id()),
+        spl_token::instruction::initialize_mint(
+            &spl_token::id(),
+            &mint.pubkey(),
+            &payer.pubkey(),
+            None,
+            0,
+        )
+        .unwrap(),
+    ];
+    for (account, owner) in [
+        (&long_user, payer.pubkey()),
+        (&short_user, payer.pubkey()),
+        (&liquidator_account, liquidator.pubkey()),
+    ]
+    .iter()
+    {
+        instructions.push(create_account(
+            &rpc_client,
+            &payer,
+            account,
+            TokenAccount::LEN,
+            &spl_token::id(),
+        ));
+        instructions.push(
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &account.pubkey(),
+                &mint.pubkey(),
+                owner,
+            )
+            .unwrap(),
+        );
+        instructions.push(
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &account.pubkey(),
+                &payer.pubkey(),
+                &[],
+                TOKEN_SUPPLY,
+            )
+            .unwrap(),
+        );
+    }
+    send(
+        &rpc_client,
+        &instructions,
+        &payer,
+        &[&mint, &long_user, &short_user, &liquidator_account],
+    );
+
+    // The swap and its program-initialized token accounts. The program creates the margin
+    // accounts at their own addresses.
+    let perpetual_swap = Keypair::new();
+    let insurance_fund = Keypair::new();
+    let (pyth_product, pyth_price) = pyth_oracle();
+    let (authority, _) = Processor::find_authority_address(&program_id, &perpetual_swap.pubkey());
+    let (long_margin, _) =
+        Processor::find_margin_address(&program_id, &perpetual_swap.pubkey(), true);
+    let (short_margin, _) =
+        Processor::find_margin_address(&program_id, &perpetual_swap.pubkey(), false);
+    let mut instructions = vec![create_account(
+        &rpc_client,
+        &payer,
+        &perpetual_swap,
+        PerpetualSwap::LEN,
+        &program_id,
+    )];
+    instructions.push(create_account(
+        &rpc_client,
+        &payer,
+        &insurance_fund,
+        TokenAccount::LEN,
+        &spl_token::id(),
+    ));
+    instructions.push(
+        initialize_perpetual_swap(
+            &program_id,
+            &perpetual_swap.pubkey(),
+            &authority,
+            &long_margin,
+            &short_margin,
+            &insurance_fund.pubkey(),
+            &mint.pubkey(),
+            &spl_token::id(),
+            &payer.pubkey(),
+            &pyth_product,
+            &pyth_price,
+            None,
+            0.01,
+            0.1,
+            0.05,
+            0,
+            LiquidationMode::AutoDeleverage,
+            POSITION_SIZE,
+            MarketKind::Perpetual,
+            0,
+            0,
+            ContractType::Linear,
+            0.0,
+        )
+        .unwrap(),
+    );
+    instructions.push(
+        oracle_update_index(
+            &program_id,
+            &perpetual_swap.pubkey(),
+            &pyth_product,
+            &pyth_price,
+            None,
+        )
+        .unwrap(),
+    );
+    send(
+        &rpc_client,
+        &instructions,
+        &payer,
+        &[&perpetual_swap, &insurance_fund],
+    );
+
+    // The payer is the swap's admin, so it marks the swap at the index it just read
+    let index_price = fetch_swap(&rpc_client, &perpetual_swap.pubkey()).index_price;
+    assert!(index_price > 0.0);
+    let update_prices = |mark_price: f64| {
+        update_prices(
+            &program_id,
+            &perpetual_swap.pubkey(),
+            &payer.pubkey(),
+            &authority,
+            &spl_token::id(),
+            &pyth_product,
+            &pyth_price,
+            None,
+            mark_price,
+        )
+        .unwrap()
+    };
+    send(&rpc_client, &[update_prices(index_price)], &payer, &[]);
+
+    // The long posts a little over the 10% initial margin, the short plenty
+    let notional = POSITION_SIZE * index_price;
+    let mut instructions = vec![];
+    for (user, margin, deposit) in [
+        (&long_user, &long_margin, (notional * 0.12).ceil() as u64),
+        (&short_user, &short_margin, (notional * 0.5).ceil() as u64),
+    ]
+    .iter()
+    {
+        instructions.push(
+            initialize_side(
+                &program_id,
+                &perpetual_swap.pubkey(),
+                &authority,
+                &payer.pubkey(),
+                &user.pubkey(),
+                margin,
+                &spl_token::id(),
+                &mint.pubkey(),
+                None,
+                None,
+                None,
+                *deposit,
+            )
+            .unwrap(),
+        );
+    }
+    send(&rpc_client, &instructions, &payer, &[]);
+
+    let swaps = scan(&rpc_client, &program_id, &perpetual_swap.pubkey());
+    let swap_accounts = swaps
+        .iter()
+        .find(|swap_accounts| swap_accounts.pubkey == perpetual_swap.pubkey())
+        .unwrap();
+    assert_eq!(
+        swap_accounts.long_margin.amount,
+        (notional * 0.12).ceil() as u64
+    );
+    assert_eq!(
+        swap_accounts.perpetual_swap.long_account_pubkey,
+        long_user.pubkey()
+    );
+    // The mark hasn't moved from the entry, so neither side is liquidatable
+    assert!(plan_liquidations(&swaps, 0.05)
+        .iter()
+        .all(|liquidation| liquidation.swap != perpetual_swap.pubkey()));
+
+    // A 5% drop leaves the long's equity below its maintenance margin
+    send(
+        &rpc_client,
+        &[update_prices(index_price * 0.95)],
+        &payer,
+        &[],
+    );
+    let swaps = scan(&rpc_client, &program_id, &perpetual_swap.pubkey());
+    let swap_accounts = swaps
+        .iter()
+        .find(|swap_accounts| swap_accounts.pubkey == perpetual_swap.pubkey())
+        .unwrap();
+    let liquidation = plan_liquidations(&swaps, 0.05)
+        .into_iter()
+        .find(|liquidation| liquidation.swap == perpetual_swap.pubkey())
+        .expect("the long is planned for liquidation");
+    assert!(liquidation.is_long);
+    assert!(!liquidation.is_bankrupt);
+
+    let liquidated_margin = swap_accounts.long_margin.amount;
+    let instruction = liquidate_instruction(
+        &program_id,
+        swap_accounts,
+        &liquidation,
+        &liquidator.pubkey(),
+        Some(&liquidator_account.pubkey()),
+    )
+    .unwrap();
+    send(&rpc_client, &[instruction], &payer, &[&liquidator]);
+
+    // The liquidator took over the long, posting its collateral and collecting the bounty
+    let swap = fetch_swap(&rpc_client, &perpetual_swap.pubkey());
+    assert_eq!(swap.long_account_pubkey, liquidator_account.pubkey());
+    assert_eq!(swap.long_owner, liquidator.pubkey());
+    assert_eq!(
+        token_balance(&rpc_client, &long_margin),
+        liquidation.collateral
+    );
+    let bounty = (0.05 * liquidated_margin as f64) as u64;
+    assert_eq!(
+        token_balance(&rpc_client, &liquidator_account.pubkey()),
+        TOKEN_SUPPLY - liquidation.collateral + bounty
+    );
+    let swaps = scan(&rpc_client, &program_id, &perpetual_swap.pubkey());
+    assert!(plan_liquidations(&swaps, 0.05)
+        .iter()
+        .all(|liquidation| liquidation.swap != perpetual_swap.pubkey()));
+}
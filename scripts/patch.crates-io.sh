@@ -13,6 +13,7 @@ fi
 
 workspace_crates=(
   "$here"/../Cargo.toml
+  "$here"/../keeper/Cargo.toml
 )
 
 if [[ ! -r "$solana_dir"/scripts/read-cargo-variable.sh ]]; then
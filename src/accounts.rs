@@ -0,0 +1,3295 @@
+//! Account lists of the instructions, parsed and checked once before a handler runs.
+//!
+//! Each `*Accounts` struct takes the accounts in the order documented on
+//! `PerpetualSwapInstruction` and enforces every constraint that doesn't depend on the
+//! instruction data or on token balances. A failed check logs the name of the account.
+
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use std::slice::Iter;
+
+use borsh::BorshDeserialize;
+
+use crate::{
+    error::PerpetualSwapError,
+    processor::Processor,
+    pyth,
+    state::{
+        CollateralAsset, CollateralConfig, CrossMarginAccount, MarketKind, PerpetualSwap,
+        ReferralLink, Referrer,
+    },
+};
+
+/// A constraint on one account of an instruction.
+#[derive(Clone, Copy)]
+pub enum Constraint<'k> {
+    /// Signed the transaction
+    Signer,
+    /// Passed as writable
+    Writable,
+    /// Owned by the given program
+    OwnedBy(&'k Pubkey),
+    /// Has the given key, usually one stored on the swap
+    Address(&'k Pubkey),
+    /// Is the given program address, e.g. the swap authority
+    ProgramAddress(&'k Pubkey),
+    /// Is the given token program
+    TokenProgram(&'k Pubkey),
+    /// Is the given collateral mint
+    Mint(&'k Pubkey),
+    /// Is the wallet that owns a position
+    Wallet(&'k Pubkey),
+    /// Is the swap's admin
+    Admin(&'k Pubkey),
+}
+
+impl<'k> Constraint<'k> {
+    /// Why `account_info` breaks the constraint, if it does.
+    fn violation(&self, account_info: &AccountInfo) -> Option<(&'static str, ProgramError)> {
+        match self {
+            Constraint::Signer if !account_info.is_signer => {
+                Some(("must sign", ProgramError::MissingRequiredSignature))
+            }
+            Constraint::Writable if !account_info.is_writable => Some((
+                "must be writable",
+                PerpetualSwapError::AccountNotWritable.into(),
+            )),
+            Constraint::OwnedBy(owner) if account_info.owner != *owner => {
+                Some(("has the wrong owner", ProgramError::IncorrectProgramId))
+            }
+            Constraint::Address(key) if account_info.key != *key => Some((
+                "has the wrong key",
+                PerpetualSwapError::InvalidAccountKeys.into(),
+            )),
+            Constraint::ProgramAddress(key) if account_info.key != *key => Some((
+                "is not the expected program address",
+                PerpetualSwapError::InvalidProgramAddress.into(),
+            )),
+            Constraint::TokenProgram(key) if account_info.key != *key => Some((
+                "is not the swap's token program",
+                PerpetualSwapError::IncorrectTokenProgramId.into(),
+            )),
+            Constraint::Mint(key) if account_info.key != *key => Some((
+                "is not the mint of the collateral",
+                PerpetualSwapError::InvalidMints.into(),
+            )),
+            Constraint::Wallet(key) if account_info.key != *key => Some((
+                "is not the wallet that owns the position",
+                PerpetualSwapError::InvalidOwner.into(),
+            )),
+            Constraint::Admin(key) if account_info.key != *key => Some((
+                "is not the swap's admin",
+                PerpetualSwapError::InvalidAdmin.into(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Takes the next account of the list, logging `name` if the list is too short.
+pub fn next<'a, 'b>(
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+    name: &str,
+) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    account_info_iter.next().ok_or_else(|| {
+        msg!("Missing account {}", name);
+        ProgramError::NotEnoughAccountKeys
+    })
+}
+
+/// Checks `constraints` in order, failing on the first one `account_info` breaks.
+pub fn check(name: &str, account_info: &AccountInfo, constraints: &[Constraint]) -> ProgramResult {
+    match constraints
+        .iter()
+        .find_map(|constraint| constraint.violation(account_info))
+    {
+        Some((reason, error)) => {
+            msg!("Account {} {}", name, reason);
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Fails if two of the named accounts share a key.
+pub fn check_unique(accounts: &[(&str, &AccountInfo)]) -> ProgramResult {
+    for (i, (name, account_info)) in accounts.iter().enumerate() {
+        if let Some((other, _)) = accounts[..i]
+            .iter()
+            .find(|(_, other_info)| other_info.key == account_info.key)
+        {
+            msg!("Account {} is the same as {}", name, other);
+            return Err(PerpetualSwapError::DuplicateAccount.into());
+        }
+    }
+    Ok(())
+}
+
+/// Deserializes the swap after checking it belongs to this program, and that it is writable if
+/// the instruction updates it.
+fn load_perpetual_swap(
+    program_id: &Pubkey,
+    perpetual_swap_info: &AccountInfo,
+    is_writable: bool,
+) -> Result<PerpetualSwap, ProgramError> {
+    check(
+        "perpetual_swap",
+        perpetual_swap_info,
+        &[Constraint::OwnedBy(program_id)],
+    )?;
+    if is_writable {
+        check(
+            "perpetual_swap",
+            perpetual_swap_info,
+            &[Constraint::Writable],
+        )?;
+    }
+    Ok(PerpetualSwap::try_from_slice(
+        &perpetual_swap_info.data.borrow(),
+    )?)
+}
+
+/// Checks the swap authority, derived from the bump stored on the swap.
+fn check_authority(
+    program_id: &Pubkey,
+    perpetual_swap_info: &AccountInfo,
+    perpetual_swap: &PerpetualSwap,
+    authority_info: &AccountInfo,
+) -> ProgramResult {
+    let authority_pubkey = Processor::authority_id(
+        program_id,
+        perpetual_swap_info.key,
+        perpetual_swap.authority_bump,
+    )?;
+    check(
+        "authority",
+        authority_info,
+        &[Constraint::ProgramAddress(&authority_pubkey)],
+    )
+}
+
+/// Checks the swap authority, token program and collateral mint of an instruction moving the
+/// swap's collateral.
+fn check_token_accounts(
+    program_id: &Pubkey,
+    perpetual_swap_info: &AccountInfo,
+    perpetual_swap: &PerpetualSwap,
+    authority_info: &AccountInfo,
+    token_program_info: &AccountInfo,
+    mint_info: &AccountInfo,
+) -> ProgramResult {
+    check_authority(
+        program_id,
+        perpetual_swap_info,
+        perpetual_swap,
+        authority_info,
+    )?;
+    check(
+        "token_program",
+        token_program_info,
+        &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+    )?;
+    check(
+        "collateral_mint",
+        mint_info,
+        &[Constraint::Mint(&perpetual_swap.collateral_mint)],
+    )
+}
+
+/// Which side `margin_info` is the margin account of.
+fn margin_side(
+    perpetual_swap: &PerpetualSwap,
+    margin_info: &AccountInfo,
+) -> Result<bool, ProgramError> {
+    if *margin_info.key == perpetual_swap.long_margin_pubkey {
+        Ok(true)
+    } else if *margin_info.key == perpetual_swap.short_margin_pubkey {
+        Ok(false)
+    } else {
+        msg!("Account margin is neither margin account of the swap");
+        Err(PerpetualSwapError::InvalidAccountKeys.into())
+    }
+}
+
+/// Checks that `owner_info` signed for a side and owns it. A tokenized side is owned by whoever
+/// holds its position token, so the token account holding it is taken next from the list.
+pub fn check_side_owner<'a, 'b>(
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+    perpetual_swap: &PerpetualSwap,
+    is_long: bool,
+    owner_info: &AccountInfo,
+) -> ProgramResult {
+    if !perpetual_swap.is_tokenized(is_long) {
+        return check(
+            "owner",
+            owner_info,
+            &[
+                Constraint::Signer,
+                Constraint::Wallet(perpetual_swap.owner(is_long)),
+            ],
+        );
+    }
+    check("owner", owner_info, &[Constraint::Signer])?;
+    let position_token_info = next(account_info_iter, "position_token")?;
+    let position_token =
+        Processor::unpack_token_account(position_token_info, &perpetual_swap.token_program_id)?;
+    if position_token.mint != *perpetual_swap.position_mint(is_long) {
+        msg!("Account position_token doesn't hold the side's position token");
+        return Err(PerpetualSwapError::InvalidMints.into());
+    }
+    if position_token.owner != *owner_info.key || position_token.amount == 0 {
+        msg!("Account position_token isn't held by the owner");
+        return Err(PerpetualSwapError::InvalidOwner.into());
+    }
+    Ok(())
+}
+
+/// Checks the Pyth product and price accounts are the ones the swap's index was bound to at init.
+fn check_oracle(
+    perpetual_swap: &PerpetualSwap,
+    pyth_product_info: &AccountInfo,
+    pyth_price_info: &AccountInfo,
+) -> ProgramResult {
+    check(
+        "pyth_product",
+        pyth_product_info,
+        &[
+            Constraint::OwnedBy(&pyth::id()),
+            Constraint::Address(&perpetual_swap.oracle_product_pubkey),
+        ],
+    )?;
+    check(
+        "pyth_price",
+        pyth_price_info,
+        &[
+            Constraint::OwnedBy(&pyth::id()),
+            Constraint::Address(&perpetual_swap.oracle_price_pubkey),
+        ],
+    )
+}
+
+/// The Pyth product and price accounts of the FX rate, taken next from the list if the swap is a
+/// quanto market. They must be the ones bound at init.
+fn fx_oracle_infos<'a, 'b>(
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+    perpetual_swap: &PerpetualSwap,
+) -> Result<Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>, ProgramError> {
+    if perpetual_swap.kind != MarketKind::Quanto {
+        return Ok(None);
+    }
+    let fx_product_info = next(account_info_iter, "fx_product")?;
+    let fx_price_info = next(account_info_iter, "fx_price")?;
+    check(
+        "fx_product",
+        fx_product_info,
+        &[
+            Constraint::OwnedBy(&pyth::id()),
+            Constraint::Address(&perpetual_swap.fx_product_pubkey),
+        ],
+    )?;
+    check(
+        "fx_price",
+        fx_price_info,
+        &[
+            Constraint::OwnedBy(&pyth::id()),
+            Constraint::Address(&perpetual_swap.fx_price_pubkey),
+        ],
+    )?;
+    Ok(Some((fx_product_info, fx_price_info)))
+}
+
+/// The swap's fee vault, taken next from the list if the swap charges fees.
+fn fee_vault_info<'a, 'b>(
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+    perpetual_swap: &PerpetualSwap,
+) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+    if !perpetual_swap.charges_fees() {
+        return Ok(None);
+    }
+    let fee_vault_info = next(account_info_iter, "fee_vault")?;
+    check(
+        "fee_vault",
+        fee_vault_info,
+        &[
+            Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+            Constraint::Writable,
+        ],
+    )?;
+    Ok(Some(fee_vault_info))
+}
+
+/// Deserializes a referrer after checking it belongs to this program and was initialized.
+fn load_referrer(
+    program_id: &Pubkey,
+    referrer_info: &AccountInfo,
+) -> Result<Referrer, ProgramError> {
+    check(
+        "referrer",
+        referrer_info,
+        &[Constraint::OwnedBy(program_id)],
+    )?;
+    let referrer = Referrer::try_from_slice(&referrer_info.data.borrow())?;
+    if !referrer.is_initialized {
+        msg!("Account referrer is not initialized");
+        return Err(PerpetualSwapError::AccountNotInitialized.into());
+    }
+    Ok(referrer)
+}
+
+/// The referrer of a taker, which gets a share of the taker's fee
+pub struct ReferralInfos<'a, 'b> {
+    pub referrer_info: &'a AccountInfo<'b>,
+    pub referrer: Referrer,
+    pub vault_info: &'a AccountInfo<'b>,
+}
+
+/// The taker's referral link, taken next from the list if the swap pays referral rebates, and
+/// then the referrer and its vault if the taker has linked one. A referrer paid in another mint
+/// than the swap's collateral earns nothing on the swap.
+fn referral_infos<'a, 'b>(
+    program_id: &Pubkey,
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+    perpetual_swap: &PerpetualSwap,
+    taker: &Pubkey,
+) -> Result<Option<ReferralInfos<'a, 'b>>, ProgramError> {
+    if !perpetual_swap.pays_referral_rebates() {
+        return Ok(None);
+    }
+    let referral_link_info = next(account_info_iter, "referral_link")?;
+    let (referral_link_pubkey, _) = Processor::find_referral_link_address(program_id, taker);
+    check(
+        "referral_link",
+        referral_link_info,
+        &[Constraint::ProgramAddress(&referral_link_pubkey)],
+    )?;
+    if referral_link_info.data_is_empty() {
+        return Ok(None);
+    }
+    check(
+        "referral_link",
+        referral_link_info,
+        &[Constraint::OwnedBy(program_id)],
+    )?;
+    let referral_link = ReferralLink::try_from_slice(&referral_link_info.data.borrow())?;
+
+    let referrer_info = next(account_info_iter, "referrer")?;
+    let vault_info = next(account_info_iter, "referrer_vault")?;
+    check(
+        "referrer",
+        referrer_info,
+        &[
+            Constraint::Address(&referral_link.referrer),
+            Constraint::Writable,
+        ],
+    )?;
+    let referrer = load_referrer(program_id, referrer_info)?;
+    check(
+        "referrer_vault",
+        vault_info,
+        &[
+            Constraint::Address(&referrer.vault_pubkey),
+            Constraint::Writable,
+        ],
+    )?;
+    if referrer.mint != perpetual_swap.collateral_mint {
+        return Ok(None);
+    }
+    Ok(Some(ReferralInfos {
+        referrer_info,
+        referrer,
+        vault_info,
+    }))
+}
+
+/// Accounts of `InitializeSide`
+pub struct InitializeSideAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub source_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The fee vault, if the swap charges fees
+    pub fee_vault_info: Option<&'a AccountInfo<'b>>,
+    /// The owner's referrer, if the side completes the swap and the owner is referred
+    pub referral_infos: Option<ReferralInfos<'a, 'b>>,
+    /// The position mint and the account receiving the position token, if the side is tokenized
+    pub position_token_infos: Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>,
+    pub is_long: bool,
+}
+
+impl<'a, 'b> InitializeSideAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let source_info = next(account_info_iter, "source")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        // The wallet funding the side owns the position from here on
+        check("owner", owner_info, &[Constraint::Signer])?;
+        check("source", source_info, &[Constraint::Writable])?;
+        check("margin", margin_info, &[Constraint::Writable])?;
+        check_unique(&[("source", source_info), ("margin", margin_info)])?;
+        let fee_vault_info = fee_vault_info(account_info_iter, &perpetual_swap)?;
+        // Only the side completing the swap is a taker
+        let referral_infos =
+            if perpetual_swap.is_long_initialized || perpetual_swap.is_short_initialized {
+                referral_infos(
+                    program_id,
+                    account_info_iter,
+                    &perpetual_swap,
+                    owner_info.key,
+                )?
+            } else {
+                None
+            };
+
+        let position_token_infos = match account_info_iter.next() {
+            Some(position_mint_info) => {
+                let position_token_info = next(account_info_iter, "position_token")?;
+                check(
+                    "position_mint",
+                    position_mint_info,
+                    &[
+                        Constraint::OwnedBy(&perpetual_swap.token_program_id),
+                        Constraint::Writable,
+                    ],
+                )?;
+                check(
+                    "position_token",
+                    position_token_info,
+                    &[Constraint::Writable],
+                )?;
+                Some((position_mint_info, position_token_info))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            source_info,
+            margin_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+            position_token_infos,
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `OpenWithVamm`
+pub struct OpenWithVammAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub source_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub vamm_margin_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The fee vault, if the swap charges fees
+    pub fee_vault_info: Option<&'a AccountInfo<'b>>,
+    /// The owner's referrer, if the owner is referred
+    pub referral_infos: Option<ReferralInfos<'a, 'b>>,
+}
+
+impl<'a, 'b> OpenWithVammAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+        is_long: bool,
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let source_info = next(account_info_iter, "source")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let vamm_margin_info = next(account_info_iter, "vamm_margin")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let (margin_pubkey, vamm_margin_pubkey) = if is_long {
+            (
+                perpetual_swap.long_margin_pubkey,
+                perpetual_swap.short_margin_pubkey,
+            )
+        } else {
+            (
+                perpetual_swap.short_margin_pubkey,
+                perpetual_swap.long_margin_pubkey,
+            )
+        };
+        check("owner", owner_info, &[Constraint::Signer])?;
+        check("source", source_info, &[Constraint::Writable])?;
+        check(
+            "margin",
+            margin_info,
+            &[Constraint::Address(&margin_pubkey), Constraint::Writable],
+        )?;
+        check(
+            "vamm_margin",
+            vamm_margin_info,
+            &[
+                Constraint::Address(&vamm_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "insurance_fund",
+            insurance_fund_info,
+            &[
+                Constraint::Address(&perpetual_swap.insurance_fund_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check_unique(&[
+            ("source", source_info),
+            ("margin", margin_info),
+            ("vamm_margin", vamm_margin_info),
+            ("insurance_fund", insurance_fund_info),
+        ])?;
+        let fee_vault_info = fee_vault_info(account_info_iter, &perpetual_swap)?;
+        let referral_infos = referral_infos(
+            program_id,
+            account_info_iter,
+            &perpetual_swap,
+            owner_info.key,
+        )?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            source_info,
+            margin_info,
+            vamm_margin_info,
+            insurance_fund_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+        })
+    }
+}
+
+/// Accounts of `DepositToMargin`
+pub struct DepositAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub source_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub is_long: bool,
+}
+
+impl<'a, 'b> DepositAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let source_info = next(account_info_iter, "source")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        // The holder of a tokenized side deposits from any account of theirs
+        if !perpetual_swap.is_tokenized(is_long) {
+            let account_pubkey = if is_long {
+                perpetual_swap.long_account_pubkey
+            } else {
+                perpetual_swap.short_account_pubkey
+            };
+            check(
+                "source",
+                source_info,
+                &[Constraint::Address(&account_pubkey)],
+            )?;
+        }
+        check("source", source_info, &[Constraint::Writable])?;
+        check("margin", margin_info, &[Constraint::Writable])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            source_info,
+            margin_info,
+            token_program_info,
+            mint_info,
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `WithdrawFromMargin`
+pub struct WithdrawAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub dest_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The collateral config and the side's vaults, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+    pub is_long: bool,
+}
+
+impl<'a, 'b> WithdrawAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let dest_info = next(account_info_iter, "destination")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        // Only the owner can withdraw
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        check("margin", margin_info, &[Constraint::Writable])?;
+        check("destination", dest_info, &[Constraint::Writable])?;
+        check_unique(&[("margin", margin_info), ("destination", dest_info)])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            margin_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            collateral_infos: account_info_iter.as_slice(),
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `TransferPosition`
+pub struct TransferPositionAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub seller_info: &'a AccountInfo<'b>,
+    pub seller_account_info: &'a AccountInfo<'b>,
+    pub buyer_info: &'a AccountInfo<'b>,
+    pub buyer_account_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The fee vault, if the swap charges fees
+    pub fee_vault_info: Option<&'a AccountInfo<'b>>,
+    /// The buyer's referrer, if the buyer is referred
+    pub referral_infos: Option<ReferralInfos<'a, 'b>>,
+    /// The collateral config and the side's vaults, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> TransferPositionAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        is_long: bool,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let seller_info = next(account_info_iter, "seller")?;
+        let seller_account_info = next(account_info_iter, "seller_account")?;
+        let buyer_info = next(account_info_iter, "buyer")?;
+        let buyer_account_info = next(account_info_iter, "buyer_account")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        let margin_pubkey = if is_long {
+            perpetual_swap.long_margin_pubkey
+        } else {
+            perpetual_swap.short_margin_pubkey
+        };
+        check(
+            "margin",
+            margin_info,
+            &[Constraint::Address(&margin_pubkey)],
+        )?;
+        check(
+            "seller",
+            seller_info,
+            &[
+                Constraint::Signer,
+                Constraint::Wallet(perpetual_swap.owner(is_long)),
+            ],
+        )?;
+        check(
+            "seller_account",
+            seller_account_info,
+            &[Constraint::Writable],
+        )?;
+        check("buyer", buyer_info, &[Constraint::Signer])?;
+        check("buyer_account", buyer_account_info, &[Constraint::Writable])?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+        )?;
+        check(
+            "collateral_mint",
+            mint_info,
+            &[Constraint::Mint(&perpetual_swap.collateral_mint)],
+        )?;
+        check_unique(&[
+            ("margin", margin_info),
+            ("seller_account", seller_account_info),
+            ("buyer_account", buyer_account_info),
+        ])?;
+        let fee_vault_info = fee_vault_info(account_info_iter, &perpetual_swap)?;
+        let referral_infos = referral_infos(
+            program_id,
+            account_info_iter,
+            &perpetual_swap,
+            buyer_info.key,
+        )?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            margin_info,
+            seller_info,
+            seller_account_info,
+            buyer_info,
+            buyer_account_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+            collateral_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Accounts of `SplitPosition`
+pub struct SplitPositionAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub new_swap_info: &'a AccountInfo<'b>,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub new_authority_info: &'a AccountInfo<'b>,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    pub new_long_margin_info: &'a AccountInfo<'b>,
+    pub new_short_margin_info: &'a AccountInfo<'b>,
+    pub new_insurance_fund_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub counterparty_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub system_program_info: &'a AccountInfo<'b>,
+    /// Bumps of the new swap's authority and long and short margin accounts
+    pub new_bumps: (u8, u8, u8),
+    /// The new swap's fee vault along with its bump, if the swap charges fees
+    pub new_fee_vault: Option<(&'a AccountInfo<'b>, u8)>,
+}
+
+impl<'a, 'b> SplitPositionAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        is_long: bool,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let new_swap_info = next(account_info_iter, "new_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let new_authority_info = next(account_info_iter, "new_authority")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+        let new_long_margin_info = next(account_info_iter, "new_long_margin")?;
+        let new_short_margin_info = next(account_info_iter, "new_short_margin")?;
+        let new_insurance_fund_info = next(account_info_iter, "new_insurance_fund")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let counterparty_info = next(account_info_iter, "counterparty")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let system_program_info = next(account_info_iter, "system_program")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        check(
+            "new_swap",
+            new_swap_info,
+            &[Constraint::OwnedBy(program_id), Constraint::Writable],
+        )?;
+        check(
+            "long_margin",
+            long_margin_info,
+            &[
+                Constraint::Address(&perpetual_swap.long_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "short_margin",
+            short_margin_info,
+            &[
+                Constraint::Address(&perpetual_swap.short_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        let (new_authority_pubkey, new_authority_bump) =
+            Processor::find_authority_address(program_id, new_swap_info.key);
+        check(
+            "new_authority",
+            new_authority_info,
+            &[Constraint::ProgramAddress(&new_authority_pubkey)],
+        )?;
+        let (new_long_margin_pubkey, new_long_margin_bump) =
+            Processor::find_margin_address(program_id, new_swap_info.key, true);
+        check(
+            "new_long_margin",
+            new_long_margin_info,
+            &[
+                Constraint::ProgramAddress(&new_long_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        let (new_short_margin_pubkey, new_short_margin_bump) =
+            Processor::find_margin_address(program_id, new_swap_info.key, false);
+        check(
+            "new_short_margin",
+            new_short_margin_info,
+            &[
+                Constraint::ProgramAddress(&new_short_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "new_insurance_fund",
+            new_insurance_fund_info,
+            &[Constraint::Writable],
+        )?;
+        // The owner of the side being split pays for the new margin accounts
+        check(
+            "owner",
+            owner_info,
+            &[
+                Constraint::Signer,
+                Constraint::Wallet(perpetual_swap.owner(is_long)),
+                Constraint::Writable,
+            ],
+        )?;
+        // Part of the counterparty's margin moves too, so it has to agree to the split
+        check(
+            "counterparty",
+            counterparty_info,
+            &[
+                Constraint::Signer,
+                Constraint::Wallet(perpetual_swap.owner(!is_long)),
+            ],
+        )?;
+        check_unique(&[
+            ("perpetual_swap", perpetual_swap_info),
+            ("new_swap", new_swap_info),
+            ("new_insurance_fund", new_insurance_fund_info),
+        ])?;
+        let new_fee_vault = if perpetual_swap.charges_fees() {
+            let new_fee_vault_info = next(account_info_iter, "new_fee_vault")?;
+            let (new_fee_vault_pubkey, new_fee_vault_bump) =
+                Processor::find_fee_vault_address(program_id, new_swap_info.key);
+            check(
+                "new_fee_vault",
+                new_fee_vault_info,
+                &[
+                    Constraint::ProgramAddress(&new_fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+            Some((new_fee_vault_info, new_fee_vault_bump))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            new_swap_info,
+            authority_info,
+            new_authority_info,
+            long_margin_info,
+            short_margin_info,
+            new_long_margin_info,
+            new_short_margin_info,
+            new_insurance_fund_info,
+            owner_info,
+            counterparty_info,
+            token_program_info,
+            mint_info,
+            rent_info,
+            system_program_info,
+            new_bumps: (
+                new_authority_bump,
+                new_long_margin_bump,
+                new_short_margin_bump,
+            ),
+            new_fee_vault,
+        })
+    }
+}
+
+/// Accounts of `TransferFunds`
+pub struct TransferFundsAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The fee vault and the account receiving the reward, if the swap pays crank rewards
+    pub crank_reward_infos: Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>,
+}
+
+impl<'a, 'b> TransferFundsAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        check(
+            "long_margin",
+            long_margin_info,
+            &[
+                Constraint::Address(&perpetual_swap.long_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "short_margin",
+            short_margin_info,
+            &[
+                Constraint::Address(&perpetual_swap.short_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+
+        let crank_reward_infos = if perpetual_swap.has_fee_vault() {
+            let fee_vault_info = next(account_info_iter, "fee_vault")?;
+            let reward_account_info = next(account_info_iter, "reward_account")?;
+            check(
+                "fee_vault",
+                fee_vault_info,
+                &[
+                    Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+            check(
+                "reward_account",
+                reward_account_info,
+                &[Constraint::Writable],
+            )?;
+            check_unique(&[
+                ("long_margin", long_margin_info),
+                ("short_margin", short_margin_info),
+                ("fee_vault", fee_vault_info),
+                ("reward_account", reward_account_info),
+            ])?;
+            Some((fee_vault_info, reward_account_info))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            crank_reward_infos,
+        })
+    }
+}
+
+/// Accounts of `UpdatePrices`
+pub struct UpdatePricesAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub pyth_product_info: &'a AccountInfo<'b>,
+    pub pyth_price_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The FX rate's product and price accounts, if the swap is a quanto market
+    pub fx_oracle_infos: Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>,
+}
+
+impl<'a, 'b> UpdatePricesAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let pyth_product_info = next(account_info_iter, "pyth_product")?;
+        let pyth_price_info = next(account_info_iter, "pyth_price")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+        let authority_pubkey = Processor::authority_id(
+            program_id,
+            perpetual_swap_info.key,
+            perpetual_swap.authority_bump,
+        )?;
+        check(
+            "authority",
+            authority_info,
+            &[Constraint::ProgramAddress(&authority_pubkey)],
+        )?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+        )?;
+        check_oracle(&perpetual_swap, pyth_product_info, pyth_price_info)?;
+        let fx_oracle_infos = fx_oracle_infos(account_info_iter, &perpetual_swap)?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+            pyth_product_info,
+            pyth_price_info,
+            clock_info,
+            fx_oracle_infos,
+        })
+    }
+}
+
+/// Accounts of `OracleUpdateIndex`
+pub struct OracleUpdateIndexAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub pyth_product_info: &'a AccountInfo<'b>,
+    pub pyth_price_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The FX rate's product and price accounts, if the swap is a quanto market
+    pub fx_oracle_infos: Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>,
+}
+
+impl<'a, 'b> OracleUpdateIndexAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let pyth_product_info = next(account_info_iter, "pyth_product")?;
+        let pyth_price_info = next(account_info_iter, "pyth_price")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_oracle(&perpetual_swap, pyth_product_info, pyth_price_info)?;
+        let fx_oracle_infos = fx_oracle_infos(account_info_iter, &perpetual_swap)?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            pyth_product_info,
+            pyth_price_info,
+            clock_info,
+            fx_oracle_infos,
+        })
+    }
+}
+
+/// Accounts of `ClosePosition`
+pub struct ClosePositionAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub counterparty_margin_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub dest_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The collateral config and vault accounts, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+    pub is_long: bool,
+}
+
+impl<'a, 'b> ClosePositionAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let counterparty_margin_info = next(account_info_iter, "counterparty_margin")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let dest_info = next(account_info_iter, "destination")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        let counterparty_margin_pubkey = if is_long {
+            perpetual_swap.short_margin_pubkey
+        } else {
+            perpetual_swap.long_margin_pubkey
+        };
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        check("margin", margin_info, &[Constraint::Writable])?;
+        check(
+            "counterparty_margin",
+            counterparty_margin_info,
+            &[
+                Constraint::Address(&counterparty_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "insurance_fund",
+            insurance_fund_info,
+            &[
+                Constraint::Address(&perpetual_swap.insurance_fund_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check("destination", dest_info, &[Constraint::Writable])?;
+        check_unique(&[
+            ("margin", margin_info),
+            ("counterparty_margin", counterparty_margin_info),
+            ("insurance_fund", insurance_fund_info),
+            ("destination", dest_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            margin_info,
+            counterparty_margin_info,
+            insurance_fund_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            collateral_infos: account_info_iter.as_slice(),
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `CloseSwap`
+pub struct CloseSwapAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub admin_token_account_info: &'a AccountInfo<'b>,
+    pub receiver_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// The fee vault, if crank rewards are configured
+    pub fee_vault_info: Option<&'a AccountInfo<'b>>,
+    /// The collateral config and vault accounts, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> CloseSwapAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let admin_token_account_info = next(account_info_iter, "admin_token_account")?;
+        let receiver_info = next(account_info_iter, "receiver")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        for (name, account_info, key) in [
+            (
+                "long_margin",
+                long_margin_info,
+                &perpetual_swap.long_margin_pubkey,
+            ),
+            (
+                "short_margin",
+                short_margin_info,
+                &perpetual_swap.short_margin_pubkey,
+            ),
+            (
+                "insurance_fund",
+                insurance_fund_info,
+                &perpetual_swap.insurance_fund_pubkey,
+            ),
+        ]
+        .iter()
+        {
+            check(
+                name,
+                account_info,
+                &[Constraint::Address(key), Constraint::Writable],
+            )?;
+        }
+        check(
+            "admin_token_account",
+            admin_token_account_info,
+            &[Constraint::Writable],
+        )?;
+        check("receiver", receiver_info, &[Constraint::Writable])?;
+
+        let fee_vault_info = if perpetual_swap.has_fee_vault() {
+            let fee_vault_info = next(account_info_iter, "fee_vault")?;
+            check(
+                "fee_vault",
+                fee_vault_info,
+                &[
+                    Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+            check_unique(&[
+                ("admin_token_account", admin_token_account_info),
+                ("fee_vault", fee_vault_info),
+            ])?;
+            Some(fee_vault_info)
+        } else {
+            None
+        };
+        // The swap's lamports would be lost if it received its own rent
+        check_unique(&[
+            ("perpetual_swap", perpetual_swap_info),
+            ("long_margin", long_margin_info),
+            ("short_margin", short_margin_info),
+            ("insurance_fund", insurance_fund_info),
+            ("admin_token_account", admin_token_account_info),
+            ("receiver", receiver_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            receiver_info,
+            token_program_info,
+            mint_info,
+            fee_vault_info,
+            collateral_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Accounts of `ConfigureFees`
+pub struct ConfigureFeesAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub fee_vault_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub system_program_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// Bump of the fee vault, if the swap has none yet and it is to be created
+    pub new_fee_vault_bump: Option<u8>,
+}
+
+impl<'a, 'b> ConfigureFeesAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let fee_vault_info = next(account_info_iter, "fee_vault")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let system_program_info = next(account_info_iter, "system_program")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        // The admin pays for the fee vault
+        check(
+            "admin",
+            admin_info,
+            &[
+                Constraint::Admin(&perpetual_swap.admin),
+                Constraint::Signer,
+                Constraint::Writable,
+            ],
+        )?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let new_fee_vault_bump = if perpetual_swap.has_fee_vault() {
+            check(
+                "fee_vault",
+                fee_vault_info,
+                &[
+                    Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+            None
+        } else {
+            let (fee_vault_pubkey, fee_vault_bump) =
+                Processor::find_fee_vault_address(program_id, perpetual_swap_info.key);
+            check(
+                "fee_vault",
+                fee_vault_info,
+                &[
+                    Constraint::ProgramAddress(&fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+            Some(fee_vault_bump)
+        };
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            fee_vault_info,
+            mint_info,
+            rent_info,
+            token_program_info,
+            admin_info,
+            system_program_info,
+            clock_info,
+            new_fee_vault_bump,
+        })
+    }
+}
+
+/// Accounts of `SweepFees`
+pub struct SweepFeesAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub fee_vault_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub admin_token_account_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> SweepFeesAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let fee_vault_info = next(account_info_iter, "fee_vault")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let admin_token_account_info = next(account_info_iter, "admin_token_account")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        check(
+            "fee_vault",
+            fee_vault_info,
+            &[
+                Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "insurance_fund",
+            insurance_fund_info,
+            &[
+                Constraint::Address(&perpetual_swap.insurance_fund_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "admin_token_account",
+            admin_token_account_info,
+            &[Constraint::Writable],
+        )?;
+        check_unique(&[
+            ("fee_vault", fee_vault_info),
+            ("insurance_fund", insurance_fund_info),
+            ("admin_token_account", admin_token_account_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+            authority_info,
+            fee_vault_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            token_program_info,
+            mint_info,
+        })
+    }
+}
+
+/// Accounts of `InitializeReferrer`
+pub struct InitializeReferrerAccounts<'a, 'b> {
+    pub referrer_info: &'a AccountInfo<'b>,
+    pub referrer: Referrer,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub authority_bump: u8,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> InitializeReferrerAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let referrer_info = next(account_info_iter, "referrer")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let mint_info = next(account_info_iter, "mint")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+
+        check(
+            "referrer",
+            referrer_info,
+            &[Constraint::OwnedBy(program_id), Constraint::Writable],
+        )?;
+        let referrer = Referrer::try_from_slice(&referrer_info.data.borrow())?;
+        let (authority_pubkey, authority_bump) =
+            Processor::find_authority_address(program_id, referrer_info.key);
+        check(
+            "authority",
+            authority_info,
+            &[Constraint::ProgramAddress(&authority_pubkey)],
+        )?;
+        check("vault", vault_info, &[Constraint::Writable])?;
+        check("owner", owner_info, &[Constraint::Signer])?;
+
+        Ok(Self {
+            referrer_info,
+            referrer,
+            authority_info,
+            authority_bump,
+            vault_info,
+            mint_info,
+            owner_info,
+            rent_info,
+            token_program_info,
+        })
+    }
+}
+
+/// Accounts of `LinkReferrer`
+pub struct LinkReferrerAccounts<'a, 'b> {
+    pub referral_link_info: &'a AccountInfo<'b>,
+    pub referral_link_bump: u8,
+    pub referrer_info: &'a AccountInfo<'b>,
+    pub user_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub system_program_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> LinkReferrerAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let referral_link_info = next(account_info_iter, "referral_link")?;
+        let referrer_info = next(account_info_iter, "referrer")?;
+        let user_info = next(account_info_iter, "user")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let system_program_info = next(account_info_iter, "system_program")?;
+
+        // The user pays for the referral link
+        check(
+            "user",
+            user_info,
+            &[Constraint::Signer, Constraint::Writable],
+        )?;
+        let (referral_link_pubkey, referral_link_bump) =
+            Processor::find_referral_link_address(program_id, user_info.key);
+        check(
+            "referral_link",
+            referral_link_info,
+            &[
+                Constraint::ProgramAddress(&referral_link_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        load_referrer(program_id, referrer_info)?;
+
+        Ok(Self {
+            referral_link_info,
+            referral_link_bump,
+            referrer_info,
+            user_info,
+            rent_info,
+            system_program_info,
+        })
+    }
+}
+
+/// Accounts of `ClaimReferralRebates`
+pub struct ClaimReferralRebatesAccounts<'a, 'b> {
+    pub referrer_info: &'a AccountInfo<'b>,
+    pub referrer: Referrer,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub destination_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ClaimReferralRebatesAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let referrer_info = next(account_info_iter, "referrer")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let destination_info = next(account_info_iter, "destination")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "mint")?;
+
+        let referrer = load_referrer(program_id, referrer_info)?;
+        check("referrer", referrer_info, &[Constraint::Writable])?;
+        let authority_pubkey =
+            Processor::authority_id(program_id, referrer_info.key, referrer.authority_bump)?;
+        check(
+            "authority",
+            authority_info,
+            &[Constraint::ProgramAddress(&authority_pubkey)],
+        )?;
+        check("owner", owner_info, &[Constraint::Signer])?;
+        if *owner_info.key != referrer.owner {
+            msg!("Account owner is not the referrer's owner");
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        check(
+            "vault",
+            vault_info,
+            &[
+                Constraint::Address(&referrer.vault_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check("destination", destination_info, &[Constraint::Writable])?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&referrer.token_program_id)],
+        )?;
+        check("mint", mint_info, &[Constraint::Mint(&referrer.mint)])?;
+        check_unique(&[("vault", vault_info), ("destination", destination_info)])?;
+
+        Ok(Self {
+            referrer_info,
+            referrer,
+            authority_info,
+            owner_info,
+            vault_info,
+            destination_info,
+            token_program_info,
+            mint_info,
+        })
+    }
+}
+
+/// Accounts of `InitializePerpetualSwap`
+pub struct InitializePerpetualSwapAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub authority_bump: u8,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub long_margin_bump: u8,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_bump: u8,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub system_program_info: &'a AccountInfo<'b>,
+    pub pyth_product_info: &'a AccountInfo<'b>,
+    pub pyth_price_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The FX rate's product and price accounts, if the swap is a quanto market
+    pub fx_oracle_infos: Option<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>,
+}
+
+impl<'a, 'b> InitializePerpetualSwapAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        kind: MarketKind,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let system_program_info = next(account_info_iter, "system_program")?;
+        let pyth_product_info = next(account_info_iter, "pyth_product")?;
+        let pyth_price_info = next(account_info_iter, "pyth_price")?;
+        let clock_info = next(account_info_iter, "clock")?;
+        // The swap isn't initialized yet, so the FX oracle follows the kind being created
+        let fx_oracle_infos = if kind == MarketKind::Quanto {
+            let fx_product_info = next(account_info_iter, "fx_product")?;
+            let fx_price_info = next(account_info_iter, "fx_price")?;
+            for (name, info) in
+                [("fx_product", fx_product_info), ("fx_price", fx_price_info)].iter()
+            {
+                check(name, info, &[Constraint::OwnedBy(&pyth::id())])?;
+            }
+            Some((fx_product_info, fx_price_info))
+        } else {
+            None
+        };
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        // The admin pays for the margin accounts
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Signer, Constraint::Writable],
+        )?;
+        let (authority_pubkey, authority_bump) =
+            Processor::find_authority_address(program_id, perpetual_swap_info.key);
+        check(
+            "authority",
+            authority_info,
+            &[Constraint::ProgramAddress(&authority_pubkey)],
+        )?;
+        // The margin accounts are created here at their program addresses
+        let (long_margin_pubkey, long_margin_bump) =
+            Processor::find_margin_address(program_id, perpetual_swap_info.key, true);
+        let (short_margin_pubkey, short_margin_bump) =
+            Processor::find_margin_address(program_id, perpetual_swap_info.key, false);
+        check(
+            "long_margin",
+            long_margin_info,
+            &[
+                Constraint::Address(&long_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "short_margin",
+            short_margin_info,
+            &[
+                Constraint::Address(&short_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "insurance_fund",
+            insurance_fund_info,
+            &[Constraint::Writable],
+        )?;
+        check(
+            "pyth_product",
+            pyth_product_info,
+            &[Constraint::OwnedBy(&pyth::id())],
+        )?;
+        check(
+            "pyth_price",
+            pyth_price_info,
+            &[Constraint::OwnedBy(&pyth::id())],
+        )?;
+        check_unique(&[
+            ("perpetual_swap", perpetual_swap_info),
+            ("insurance_fund", insurance_fund_info),
+            ("admin", admin_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            authority_bump,
+            long_margin_info,
+            long_margin_bump,
+            short_margin_info,
+            short_margin_bump,
+            insurance_fund_info,
+            mint_info,
+            rent_info,
+            token_program_info,
+            admin_info,
+            system_program_info,
+            pyth_product_info,
+            pyth_price_info,
+            clock_info,
+            fx_oracle_infos,
+        })
+    }
+}
+
+/// Accounts of `TryToLiquidate`
+pub struct TryToLiquidateAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub liquidator_info: &'a AccountInfo<'b>,
+    pub liquidated_margin_info: &'a AccountInfo<'b>,
+    pub liquidator_account_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub counterparty_margin_info: &'a AccountInfo<'b>,
+    pub counterparty_account_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// The collateral config followed by the side's `(vault, liquidator account)` pair of every
+    /// asset, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+    /// Whether the liquidated side is the long one
+    pub is_long: bool,
+}
+
+impl<'a, 'b> TryToLiquidateAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let liquidator_info = next(account_info_iter, "liquidator")?;
+        let liquidated_margin_info = next(account_info_iter, "liquidated_margin")?;
+        let liquidator_account_info = next(account_info_iter, "liquidator_account")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let counterparty_margin_info = next(account_info_iter, "counterparty_margin")?;
+        let counterparty_account_info = next(account_info_iter, "counterparty_account")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        let is_long = margin_side(&perpetual_swap, liquidated_margin_info)?;
+        let (counterparty_margin_pubkey, counterparty_account_pubkey) = if is_long {
+            (
+                &perpetual_swap.short_margin_pubkey,
+                &perpetual_swap.short_account_pubkey,
+            )
+        } else {
+            (
+                &perpetual_swap.long_margin_pubkey,
+                &perpetual_swap.long_account_pubkey,
+            )
+        };
+        // The liquidator pays the collateral it takes the position over with
+        check("liquidator", liquidator_info, &[Constraint::Signer])?;
+        check(
+            "liquidated_margin",
+            liquidated_margin_info,
+            &[Constraint::Writable],
+        )?;
+        check(
+            "liquidator_account",
+            liquidator_account_info,
+            &[Constraint::Writable],
+        )?;
+        check(
+            "insurance_fund",
+            insurance_fund_info,
+            &[
+                Constraint::Address(&perpetual_swap.insurance_fund_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "counterparty_margin",
+            counterparty_margin_info,
+            &[
+                Constraint::Address(counterparty_margin_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check(
+            "counterparty_account",
+            counterparty_account_info,
+            &[
+                Constraint::Address(counterparty_account_pubkey),
+                Constraint::Writable,
+            ],
+        )?;
+        check_unique(&[
+            ("liquidated_margin", liquidated_margin_info),
+            ("liquidator_account", liquidator_account_info),
+            ("insurance_fund", insurance_fund_info),
+            ("counterparty_margin", counterparty_margin_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            liquidator_info,
+            liquidated_margin_info,
+            liquidator_account_info,
+            insurance_fund_info,
+            counterparty_margin_info,
+            counterparty_account_info,
+            token_program_info,
+            mint_info,
+            collateral_infos: account_info_iter.as_slice(),
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `InitializeCrossMargin`
+pub struct InitializeCrossMarginAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub authority_bump: u8,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> InitializeCrossMarginAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let mint_info = next(account_info_iter, "mint")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+
+        check(
+            "cross_margin",
+            cross_margin_info,
+            &[Constraint::OwnedBy(program_id), Constraint::Writable],
+        )?;
+        let cross_margin = CrossMarginAccount::try_from_slice(&cross_margin_info.data.borrow())?;
+        check("owner", owner_info, &[Constraint::Signer])?;
+        let (authority_pubkey, authority_bump) =
+            Processor::find_authority_address(program_id, cross_margin_info.key);
+        check(
+            "authority",
+            authority_info,
+            &[Constraint::ProgramAddress(&authority_pubkey)],
+        )?;
+        check("vault", vault_info, &[Constraint::Writable])?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            authority_bump,
+            vault_info,
+            mint_info,
+            owner_info,
+            rent_info,
+            token_program_info,
+        })
+    }
+}
+
+/// Deserializes a cross-margin account after checking it belongs to this program and was
+/// initialized.
+fn load_cross_margin(
+    program_id: &Pubkey,
+    cross_margin_info: &AccountInfo,
+) -> Result<CrossMarginAccount, ProgramError> {
+    check(
+        "cross_margin",
+        cross_margin_info,
+        &[Constraint::OwnedBy(program_id)],
+    )?;
+    let cross_margin = CrossMarginAccount::try_from_slice(&cross_margin_info.data.borrow())?;
+    if !cross_margin.is_initialized {
+        msg!("Account cross_margin is not initialized");
+        return Err(PerpetualSwapError::AccountNotInitialized.into());
+    }
+    Ok(cross_margin)
+}
+
+/// Checks the authority, token program and vault of an instruction moving a cross-margin
+/// account's collateral.
+fn check_cross_margin_token_accounts(
+    program_id: &Pubkey,
+    cross_margin_info: &AccountInfo,
+    cross_margin: &CrossMarginAccount,
+    authority_info: &AccountInfo,
+    token_program_info: &AccountInfo,
+    vault_info: &AccountInfo,
+) -> ProgramResult {
+    let authority_pubkey = Processor::authority_id(
+        program_id,
+        cross_margin_info.key,
+        cross_margin.authority_bump,
+    )?;
+    check(
+        "authority",
+        authority_info,
+        &[Constraint::ProgramAddress(&authority_pubkey)],
+    )?;
+    check(
+        "token_program",
+        token_program_info,
+        &[Constraint::TokenProgram(&cross_margin.token_program_id)],
+    )?;
+    check(
+        "vault",
+        vault_info,
+        &[
+            Constraint::Address(&cross_margin.vault_pubkey),
+            Constraint::Writable,
+        ],
+    )
+}
+
+/// Accounts of `DepositToCrossMargin`
+pub struct DepositToCrossMarginAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub source_info: &'a AccountInfo<'b>,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> DepositToCrossMarginAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let source_info = next(account_info_iter, "source")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "mint")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check_cross_margin_token_accounts(
+            program_id,
+            cross_margin_info,
+            &cross_margin,
+            authority_info,
+            token_program_info,
+            vault_info,
+        )?;
+        check(
+            "owner",
+            owner_info,
+            &[Constraint::Wallet(&cross_margin.owner), Constraint::Signer],
+        )?;
+        check("source", source_info, &[Constraint::Writable])?;
+        check_unique(&[("source", source_info), ("vault", vault_info)])?;
+        check("mint", mint_info, &[Constraint::Mint(&cross_margin.mint)])?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            owner_info,
+            source_info,
+            vault_info,
+            token_program_info,
+            mint_info,
+        })
+    }
+}
+
+/// Accounts of `WithdrawFromCrossMargin`
+pub struct WithdrawFromCrossMarginAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub dest_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// The swap and margin account of every registered market, in order
+    pub market_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> WithdrawFromCrossMarginAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let dest_info = next(account_info_iter, "destination")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "mint")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check_cross_margin_token_accounts(
+            program_id,
+            cross_margin_info,
+            &cross_margin,
+            authority_info,
+            token_program_info,
+            vault_info,
+        )?;
+        check(
+            "owner",
+            owner_info,
+            &[Constraint::Wallet(&cross_margin.owner), Constraint::Signer],
+        )?;
+        check("destination", dest_info, &[Constraint::Writable])?;
+        check_unique(&[("vault", vault_info), ("destination", dest_info)])?;
+        check("mint", mint_info, &[Constraint::Mint(&cross_margin.mint)])?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            owner_info,
+            vault_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            market_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Accounts of `AddCrossMarginMarket`
+pub struct AddCrossMarginMarketAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub position_account_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AddCrossMarginMarketAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let position_account_info = next(account_info_iter, "position_account")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check("cross_margin", cross_margin_info, &[Constraint::Writable])?;
+        check(
+            "owner",
+            owner_info,
+            &[Constraint::Wallet(&cross_margin.owner), Constraint::Signer],
+        )?;
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            owner_info,
+            perpetual_swap_info,
+            perpetual_swap,
+            position_account_info,
+        })
+    }
+}
+
+/// Accounts of `RemoveCrossMarginMarket`
+pub struct RemoveCrossMarginMarketAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub owner_info: &'a AccountInfo<'b>,
+    /// Not checked here, since it may have been closed since the market was registered
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> RemoveCrossMarginMarketAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check("cross_margin", cross_margin_info, &[Constraint::Writable])?;
+        check(
+            "owner",
+            owner_info,
+            &[Constraint::Wallet(&cross_margin.owner), Constraint::Signer],
+        )?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            owner_info,
+            perpetual_swap_info,
+        })
+    }
+}
+
+/// Accounts of `CrossMarginTopUp`
+pub struct CrossMarginTopUpAccounts<'a, 'b> {
+    pub cross_margin_info: &'a AccountInfo<'b>,
+    pub cross_margin: CrossMarginAccount,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// Whether the cross-margin account holds the long side of the swap
+    pub is_long: bool,
+}
+
+impl<'a, 'b> CrossMarginTopUpAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check_cross_margin_token_accounts(
+            program_id,
+            cross_margin_info,
+            &cross_margin,
+            authority_info,
+            token_program_info,
+            vault_info,
+        )?;
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        let is_long = match cross_margin
+            .markets()
+            .iter()
+            .find(|market| market.swap_pubkey == *perpetual_swap_info.key)
+        {
+            Some(market) => market.is_long,
+            None => {
+                msg!("Account perpetual_swap is not a market of the cross-margin account");
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+        };
+        if *perpetual_swap.user_account_pubkey(is_long) != cross_margin.vault_pubkey {
+            msg!("Account vault no longer holds the position in perpetual_swap");
+            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        }
+        let margin_pubkey = if is_long {
+            perpetual_swap.long_margin_pubkey
+        } else {
+            perpetual_swap.short_margin_pubkey
+        };
+        check(
+            "margin",
+            margin_info,
+            &[Constraint::Address(&margin_pubkey), Constraint::Writable],
+        )?;
+        check(
+            "collateral_mint",
+            mint_info,
+            &[Constraint::Mint(&perpetual_swap.collateral_mint)],
+        )?;
+
+        Ok(Self {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            vault_info,
+            perpetual_swap_info,
+            perpetual_swap,
+            margin_info,
+            token_program_info,
+            mint_info,
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `CrossMarginHealth`
+pub struct CrossMarginHealthAccounts<'a, 'b> {
+    pub cross_margin: CrossMarginAccount,
+    pub vault_info: &'a AccountInfo<'b>,
+    /// The swap and margin account of every registered market, in order
+    pub market_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> CrossMarginHealthAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let cross_margin_info = next(account_info_iter, "cross_margin")?;
+        let vault_info = next(account_info_iter, "vault")?;
+
+        let cross_margin = load_cross_margin(program_id, cross_margin_info)?;
+        check(
+            "vault",
+            vault_info,
+            &[Constraint::Address(&cross_margin.vault_pubkey)],
+        )?;
+
+        Ok(Self {
+            cross_margin,
+            vault_info,
+            market_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Deserializes the swap's collateral config after checking it is the one the swap points to,
+/// and that it is writable if the instruction updates it.
+fn load_collateral_config(
+    program_id: &Pubkey,
+    perpetual_swap: &PerpetualSwap,
+    collateral_config_info: &AccountInfo,
+    is_writable: bool,
+) -> Result<CollateralConfig, ProgramError> {
+    if is_writable {
+        check(
+            "collateral_config",
+            collateral_config_info,
+            &[Constraint::Writable],
+        )?;
+    }
+    Processor::unpack_collateral_config(program_id, perpetual_swap, collateral_config_info)
+}
+
+/// Accounts of `AddCollateralAsset`
+pub struct AddCollateralAssetAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub collateral_config_info: &'a AccountInfo<'b>,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub pyth_product_info: &'a AccountInfo<'b>,
+    pub pyth_price_info: &'a AccountInfo<'b>,
+    pub long_vault_info: &'a AccountInfo<'b>,
+    pub short_vault_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub admin_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AddCollateralAssetAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let collateral_config_info = next(account_info_iter, "collateral_config")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let mint_info = next(account_info_iter, "mint")?;
+        let pyth_product_info = next(account_info_iter, "pyth_product")?;
+        let pyth_price_info = next(account_info_iter, "pyth_price")?;
+        let long_vault_info = next(account_info_iter, "long_vault")?;
+        let short_vault_info = next(account_info_iter, "short_vault")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let admin_info = next(account_info_iter, "admin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        // The first asset creates the config, so it is only checked against the swap after that
+        check(
+            "collateral_config",
+            collateral_config_info,
+            &[Constraint::OwnedBy(program_id), Constraint::Writable],
+        )?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+        check_authority(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+        )?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+        )?;
+        check("long_vault", long_vault_info, &[Constraint::Writable])?;
+        check("short_vault", short_vault_info, &[Constraint::Writable])?;
+        check_unique(&[
+            ("collateral_config", collateral_config_info),
+            ("long_vault", long_vault_info),
+            ("short_vault", short_vault_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            collateral_config_info,
+            authority_info,
+            mint_info,
+            pyth_product_info,
+            pyth_price_info,
+            long_vault_info,
+            short_vault_info,
+            rent_info,
+            token_program_info,
+            admin_info,
+        })
+    }
+}
+
+/// Accounts of `UpdateCollateralPrices`
+pub struct UpdateCollateralPricesAccounts<'a, 'b> {
+    pub collateral_config_info: &'a AccountInfo<'b>,
+    pub collateral_config: CollateralConfig,
+    /// The Pyth product and price accounts of every asset, in order
+    pub oracle_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> UpdateCollateralPricesAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let collateral_config_info = next(account_info_iter, "collateral_config")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        let collateral_config =
+            load_collateral_config(program_id, &perpetual_swap, collateral_config_info, true)?;
+
+        Ok(Self {
+            collateral_config_info,
+            collateral_config,
+            oracle_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Accounts of `DepositCollateral`
+pub struct DepositCollateralAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub source_info: &'a AccountInfo<'b>,
+    pub vault_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// The asset `vault_info` holds
+    pub asset: CollateralAsset,
+}
+
+impl<'a, 'b> DepositCollateralAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let collateral_config_info = next(account_info_iter, "collateral_config")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let source_info = next(account_info_iter, "source")?;
+        let vault_info = next(account_info_iter, "vault")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        let collateral_config =
+            load_collateral_config(program_id, &perpetual_swap, collateral_config_info, false)?;
+        check_authority(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+        )?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+        )?;
+        let (asset, is_long) = match collateral_config.assets().iter().find_map(|asset| {
+            if asset.long_vault_pubkey == *vault_info.key {
+                Some((*asset, true))
+            } else if asset.short_vault_pubkey == *vault_info.key {
+                Some((*asset, false))
+            } else {
+                None
+            }
+        }) {
+            Some(found) => found,
+            None => {
+                msg!("Account vault is not a collateral vault of the swap");
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+        };
+        check("mint", mint_info, &[Constraint::Mint(&asset.mint)])?;
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        check("source", source_info, &[Constraint::Writable])?;
+        check("vault", vault_info, &[Constraint::Writable])?;
+        check_unique(&[("source", source_info), ("vault", vault_info)])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            source_info,
+            vault_info,
+            token_program_info,
+            mint_info,
+            asset,
+        })
+    }
+}
+
+/// Accounts of `WithdrawCollateral`
+pub struct WithdrawCollateralAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub collateral_config: CollateralConfig,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub dest_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    /// The mint of the asset withdrawn, checked against it by the processor
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+    /// The side's vault of every asset, in order
+    pub vault_infos: &'a [AccountInfo<'b>],
+    pub is_long: bool,
+}
+
+impl<'a, 'b> WithdrawCollateralAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let collateral_config_info = next(account_info_iter, "collateral_config")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let user_account_info = next(account_info_iter, "user_account")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let dest_info = next(account_info_iter, "destination")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        let collateral_config =
+            load_collateral_config(program_id, &perpetual_swap, collateral_config_info, false)?;
+        check_authority(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+        )?;
+        check(
+            "token_program",
+            token_program_info,
+            &[Constraint::TokenProgram(&perpetual_swap.token_program_id)],
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        // A tokenized side has no user account of its own, so any account stands in for it
+        if !perpetual_swap.is_tokenized(is_long) {
+            let account_pubkey = if is_long {
+                &perpetual_swap.long_account_pubkey
+            } else {
+                &perpetual_swap.short_account_pubkey
+            };
+            check(
+                "user_account",
+                user_account_info,
+                &[Constraint::Address(account_pubkey)],
+            )?;
+        }
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        check("destination", dest_info, &[Constraint::Writable])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            collateral_config,
+            authority_info,
+            owner_info,
+            margin_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            vault_infos: account_info_iter.as_slice(),
+            is_long,
+        })
+    }
+}
+
+/// Accounts of `SetMarketStatus`, `InitializeVamm`, `RepegVamm` and `AdjustVammDepth`
+pub struct AdminAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AdminAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+        })
+    }
+}
+
+/// Accounts of `UpdateMarketParams` and `UpdateMarginTiers`
+pub struct UpdateMarketParamsAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> UpdateMarketParamsAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+            clock_info,
+        })
+    }
+}
+
+/// Accounts of `ApplyMarketParams`
+pub struct ApplyMarketParamsAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub clock_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ApplyMarketParamsAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            clock_info,
+        })
+    }
+}
+
+/// Accounts of `ProposeAdmin`
+pub struct ProposeAdminAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub new_admin_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ProposeAdminAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let new_admin_info = next(account_info_iter, "new_admin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            new_admin_info,
+        })
+    }
+}
+
+/// Accounts of `AcceptAdmin`
+pub struct AcceptAdminAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub new_admin_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AcceptAdminAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let new_admin_info = next(account_info_iter, "new_admin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        if perpetual_swap.pending_admin == Pubkey::default() {
+            msg!("No admin was proposed");
+            return Err(PerpetualSwapError::NoPendingChange.into());
+        }
+        if *new_admin_info.key != perpetual_swap.pending_admin {
+            msg!("Account new_admin is not the proposed admin");
+            return Err(PerpetualSwapError::InvalidAdmin.into());
+        }
+        check("new_admin", new_admin_info, &[Constraint::Signer])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            new_admin_info,
+        })
+    }
+}
+
+/// Accounts of `ConfigureCrankReward`
+pub struct ConfigureCrankRewardAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub fee_vault_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub rent_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ConfigureCrankRewardAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let fee_vault_info = next(account_info_iter, "fee_vault")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let rent_info = next(account_info_iter, "rent")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let admin_info = next(account_info_iter, "admin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check(
+            "admin",
+            admin_info,
+            &[Constraint::Admin(&perpetual_swap.admin), Constraint::Signer],
+        )?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        if perpetual_swap.has_fee_vault() {
+            check(
+                "fee_vault",
+                fee_vault_info,
+                &[
+                    Constraint::Address(&perpetual_swap.fee_vault_pubkey),
+                    Constraint::Writable,
+                ],
+            )?;
+        } else {
+            check("fee_vault", fee_vault_info, &[Constraint::Writable])?;
+        }
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            fee_vault_info,
+            mint_info,
+            rent_info,
+            token_program_info,
+        })
+    }
+}
+
+/// Accounts of `GetHealth`
+pub struct GetHealthAccounts<'a, 'b> {
+    pub perpetual_swap: PerpetualSwap,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    /// The collateral config and both sides' vaults, if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> GetHealthAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, false)?;
+        check(
+            "long_margin",
+            long_margin_info,
+            &[Constraint::Address(&perpetual_swap.long_margin_pubkey)],
+        )?;
+        check(
+            "short_margin",
+            short_margin_info,
+            &[Constraint::Address(&perpetual_swap.short_margin_pubkey)],
+        )?;
+
+        Ok(Self {
+            perpetual_swap,
+            long_margin_info,
+            short_margin_info,
+            collateral_infos: account_info_iter.as_slice(),
+        })
+    }
+}
+
+/// Accounts of `SettleMarket`
+pub struct SettleMarketAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub long_margin_info: &'a AccountInfo<'b>,
+    pub short_margin_info: &'a AccountInfo<'b>,
+    pub insurance_fund_info: &'a AccountInfo<'b>,
+    pub admin_token_account_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    pub clock_info: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> SettleMarketAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let long_margin_info = next(account_info_iter, "long_margin")?;
+        let short_margin_info = next(account_info_iter, "short_margin")?;
+        let insurance_fund_info = next(account_info_iter, "insurance_fund")?;
+        let admin_token_account_info = next(account_info_iter, "admin_token_account")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+        let clock_info = next(account_info_iter, "clock")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        // Anyone can settle an expired future, so whether the admin has to sign is up to the
+        // handler, but the rent of the margin accounts it closes always goes back to the admin
+        check(
+            "admin",
+            admin_info,
+            &[
+                Constraint::Admin(&perpetual_swap.admin),
+                Constraint::Writable,
+            ],
+        )?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        for (name, account_info, key) in [
+            (
+                "long_margin",
+                long_margin_info,
+                &perpetual_swap.long_margin_pubkey,
+            ),
+            (
+                "short_margin",
+                short_margin_info,
+                &perpetual_swap.short_margin_pubkey,
+            ),
+            (
+                "insurance_fund",
+                insurance_fund_info,
+                &perpetual_swap.insurance_fund_pubkey,
+            ),
+        ]
+        .iter()
+        {
+            check(
+                name,
+                account_info,
+                &[Constraint::Address(key), Constraint::Writable],
+            )?;
+        }
+        check(
+            "admin_token_account",
+            admin_token_account_info,
+            &[Constraint::Writable],
+        )?;
+        check_unique(&[
+            ("long_margin", long_margin_info),
+            ("short_margin", short_margin_info),
+            ("insurance_fund", insurance_fund_info),
+            ("admin_token_account", admin_token_account_info),
+        ])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+        })
+    }
+}
+
+/// Accounts of `RedeemSettled`
+pub struct RedeemSettledAccounts<'a, 'b> {
+    pub perpetual_swap_info: &'a AccountInfo<'b>,
+    pub perpetual_swap: PerpetualSwap,
+    pub authority_info: &'a AccountInfo<'b>,
+    pub owner_info: &'a AccountInfo<'b>,
+    pub margin_info: &'a AccountInfo<'b>,
+    pub dest_info: &'a AccountInfo<'b>,
+    pub admin_info: &'a AccountInfo<'b>,
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub mint_info: &'a AccountInfo<'b>,
+    /// The collateral config followed by the side's `(vault, destination)` pair of every asset,
+    /// if the swap takes non-primary collateral
+    pub collateral_infos: &'a [AccountInfo<'b>],
+    pub is_long: bool,
+}
+
+impl<'a, 'b> RedeemSettledAccounts<'a, 'b> {
+    pub fn load(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let perpetual_swap_info = next(account_info_iter, "perpetual_swap")?;
+        let authority_info = next(account_info_iter, "authority")?;
+        let owner_info = next(account_info_iter, "owner")?;
+        let margin_info = next(account_info_iter, "margin")?;
+        let dest_info = next(account_info_iter, "destination")?;
+        let admin_info = next(account_info_iter, "admin")?;
+        let token_program_info = next(account_info_iter, "token_program")?;
+        let mint_info = next(account_info_iter, "collateral_mint")?;
+
+        let perpetual_swap = load_perpetual_swap(program_id, perpetual_swap_info, true)?;
+        check_token_accounts(
+            program_id,
+            perpetual_swap_info,
+            &perpetual_swap,
+            authority_info,
+            token_program_info,
+            mint_info,
+        )?;
+        // The admin gets the rent of the closed accounts
+        check(
+            "admin",
+            admin_info,
+            &[
+                Constraint::Admin(&perpetual_swap.admin),
+                Constraint::Writable,
+            ],
+        )?;
+        let is_long = margin_side(&perpetual_swap, margin_info)?;
+        // `SettleMarket` already closed the vAMM's side, so every side left has an owner to sign
+        check_side_owner(account_info_iter, &perpetual_swap, is_long, owner_info)?;
+        check("margin", margin_info, &[Constraint::Writable])?;
+        check("destination", dest_info, &[Constraint::Writable])?;
+        check_unique(&[("margin", margin_info), ("destination", dest_info)])?;
+
+        Ok(Self {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info,
+            margin_info,
+            dest_info,
+            admin_info,
+            token_program_info,
+            mint_info,
+            collateral_infos: account_info_iter.as_slice(),
+            is_long,
+        })
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use solana_program::clock::Epoch;
+
+    #[test]
+    pub fn test_constraints() {
+        let key = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![];
+        let account_info = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+
+        assert_eq!(
+            check(
+                "account",
+                &account_info,
+                &[
+                    Constraint::Writable,
+                    Constraint::OwnedBy(&owner),
+                    Constraint::Address(&key)
+                ]
+            ),
+            Ok(())
+        );
+        for (constraint, error) in [
+            (Constraint::Signer, ProgramError::MissingRequiredSignature),
+            (
+                Constraint::OwnedBy(&other),
+                ProgramError::IncorrectProgramId,
+            ),
+            (
+                Constraint::Address(&other),
+                PerpetualSwapError::InvalidAccountKeys.into(),
+            ),
+            (
+                Constraint::ProgramAddress(&other),
+                PerpetualSwapError::InvalidProgramAddress.into(),
+            ),
+            (
+                Constraint::TokenProgram(&other),
+                PerpetualSwapError::IncorrectTokenProgramId.into(),
+            ),
+            (
+                Constraint::Mint(&other),
+                PerpetualSwapError::InvalidMints.into(),
+            ),
+            (
+                Constraint::Wallet(&other),
+                PerpetualSwapError::InvalidOwner.into(),
+            ),
+            (
+                Constraint::Admin(&other),
+                PerpetualSwapError::InvalidAdmin.into(),
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                check(
+                    "account",
+                    &account_info,
+                    &[Constraint::Writable, *constraint]
+                ),
+                Err(error.clone())
+            );
+        }
+
+        assert_eq!(
+            check_unique(&[("first", &account_info), ("second", &account_info)]),
+            Err(PerpetualSwapError::DuplicateAccount.into())
+        );
+        assert_eq!(
+            next(&mut [].iter(), "account").unwrap_err(),
+            ProgramError::NotEnoughAccountKeys
+        );
+    }
+}
@@ -48,6 +48,50 @@ pub enum PerpetualSwapError {
     AccountNotInitialized,
     #[error("DoesNotNeedLiquidation")]
     DoesNotNeedLiquidation,
+    #[error("CalculationFailure")]
+    CalculationFailure,
+    #[error("CrossMarginFull")]
+    CrossMarginFull,
+    #[error("InvalidMarketParameter")]
+    InvalidMarketParameter,
+    #[error("CollateralConfigFull")]
+    CollateralConfigFull,
+    #[error("InvalidAdmin")]
+    InvalidAdmin,
+    #[error("NoPendingChange")]
+    NoPendingChange,
+    #[error("TimelockNotElapsed")]
+    TimelockNotElapsed,
+    #[error("InvalidMarketStatus")]
+    InvalidMarketStatus,
+    #[error("NoIndexPrice")]
+    NoIndexPrice,
+    #[error("AccountNotWritable")]
+    AccountNotWritable,
+    #[error("DuplicateAccount")]
+    DuplicateAccount,
+    #[error("PositionStillOpen")]
+    PositionStillOpen,
+    #[error("NoFunding")]
+    NoFunding,
+    #[error("MarketExpired")]
+    MarketExpired,
+    #[error("InvalidPositionSize")]
+    InvalidPositionSize,
+    #[error("CollateralNotSplittable")]
+    CollateralNotSplittable,
+    #[error("PositionTokenized")]
+    PositionTokenized,
+    #[error("NoVamm")]
+    NoVamm,
+    #[error("PriceLimitExceeded")]
+    PriceLimitExceeded,
+    #[error("HeldByVamm")]
+    HeldByVamm,
+    #[error("OracleNotTrading")]
+    OracleNotTrading,
+    #[error("MarkPriceOutOfBand")]
+    MarkPriceOutOfBand,
 }
 
 impl From<PerpetualSwapError> for ProgramError {
@@ -0,0 +1,134 @@
+//! Events logged by the program so off-chain observers can follow position changes.
+
+use solana_program::{msg, pubkey::Pubkey};
+
+use crate::state::MarketStatus;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PerpetualSwapEvent {
+    /// A profitable position was force-closed at the bankruptcy price of its counterparty
+    AutoDeleveraged {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        bankruptcy_price: f64,
+        mark_price: f64,
+        realized_pnl: u64,
+        priority: f64,
+    },
+    /// A bankrupt position's deficit was paid to its counterparty out of the insurance fund
+    NegativeEquitySettled {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        bankruptcy_price: f64,
+        deficit: u64,
+        insurance_payout: u64,
+    },
+    /// The part of a bankrupt position's loss that neither its margin nor the insurance fund covered
+    SocializedLoss {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        amount: u64,
+    },
+    /// A funding crank was rewarded out of the swap's fee vault
+    CrankRewardPaid {
+        swap: Pubkey,
+        account: Pubkey,
+        amount: u64,
+    },
+    /// New market params took effect
+    MarketParamsUpdated { swap: Pubkey },
+    /// Stricter market params were queued and can be applied from `effective_time`
+    MarketParamsQueued { swap: Pubkey, effective_time: u128 },
+    /// The admin proposed handing the swap over to `admin`
+    AdminProposed { swap: Pubkey, admin: Pubkey },
+    /// `admin` accepted the swap and is now its admin
+    AdminTransferred { swap: Pubkey, admin: Pubkey },
+    /// The admin moved the market to `status`
+    MarketStatusChanged { swap: Pubkey, status: MarketStatus },
+    /// The market was settled and its prices frozen at `settlement_price`
+    MarketSettled { swap: Pubkey, settlement_price: f64 },
+    /// A side of a settled market was paid out `amount` of the primary collateral
+    SettledPositionRedeemed {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        amount: u64,
+    },
+    /// A side left the swap at `price`, its PnL realized, and was paid out `amount` of its margin
+    PositionClosed {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        price: f64,
+        amount: u64,
+    },
+    /// A side changed hands at `price`, realizing `realized_pnl` for the seller
+    PositionTransferred {
+        swap: Pubkey,
+        seller: Pubkey,
+        buyer: Pubkey,
+        is_long: bool,
+        size: f64,
+        price: f64,
+        realized_pnl: f64,
+    },
+    /// `size` units of both sides were split off into `new_swap`, with the same share of each
+    /// side's margin
+    PositionSplit {
+        swap: Pubkey,
+        new_swap: Pubkey,
+        is_long: bool,
+        size: f64,
+    },
+    /// Both sides had left and the swap's accounts were closed
+    SwapClosed { swap: Pubkey },
+    /// A maker or taker fee was charged on an open or transfer, into the fee vault
+    TradingFeeCharged {
+        swap: Pubkey,
+        payer: Pubkey,
+        is_maker: bool,
+        amount: u64,
+    },
+    /// The admin swept `amount` of fees out of the fee vault, `insurance_fund_amount` of them into
+    /// the insurance fund
+    FeesSwept {
+        swap: Pubkey,
+        amount: u64,
+        insurance_fund_amount: u64,
+    },
+    /// `user` linked itself to `referrer`, which now earns a share of its taker fees
+    ReferrerLinked { user: Pubkey, referrer: Pubkey },
+    /// `referrer` was paid `amount` of the taker fee `user` paid on the swap
+    ReferralRebatePaid {
+        swap: Pubkey,
+        referrer: Pubkey,
+        user: Pubkey,
+        amount: u64,
+    },
+    /// The referrer's owner claimed `amount` of rebates out of its vault
+    ReferralRebatesClaimed { referrer: Pubkey, amount: u64 },
+    /// The vAMM's reserves or peg changed outside a trade, by the admin or by funding, moving its
+    /// price to `price`
+    VammUpdated {
+        swap: Pubkey,
+        price: f64,
+        base_reserve: f64,
+    },
+    /// `account` opened a side at `price` with the vAMM taking the other side
+    VammPositionOpened {
+        swap: Pubkey,
+        account: Pubkey,
+        is_long: bool,
+        size: f64,
+        price: f64,
+    },
+}
+
+impl PerpetualSwapEvent {
+    pub fn emit(&self) {
+        msg!("Event: {:?}", self);
+    }
+}
@@ -1,113 +1,677 @@
-use solana_program::program_error::ProgramError;
-use std::convert::TryInto;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
 
 use crate::error::PerpetualSwapError;
+use crate::state::{ContractType, LiquidationMode, MarginTier, MarketKind, MarketStatus};
 use crate::traits::Unpackable;
 
 pub enum PerpetualSwapInstruction {
     /// Accounts expected:
-    /// 0. `[w, signer]` New PerpetualSwap to create.
-    /// 1. `[]` swap authority derived from `create_program_address(&[Token-swap account])`
-    /// 2. `[]` long margin account
-    /// 3. `[]` long user account
-    /// 4. `[]` short margin account
-    /// 5. `[]` short user account
-    /// 6. `[w]` Pool Token Mint. Must be empty, owned by swap authority.
-    /// 7. `[w]` Pool Token Account to deposit trading and withdraw fees.
-    /// Must be empty, not owned by swap authority
-    /// 8. `[w]` Pool Token Account to deposit the initial pool token
-    /// supply.  Must be empty, not owned by swap authority.
-    /// 9. `[]` Token program id
+    /// 0. `[w]` New PerpetualSwap to create.
+    /// 1. `[]` swap authority derived from `find_program_address(&["authority", PerpetualSwap account])`
+    /// 2. `[w]` long margin account derived from `find_program_address(&["margin", PerpetualSwap account, "long"])`, created here
+    /// 3. `[w]` short margin account derived from `find_program_address(&["margin", PerpetualSwap account, "short"])`, created here
+    /// 4. `[w]` insurance fund account, uninitialized
+    /// 5. `[]` collateral mint
+    /// 6. `[]` Rent sysvar
+    /// 7. `[]` Token program id
+    /// 8. `[ws]` admin, who can change the market params afterwards and pays for the margin accounts
+    /// 9. `[]` System program
+    /// 10. `[]` Pyth product info of the index, owned by the Pyth program
+    /// 11. `[]` Pyth price info of the index, the one the product lists
+    /// 12. `[]` Clock sysvar
+    ///
+    /// If the swap is a quanto market:
+    /// 13. `[]` Pyth product info of the FX rate, owned by the Pyth program
+    /// 14. `[]` Pyth price info of the FX rate, the one the product lists
+    ///
+    /// The index, and a quanto market's FX rate, are only ever read from the oracle accounts
+    /// bound here.
+    ///
+    /// A dated future takes its `expiry` and the length of the `settlement_window` before it, in
+    /// milliseconds. Both are 0 for a perpetual. The expiry must be in the future.
+    ///
+    /// An inverse `contract_type` takes the base asset as collateral and pays out in it, so a
+    /// position of `position_size` is worth `position_size / price` of the collateral.
+    ///
+    /// A quanto market takes the collateral paid per point of price per unit of size as its
+    /// `quanto_multiplier`, which is 0 for every other kind. It must be linear.
+    ///
+    /// Breaking change: `position_size` sits between `liquidation_mode` and `kind`, so data packed
+    /// for the layout without it no longer unpacks. Clients have to send it.
     InitializePerpetualSwap {
-        nonce: u8,
         funding_rate: f64,
         minimum_margin: f64,
         liquidation_bounty: f64,
         minimum_funding_period: u128,
+        liquidation_mode: LiquidationMode,
+        position_size: f64,
+        kind: MarketKind,
+        expiry: u128,
+        settlement_window: u128,
+        contract_type: ContractType,
+        quanto_multiplier: f64,
     },
 
     /// Accounts expected:
     /// 0. `[w]` PerpetualSwap
     /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w, s]` The account of the person depositing to the margin account
+    /// 2. `[s]` Owner of the depositing account, who owns the side from then on
+    /// 3. `[w]` The account of the person depositing to the margin account
     /// 4. `[w]` The margin account
     /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    /// 7. `[]` Clock sysvar
+    /// 8. `[w]` fee vault, only if the swap charges fees
+    ///
+    /// If the swap pays referral rebates and this side completes it:
+    /// 9. `[]` The owner's referral link, derived from `find_program_address(&["referral", owner])`, even if the owner has none
+    /// 10. `[w]` The Referrer it links to, only if the owner has a referral link
+    /// 11. `[w]` The referrer's vault, only if the owner has a referral link
+    ///
+    /// To tokenize the side, so that whoever holds its position token owns it, after any of the
+    /// accounts above:
+    /// 12. `[w]` position mint, with no supply, decimals or freeze authority, and the swap
+    ///    authority as its mint authority
+    /// 13. `[w]` The account receiving the position token
+    ///
+    /// The side opened first pays the maker fee and the side completing the swap the taker fee,
+    /// on the notional at the mark price, from the depositing account on top of the deposit. The
+    /// owner's referrer gets `referral_rebate_share_bps` of the taker fee.
     InitializeSide { amount_to_deposit: u64 },
 
     /// Accounts expected:
     /// 0. `[]` PerpetualSwap
     /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w, s]` The account of the person depositing to the margin account
+    /// 2. `[s]` Owner of the side
+    /// 3. `[w]` The side's user account, held by the owner, or any account of the owner's if the
+    ///    side is tokenized
     /// 4. `[w]` The margin account
     /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    /// 7. `[]` The owner's account holding the position token, only if the side is tokenized
     DepositToMargin { amount_to_deposit: u64 },
 
     /// Accounts expected:
     /// 0. `[]` PerpetualSwap
     /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w]` The account of the person withdrawing from the margin account
-    /// 4. `[w, s]` The margin account
+    /// 2. `[s]` Owner of the side
+    /// 3. `[w]` The margin account
+    /// 4. `[w]` Any token account held by the owner
     /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    /// 7. `[]` Clock sysvar
+    /// 8. `[]` The owner's account holding the position token, only if the side is tokenized
+    /// 9. `[]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 10. `[]` For every collateral asset, in order: the side's vault
+    ///
+    /// The side's non-primary collateral counts toward its initial margin at its haircut value.
+    /// A dated future can't be withdrawn from once it has expired, until it is settled and the
+    /// side redeems with `RedeemSettled`.
     WithdrawFromMargin { amount_to_withdraw: u64 },
 
+    /// Sells a side, of `size` units, to a new owner at `price`. The margin and non-primary
+    /// collateral stay where they are and the buyer takes the position over at its entry price, so
+    /// the buyer pays the seller the margin, the collateral at its haircut value and the seller's
+    /// PnL at `price`, or the seller pays the buyer if that's negative. The counterparty isn't
+    /// affected. A side that can be liquidated can't be sold, and a tokenized side is sold by
+    /// transferring its position token instead. The seller pays the maker fee and the buyer the
+    /// taker fee, on the notional at `price`. The buyer's referrer gets
+    /// `referral_rebate_share_bps` of the taker fee. An expired dated future can't change hands.
+    ///
     /// Accounts expected:
-    /// 0. `[]` PerpetualSwap
-    /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w]` The margin account of the long party who is selling
-    /// 4. `[w]` The user account of the long party who is selling
-    /// 5. `[w]` The account of the party who is buying
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` The side's margin account
+    /// 2. `[s]` Owner of the side, who is selling
+    /// 3. `[w]` The seller's token account
+    /// 4. `[s]` The buyer
+    /// 5. `[w]` The buyer's token account, the side's user account from then on
     /// 6. `[]` The token program
-    TransferLong { amount: u64 },
+    /// 7. `[]` The collateral mint
+    /// 8. `[]` Clock sysvar
+    /// 9. `[w]` fee vault, only if the swap charges fees
+    /// 10. `[]` The buyer's referral link, derived from `find_program_address(&["referral", buyer])`, only if the swap pays referral rebates
+    /// 11. `[w]` The Referrer it links to, only if the buyer has a referral link
+    /// 12. `[w]` The referrer's vault, only if the buyer has a referral link
+    /// 13. `[]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 14. `[]` For every collateral asset, in order: the side's vault
+    TransferPosition { is_long: bool, size: f64, price: f64 },
 
+    /// Splits `size` units off both sides into a new swap, which takes the same share of each
+    /// side's margin and keeps the same owners, user accounts, prices, params and status. It
+    /// starts without a vAMM, crank rewards or pending admin changes. Both owners sign, and the
+    /// owner of the long (`is_long`) or short side pays for the new margin accounts. Each side
+    /// has to meet initial margin in both swaps afterwards. Swaps taking non-primary collateral
+    /// or with a tokenized side can't be split.
+    ///
+    /// Every swap is one long against one short, so splitting one side splits the other too.
+    /// That's why the counterparty has to co-sign: nobody can sell part of a side, even a market
+    /// maker its own, without the counterparty's consent.
+    ///
+    /// To sell part of a side, split it off and `TransferPosition` the new swap's side, in one
+    /// transaction.
+    ///
     /// Accounts expected:
     /// 0. `[w]` PerpetualSwap
-    /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w]` The account of the short party who is buying
-    /// 4. `[w]` The account of the party who is selling
-    /// 5. `[w]` The new margin account of the party who is selling
-    /// 7. `[]` The token program
-    TransferShort { amount: u64 },
+    /// 1. `[w]` New PerpetualSwap to create
+    /// 2. `[]` swap authority
+    /// 3. `[]` new swap authority derived from `find_program_address(&["authority", new PerpetualSwap account])`
+    /// 4. `[w]` long margin account
+    /// 5. `[w]` short margin account
+    /// 6. `[w]` new long margin account derived from `find_program_address(&["margin", new PerpetualSwap account, "long"])`, created here
+    /// 7. `[w]` new short margin account derived from `find_program_address(&["margin", new PerpetualSwap account, "short"])`, created here
+    /// 8. `[w]` new insurance fund account, uninitialized
+    /// 9. `[ws]` Owner of the side
+    /// 10. `[s]` Owner of the other side, who has to consent to the split
+    /// 11. `[]` The token program
+    /// 12. `[]` The collateral mint
+    /// 13. `[]` Rent sysvar
+    /// 14. `[]` System program
+    /// 15. `[w]` new fee vault derived from `find_program_address(&["fee_vault", new PerpetualSwap account])`, created here, only if the swap charges fees
+    SplitPosition { is_long: bool, size: f64 },
 
     /// Accounts expected:
-    /// 0. `[]` PerpetualSwap
+    /// 0. `[w]` PerpetualSwap
     /// 1. `[]` swap authority
     /// 2. `[]` user transfer authority
-    /// 3. `[w]` The account of the party to be liquidated
-    /// 4. `[w]` The account of the counterparty
-    /// 5. `[w]` The margin account of the party to be liquidated
-    /// 6. `[w]` The insurance fund
+    /// 3. `[w]` The margin account of the party to be liquidated
+    /// 4. `[w]` The account of the liquidator
+    /// 5. `[w]` The insurance fund
+    /// 6. `[w]` The margin account of the counterparty
+    /// 7. `[w]` The account of the counterparty
     /// 8. `[]` The token program
+    /// 9. `[]` The collateral mint
+    ///
+    /// If the swap accepts non-primary collateral, the liquidator buys the liquidated side's
+    /// collateral at its haircut value before the liquidation runs:
+    /// 10. `[]` CollateralConfig
+    /// 11. `[w]` For every collateral asset, in order: the liquidated side's vault, the
+    ///     liquidator's token account for that mint and, read-only, the mint
+    /// 12. `[w]` Then, if the swap auto-deleverages, the same for the counterparty: its vault, its
+    ///     owner's token account for that mint and, read-only, the mint. A force-closed
+    ///     counterparty gets its non-primary collateral back.
     TryToLiquidate { collateral: u64 },
 
     /// Accounts expected:
     /// 0. `[w]` PerpetualSwap (w because reference time needs to be updated)
     /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
-    /// 3. `[w]` The account of the party who is long
-    /// 4. `[w]` The account of the party who is short
-    /// 3. `[]` The token program
+    /// 2. `[w]` The margin account of the party who is long
+    /// 3. `[w]` The margin account of the party who is short
+    /// 4. `[]` The token program
+    /// 5. `[]` The collateral mint
+    /// 6. `[]` Clock sysvar
+    ///
+    /// If the swap pays crank rewards:
+    /// 7. `[w]` The fee vault
+    /// 8. `[w]` The account receiving the reward
+    ///
+    /// Funding also pulls the price of the swap's vAMM, if it has one, toward the index by the
+    /// share of the gap `funding_rate` closes over the elapsed time.
     TransferFunds {},
 
+    /// Only the admin can set the mark price, and only within `mark_price_band` of the index.
+    /// While the vAMM holds a side, the mark price is the vAMM's price and `mark_price` is ignored.
+    /// An expired dated future keeps its mark price until it is settled.
+    ///
     /// Accounts expected:
-    /// 0. `[]` PerpetualSwap
-    /// 1. `[]` swap authority
-    /// 2. `[]` user transfer authority
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` swap authority
     /// 3. `[]` The token program
-    /// 4. `[]` Pyth product info
-    /// 5. `[]` Pyth Price Info
+    /// 4. `[]` Pyth product info the swap was initialized with
+    /// 5. `[]` Pyth Price Info the swap was initialized with
+    /// 6. `[]` Clock sysvar, to roll the index TWAP forward
+    ///
+    /// If the swap is a quanto market:
+    /// 7. `[]` Pyth product info of the FX rate, in collateral per unit of the price's currency
+    /// 8. `[]` Pyth price info of the FX rate
     UpdatePrices { mark_price: f64 },
 
     /// Accounts expected:
     /// 0. `[w]` PerpetualSwap
-    /// 1. `[]` Pyth product info
-    /// 2. `[]` Pyth Price Info
+    /// 1. `[]` Pyth product info the swap was initialized with
+    /// 2. `[]` Pyth Price Info the swap was initialized with
+    /// 3. `[]` Clock sysvar, to roll the index TWAP forward
+    ///
+    /// If the swap is a quanto market:
+    /// 4. `[]` Pyth product info of the FX rate, in collateral per unit of the price's currency
+    /// 5. `[]` Pyth price info of the FX rate
     OracleUpdateIndex {},
 
+    /// Accounts expected:
+    /// 0. `[w]` New CrossMarginAccount to create
+    /// 1. `[]` cross-margin authority derived from `find_program_address(&["authority", CrossMarginAccount account])`
+    /// 2. `[w]` collateral vault, uninitialized
+    /// 3. `[]` collateral mint
+    /// 4. `[s]` owner
+    /// 5. `[]` Rent sysvar
+    /// 6. `[]` Token program id
+    InitializeCrossMargin {},
+
+    /// Accounts expected:
+    /// 0. `[]` CrossMarginAccount
+    /// 1. `[]` cross-margin authority
+    /// 2. `[s]` owner
+    /// 3. `[w]` The owner's account depositing to the vault
+    /// 4. `[w]` The collateral vault
+    /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    DepositToCrossMargin { amount_to_deposit: u64 },
+
+    /// Accounts expected:
+    /// 0. `[]` CrossMarginAccount
+    /// 1. `[]` cross-margin authority
+    /// 2. `[s]` owner
+    /// 3. `[w]` The collateral vault
+    /// 4. `[w]` The account receiving the withdrawal
+    /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    /// 7. `[]` For every registered market, in order: the PerpetualSwap and the position's margin account
+    WithdrawFromCrossMargin { amount_to_withdraw: u64 },
+
+    /// Moves a position the owner holds into the cross-margin account by making the vault its user account
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` CrossMarginAccount
+    /// 1. `[s]` owner
+    /// 2. `[w]` PerpetualSwap
+    /// 3. `[]` The owner's token account currently holding the position
+    AddCrossMarginMarket {},
+
+    /// Tops up a registered position that is below maintenance margin out of the vault
+    ///
+    /// Accounts expected:
+    /// 0. `[]` CrossMarginAccount
+    /// 1. `[]` cross-margin authority
+    /// 2. `[w]` The collateral vault
+    /// 3. `[]` PerpetualSwap of a registered market
+    /// 4. `[w]` The margin account of the position
+    /// 5. `[]` The token program
+    /// 6. `[]` The collateral mint
+    CrossMarginTopUp {},
+
+    /// Logs the health of a cross-margin account across all of its markets
+    ///
+    /// Accounts expected:
+    /// 0. `[]` CrossMarginAccount
+    /// 1. `[]` The collateral vault
+    /// 2. `[]` For every registered market, in order: the PerpetualSwap and the position's margin account
+    CrossMarginHealth {},
+
+    /// Accepts a non-primary collateral mint on the swap
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[w]` CollateralConfig, created on the first asset
+    /// 2. `[]` swap authority
+    /// 3. `[]` collateral mint
+    /// 4. `[]` Pyth product info for the mint's price
+    /// 5. `[]` Pyth price info it lists, the asset's starting price
+    /// 6. `[w]` long collateral vault, uninitialized
+    /// 7. `[w]` short collateral vault, uninitialized
+    /// 8. `[]` Rent sysvar
+    /// 9. `[]` Token program id
+    /// 10. `[s]` admin
+    AddCollateralAsset { haircut: f64 },
+
+    /// Accounts expected:
+    /// 0. `[]` PerpetualSwap
+    /// 1. `[w]` CollateralConfig
+    /// 2. `[]` For every collateral asset, in order: Pyth product info and Pyth price info
+    UpdateCollateralPrices {},
+
+    /// Accounts expected:
+    /// 0. `[]` PerpetualSwap
+    /// 1. `[]` CollateralConfig
+    /// 2. `[]` swap authority
+    /// 3. `[s]` Owner of the side
+    /// 4. `[w]` The owner's account depositing the collateral
+    /// 5. `[w]` The collateral vault of the side
+    /// 6. `[]` The token program
+    /// 7. `[]` The mint of the collateral deposited
+    /// 8. `[]` The owner's account holding the position token, only if the side is tokenized
+    DepositCollateral { amount_to_deposit: u64 },
+
+    /// Accounts expected:
+    /// 0. `[]` PerpetualSwap
+    /// 1. `[]` CollateralConfig
+    /// 2. `[]` swap authority
+    /// 3. `[s]` Owner of the side
+    /// 4. `[]` The user account of the side, or any account if the side is tokenized
+    /// 5. `[]` The margin account of the side
+    /// 6. `[w]` The owner's account receiving the collateral
+    /// 7. `[]` The token program
+    /// 8. `[]` The mint of the collateral withdrawn
+    /// 9. `[]` Clock sysvar
+    /// 10. `[]` The owner's account holding the position token, only if the side is tokenized
+    /// 11. `[w]` For every collateral asset, in order: the side's vault
+    ///
+    /// Like `WithdrawFromMargin`, this fails once a dated future has expired.
+    WithdrawCollateral {
+        asset_index: u8,
+        amount_to_withdraw: u64,
+    },
+
+    /// Replaces the swap's margin tiers, sorted by `max_notional`. Tiers that raise the margin of
+    /// any position are queued like stricter `UpdateMarketParams`.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` Clock sysvar
+    UpdateMarginTiers { margin_tiers: Vec<MarginTier> },
+
+    /// Logs margin ratio, free collateral, max withdrawable amount and liquidation price of each
+    /// side, counting non-primary collateral at its haircut value
+    ///
+    /// Accounts expected:
+    /// 0. `[]` PerpetualSwap
+    /// 1. `[]` long margin account
+    /// 2. `[]` short margin account
+    /// 3. `[]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 4. `[]` For every collateral asset, in order: the long's vault and the short's vault
+    GetHealth {},
+
+    /// Sets the reward paid out of the fee vault for every successful `TransferFunds`, capped at
+    /// `crank_reward_cap` per `crank_reward_interval` milliseconds. Anyone can fund the vault.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` swap authority
+    /// 2. `[w]` fee vault, uninitialized the first time
+    /// 3. `[]` the swap's collateral mint
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` Token program id
+    /// 6. `[s]` admin
+    ConfigureCrankReward {
+        crank_reward: u64,
+        crank_reward_cap: u64,
+        crank_reward_interval: u128,
+    },
+
+    /// Changes the market params. Changes that raise margin requirements, raise the liquidation
+    /// bounty, widen the mark price band or shorten the timelock are queued for `params_timelock`
+    /// milliseconds, others apply right away. Either way, any change already queued is replaced.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` Clock sysvar
+    UpdateMarketParams {
+        funding_rate: f64,
+        minimum_margin: f64,
+        liquidation_bounty: f64,
+        minimum_funding_period: u128,
+        params_timelock: u128,
+        mark_price_band: f64,
+    },
+
+    /// Applies the queued market params once their timelock has elapsed. Anyone can call it.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` Clock sysvar
+    ApplyMarketParams {},
+
+    /// Proposes a new admin, who takes over once they accept
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` proposed admin
+    ProposeAdmin {},
+
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` proposed admin
+    AcceptAdmin {},
+
+    /// Moves the market to a new status. A settled market can't be moved out of it.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    SetMarketStatus { status: MarketStatus },
+
+    /// Settles the market at the index TWAP. Each side's PnL is paid to the other out of its
+    /// margin, and the insurance fund covers whatever a bankrupt side can't pay. The margin of a
+    /// side nobody holds is emptied into the admin's token account and closed. A side held by the
+    /// vAMM has nobody to redeem it, so its margin goes back to the insurance fund and the vAMM
+    /// leaves the swap. From then on the market only allows withdrawals, and each side gets its
+    /// balances back with `RedeemSettled`. The insurance fund and fee vault stay with the swap
+    /// until `CloseSwap`.
+    ///
+    /// Once a dated future has expired, anyone can settle it, at the index TWAP over its settlement
+    /// window, and the admin doesn't need to sign.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[ws]` admin, who gets the rent of the closed accounts
+    /// 2. `[]` swap authority
+    /// 3. `[w]` long margin account
+    /// 4. `[w]` short margin account
+    /// 5. `[w]` insurance fund account
+    /// 6. `[w]` admin's token account, receiving the margin of a side nobody holds
+    /// 7. `[]` The token program
+    /// 8. `[]` The collateral mint
+    /// 9. `[]` Clock sysvar
+    SettleMarket {},
+
+    /// Pays a side of a settled market its margin, now including its final PnL, and any
+    /// non-primary collateral, then closes its token accounts. The rent goes to the admin. Once
+    /// both sides have redeemed, the admin closes the swap with `CloseSwap`. The vAMM's side was
+    /// already closed by `SettleMarket`.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` swap authority
+    /// 2. `[s]` Owner of the side
+    /// 3. `[w]` The side's margin account
+    /// 4. `[w]` The owner's token account to pay the margin to
+    /// 5. `[w]` admin
+    /// 6. `[]` The token program
+    /// 7. `[]` The collateral mint
+    /// 8. `[]` The owner's account holding the position token, only if the side is tokenized
+    /// 9. `[]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 10. `[w]`, `[w]`, `[]` The side's vault, the owner's token account and the asset's mint,
+    ///     for every collateral asset
+    RedeemSettled {},
+
+    /// Takes a side out of the swap. If the counterparty is still in, the side's PnL is realized
+    /// at the mark price, the same way `SettleMarket` does, and the counterparty is left flat. A
+    /// side that can be liquidated can't close. The side's margin and non-primary collateral are
+    /// paid to its owner, and the side is free to be opened again.
+    ///
+    /// A side opened against the vAMM instead sells its position back to the vAMM, realizing its
+    /// PnL at the vAMM's price for the trade, and both sides leave. What's left of the vAMM's
+    /// margin goes back to the insurance fund.
+    ///
+    /// An expired dated future can't be closed this way. It is settled instead.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` swap authority
+    /// 2. `[s]` Owner of the side
+    /// 3. `[w]` The side's margin account
+    /// 4. `[w]` The counterparty's margin account
+    /// 5. `[w]` insurance fund account
+    /// 6. `[w]` The owner's token account to pay the margin to
+    /// 7. `[]` The token program
+    /// 8. `[]` The collateral mint
+    /// 9. `[]` Clock sysvar
+    /// 10. `[]` The owner's account holding the position token, only if the side is tokenized
+    /// 11. `[]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 12. `[w]`, `[w]`, `[]` The side's vault, the owner's token account and the asset's mint,
+    ///     for every collateral asset
+    ClosePosition {},
+
+    /// Closes a swap both sides have left, or a settled swap both sides have redeemed. The margin
+    /// accounts, the insurance fund and the fee vault are emptied into the admin's token account
+    /// and closed, then the swap account is zeroed. Accounts settlement already closed are
+    /// skipped. The rent of every closed account goes to the receiver.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` swap authority
+    /// 3. `[w]` long margin account
+    /// 4. `[w]` short margin account
+    /// 5. `[w]` insurance fund account
+    /// 6. `[w]` admin's token account, receiving what's left in the swap's token accounts
+    /// 7. `[w]` The account receiving the rent
+    /// 8. `[]` The token program
+    /// 9. `[]` The collateral mint
+    /// 10. `[w]` fee vault, only if crank rewards or fees are configured
+    /// 11. `[w]` CollateralConfig, only if the swap takes non-primary collateral
+    /// 12. `[w]`, `[w]` The long and short vaults, for every collateral asset
+    CloseSwap {},
+
+    /// Sets the maker and taker fees charged on the notional of every open and transfer, in basis
+    /// points, the share of them `SweepFees` sends to the insurance fund, and the share of a
+    /// referred taker's fee paid to its referrer. Fees accrue in the fee vault, which is created
+    /// at its program address if the swap doesn't have one yet.
+    ///
+    /// The fees are market params: a higher maker or taker fee is queued for `params_timelock`
+    /// milliseconds and takes effect through `ApplyMarketParams`, other changes apply right away.
+    /// Either way, any change already queued is replaced.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` swap authority
+    /// 2. `[w]` the swap's fee vault, or one derived from `find_program_address(&["fee_vault", PerpetualSwap account])` to create
+    /// 3. `[]` the swap's collateral mint
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` Token program id
+    /// 6. `[ws]` admin, who pays for the fee vault
+    /// 7. `[]` System program
+    /// 8. `[]` Clock sysvar
+    ConfigureFees {
+        maker_fee_bps: u16,
+        taker_fee_bps: u16,
+        insurance_fund_fee_share_bps: u16,
+        referral_rebate_share_bps: u16,
+    },
+
+    /// Sweeps the fees accrued since the last sweep out of the fee vault, sending
+    /// `insurance_fund_fee_share_bps` of them to the insurance fund and the rest to the admin's
+    /// account. Whatever else the vault holds stays there for crank rewards.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    /// 2. `[]` swap authority
+    /// 3. `[w]` fee vault
+    /// 4. `[w]` insurance fund account
+    /// 5. `[w]` admin's token account, receiving the rest of the fees
+    /// 6. `[]` The token program
+    /// 7. `[]` The collateral mint
+    SweepFees {},
+
+    /// Creates a referrer, which earns a share of the taker fees of the wallets linked to it, paid
+    /// into its vault in `mint` by swaps with that collateral.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` New Referrer to create
+    /// 1. `[]` referrer authority derived from `find_program_address(&["authority", Referrer account])`
+    /// 2. `[w]` rebate vault, uninitialized
+    /// 3. `[]` mint the rebates are paid in
+    /// 4. `[s]` owner, who claims the rebates
+    /// 5. `[]` Rent sysvar
+    /// 6. `[]` Token program id
+    InitializeReferrer {},
+
+    /// Links the user to a referrer. A wallet can only ever link once.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` referral link derived from `find_program_address(&["referral", user])`, created here
+    /// 1. `[]` Referrer
+    /// 2. `[ws]` user, who pays for the referral link
+    /// 3. `[]` Rent sysvar
+    /// 4. `[]` System program
+    LinkReferrer {},
+
+    /// Pays the referrer's unclaimed rebates out of its vault.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` Referrer
+    /// 1. `[]` referrer authority
+    /// 2. `[s]` owner
+    /// 3. `[w]` rebate vault
+    /// 4. `[w]` The account receiving the rebates
+    /// 5. `[]` The token program
+    /// 6. `[]` The mint of the rebates
+    ClaimReferralRebates {},
+
+    /// Gives the swap a constant-product vAMM to open against when no one takes the other side.
+    /// Both of its reserves start at `base_reserve`, so it prices at `peg_multiplier`, and it has
+    /// to hold more than `position_size` of the base.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    InitializeVamm {
+        base_reserve: f64,
+        peg_multiplier: f64,
+    },
+
+    /// Sets the vAMM's peg multiplier, moving its price by the same factor, e.g. back to the index
+    /// after trades or a market move left it behind. Fails with `HeldByVamm` while a position is
+    /// open against the vAMM, since it would be marked at the new price.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    RepegVamm { peg_multiplier: f64 },
+
+    /// Scales both of the vAMM's reserves by `depth_multiplier`. Its price stays the same, but a
+    /// deeper vAMM moves less on a trade. Fails with `HeldByVamm` while a position is open against
+    /// the vAMM, since it would change the price the position closes at.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[s]` admin
+    AdjustVammDepth { depth_multiplier: f64 },
+
+    /// Opens the long (`is_long`) or short side of an empty swap with the vAMM taking the other
+    /// side. The position of `position_size` units is bought from or sold to the vAMM at its
+    /// average price for the trade, which moves the vAMM's price, and fails if that's worse than
+    /// `limit_price`. The vAMM's initial margin is put up by the insurance fund. The owner pays
+    /// the taker fee on the notional at the trade price, and its referrer gets
+    /// `referral_rebate_share_bps` of it.
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` PerpetualSwap
+    /// 1. `[]` swap authority
+    /// 2. `[s]` Owner of the depositing account, who owns the side from then on
+    /// 3. `[w]` The account of the person depositing to the margin account
+    /// 4. `[w]` The side's margin account
+    /// 5. `[w]` The other side's margin account, the vAMM's
+    /// 6. `[w]` insurance fund account
+    /// 7. `[]` The token program
+    /// 8. `[]` The collateral mint
+    /// 9. `[]` Clock sysvar
+    /// 10. `[w]` fee vault, only if the swap charges fees
+    /// 11. `[]` The owner's referral link, derived from `find_program_address(&["referral", owner])`, only if the swap pays referral rebates
+    /// 12. `[w]` The Referrer it links to, only if the owner has a referral link
+    /// 13. `[w]` The referrer's vault, only if the owner has a referral link
+    OpenWithVamm {
+        is_long: bool,
+        amount_to_deposit: u64,
+        limit_price: f64,
+    },
+
+    /// Drops a market from a cross-margin account once its swap is closed or the position no
+    /// longer uses the vault, freeing the slot for another market
+    ///
+    /// Accounts expected:
+    /// 0. `[w]` CrossMarginAccount
+    /// 1. `[s]` owner
+    /// 2. `[]` PerpetualSwap of the registered market, which may have been closed
+    RemoveCrossMarginMarket {},
 }
 
 impl PerpetualSwapInstruction {
@@ -119,19 +683,51 @@ impl PerpetualSwapInstruction {
 
         Ok(match tag {
             0 => {
-                let (&nonce, rest) = rest
-                    .split_first()
-                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
                 let (funding_rate, rest) = Self::unpack_fn::<f64>(rest)?;
                 let (minimum_margin, rest) = Self::unpack_fn::<f64>(rest)?;
                 let (liquidation_bounty, rest) = Self::unpack_fn::<f64>(rest)?;
-                let (minimum_funding_period, _rest) = Self::unpack_fn::<u128>(rest)?;
+                let (minimum_funding_period, rest) = Self::unpack_fn::<u128>(rest)?;
+                let (&liquidation_mode, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let liquidation_mode = match liquidation_mode {
+                    0 => LiquidationMode::AutoDeleverage,
+                    1 => LiquidationMode::SocializedLoss,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (position_size, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (&kind, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let kind = match kind {
+                    0 => MarketKind::Perpetual,
+                    1 => MarketKind::DatedFuture,
+                    2 => MarketKind::Quanto,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (expiry, rest) = Self::unpack_fn::<u128>(rest)?;
+                let (settlement_window, rest) = Self::unpack_fn::<u128>(rest)?;
+                let (&contract_type, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let contract_type = match contract_type {
+                    0 => ContractType::Linear,
+                    1 => ContractType::Inverse,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (quanto_multiplier, _rest) = Self::unpack_fn::<f64>(rest)?;
                 Self::InitializePerpetualSwap {
-                    nonce,
                     funding_rate,
                     minimum_margin,
                     liquidation_bounty,
                     minimum_funding_period,
+                    liquidation_mode,
+                    position_size,
+                    kind,
+                    expiry,
+                    settlement_window,
+                    contract_type,
+                    quanto_multiplier,
                 }
             }
             1 => {
@@ -159,21 +755,1515 @@ impl PerpetualSwapInstruction {
                 }
             }
             7 => Self::OracleUpdateIndex{},
+            8 => Self::InitializeCrossMargin {},
+            9 => {
+                let (amount_to_deposit, _rest) = Self::unpack_fn::<u64>(rest)?;
+                Self::DepositToCrossMargin { amount_to_deposit }
+            }
+            10 => {
+                let (amount_to_withdraw, _rest) = Self::unpack_fn::<u64>(rest)?;
+                Self::WithdrawFromCrossMargin { amount_to_withdraw }
+            }
+            11 => Self::AddCrossMarginMarket {},
+            12 => Self::CrossMarginTopUp {},
+            13 => Self::CrossMarginHealth {},
+            14 => {
+                let (haircut, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::AddCollateralAsset { haircut }
+            }
+            15 => Self::UpdateCollateralPrices {},
+            16 => {
+                let (amount_to_deposit, _rest) = Self::unpack_fn::<u64>(rest)?;
+                Self::DepositCollateral { amount_to_deposit }
+            }
+            17 => {
+                let (&asset_index, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let (amount_to_withdraw, _rest) = Self::unpack_fn::<u64>(rest)?;
+                Self::WithdrawCollateral {
+                    asset_index,
+                    amount_to_withdraw,
+                }
+            }
+            18 => {
+                let (&num_margin_tiers, mut rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let mut margin_tiers = Vec::with_capacity(num_margin_tiers as usize);
+                for _ in 0..num_margin_tiers {
+                    let (max_notional, tier_rest) = Self::unpack_fn::<f64>(rest)?;
+                    let (initial_margin_ratio, tier_rest) = Self::unpack_fn::<f64>(tier_rest)?;
+                    let (maintenance_margin_ratio, tier_rest) = Self::unpack_fn::<f64>(tier_rest)?;
+                    let (max_leverage, tier_rest) = Self::unpack_fn::<f64>(tier_rest)?;
+                    margin_tiers.push(MarginTier {
+                        max_notional,
+                        initial_margin_ratio,
+                        maintenance_margin_ratio,
+                        max_leverage,
+                    });
+                    rest = tier_rest;
+                }
+                Self::UpdateMarginTiers { margin_tiers }
+            }
+            19 => Self::GetHealth {},
+            20 => {
+                let (crank_reward, rest) = Self::unpack_fn::<u64>(rest)?;
+                let (crank_reward_cap, rest) = Self::unpack_fn::<u64>(rest)?;
+                let (crank_reward_interval, _rest) = Self::unpack_fn::<u128>(rest)?;
+                Self::ConfigureCrankReward {
+                    crank_reward,
+                    crank_reward_cap,
+                    crank_reward_interval,
+                }
+            }
+            21 => {
+                let (funding_rate, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (minimum_margin, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (liquidation_bounty, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (minimum_funding_period, rest) = Self::unpack_fn::<u128>(rest)?;
+                let (params_timelock, rest) = Self::unpack_fn::<u128>(rest)?;
+                let (mark_price_band, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::UpdateMarketParams {
+                    funding_rate,
+                    minimum_margin,
+                    liquidation_bounty,
+                    minimum_funding_period,
+                    params_timelock,
+                    mark_price_band,
+                }
+            }
+            22 => Self::ApplyMarketParams {},
+            23 => Self::ProposeAdmin {},
+            24 => Self::AcceptAdmin {},
+            25 => {
+                let (&status, _rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let status = match status {
+                    0 => MarketStatus::Active,
+                    1 => MarketStatus::ReduceOnly,
+                    2 => MarketStatus::Paused,
+                    3 => MarketStatus::Settled,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                Self::SetMarketStatus { status }
+            }
+            26 => Self::SettleMarket {},
+            27 => Self::RedeemSettled {},
+            28 => Self::ClosePosition {},
+            29 => Self::CloseSwap {},
+            30 => {
+                let (&is_long, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let is_long = match is_long {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (size, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (price, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::TransferPosition {
+                    is_long,
+                    size,
+                    price,
+                }
+            }
+            31 => {
+                let (&is_long, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let is_long = match is_long {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (size, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::SplitPosition { is_long, size }
+            }
+            32 => {
+                let (maker_fee_bps, rest) = Self::unpack_fn::<u16>(rest)?;
+                let (taker_fee_bps, rest) = Self::unpack_fn::<u16>(rest)?;
+                let (insurance_fund_fee_share_bps, rest) = Self::unpack_fn::<u16>(rest)?;
+                let (referral_rebate_share_bps, _rest) = Self::unpack_fn::<u16>(rest)?;
+                Self::ConfigureFees {
+                    maker_fee_bps,
+                    taker_fee_bps,
+                    insurance_fund_fee_share_bps,
+                    referral_rebate_share_bps,
+                }
+            }
+            33 => Self::SweepFees {},
+            34 => Self::InitializeReferrer {},
+            35 => Self::LinkReferrer {},
+            36 => Self::ClaimReferralRebates {},
+            37 => {
+                let (base_reserve, rest) = Self::unpack_fn::<f64>(rest)?;
+                let (peg_multiplier, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::InitializeVamm {
+                    base_reserve,
+                    peg_multiplier,
+                }
+            }
+            38 => {
+                let (peg_multiplier, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::RepegVamm { peg_multiplier }
+            }
+            39 => {
+                let (depth_multiplier, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::AdjustVammDepth { depth_multiplier }
+            }
+            40 => {
+                let (&is_long, rest) = rest
+                    .split_first()
+                    .ok_or(PerpetualSwapError::InvalidInstruction)?;
+                let is_long = match is_long {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
+                };
+                let (amount_to_deposit, rest) = Self::unpack_fn::<u64>(rest)?;
+                let (limit_price, _rest) = Self::unpack_fn::<f64>(rest)?;
+                Self::OpenWithVamm {
+                    is_long,
+                    amount_to_deposit,
+                    limit_price,
+                }
+            }
+            41 => Self::RemoveCrossMarginMarket {},
             _ => return Err(PerpetualSwapError::InvalidInstruction.into()),
         })
     }
 
     fn unpack_fn<T: Unpackable>(input: &[u8]) -> Result<(T, &[u8]), ProgramError> {
         if input.len() >= T::get_bytes() {
-            let (amount, rest) = input.split_at(8);
-            let amount = amount
-                .get(..T::get_bytes())
-                .and_then(|slice| slice.try_into().ok())
-                .map(T::from_le_bytes)
-                .ok_or(PerpetualSwapError::InvalidInstruction)?;
-            Ok((amount, rest))
+            let (amount, rest) = input.split_at(T::get_bytes());
+            Ok((T::from_le_bytes(amount), rest))
         } else {
             Err(PerpetualSwapError::InvalidInstruction.into())
         }
     }
 }
+
+/// Creates an `InitializePerpetualSwap` instruction. A quanto market also passes the product and
+/// price accounts of its FX rate.
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_perpetual_swap(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    long_margin_pubkey: &Pubkey,
+    short_margin_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    admin_pubkey: &Pubkey,
+    pyth_product_pubkey: &Pubkey,
+    pyth_price_pubkey: &Pubkey,
+    fx_oracle_pubkeys: Option<(&Pubkey, &Pubkey)>,
+    funding_rate: f64,
+    minimum_margin: f64,
+    liquidation_bounty: f64,
+    minimum_funding_period: u128,
+    liquidation_mode: LiquidationMode,
+    position_size: f64,
+    kind: MarketKind,
+    expiry: u128,
+    settlement_window: u128,
+    contract_type: ContractType,
+    quanto_multiplier: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![0];
+    data.extend_from_slice(&funding_rate.to_le_bytes());
+    data.extend_from_slice(&minimum_margin.to_le_bytes());
+    data.extend_from_slice(&liquidation_bounty.to_le_bytes());
+    data.extend_from_slice(&minimum_funding_period.to_le_bytes());
+    data.push(match liquidation_mode {
+        LiquidationMode::AutoDeleverage => 0,
+        LiquidationMode::SocializedLoss => 1,
+    });
+    data.extend_from_slice(&position_size.to_le_bytes());
+    data.push(match kind {
+        MarketKind::Perpetual => 0,
+        MarketKind::DatedFuture => 1,
+        MarketKind::Quanto => 2,
+    });
+    data.extend_from_slice(&expiry.to_le_bytes());
+    data.extend_from_slice(&settlement_window.to_le_bytes());
+    data.push(match contract_type {
+        ContractType::Linear => 0,
+        ContractType::Inverse => 1,
+    });
+    data.extend_from_slice(&quanto_multiplier.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*long_margin_pubkey, false),
+        AccountMeta::new(*short_margin_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new(*admin_pubkey, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*pyth_product_pubkey, false),
+        AccountMeta::new_readonly(*pyth_price_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some((fx_product_pubkey, fx_price_pubkey)) = fx_oracle_pubkeys {
+        accounts.push(AccountMeta::new_readonly(*fx_product_pubkey, false));
+        accounts.push(AccountMeta::new_readonly(*fx_price_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `InitializeSide` instruction. `referral` is the owner's referral link, with the
+/// referrer and its vault if it links to one, and `position_token` pairs the position mint with
+/// the account receiving the position token, to tokenize the side.
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_side(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    user_transfer_authority_pubkey: &Pubkey,
+    source_pubkey: &Pubkey,
+    margin_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    fee_vault_pubkey: Option<&Pubkey>,
+    referral: Option<(&Pubkey, Option<(&Pubkey, &Pubkey)>)>,
+    position_token: Option<(&Pubkey, &Pubkey)>,
+    amount_to_deposit: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![1];
+    data.extend_from_slice(&amount_to_deposit.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*user_transfer_authority_pubkey, true),
+        AccountMeta::new(*source_pubkey, false),
+        AccountMeta::new(*margin_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(fee_vault_pubkey) = fee_vault_pubkey {
+        accounts.push(AccountMeta::new(*fee_vault_pubkey, false));
+    }
+    push_referral_accounts(&mut accounts, referral);
+    if let Some((position_mint_pubkey, position_token_pubkey)) = position_token {
+        accounts.push(AccountMeta::new(*position_mint_pubkey, false));
+        accounts.push(AccountMeta::new(*position_token_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `TransferFunds` instruction. `crank_reward_accounts` pairs the swap's fee vault with
+/// the account receiving the reward, and is required once the swap pays crank rewards.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_funds(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    long_margin_pubkey: &Pubkey,
+    short_margin_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    crank_reward_accounts: Option<(&Pubkey, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*long_margin_pubkey, false),
+        AccountMeta::new(*short_margin_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some((fee_vault_pubkey, reward_account_pubkey)) = crank_reward_accounts {
+        accounts.push(AccountMeta::new(*fee_vault_pubkey, false));
+        accounts.push(AccountMeta::new(*reward_account_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![5],
+    })
+}
+
+/// Creates a `TransferPosition` instruction. `referral` is the buyer's referral link, with the
+/// referrer and its vault if it links to one. `collateral` is the collateral config and the side's
+/// vault of every asset, if the swap takes non-primary collateral.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_position(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    margin_pubkey: &Pubkey,
+    seller_pubkey: &Pubkey,
+    seller_account_pubkey: &Pubkey,
+    buyer_pubkey: &Pubkey,
+    buyer_account_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    fee_vault_pubkey: Option<&Pubkey>,
+    referral: Option<(&Pubkey, Option<(&Pubkey, &Pubkey)>)>,
+    collateral: Option<(&Pubkey, &[Pubkey])>,
+    is_long: bool,
+    size: f64,
+    price: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![30, is_long as u8];
+    data.extend_from_slice(&size.to_le_bytes());
+    data.extend_from_slice(&price.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*margin_pubkey, false),
+        AccountMeta::new_readonly(*seller_pubkey, true),
+        AccountMeta::new(*seller_account_pubkey, false),
+        AccountMeta::new_readonly(*buyer_pubkey, true),
+        AccountMeta::new(*buyer_account_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(fee_vault_pubkey) = fee_vault_pubkey {
+        accounts.push(AccountMeta::new(*fee_vault_pubkey, false));
+    }
+    push_referral_accounts(&mut accounts, referral);
+    if let Some((collateral_config_pubkey, vault_pubkeys)) = collateral {
+        accounts.push(AccountMeta::new_readonly(*collateral_config_pubkey, false));
+        for vault_pubkey in vault_pubkeys {
+            accounts.push(AccountMeta::new_readonly(*vault_pubkey, false));
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Appends a taker's referral link and, if it links to one, the referrer and its vault.
+fn push_referral_accounts(
+    accounts: &mut Vec<AccountMeta>,
+    referral: Option<(&Pubkey, Option<(&Pubkey, &Pubkey)>)>,
+) {
+    if let Some((referral_link_pubkey, referrer)) = referral {
+        accounts.push(AccountMeta::new_readonly(*referral_link_pubkey, false));
+        if let Some((referrer_pubkey, referrer_vault_pubkey)) = referrer {
+            accounts.push(AccountMeta::new(*referrer_pubkey, false));
+            accounts.push(AccountMeta::new(*referrer_vault_pubkey, false));
+        }
+    }
+}
+
+/// Creates a `SplitPosition` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn split_position(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    new_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    new_authority_pubkey: &Pubkey,
+    long_margin_pubkey: &Pubkey,
+    short_margin_pubkey: &Pubkey,
+    new_long_margin_pubkey: &Pubkey,
+    new_short_margin_pubkey: &Pubkey,
+    new_insurance_fund_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+    counterparty_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    new_fee_vault_pubkey: Option<&Pubkey>,
+    is_long: bool,
+    size: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![31, is_long as u8];
+    data.extend_from_slice(&size.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new(*new_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*new_authority_pubkey, false),
+        AccountMeta::new(*long_margin_pubkey, false),
+        AccountMeta::new(*short_margin_pubkey, false),
+        AccountMeta::new(*new_long_margin_pubkey, false),
+        AccountMeta::new(*new_short_margin_pubkey, false),
+        AccountMeta::new(*new_insurance_fund_pubkey, false),
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new_readonly(*counterparty_pubkey, true),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(new_fee_vault_pubkey) = new_fee_vault_pubkey {
+        accounts.push(AccountMeta::new(*new_fee_vault_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// A side's vault of a non-primary collateral asset, the token account on the other end of the
+/// transfer out of it, and the asset's mint
+pub type CollateralVaultKeys = (Pubkey, Pubkey, Pubkey);
+
+/// Creates a `TryToLiquidate` instruction. `collateral_vaults` holds the liquidated side's vault,
+/// the liquidator's token account and the mint of every asset of `collateral_config`, in order.
+/// `counterparty_collateral_vaults` holds the counterparty's vault, its owner's token account and
+/// the mint of every asset, and is only needed if the swap auto-deleverages.
+#[allow(clippy::too_many_arguments)]
+pub fn try_to_liquidate(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    user_transfer_authority_pubkey: &Pubkey,
+    liquidated_margin_pubkey: &Pubkey,
+    liquidator_account_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    counterparty_margin_pubkey: &Pubkey,
+    counterparty_account_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    collateral_config_pubkey: Option<&Pubkey>,
+    collateral_vaults: &[CollateralVaultKeys],
+    counterparty_collateral_vaults: &[CollateralVaultKeys],
+    collateral: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![4];
+    data.extend_from_slice(&collateral.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*user_transfer_authority_pubkey, true),
+        AccountMeta::new(*liquidated_margin_pubkey, false),
+        AccountMeta::new(*liquidator_account_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new(*counterparty_margin_pubkey, false),
+        AccountMeta::new(*counterparty_account_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+    ];
+    if let Some(collateral_config_pubkey) = collateral_config_pubkey {
+        accounts.push(AccountMeta::new_readonly(*collateral_config_pubkey, false));
+        for (vault_pubkey, dest_pubkey, asset_mint_pubkey) in
+            collateral_vaults.iter().chain(counterparty_collateral_vaults)
+        {
+            accounts.push(AccountMeta::new(*vault_pubkey, false));
+            accounts.push(AccountMeta::new(*dest_pubkey, false));
+            accounts.push(AccountMeta::new_readonly(*asset_mint_pubkey, false));
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `UpdatePrices` instruction, signed by the swap's admin. A quanto market also passes
+/// the product and price accounts of its FX rate.
+#[allow(clippy::too_many_arguments)]
+pub fn update_prices(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    pyth_product_pubkey: &Pubkey,
+    pyth_price_pubkey: &Pubkey,
+    fx_oracle_pubkeys: Option<(&Pubkey, &Pubkey)>,
+    mark_price: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![6];
+    data.extend_from_slice(&mark_price.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*pyth_product_pubkey, false),
+        AccountMeta::new_readonly(*pyth_price_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some((fx_product_pubkey, fx_price_pubkey)) = fx_oracle_pubkeys {
+        accounts.push(AccountMeta::new_readonly(*fx_product_pubkey, false));
+        accounts.push(AccountMeta::new_readonly(*fx_price_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `OracleUpdateIndex` instruction. A quanto market also passes the product and price
+/// accounts of its FX rate.
+pub fn oracle_update_index(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    pyth_product_pubkey: &Pubkey,
+    pyth_price_pubkey: &Pubkey,
+    fx_oracle_pubkeys: Option<(&Pubkey, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*pyth_product_pubkey, false),
+        AccountMeta::new_readonly(*pyth_price_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some((fx_product_pubkey, fx_price_pubkey)) = fx_oracle_pubkeys {
+        accounts.push(AccountMeta::new_readonly(*fx_product_pubkey, false));
+        accounts.push(AccountMeta::new_readonly(*fx_price_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![7],
+    })
+}
+
+/// Creates an `UpdateMarketParams` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn update_market_params(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    funding_rate: f64,
+    minimum_margin: f64,
+    liquidation_bounty: f64,
+    minimum_funding_period: u128,
+    params_timelock: u128,
+    mark_price_band: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![21];
+    data.extend_from_slice(&funding_rate.to_le_bytes());
+    data.extend_from_slice(&minimum_margin.to_le_bytes());
+    data.extend_from_slice(&liquidation_bounty.to_le_bytes());
+    data.extend_from_slice(&minimum_funding_period.to_le_bytes());
+    data.extend_from_slice(&params_timelock.to_le_bytes());
+    data.extend_from_slice(&mark_price_band.to_le_bytes());
+
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `ApplyMarketParams` instruction.
+pub fn apply_market_params(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![22],
+    })
+}
+
+/// Creates a `ProposeAdmin` instruction.
+pub fn propose_admin(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    new_admin_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+        AccountMeta::new_readonly(*new_admin_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![23],
+    })
+}
+
+/// Creates an `AcceptAdmin` instruction.
+pub fn accept_admin(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    new_admin_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*new_admin_pubkey, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![24],
+    })
+}
+
+/// Creates a `SetMarketStatus` instruction.
+pub fn set_market_status(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    status: MarketStatus,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+    ];
+    let status = match status {
+        MarketStatus::Active => 0,
+        MarketStatus::ReduceOnly => 1,
+        MarketStatus::Paused => 2,
+        MarketStatus::Settled => 3,
+    };
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![25, status],
+    })
+}
+
+/// Creates a `SettleMarket` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn settle_market(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    long_margin_pubkey: &Pubkey,
+    short_margin_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    admin_token_account_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new(*admin_pubkey, true),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*long_margin_pubkey, false),
+        AccountMeta::new(*short_margin_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new(*admin_token_account_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![26],
+    })
+}
+
+/// Creates a `RedeemSettled` instruction. `position_token_pubkey` is the owner's account holding
+/// the position token of a tokenized side. `collateral` is the collateral config followed by the
+/// side's `(vault, destination, mint)` of every asset, if the swap takes non-primary collateral.
+#[allow(clippy::too_many_arguments)]
+pub fn redeem_settled(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+    margin_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    position_token_pubkey: Option<&Pubkey>,
+    collateral: Option<(&Pubkey, &[CollateralVaultKeys])>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*margin_pubkey, false),
+        AccountMeta::new(*destination_pubkey, false),
+        AccountMeta::new(*admin_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+    ];
+    if let Some(position_token_pubkey) = position_token_pubkey {
+        accounts.push(AccountMeta::new_readonly(*position_token_pubkey, false));
+    }
+    if let Some((collateral_config_pubkey, vaults)) = collateral {
+        accounts.push(AccountMeta::new_readonly(*collateral_config_pubkey, false));
+        for (vault_pubkey, destination_pubkey, asset_mint_pubkey) in vaults {
+            accounts.push(AccountMeta::new(*vault_pubkey, false));
+            accounts.push(AccountMeta::new(*destination_pubkey, false));
+            accounts.push(AccountMeta::new_readonly(*asset_mint_pubkey, false));
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![27],
+    })
+}
+
+/// Creates a `ClosePosition` instruction. `position_token_pubkey` is the owner's account holding
+/// the position token of a tokenized side. `collateral` is the collateral config followed by the
+/// side's `(vault, destination, mint)` of every asset, if the swap takes non-primary collateral.
+#[allow(clippy::too_many_arguments)]
+pub fn close_position(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+    margin_pubkey: &Pubkey,
+    counterparty_margin_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    position_token_pubkey: Option<&Pubkey>,
+    collateral: Option<(&Pubkey, &[CollateralVaultKeys])>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*margin_pubkey, false),
+        AccountMeta::new(*counterparty_margin_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new(*destination_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(position_token_pubkey) = position_token_pubkey {
+        accounts.push(AccountMeta::new_readonly(*position_token_pubkey, false));
+    }
+    if let Some((collateral_config_pubkey, vaults)) = collateral {
+        accounts.push(AccountMeta::new_readonly(*collateral_config_pubkey, false));
+        for (vault_pubkey, destination_pubkey, asset_mint_pubkey) in vaults {
+            accounts.push(AccountMeta::new(*vault_pubkey, false));
+            accounts.push(AccountMeta::new(*destination_pubkey, false));
+            accounts.push(AccountMeta::new_readonly(*asset_mint_pubkey, false));
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![28],
+    })
+}
+
+/// Creates a `CloseSwap` instruction. `collateral` is the collateral config followed by the
+/// `(long vault, short vault)` pair of every asset, if the swap takes non-primary collateral.
+#[allow(clippy::too_many_arguments)]
+pub fn close_swap(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    long_margin_pubkey: &Pubkey,
+    short_margin_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    admin_token_account_pubkey: &Pubkey,
+    receiver_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    fee_vault_pubkey: Option<&Pubkey>,
+    collateral: Option<(&Pubkey, &[(Pubkey, Pubkey)])>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*long_margin_pubkey, false),
+        AccountMeta::new(*short_margin_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new(*admin_token_account_pubkey, false),
+        AccountMeta::new(*receiver_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+    ];
+    if let Some(fee_vault_pubkey) = fee_vault_pubkey {
+        accounts.push(AccountMeta::new(*fee_vault_pubkey, false));
+    }
+    if let Some((collateral_config_pubkey, vaults)) = collateral {
+        accounts.push(AccountMeta::new(*collateral_config_pubkey, false));
+        for (long_vault_pubkey, short_vault_pubkey) in vaults {
+            accounts.push(AccountMeta::new(*long_vault_pubkey, false));
+            accounts.push(AccountMeta::new(*short_vault_pubkey, false));
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![29],
+    })
+}
+
+/// Creates a `ConfigureFees` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn configure_fees(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    fee_vault_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    admin_pubkey: &Pubkey,
+    maker_fee_bps: u16,
+    taker_fee_bps: u16,
+    insurance_fund_fee_share_bps: u16,
+    referral_rebate_share_bps: u16,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![32];
+    data.extend_from_slice(&maker_fee_bps.to_le_bytes());
+    data.extend_from_slice(&taker_fee_bps.to_le_bytes());
+    data.extend_from_slice(&insurance_fund_fee_share_bps.to_le_bytes());
+    data.extend_from_slice(&referral_rebate_share_bps.to_le_bytes());
+
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*fee_vault_pubkey, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new(*admin_pubkey, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `SweepFees` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn sweep_fees(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    fee_vault_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    admin_token_account_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*fee_vault_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new(*admin_token_account_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![33],
+    })
+}
+
+/// Creates an `InitializeReferrer` instruction.
+pub fn initialize_referrer(
+    program_id: &Pubkey,
+    referrer_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    vault_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*referrer_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new(*vault_pubkey, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![34],
+    })
+}
+
+/// Creates a `LinkReferrer` instruction.
+pub fn link_referrer(
+    program_id: &Pubkey,
+    referral_link_pubkey: &Pubkey,
+    referrer_pubkey: &Pubkey,
+    user_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*referral_link_pubkey, false),
+        AccountMeta::new_readonly(*referrer_pubkey, false),
+        AccountMeta::new(*user_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![35],
+    })
+}
+
+/// Creates a `ClaimReferralRebates` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn claim_referral_rebates(
+    program_id: &Pubkey,
+    referrer_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+    vault_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*referrer_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*vault_pubkey, false),
+        AccountMeta::new(*destination_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![36],
+    })
+}
+
+/// Creates an `InitializeVamm` instruction.
+pub fn initialize_vamm(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    base_reserve: f64,
+    peg_multiplier: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![37];
+    data.extend_from_slice(&base_reserve.to_le_bytes());
+    data.extend_from_slice(&peg_multiplier.to_le_bytes());
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vamm_admin_accounts(perpetual_swap_pubkey, admin_pubkey),
+        data,
+    })
+}
+
+/// Creates a `RepegVamm` instruction.
+pub fn repeg_vamm(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    peg_multiplier: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![38];
+    data.extend_from_slice(&peg_multiplier.to_le_bytes());
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vamm_admin_accounts(perpetual_swap_pubkey, admin_pubkey),
+        data,
+    })
+}
+
+/// Creates an `AdjustVammDepth` instruction.
+pub fn adjust_vamm_depth(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    admin_pubkey: &Pubkey,
+    depth_multiplier: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![39];
+    data.extend_from_slice(&depth_multiplier.to_le_bytes());
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vamm_admin_accounts(perpetual_swap_pubkey, admin_pubkey),
+        data,
+    })
+}
+
+/// The swap and its signing admin, the accounts of every vAMM admin instruction.
+fn vamm_admin_accounts(perpetual_swap_pubkey: &Pubkey, admin_pubkey: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*admin_pubkey, true),
+    ]
+}
+
+/// Creates an `OpenWithVamm` instruction. `referral` is the owner's referral link, with the
+/// referrer and its vault if it links to one.
+#[allow(clippy::too_many_arguments)]
+pub fn open_with_vamm(
+    program_id: &Pubkey,
+    perpetual_swap_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    user_transfer_authority_pubkey: &Pubkey,
+    source_pubkey: &Pubkey,
+    margin_pubkey: &Pubkey,
+    vamm_margin_pubkey: &Pubkey,
+    insurance_fund_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+    mint_pubkey: &Pubkey,
+    fee_vault_pubkey: Option<&Pubkey>,
+    referral: Option<(&Pubkey, Option<(&Pubkey, &Pubkey)>)>,
+    is_long: bool,
+    amount_to_deposit: u64,
+    limit_price: f64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![40, is_long as u8];
+    data.extend_from_slice(&amount_to_deposit.to_le_bytes());
+    data.extend_from_slice(&limit_price.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*perpetual_swap_pubkey, false),
+        AccountMeta::new_readonly(*authority_pubkey, false),
+        AccountMeta::new_readonly(*user_transfer_authority_pubkey, true),
+        AccountMeta::new(*source_pubkey, false),
+        AccountMeta::new(*margin_pubkey, false),
+        AccountMeta::new(*vamm_margin_pubkey, false),
+        AccountMeta::new(*insurance_fund_pubkey, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(fee_vault_pubkey) = fee_vault_pubkey {
+        accounts.push(AccountMeta::new(*fee_vault_pubkey, false));
+    }
+    push_referral_accounts(&mut accounts, referral);
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    pub fn test_instruction_builders_unpack() {
+        let program_id = Pubkey::new_unique();
+        let keys: Vec<Pubkey> = (0..13).map(|_| Pubkey::new_unique()).collect();
+
+        let instruction = initialize_perpetual_swap(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], &keys[9], None, 0.01, 0.1, 0.05, 3_600_000,
+            LiquidationMode::SocializedLoss, 2.5, MarketKind::DatedFuture, 1_700_000_000_000,
+            3_600_000, ContractType::Inverse, 0.5,
+        )
+        .unwrap();
+        assert!(instruction.accounts[8].is_signer);
+        assert_eq!(instruction.accounts[9].pubkey, system_program::id());
+        assert_eq!(instruction.accounts[11].pubkey, keys[9]);
+        assert_eq!(instruction.accounts[12].pubkey, sysvar::clock::id());
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::InitializePerpetualSwap {
+                funding_rate,
+                minimum_margin,
+                liquidation_bounty,
+                minimum_funding_period,
+                liquidation_mode,
+                position_size,
+                kind,
+                expiry,
+                settlement_window,
+                contract_type,
+                quanto_multiplier,
+            } => {
+                assert_eq!(funding_rate, 0.01);
+                assert_eq!(minimum_margin, 0.1);
+                assert_eq!(liquidation_bounty, 0.05);
+                assert_eq!(minimum_funding_period, 3_600_000);
+                assert_eq!(liquidation_mode, LiquidationMode::SocializedLoss);
+                assert_eq!(position_size, 2.5);
+                assert_eq!(kind, MarketKind::DatedFuture);
+                assert_eq!(expiry, 1_700_000_000_000);
+                assert_eq!(settlement_window, 3_600_000);
+                assert_eq!(contract_type, ContractType::Inverse);
+                assert_eq!(quanto_multiplier, 0.5);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = initialize_side(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            Some(&keys[9]), Some((&keys[10], None)), Some((&keys[7], &keys[8])), 100,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 12);
+        assert_eq!(instruction.accounts[8].pubkey, keys[9]);
+        assert!(!instruction.accounts[9].is_writable);
+        assert!(instruction.accounts[10].is_writable && instruction.accounts[11].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::InitializeSide { amount_to_deposit } => {
+                assert_eq!(amount_to_deposit, 100)
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = transfer_funds(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5],
+            Some((&keys[6], &keys[7])),
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 9);
+        assert_eq!(instruction.accounts[5].pubkey, keys[5]);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::TransferFunds {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = transfer_position(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], Some(&keys[8]), Some((&keys[9], Some((&keys[10], &keys[11])))),
+            Some((&keys[12], &[keys[1]])), false, 1.5, 101.25,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 15);
+        assert_eq!(instruction.accounts[13].pubkey, keys[12]);
+        assert!(!instruction.accounts[14].is_writable);
+        assert!(instruction.accounts[2].is_signer && instruction.accounts[4].is_signer);
+        assert_eq!(instruction.accounts[8].pubkey, sysvar::clock::id());
+        assert!(instruction.accounts[9].is_writable);
+        assert_eq!(instruction.accounts[10].pubkey, keys[9]);
+        assert!(instruction.accounts[11].is_writable && instruction.accounts[12].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::TransferPosition {
+                is_long,
+                size,
+                price,
+            } => {
+                assert!(!is_long);
+                assert_eq!(size, 1.5);
+                assert_eq!(price, 101.25);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+        assert!(PerpetualSwapInstruction::unpack(&[30, 2]).is_err());
+
+        let instruction = split_position(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], &keys[9], &keys[10], &keys[11], &keys[12], None, true, 0.25,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 15);
+        assert!(instruction.accounts[9].is_signer && instruction.accounts[9].is_writable);
+        assert!(instruction.accounts[10].is_signer && !instruction.accounts[10].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::SplitPosition { is_long, size } => {
+                assert!(is_long);
+                assert_eq!(size, 0.25);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = try_to_liquidate(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], &keys[5], Some(&keys[1]), &[(keys[2], keys[3], keys[4])],
+            &[(keys[6], keys[7], keys[4])], 42,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 17);
+        assert!(!instruction.accounts[13].is_writable);
+        assert_eq!(instruction.accounts[14].pubkey, keys[6]);
+        assert!(instruction.accounts[15].is_writable && !instruction.accounts[16].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::TryToLiquidate { collateral } => assert_eq!(collateral, 42),
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = update_prices(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5],
+            Some((&keys[6], &keys[7])), 101.5,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 9);
+        assert!(instruction.accounts[1].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::UpdatePrices { mark_price } => assert_eq!(mark_price, 101.5),
+            _ => panic!("unexpected instruction"),
+        }
+        let instruction =
+            oracle_update_index(&program_id, &keys[0], &keys[1], &keys[2], None).unwrap();
+        assert_eq!(instruction.accounts.len(), 4);
+        assert_eq!(instruction.data, vec![7]);
+
+        let instruction = update_market_params(
+            &program_id, &keys[0], &keys[1], 0.02, 0.2, 0.1, 60_000, 86_400_000, 0.05,
+        )
+        .unwrap();
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::UpdateMarketParams {
+                funding_rate,
+                minimum_margin,
+                liquidation_bounty,
+                minimum_funding_period,
+                params_timelock,
+                mark_price_band,
+            } => {
+                assert_eq!(funding_rate, 0.02);
+                assert_eq!(minimum_margin, 0.2);
+                assert_eq!(liquidation_bounty, 0.1);
+                assert_eq!(minimum_funding_period, 60_000);
+                assert_eq!(params_timelock, 86_400_000);
+                assert_eq!(mark_price_band, 0.05);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+        for (instruction, tag) in [
+            (apply_market_params(&program_id, &keys[0]).unwrap(), 22),
+            (propose_admin(&program_id, &keys[0], &keys[1], &keys[2]).unwrap(), 23),
+            (accept_admin(&program_id, &keys[0], &keys[2]).unwrap(), 24),
+        ]
+        .iter()
+        {
+            assert_eq!(instruction.data, vec![*tag]);
+            assert!(PerpetualSwapInstruction::unpack(&instruction.data).is_ok());
+        }
+
+        let instruction =
+            set_market_status(&program_id, &keys[0], &keys[1], MarketStatus::Paused).unwrap();
+        assert!(instruction.accounts[1].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::SetMarketStatus { status } => {
+                assert_eq!(status, MarketStatus::Paused)
+            }
+            _ => panic!("unexpected instruction"),
+        }
+        assert!(PerpetualSwapInstruction::unpack(&[25, 4]).is_err());
+
+        let instruction = settle_market(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[5],
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 10);
+        assert!(instruction.accounts[1].is_signer && instruction.accounts[1].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::SettleMarket {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = redeem_settled(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[5], None, Some((&keys[7], &[(keys[8], keys[3], keys[4])])),
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 12);
+        assert!(instruction.accounts[2].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::RedeemSettled {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = close_position(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], Some(&keys[9]), None,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 11);
+        assert_eq!(instruction.accounts[10].pubkey, keys[9]);
+        assert!(instruction.accounts[2].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::ClosePosition {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = close_swap(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], &keys[9], Some(&keys[10]), Some((&keys[11], &[(keys[3], keys[4])])),
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 14);
+        assert!(instruction.accounts[1].is_signer);
+        assert!(instruction.accounts[7].is_writable);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::CloseSwap {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = configure_fees(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], 2, 5, 2_500,
+            2_000,
+        )
+        .unwrap();
+        assert!(instruction.accounts[6].is_signer && instruction.accounts[6].is_writable);
+        assert_eq!(instruction.accounts[7].pubkey, system_program::id());
+        assert_eq!(instruction.accounts[8].pubkey, sysvar::clock::id());
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::ConfigureFees {
+                maker_fee_bps,
+                taker_fee_bps,
+                insurance_fund_fee_share_bps,
+                referral_rebate_share_bps,
+            } => {
+                assert_eq!(maker_fee_bps, 2);
+                assert_eq!(taker_fee_bps, 5);
+                assert_eq!(insurance_fund_fee_share_bps, 2_500);
+                assert_eq!(referral_rebate_share_bps, 2_000);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+        assert!(PerpetualSwapInstruction::unpack(&[32, 2, 0, 5, 0, 196, 9]).is_err());
+
+        let instruction = sweep_fees(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7],
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 8);
+        assert!(instruction.accounts[1].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::SweepFees {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = initialize_referrer(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5],
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 7);
+        assert!(instruction.accounts[4].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::InitializeReferrer {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = link_referrer(&program_id, &keys[0], &keys[1], &keys[2]).unwrap();
+        assert!(instruction.accounts[2].is_signer && instruction.accounts[2].is_writable);
+        assert_eq!(instruction.accounts[4].pubkey, system_program::id());
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::LinkReferrer {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = claim_referral_rebates(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 7);
+        assert!(instruction.accounts[2].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::ClaimReferralRebates {} => {}
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = initialize_vamm(&program_id, &keys[0], &keys[1], 1_000.0, 100.0).unwrap();
+        assert!(instruction.accounts[1].is_signer);
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::InitializeVamm {
+                base_reserve,
+                peg_multiplier,
+            } => {
+                assert_eq!(base_reserve, 1_000.0);
+                assert_eq!(peg_multiplier, 100.0);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = repeg_vamm(&program_id, &keys[0], &keys[1], 101.5).unwrap();
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::RepegVamm { peg_multiplier } => {
+                assert_eq!(peg_multiplier, 101.5)
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = adjust_vamm_depth(&program_id, &keys[0], &keys[1], 2.0).unwrap();
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::AdjustVammDepth { depth_multiplier } => {
+                assert_eq!(depth_multiplier, 2.0)
+            }
+            _ => panic!("unexpected instruction"),
+        }
+
+        let instruction = open_with_vamm(
+            &program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6],
+            &keys[7], &keys[8], Some(&keys[9]), Some((&keys[10], None)), false, 50, 99.0,
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 12);
+        assert!(instruction.accounts[2].is_signer);
+        assert!(instruction.accounts[5].is_writable && instruction.accounts[6].is_writable);
+        assert_eq!(instruction.accounts[9].pubkey, sysvar::clock::id());
+        match PerpetualSwapInstruction::unpack(&instruction.data).unwrap() {
+            PerpetualSwapInstruction::OpenWithVamm {
+                is_long,
+                amount_to_deposit,
+                limit_price,
+            } => {
+                assert!(!is_long);
+                assert_eq!(amount_to_deposit, 50);
+                assert_eq!(limit_price, 99.0);
+            }
+            _ => panic!("unexpected instruction"),
+        }
+        assert!(PerpetualSwapInstruction::unpack(&[40, 2, 50, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(matches!(
+            PerpetualSwapInstruction::unpack(&[41]).unwrap(),
+            PerpetualSwapInstruction::RemoveCrossMarginMarket {}
+        ));
+    }
+}
@@ -1,6 +1,9 @@
+pub mod accounts;
 pub mod entrypoint;
 pub mod error;
+pub mod event;
 pub mod instruction;
+pub mod math;
 pub mod processor;
 pub mod state;
 pub mod traits;
@@ -9,3 +12,8 @@ pub mod traits;
 pub use solana_program;
 
 solana_program::declare_id!("Perp111111111111111111111111111111111111111");
+
+/// The Pyth oracle program, which must own every product and price account a swap reads
+pub mod pyth {
+    solana_program::declare_id!("FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH");
+}
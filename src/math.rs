@@ -0,0 +1,1121 @@
+//! Pure position math shared by the processor and off-chain clients.
+//!
+//! Nothing in here touches accounts or syscalls, so front-ends and risk tooling can call these
+//! functions natively on a deserialized `PerpetualSwap` to get the same figures the program uses.
+
+use crate::state::{ContractType, MarginTier, MarketKind, MarketParams, PerpetualSwap};
+
+/// Collateral paid per point of price per unit of size. Only a quanto market pays other than one,
+/// at its fixed multiplier whatever the FX rate does.
+pub fn quanto_multiplier(perpetual_swap: &PerpetualSwap) -> f64 {
+    if perpetual_swap.kind == MarketKind::Quanto {
+        perpetual_swap.quanto_multiplier
+    } else {
+        1.0
+    }
+}
+
+/// Collateral per unit of the price's currency at the live FX rate. A quanto market falls back to
+/// its multiplier until the FX oracle has been read.
+pub fn fx_rate(perpetual_swap: &PerpetualSwap) -> f64 {
+    if perpetual_swap.kind != MarketKind::Quanto {
+        1.0
+    } else if perpetual_swap.fx_rate > 0.0 {
+        perpetual_swap.fx_rate
+    } else {
+        perpetual_swap.quanto_multiplier
+    }
+}
+
+/// Value of one side's position at the current mark price, in units of the collateral.
+pub fn notional(perpetual_swap: &PerpetualSwap) -> f64 {
+    notional_at_price(
+        perpetual_swap,
+        perpetual_swap.position_size,
+        perpetual_swap.mark_price,
+    )
+}
+
+/// Value of `size` units at `price`, in units of the collateral.
+pub fn notional_at_price(perpetual_swap: &PerpetualSwap, size: f64, price: f64) -> f64 {
+    match perpetual_swap.contract_type {
+        ContractType::Linear => size * price * fx_rate(perpetual_swap),
+        ContractType::Inverse => size / price,
+    }
+}
+
+/// The fee of `fee_bps` on `size` units traded at `price`, rounded down. Nothing is charged before
+/// there's a price.
+pub fn trading_fee(perpetual_swap: &PerpetualSwap, size: f64, price: f64, fee_bps: u16) -> u64 {
+    if price <= 0.0 {
+        return 0;
+    }
+    (notional_at_price(perpetual_swap, size, price) * fee_bps as f64 / 10_000.0) as u64
+}
+
+/// Price of the vAMM: its quote reserve per unit of base reserve, scaled by the peg multiplier.
+pub fn vamm_price(perpetual_swap: &PerpetualSwap) -> f64 {
+    perpetual_swap.vamm_quote_reserve / perpetual_swap.vamm_base_reserve
+        * perpetual_swap.vamm_peg_multiplier
+}
+
+/// The vAMM's base and quote reserves once a taker buys (`is_buy`) or sells `size` units of the
+/// base, keeping their product constant. None if the vAMM can't sell `size` units.
+pub fn vamm_reserves_after(
+    perpetual_swap: &PerpetualSwap,
+    is_buy: bool,
+    size: f64,
+) -> Option<(f64, f64)> {
+    let base = perpetual_swap.vamm_base_reserve;
+    let k = base * perpetual_swap.vamm_quote_reserve;
+    let base_after = if is_buy { base - size } else { base + size };
+    if !(size > 0.0 && base_after > 0.0 && k.is_finite()) {
+        return None;
+    }
+    Some((base_after, k / base_after))
+}
+
+/// Average price of a taker buying (`is_buy`) or selling `size` units against the vAMM. It
+/// moves against the taker the more of the reserves the trade takes.
+pub fn vamm_execution_price(
+    perpetual_swap: &PerpetualSwap,
+    is_buy: bool,
+    size: f64,
+) -> Option<f64> {
+    let (_, quote_after) = vamm_reserves_after(perpetual_swap, is_buy, size)?;
+    let quote_traded = (quote_after - perpetual_swap.vamm_quote_reserve).abs();
+    Some(quote_traded / size * perpetual_swap.vamm_peg_multiplier)
+}
+
+/// The peg multiplier that moves the vAMM's price toward the index by the share of the gap that
+/// funding over `days` closes, all of it once a full funding rate has accrued. The peg stays put
+/// until there is an index to move toward.
+pub fn vamm_funding_peg(perpetual_swap: &PerpetualSwap, days: f64) -> f64 {
+    if perpetual_swap.index_price <= 0.0 {
+        return perpetual_swap.vamm_peg_multiplier;
+    }
+    let price = vamm_price(perpetual_swap);
+    let pull = (perpetual_swap.funding_rate * days).clamp(0.0, 1.0);
+    let target = price + (perpetual_swap.index_price - price) * pull;
+    perpetual_swap.vamm_peg_multiplier * target / price
+}
+
+/// What a long of `size` units makes as the price moves from `from` to `to`, in units of the
+/// collateral. An inverse contract makes `size * (1 / from - 1 / to)`, so a long's gain is capped
+/// at `size / from` while its loss grows without bound as the price falls to zero.
+pub fn price_change_value(perpetual_swap: &PerpetualSwap, size: f64, from: f64, to: f64) -> f64 {
+    match perpetual_swap.contract_type {
+        ContractType::Linear => (to - from) * size * quanto_multiplier(perpetual_swap),
+        // Nothing is made before a price is known
+        ContractType::Inverse if from <= 0.0 || to <= 0.0 => 0.0,
+        ContractType::Inverse => size * (1.0 / from - 1.0 / to),
+    }
+}
+
+/// Unrealized PnL of one side of the swap at the current mark price.
+pub fn unrealized_pnl(perpetual_swap: &PerpetualSwap, is_long: bool) -> f64 {
+    pnl_at_price(
+        perpetual_swap,
+        is_long,
+        perpetual_swap.position_size,
+        perpetual_swap.mark_price,
+    )
+}
+
+/// PnL of `size` units of one side, had the mark price been `price`.
+pub fn pnl_at_price(perpetual_swap: &PerpetualSwap, is_long: bool, size: f64, price: f64) -> f64 {
+    let price_change = price_change_value(perpetual_swap, size, perpetual_swap.entry_price, price);
+    if is_long {
+        price_change
+    } else {
+        -price_change
+    }
+}
+
+/// Whether `mark_price` is within the swap's band of `index_price`. A NaN mark never is.
+pub fn is_mark_within_band(
+    perpetual_swap: &PerpetualSwap,
+    mark_price: f64,
+    index_price: f64,
+) -> bool {
+    (mark_price - index_price).abs() <= perpetual_swap.mark_price_band * index_price
+}
+
+/// Funding the long owes the short for `days` of funding, negative when the short owes the long.
+/// It's the value of the gap between the mark and the index, times the funding rate.
+pub fn funding_payment(perpetual_swap: &PerpetualSwap, days: f64) -> f64 {
+    price_change_value(
+        perpetual_swap,
+        perpetual_swap.position_size,
+        perpetual_swap.index_price,
+        perpetual_swap.mark_price,
+    ) * perpetual_swap.funding_rate
+        * days
+}
+
+/// Margin balance plus unrealized PnL. A negative equity means the side is bankrupt.
+pub fn equity(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> f64 {
+    margin as f64 + unrealized_pnl(perpetual_swap, is_long)
+}
+
+/// The index weighted by the milliseconds it held for, up to `now`, on the assumption that the
+/// current index has held since its last update.
+pub fn index_cumulative(perpetual_swap: &PerpetualSwap, now: u128) -> f64 {
+    perpetual_swap.index_cumulative
+        + perpetual_swap.index_price * now.saturating_sub(perpetual_swap.index_twap_time) as f64
+}
+
+/// Time-weighted average of the index from the TWAP's start up to `now`. The start is rolled
+/// forward on index updates, so it lies one to two `INDEX_TWAP_WINDOW`s back, plus however long
+/// the index went without an update.
+pub fn index_twap(perpetual_swap: &PerpetualSwap, now: u128) -> f64 {
+    let now = now.max(perpetual_swap.index_twap_time);
+    if perpetual_swap.index_twap_time == 0 || now <= perpetual_swap.index_twap_start_time {
+        return perpetual_swap.index_price;
+    }
+    (index_cumulative(perpetual_swap, now) - perpetual_swap.index_twap_start_cumulative)
+        / (now - perpetual_swap.index_twap_start_time) as f64
+}
+
+/// A dated future's index sum and the milliseconds of its settlement window it covers, rolled
+/// forward to `now` on the assumption that the current index has held since its last update.
+pub fn settlement_index_sum(perpetual_swap: &PerpetualSwap, now: u128) -> (f64, u128) {
+    let mut sum = perpetual_swap.settlement_index_sum;
+    let mut time = perpetual_swap.settlement_index_time;
+    if perpetual_swap.index_twap_time != 0 {
+        let window_start = perpetual_swap
+            .expiry
+            .saturating_sub(perpetual_swap.settlement_window);
+        let start = perpetual_swap.index_twap_time.max(window_start);
+        let end = now.min(perpetual_swap.expiry);
+        if end > start {
+            sum += perpetual_swap.index_price * (end - start) as f64;
+            time += end - start;
+        }
+    }
+    (sum, time)
+}
+
+/// Time-weighted average of the index over the part of a dated future's settlement window up to
+/// `now`. `None` while no index is known for any of the window.
+pub fn settlement_twap(perpetual_swap: &PerpetualSwap, now: u128) -> Option<f64> {
+    let (sum, time) = settlement_index_sum(perpetual_swap, now);
+    if time == 0 {
+        None
+    } else {
+        Some(sum / time as f64)
+    }
+}
+
+/// The mark price at which the side's equity hits zero. An inverse short with more margin than the
+/// position is worth at entry can't go bankrupt, and gets infinity.
+pub fn bankruptcy_price(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> f64 {
+    let size = perpetual_swap.position_size;
+    let entry_price = perpetual_swap.entry_price;
+    match perpetual_swap.contract_type {
+        ContractType::Linear if is_long => {
+            entry_price - margin as f64 / (size * quanto_multiplier(perpetual_swap))
+        }
+        ContractType::Linear => {
+            entry_price + margin as f64 / (size * quanto_multiplier(perpetual_swap))
+        }
+        ContractType::Inverse => {
+            // Solve `margin ± size * (1 / entry - 1 / price) = 0` for 1 / price
+            let margin_per_size = margin as f64 / size;
+            let inverse_price = if is_long {
+                1.0 / entry_price + margin_per_size
+            } else {
+                1.0 / entry_price - margin_per_size
+            };
+            if inverse_price > 0.0 {
+                1.0 / inverse_price
+            } else {
+                f64::INFINITY
+            }
+        }
+    }
+}
+
+/// How much the side's losses exceed its margin, rounded up so the counterparty is paid in full.
+pub fn negative_equity_deficit(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> u64 {
+    let equity = equity(perpetual_swap, is_long, margin);
+    if equity < 0.0 {
+        (-equity).ceil() as u64
+    } else {
+        0
+    }
+}
+
+/// Value of non-primary collateral in units of the primary collateral, after its haircut.
+pub fn haircut_value(amount: u64, price: f64, haircut: f64) -> f64 {
+    amount as f64 * price * haircut
+}
+
+/// Margin value of a side: its primary margin plus the haircut-weighted value of every
+/// `(amount, price, haircut)` non-primary collateral balance. Rounded down.
+pub fn margin_value(primary_margin: u64, collateral: &[(u64, f64, f64)]) -> u64 {
+    let collateral_value: f64 = collateral
+        .iter()
+        .map(|(amount, price, haircut)| haircut_value(*amount, *price, *haircut))
+        .sum();
+    primary_margin.saturating_add(collateral_value as u64)
+}
+
+/// The tier covering a notional, with `minimum_margin` applied as a floor on its ratios. Notionals
+/// larger than every tier use the last one.
+fn tier_for_notional(tiers: &[MarginTier], minimum_margin: f64, notional: f64) -> MarginTier {
+    tiers
+        .iter()
+        .find(|tier| notional <= tier.max_notional)
+        .or_else(|| tiers.last())
+        .copied()
+        .unwrap_or_default()
+        .with_minimum_margin(minimum_margin)
+}
+
+/// The tier covering the position's current notional. Positions larger than every tier use the last one.
+pub fn margin_tier(perpetual_swap: &PerpetualSwap) -> MarginTier {
+    tier_for_notional(
+        perpetual_swap.margin_tiers(),
+        perpetual_swap.minimum_margin,
+        notional(perpetual_swap),
+    )
+}
+
+/// Equity a side needs to open a position, add to it or withdraw margin.
+pub fn initial_margin(perpetual_swap: &PerpetualSwap) -> f64 {
+    let tier = margin_tier(perpetual_swap);
+    notional(perpetual_swap) * tier.initial_margin_ratio.max(1.0 / tier.max_leverage)
+}
+
+/// Equity a side must keep to stay clear of liquidation.
+pub fn maintenance_margin(perpetual_swap: &PerpetualSwap) -> f64 {
+    notional(perpetual_swap) * margin_tier(perpetual_swap).maintenance_margin_ratio
+}
+
+/// Whether `TryToLiquidate` would accept the side: its equity is below maintenance margin or negative.
+pub fn needs_liquidation(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> bool {
+    equity(perpetual_swap, is_long, margin) < maintenance_margin(perpetual_swap)
+}
+
+/// The least collateral a liquidator has to post to take over the side. A bankrupt position
+/// restarts from the mark price, any other keeps its unrealized PnL.
+pub fn liquidation_collateral(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> u64 {
+    let initial_margin = initial_margin(perpetual_swap);
+    let required = if negative_equity_deficit(perpetual_swap, is_long, margin) > 0 {
+        initial_margin
+    } else {
+        initial_margin - unrealized_pnl(perpetual_swap, is_long)
+    };
+    if required <= 0.0 {
+        0
+    } else {
+        required.ceil() as u64
+    }
+}
+
+/// Equity as a fraction of the position's notional.
+pub fn margin_ratio(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> f64 {
+    equity(perpetual_swap, is_long, margin) / notional(perpetual_swap)
+}
+
+/// Equity left over once the initial margin is covered. Negative when the side can't withdraw.
+pub fn free_collateral(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> f64 {
+    equity(perpetual_swap, is_long, margin) - initial_margin(perpetual_swap)
+}
+
+/// The most a side can withdraw from its margin account and still meet the initial margin.
+pub fn max_withdrawable(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> u64 {
+    let free_collateral = free_collateral(perpetual_swap, is_long, margin);
+    if free_collateral <= 0.0 {
+        return 0;
+    }
+    (free_collateral.floor() as u64).min(margin)
+}
+
+/// The mark price at which the side's equity falls to its maintenance margin. Returns 0 for a long
+/// that can't be liquidated at any positive price.
+pub fn liquidation_price(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> f64 {
+    let size = perpetual_swap.position_size;
+    let margin = margin as f64;
+    let tiers = perpetual_swap.margin_tiers();
+
+    // Solve `margin ± (price - entry) * size * multiplier = ratio * size * price * fx` in every
+    // tier, or `margin ± size * (1 / entry - 1 / price) = ratio * size / price` for an inverse
+    // contract, and keep the roots whose notional actually falls in that tier
+    let mut lower_bound = 0.0;
+    let mut candidates = Vec::with_capacity(tiers.len());
+    for (i, tier) in tiers.iter().enumerate() {
+        let ratio = tier
+            .maintenance_margin_ratio
+            .max(perpetual_swap.minimum_margin);
+        let (price, notional) = match perpetual_swap.contract_type {
+            ContractType::Linear => {
+                // The size PnL is paid on, and the size margin is held against
+                let pnl_size = size * quanto_multiplier(perpetual_swap);
+                let notional_size = size * fx_rate(perpetual_swap);
+                let entry_value = perpetual_swap.entry_price * pnl_size;
+                let price = if is_long {
+                    (entry_value - margin) / (pnl_size - ratio * notional_size)
+                } else {
+                    (entry_value + margin) / (pnl_size + ratio * notional_size)
+                };
+                (price, price * notional_size)
+            }
+            ContractType::Inverse => {
+                let entry_value = size / perpetual_swap.entry_price;
+                let price = if is_long {
+                    size * (1.0 + ratio) / (margin + entry_value)
+                } else {
+                    size * (1.0 - ratio) / (entry_value - margin)
+                };
+                (price, size / price)
+            }
+        };
+        let is_last = i + 1 == tiers.len();
+        if price > 0.0 && price.is_finite() && notional > lower_bound && (notional <= tier.max_notional || is_last) {
+            candidates.push(price);
+        }
+        lower_bound = tier.max_notional;
+    }
+
+    // A long is liquidated by the first root hit as the price falls, a short as it rises
+    let price = if is_long {
+        candidates.into_iter().fold(None, |max: Option<f64>, price| {
+            Some(max.map_or(price, |max| max.max(price)))
+        })
+    } else {
+        candidates.into_iter().fold(None, |min: Option<f64>, price| {
+            Some(min.map_or(price, |min| min.min(price)))
+        })
+    };
+    price.unwrap_or(0.0)
+}
+
+/// A crank reward along with the reward interval it is paid in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrankReward {
+    pub amount: u64,
+    pub interval_start: u128,
+    /// Paid out in the interval, this reward included
+    pub paid_in_interval: u64,
+}
+
+/// The reward for a funding crank at `now`, capped by what's left of the interval's cap and by the
+/// fee vault's balance. A new interval starts once the current one has elapsed.
+pub fn crank_reward(perpetual_swap: &PerpetualSwap, now: u128, fee_vault_balance: u64) -> CrankReward {
+    let interval_end = perpetual_swap
+        .crank_reward_interval_start
+        .saturating_add(perpetual_swap.crank_reward_interval);
+    let (interval_start, paid) = if now >= interval_end {
+        (now, 0)
+    } else {
+        (
+            perpetual_swap.crank_reward_interval_start,
+            perpetual_swap.crank_rewards_paid,
+        )
+    };
+    let amount = perpetual_swap
+        .crank_reward
+        .min(perpetual_swap.crank_reward_cap.saturating_sub(paid))
+        .min(fee_vault_balance);
+    CrankReward {
+        amount,
+        interval_start,
+        paid_in_interval: paid + amount,
+    }
+}
+
+/// Health figures for one side of a swap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SideHealth {
+    pub equity: f64,
+    pub margin_ratio: f64,
+    pub free_collateral: f64,
+    pub max_withdrawable: u64,
+    pub liquidation_price: f64,
+}
+
+/// Health figures for both sides of a swap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwapHealth {
+    pub long: SideHealth,
+    pub short: SideHealth,
+}
+
+pub fn side_health(perpetual_swap: &PerpetualSwap, is_long: bool, margin: u64) -> SideHealth {
+    SideHealth {
+        equity: equity(perpetual_swap, is_long, margin),
+        margin_ratio: margin_ratio(perpetual_swap, is_long, margin),
+        free_collateral: free_collateral(perpetual_swap, is_long, margin),
+        max_withdrawable: max_withdrawable(perpetual_swap, is_long, margin),
+        liquidation_price: liquidation_price(perpetual_swap, is_long, margin),
+    }
+}
+
+pub fn swap_health(perpetual_swap: &PerpetualSwap, long_margin: u64, short_margin: u64) -> SwapHealth {
+    SwapHealth {
+        long: side_health(perpetual_swap, true, long_margin),
+        short: side_health(perpetual_swap, false, short_margin),
+    }
+}
+
+/// Checks that tiers are sorted by notional, get stricter as positions grow and have consistent ratios.
+/// A maintenance ratio must stay below 1, or a long has no liquidation price.
+pub fn validate_margin_tiers(tiers: &[MarginTier]) -> bool {
+    if tiers.is_empty() || tiers.len() > PerpetualSwap::MAX_MARGIN_TIERS {
+        return false;
+    }
+    let ratios_valid = tiers.iter().all(|tier| {
+        tier.max_notional > 0.0
+            && tier.maintenance_margin_ratio > 0.0
+            && tier.maintenance_margin_ratio < 1.0
+            && tier.maintenance_margin_ratio <= tier.initial_margin_ratio
+            && tier.initial_margin_ratio <= 1.0
+            && tier.max_leverage >= 1.0
+    });
+    ratios_valid
+        && tiers.windows(2).all(|pair| {
+            pair[0].max_notional < pair[1].max_notional
+                && pair[0].maintenance_margin_ratio <= pair[1].maintenance_margin_ratio
+                && pair[0].initial_margin_ratio <= pair[1].initial_margin_ratio
+                && pair[0].max_leverage >= pair[1].max_leverage
+        })
+}
+
+/// Checks that every market parameter is within the bounds the program accepts.
+pub fn validate_market_params(params: &MarketParams) -> bool {
+    params.funding_rate >= 0.0
+        && params.funding_rate <= PerpetualSwap::MAX_FUNDING_RATE
+        && params.minimum_margin > 0.0
+        && params.minimum_margin < 1.0
+        && params.liquidation_bounty >= 0.0
+        && params.liquidation_bounty <= 1.0
+        && params.minimum_funding_period <= PerpetualSwap::MAX_MINIMUM_FUNDING_PERIOD
+        && params.params_timelock <= PerpetualSwap::MAX_PARAMS_TIMELOCK
+        && params.mark_price_band > 0.0
+        && params.mark_price_band <= PerpetualSwap::MAX_MARK_PRICE_BAND
+        && params.maker_fee_bps <= PerpetualSwap::MAX_FEE_BPS
+        && params.taker_fee_bps <= PerpetualSwap::MAX_FEE_BPS
+        && params.insurance_fund_fee_share_bps <= 10_000
+        && params.referral_rebate_share_bps <= 10_000
+        && validate_margin_tiers(params.margin_tiers())
+}
+
+/// Whether moving from `current` to `new` raises the margin any position needs, takes a larger
+/// bounty from liquidated positions, lets the mark stray further from the index, shortens the
+/// notice traders get or charges them higher fees. Such changes are timelocked.
+pub fn is_stricter(current: &MarketParams, new: &MarketParams) -> bool {
+    if new.minimum_margin > current.minimum_margin
+        || new.liquidation_bounty > current.liquidation_bounty
+        || new.mark_price_band > current.mark_price_band
+        || new.params_timelock < current.params_timelock
+        || new.maker_fee_bps > current.maker_fee_bps
+        || new.taker_fee_bps > current.taker_fee_bps
+    {
+        return true;
+    }
+    // Both tables are constant between tier boundaries, so comparing them at every boundary
+    // covers every notional
+    let ratios = |params: &MarketParams, notional: f64| {
+        let tier = tier_for_notional(params.margin_tiers(), params.minimum_margin, notional);
+        (
+            tier.initial_margin_ratio.max(1.0 / tier.max_leverage),
+            tier.maintenance_margin_ratio,
+        )
+    };
+    current
+        .margin_tiers()
+        .iter()
+        .chain(new.margin_tiers())
+        .map(|tier| tier.max_notional)
+        .chain(std::iter::once(f64::MAX))
+        .any(|notional| {
+            let (current_initial, current_maintenance) = ratios(current, notional);
+            let (new_initial, new_maintenance) = ratios(new, notional);
+            new_initial > current_initial || new_maintenance > current_maintenance
+        })
+}
+
+/// Aggregate health of a cross-margin account over all of its positions.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CrossMarginHealth {
+    pub equity: f64,
+    pub maintenance_margin: f64,
+}
+
+impl CrossMarginHealth {
+    /// Equity left over once every position's maintenance margin is covered.
+    pub fn free_collateral(&self) -> f64 {
+        self.equity - self.maintenance_margin
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.free_collateral() >= 0.0
+    }
+}
+
+/// Health of a vault balance plus `(swap, is_long, margin)` positions it backs.
+pub fn cross_margin_health(vault: u64, positions: &[(&PerpetualSwap, bool, u64)]) -> CrossMarginHealth {
+    positions.iter().fold(
+        CrossMarginHealth {
+            equity: vault as f64,
+            maintenance_margin: 0.0,
+        },
+        |health, (perpetual_swap, is_long, margin)| CrossMarginHealth {
+            equity: health.equity + equity(perpetual_swap, *is_long, *margin),
+            maintenance_margin: health.maintenance_margin + maintenance_margin(perpetual_swap),
+        },
+    )
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use borsh::BorshDeserialize;
+
+    #[test]
+    pub fn test_bankruptcy_price_zeroes_equity() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.position_size = 1.0;
+
+        p.mark_price = bankruptcy_price(&p, true, 30);
+        assert_eq!(p.mark_price, 70.0);
+        assert_eq!(equity(&p, true, 30), 0.0);
+
+        p.mark_price = bankruptcy_price(&p, false, 30);
+        assert_eq!(p.mark_price, 130.0);
+        assert_eq!(equity(&p, false, 30), 0.0);
+
+        p.position_size = 3.0;
+        p.mark_price = bankruptcy_price(&p, true, 30);
+        assert_eq!(p.mark_price, 90.0);
+        assert_eq!(equity(&p, true, 30), 0.0);
+    }
+
+    #[test]
+    pub fn test_negative_equity_deficit() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.mark_price = 60.5;
+        p.position_size = 1.0;
+
+        assert_eq!(negative_equity_deficit(&p, true, 50), 0);
+        assert_eq!(negative_equity_deficit(&p, true, 30), 10);
+        assert_eq!(negative_equity_deficit(&p, false, 0), 0);
+    }
+
+    #[test]
+    pub fn test_inverse_pnl_convexity() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.contract_type = ContractType::Inverse;
+        p.entry_price = 100.0;
+        p.mark_price = 100.0;
+        p.position_size = 10_000.0;
+        assert_eq!(notional(&p), 100.0);
+        assert_eq!(unrealized_pnl(&p, true), 0.0);
+
+        // A move up pays the long less than the same move down costs it
+        assert_eq!(pnl_at_price(&p, true, 10_000.0, 200.0), 50.0);
+        assert_eq!(pnl_at_price(&p, true, 10_000.0, 50.0), -100.0);
+
+        // The long's gain is capped at the position's value at entry...
+        assert!(pnl_at_price(&p, true, 10_000.0, 1e12) < 100.0);
+        assert!(pnl_at_price(&p, true, 10_000.0, 1e12) > 99.99);
+        // ...while its loss grows without bound as the price falls to zero
+        assert_eq!(pnl_at_price(&p, true, 10_000.0, 1.0), -9_900.0);
+        assert_eq!(pnl_at_price(&p, true, 10_000.0, 1e-6), -1e10 + 100.0);
+        // The short is the mirror image
+        assert_eq!(pnl_at_price(&p, false, 10_000.0, 1e-6), 1e10 - 100.0);
+        assert!(pnl_at_price(&p, false, 10_000.0, 1e12) > -100.0);
+
+        // No PnL before there's a price
+        assert_eq!(pnl_at_price(&p, true, 10_000.0, 0.0), 0.0);
+        p.entry_price = 0.0;
+        assert_eq!(unrealized_pnl(&p, true), 0.0);
+    }
+
+    #[test]
+    pub fn test_inverse_bankruptcy_and_liquidation_price() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.contract_type = ContractType::Inverse;
+        p.entry_price = 100.0;
+        p.position_size = 10_000.0;
+        p.num_margin_tiers = 1;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+
+        p.mark_price = bankruptcy_price(&p, true, 25);
+        assert_eq!(p.mark_price, 80.0);
+        assert!(equity(&p, true, 25).abs() < 1e-9);
+        p.mark_price = bankruptcy_price(&p, false, 25);
+        assert!((p.mark_price - 400.0 / 3.0).abs() < 1e-9);
+        assert!(equity(&p, false, 25).abs() < 1e-9);
+        // A short holding more than the position is worth at entry can't go bankrupt
+        assert_eq!(bankruptcy_price(&p, false, 100), f64::INFINITY);
+
+        p.mark_price = liquidation_price(&p, true, 25);
+        assert!(p.mark_price > 80.0 && p.mark_price < 100.0);
+        assert!((equity(&p, true, 25) - maintenance_margin(&p)).abs() < 1e-9);
+        p.mark_price = liquidation_price(&p, false, 25);
+        assert!(p.mark_price > 100.0 && p.mark_price < 400.0 / 3.0);
+        assert!((equity(&p, false, 25) - maintenance_margin(&p)).abs() < 1e-9);
+        assert_eq!(liquidation_price(&p, false, 100), 0.0);
+    }
+
+    #[test]
+    pub fn test_quanto_pnl_and_margin() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.kind = MarketKind::Quanto;
+        p.quanto_multiplier = 0.5;
+        p.entry_price = 100.0;
+        p.mark_price = 120.0;
+        p.index_price = 100.0;
+        p.position_size = 2.0;
+        p.funding_rate = 0.1;
+        p.num_margin_tiers = 1;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+
+        // PnL and funding are paid at the multiplier...
+        assert_eq!(unrealized_pnl(&p, true), 20.0);
+        assert_eq!(funding_payment(&p, 1.0), 2.0);
+        // ...and margin is sized at it until the FX rate is known
+        assert_eq!(notional(&p), 120.0);
+        p.fx_rate = 0.8;
+        assert_eq!(notional(&p), 192.0);
+        // PnL doesn't move with the FX rate
+        assert_eq!(unrealized_pnl(&p, true), 20.0);
+
+        p.mark_price = bankruptcy_price(&p, true, 30);
+        assert_eq!(p.mark_price, 70.0);
+        assert_eq!(equity(&p, true, 30), 0.0);
+
+        for &is_long in &[true, false] {
+            p.mark_price = liquidation_price(&p, is_long, 30);
+            assert!((equity(&p, is_long, 30) - maintenance_margin(&p)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    pub fn test_trading_fee() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        assert_eq!(trading_fee(&p, 3.0, 1_000.0, 5), 1);
+        assert_eq!(trading_fee(&p, 3.0, 10_000.0, 5), 15);
+        assert_eq!(trading_fee(&p, 3.0, 10_000.0, 0), 0);
+        assert_eq!(trading_fee(&p, 3.0, 0.0, 5), 0);
+
+        p.contract_type = ContractType::Inverse;
+        assert_eq!(trading_fee(&p, 30_000_000.0, 100.0, 5), 150);
+    }
+
+    #[test]
+    pub fn test_vamm_price_impact() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.vamm_base_reserve = 1_000.0;
+        p.vamm_quote_reserve = 1_000.0;
+        p.vamm_peg_multiplier = 100.0;
+        assert_eq!(vamm_price(&p), 100.0);
+
+        // Buying pays more than the price, and the more the larger the trade
+        let small = vamm_execution_price(&p, true, 1.0).unwrap();
+        let large = vamm_execution_price(&p, true, 10.0).unwrap();
+        assert!(100.0 < small && small < large);
+        assert!((large - 100_000.0 / 990.0).abs() < 1e-9);
+        assert!(vamm_execution_price(&p, false, 10.0).unwrap() < 100.0);
+
+        // Trading back out undoes the trade at the same price
+        let (base, quote) = vamm_reserves_after(&p, true, 10.0).unwrap();
+        p.vamm_base_reserve = base;
+        p.vamm_quote_reserve = quote;
+        assert!(vamm_price(&p) > large);
+        assert!((vamm_execution_price(&p, false, 10.0).unwrap() - large).abs() < 1e-9);
+        let (base, quote) = vamm_reserves_after(&p, false, 10.0).unwrap();
+        assert!((base - 1_000.0).abs() < 1e-9 && (quote - 1_000.0).abs() < 1e-9);
+
+        // The vAMM can't sell more than its reserve
+        assert_eq!(vamm_execution_price(&p, true, 990.0), None);
+        assert_eq!(vamm_execution_price(&p, true, 0.0), None);
+    }
+
+    #[test]
+    pub fn test_vamm_funding_peg() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.vamm_base_reserve = 1_000.0;
+        p.vamm_quote_reserve = 1_100.0;
+        p.vamm_peg_multiplier = 100.0;
+        p.index_price = 100.0;
+        p.funding_rate = 0.5;
+
+        // Half a day at half the gap per day closes a quarter of it
+        p.vamm_peg_multiplier = vamm_funding_peg(&p, 0.5);
+        assert!((vamm_price(&p) - 107.5).abs() < 1e-9);
+        // Funding never pulls past the index
+        p.vamm_peg_multiplier = vamm_funding_peg(&p, 10.0);
+        assert!((vamm_price(&p) - 100.0).abs() < 1e-9);
+
+        // Without an index yet, funding leaves the peg alone rather than zeroing it
+        p.index_price = 0.0;
+        assert_eq!(vamm_funding_peg(&p, 0.5), p.vamm_peg_multiplier);
+    }
+
+    #[test]
+    pub fn test_is_mark_within_band() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.mark_price_band = 0.1;
+        assert!(is_mark_within_band(&p, 110.0, 100.0));
+        assert!(is_mark_within_band(&p, 90.0, 100.0));
+        assert!(!is_mark_within_band(&p, 110.5, 100.0));
+        assert!(!is_mark_within_band(&p, 89.5, 100.0));
+        assert!(!is_mark_within_band(&p, f64::NAN, 100.0));
+    }
+
+    #[test]
+    pub fn test_funding_payment() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.index_price = 100.0;
+        p.mark_price = 125.0;
+        p.position_size = 2.0;
+        p.funding_rate = 0.1;
+        assert_eq!(funding_payment(&p, 2.0), 10.0);
+
+        // An inverse contract values the gap in the base asset
+        p.contract_type = ContractType::Inverse;
+        p.position_size = 10_000.0;
+        assert!((funding_payment(&p, 2.0) - 4.0).abs() < 1e-9);
+        p.mark_price = 80.0;
+        assert!((funding_payment(&p, 2.0) + 5.0).abs() < 1e-9);
+        p.index_price = 0.0;
+        assert_eq!(funding_payment(&p, 2.0), 0.0);
+    }
+
+    #[test]
+    pub fn test_index_twap() {
+        let window = PerpetualSwap::INDEX_TWAP_WINDOW;
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.index_price = 100.0;
+        // Before the first update there is only the spot index
+        assert_eq!(index_twap(&p, window), 100.0);
+
+        // 100 for a window, then 120 from then on
+        p.index_twap_start_time = window;
+        p.index_cumulative = 100.0 * window as f64;
+        p.index_twap_time = 2 * window;
+        p.index_price = 120.0;
+        assert_eq!(index_cumulative(&p, 3 * window), 220.0 * window as f64);
+        assert_eq!(index_twap(&p, 2 * window), 100.0);
+        assert_eq!(index_twap(&p, 3 * window), 110.0);
+        // A price that held for a long time outweighs the earlier one, but doesn't replace it
+        assert_eq!(index_twap(&p, 6 * window), 116.0);
+        // A clock that reads earlier than the last update counts as the time of the update
+        assert_eq!(index_twap(&p, 0), 100.0);
+    }
+
+    #[test]
+    pub fn test_settlement_twap() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.expiry = 10_000;
+        p.settlement_window = 4_000;
+        p.index_price = 100.0;
+        // No index yet
+        assert_eq!(settlement_twap(&p, 8_000), None);
+
+        // An index set before the window only counts from the window's start
+        p.index_twap_time = 1_000;
+        assert_eq!(settlement_twap(&p, 5_000), None);
+        assert_eq!(settlement_twap(&p, 8_000), Some(100.0));
+
+        // Half the window at 100, then a new index of 120 from 8 seconds on
+        let (sum, time) = settlement_index_sum(&p, 8_000);
+        p.settlement_index_sum = sum;
+        p.settlement_index_time = time;
+        p.index_twap_time = 8_000;
+        p.index_price = 120.0;
+        assert_eq!(settlement_twap(&p, 10_000), Some(110.0));
+        // Nothing after expiry counts
+        assert_eq!(settlement_twap(&p, 50_000), Some(110.0));
+    }
+
+    #[test]
+    pub fn test_margin_value() {
+        assert_eq!(margin_value(100, &[]), 100);
+        assert_eq!(margin_value(100, &[(10, 2.0, 0.5), (3, 1.0, 0.9)]), 112);
+    }
+
+    #[test]
+    pub fn test_tiered_margin() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.mark_price = 100.0;
+        p.position_size = 1.0;
+        let tiers = [
+            MarginTier {
+                max_notional: 1_000.0,
+                initial_margin_ratio: 0.05,
+                maintenance_margin_ratio: 0.025,
+                max_leverage: 10.0,
+            },
+            MarginTier {
+                max_notional: 10_000.0,
+                initial_margin_ratio: 0.2,
+                maintenance_margin_ratio: 0.1,
+                max_leverage: 5.0,
+            },
+        ];
+        assert!(validate_margin_tiers(&tiers));
+        assert!(!validate_margin_tiers(&[tiers[1], tiers[0]]));
+        // `liquidation_price` divides by `1 - maintenance_margin_ratio`
+        let mut unliquidatable = tiers[1];
+        unliquidatable.initial_margin_ratio = 1.0;
+        unliquidatable.maintenance_margin_ratio = 1.0;
+        assert!(!validate_margin_tiers(&[tiers[0], unliquidatable]));
+        p.num_margin_tiers = 2;
+        p.margin_tiers[..2].copy_from_slice(&tiers);
+
+        // Max leverage binds over the initial ratio in the first tier
+        assert_eq!(initial_margin(&p), 10.0);
+        assert_eq!(maintenance_margin(&p), 2.5);
+
+        p.position_size = 50.0;
+        assert_eq!(initial_margin(&p), 1_000.0);
+        assert_eq!(maintenance_margin(&p), 500.0);
+
+        // Beyond the last tier
+        p.position_size = 500.0;
+        assert_eq!(maintenance_margin(&p), 5_000.0);
+
+        // The minimum margin is a floor on every tier
+        p.minimum_margin = 0.15;
+        assert_eq!(maintenance_margin(&p), 7_500.0);
+        assert_eq!(initial_margin(&p), 10_000.0);
+    }
+
+    #[test]
+    pub fn test_crank_reward() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.crank_reward = 100;
+        p.crank_reward_cap = 250;
+        p.crank_reward_interval = 1_000;
+        p.crank_reward_interval_start = 5_000;
+        p.crank_rewards_paid = 200;
+
+        // Only 50 left under the cap in the current interval
+        let reward = crank_reward(&p, 5_500, 1_000);
+        assert_eq!(reward.amount, 50);
+        assert_eq!(reward.interval_start, 5_000);
+        assert_eq!(reward.paid_in_interval, 250);
+
+        // A new interval resets the cap
+        let reward = crank_reward(&p, 6_000, 1_000);
+        assert_eq!(reward.amount, 100);
+        assert_eq!(reward.interval_start, 6_000);
+        assert_eq!(reward.paid_in_interval, 100);
+
+        // The vault can't pay more than it holds
+        assert_eq!(crank_reward(&p, 6_000, 30).amount, 30);
+    }
+
+    #[test]
+    pub fn test_liquidation_collateral() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.mark_price = 90.0;
+        p.position_size = 1.0;
+        p.num_margin_tiers = 1;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+
+        // Long equity 3 is under the 4.5 maintenance margin, the takeover needs 9 + 10 of loss
+        assert!(needs_liquidation(&p, true, 13));
+        assert!(!needs_liquidation(&p, true, 15));
+        assert_eq!(liquidation_collateral(&p, true, 13), 19);
+        // A bankrupt long restarts from the mark price
+        assert_eq!(liquidation_collateral(&p, true, 5), 9);
+        // The short's profit covers the whole initial margin
+        assert_eq!(liquidation_collateral(&p, false, 1), 0);
+    }
+
+    #[test]
+    pub fn test_liquidation_price() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.mark_price = 100.0;
+        p.position_size = 2.0;
+        p.num_margin_tiers = 2;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: 150.0,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+        p.margin_tiers[1] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.2,
+            maintenance_margin_ratio: 0.1,
+            max_leverage: 5.0,
+        };
+
+        // Long with notional 200 stays in the second tier down to the liquidation price
+        let long_price = liquidation_price(&p, true, 40);
+        p.mark_price = long_price;
+        assert!((equity(&p, true, 40) - maintenance_margin(&p)).abs() < 1e-9);
+
+        // Short crosses into the second tier before it is liquidated
+        p.mark_price = 100.0;
+        let short_price = liquidation_price(&p, false, 40);
+        assert!(short_price > 100.0);
+        p.mark_price = short_price;
+        assert!((equity(&p, false, 40) - maintenance_margin(&p)).abs() < 1e-9);
+
+        // Fully collateralized long
+        p.mark_price = 100.0;
+        assert_eq!(liquidation_price(&p, true, 1_000), 0.0);
+    }
+
+    #[test]
+    pub fn test_max_withdrawable() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.mark_price = 130.0;
+        p.position_size = 1.0;
+        p.num_margin_tiers = 1;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+
+        let health = swap_health(&p, 20, 20);
+        // Long: equity 50 leaves 37 over the initial 13, but only 20 is in the margin account
+        assert_eq!(health.long.free_collateral, 37.0);
+        assert_eq!(health.long.max_withdrawable, 20);
+        // Short: equity -10 is below the initial 13
+        assert_eq!(health.short.free_collateral, -23.0);
+        assert_eq!(health.short.max_withdrawable, 0);
+        assert!((health.short.margin_ratio + 10.0 / 130.0).abs() < 1e-12);
+
+        p.mark_price = 110.0;
+        assert_eq!(max_withdrawable(&p, true, 15), 14);
+    }
+
+    #[test]
+    pub fn test_market_params() {
+        let mut current = MarketParams {
+            funding_rate: 0.01,
+            minimum_margin: 0.05,
+            liquidation_bounty: 0.05,
+            minimum_funding_period: 3_600_000,
+            params_timelock: 86_400_000,
+            num_margin_tiers: 2,
+            margin_tiers: [MarginTier::default(); PerpetualSwap::MAX_MARGIN_TIERS],
+            mark_price_band: 0.1,
+            maker_fee_bps: 2,
+            taker_fee_bps: 5,
+            insurance_fund_fee_share_bps: 0,
+            referral_rebate_share_bps: 0,
+        };
+        current.margin_tiers[0] = MarginTier {
+            max_notional: 1_000.0,
+            initial_margin_ratio: 0.1,
+            maintenance_margin_ratio: 0.05,
+            max_leverage: 10.0,
+        };
+        current.margin_tiers[1] = MarginTier {
+            max_notional: 10_000.0,
+            initial_margin_ratio: 0.2,
+            maintenance_margin_ratio: 0.1,
+            max_leverage: 5.0,
+        };
+        assert!(validate_market_params(&current));
+        assert!(!is_stricter(&current, &current));
+
+        let mut new = current;
+        new.funding_rate = 2.0;
+        assert!(!validate_market_params(&new));
+        new.funding_rate = 0.02;
+        new.minimum_funding_period = 60_000;
+        assert!(validate_market_params(&new));
+        assert!(!is_stricter(&current, &new));
+
+        let mut new = current;
+        new.minimum_margin = 0.08;
+        assert!(is_stricter(&current, &new));
+        assert!(!is_stricter(&new, &current));
+
+        let mut new = current;
+        new.liquidation_bounty = 0.1;
+        assert!(is_stricter(&current, &new));
+
+        let mut new = current;
+        new.params_timelock = 0;
+        assert!(is_stricter(&current, &new));
+
+        let mut new = current;
+        new.mark_price_band = 0.2;
+        assert!(is_stricter(&current, &new));
+        assert!(!is_stricter(&new, &current));
+        new.mark_price_band = 0.0;
+        assert!(!validate_market_params(&new));
+
+        // Higher fees wait, lower ones and the shares of them don't
+        let mut new = current;
+        new.taker_fee_bps = 6;
+        assert!(is_stricter(&current, &new));
+        assert!(!is_stricter(&new, &current));
+        new.taker_fee_bps = PerpetualSwap::MAX_FEE_BPS + 1;
+        assert!(!validate_market_params(&new));
+        let mut new = current;
+        new.insurance_fund_fee_share_bps = 10_000;
+        new.referral_rebate_share_bps = 10_000;
+        assert!(!is_stricter(&current, &new));
+
+        // Moving the first boundary down puts notionals between 500 and 1,000 in the stricter tier
+        let mut new = current;
+        new.margin_tiers[0].max_notional = 500.0;
+        assert!(is_stricter(&current, &new));
+        assert!(!is_stricter(&new, &current));
+
+        // A looser tier applies right away, but not alongside a stricter one
+        let mut new = current;
+        new.margin_tiers[0].maintenance_margin_ratio = 0.025;
+        assert!(!is_stricter(&current, &new));
+        new.margin_tiers[1].max_leverage = 4.0;
+        assert!(is_stricter(&current, &new));
+    }
+
+    #[test]
+    pub fn test_cross_margin_health() {
+        let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+        p.entry_price = 100.0;
+        p.mark_price = 90.0;
+        p.position_size = 1.0;
+        p.num_margin_tiers = 1;
+        p.margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: 0.2,
+            maintenance_margin_ratio: 0.1,
+            max_leverage: 5.0,
+        };
+
+        // The short's profit in one market offsets the long's loss in the other
+        let health = cross_margin_health(5, &[(&p, true, 12), (&p, false, 0)]);
+        assert_eq!(health.equity, 17.0);
+        assert_eq!(health.maintenance_margin, 18.0);
+        assert!(!health.is_healthy());
+        assert!(cross_margin_health(6, &[(&p, true, 12), (&p, false, 0)]).is_healthy());
+    }
+}
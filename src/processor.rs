@@ -4,22 +4,51 @@ use solana_program::{
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
     rent::Rent,
-    sysvar::Sysvar,
+    system_instruction,
+    sysvar::{clock::Clock, Sysvar},
 };
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use spl_token::state::Account;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
-    error::PerpetualSwapError, instruction::PerpetualSwapInstruction, state::PerpetualSwap,
+    accounts::{
+        AcceptAdminAccounts, AddCollateralAssetAccounts, AddCrossMarginMarketAccounts,
+        AdminAccounts, ApplyMarketParamsAccounts, ClaimReferralRebatesAccounts,
+        ClosePositionAccounts, CloseSwapAccounts, ConfigureCrankRewardAccounts,
+        ConfigureFeesAccounts, CrossMarginHealthAccounts, CrossMarginTopUpAccounts,
+        DepositAccounts, DepositCollateralAccounts, DepositToCrossMarginAccounts,
+        GetHealthAccounts, InitializeCrossMarginAccounts, InitializePerpetualSwapAccounts,
+        InitializeReferrerAccounts, InitializeSideAccounts, LinkReferrerAccounts,
+        OpenWithVammAccounts, OracleUpdateIndexAccounts, ProposeAdminAccounts,
+        RedeemSettledAccounts, ReferralInfos, RemoveCrossMarginMarketAccounts,
+        SettleMarketAccounts, SplitPositionAccounts, SweepFeesAccounts, TransferFundsAccounts,
+        TransferPositionAccounts, TryToLiquidateAccounts, UpdateCollateralPricesAccounts,
+        UpdateMarketParamsAccounts, UpdatePricesAccounts, WithdrawAccounts,
+        WithdrawCollateralAccounts, WithdrawFromCrossMarginAccounts,
+    },
+    error::PerpetualSwapError,
+    event::PerpetualSwapEvent,
+    instruction::PerpetualSwapInstruction,
+    math,
+    state::{
+        CollateralAsset, CollateralConfig, ContractType, CrossMarginAccount, CrossMarginMarket,
+        LiquidationMode, MarginTier, MarketAction, MarketKind, MarketParams, MarketStatus,
+        PerpetualSwap, ReferralLink, Referrer, VammSide,
+    },
 };
 
 pub struct Processor;
 impl Processor {
+    pub const AUTHORITY_SEED: &'static [u8] = b"authority";
+    pub const MARGIN_SEED: &'static [u8] = b"margin";
+    pub const FEE_VAULT_SEED: &'static [u8] = b"fee_vault";
+    pub const REFERRAL_SEED: &'static [u8] = b"referral";
+
     /// Unpacks a spl_token `Account`.
     pub fn unpack_token_account(
         account_info: &AccountInfo,
@@ -46,16 +75,139 @@ impl Processor {
         }
     }
 
-    /// Calculates the authority id by generating a program address.
+    /// Unpacks a spl_token `Account` of the swap's collateral mint.
+    pub fn unpack_collateral_account(
+        perpetual_swap: &PerpetualSwap,
+        account_info: &AccountInfo,
+    ) -> Result<spl_token::state::Account, PerpetualSwapError> {
+        let account = Self::unpack_token_account(account_info, &perpetual_swap.token_program_id)?;
+        if account.mint != perpetual_swap.collateral_mint {
+            return Err(PerpetualSwapError::InvalidMints);
+        }
+        Ok(account)
+    }
+
+    /// Milliseconds since the epoch according to the Clock sysvar.
+    pub fn unix_timestamp_ms(clock_info: &AccountInfo) -> Result<u128, ProgramError> {
+        let clock = Clock::from_account_info(clock_info)?;
+        Ok(clock.unix_timestamp.max(0) as u128 * 1000)
+    }
+
+    fn side_seed(is_long: bool) -> &'static [u8] {
+        if is_long {
+            b"long"
+        } else {
+            b"short"
+        }
+    }
+
+    /// Calculates the authority id of a swap or cross-margin account from its stored bump.
     pub fn authority_id(
         program_id: &Pubkey,
         my_info: &Pubkey,
-        nonce: u8,
+        bump: u8,
     ) -> Result<Pubkey, PerpetualSwapError> {
-        Pubkey::create_program_address(&[&my_info.to_bytes()[..32], &[nonce]], program_id)
+        Pubkey::create_program_address(&[Self::AUTHORITY_SEED, &my_info.to_bytes(), &[bump]], program_id)
             .or(Err(PerpetualSwapError::InvalidProgramAddress))
     }
 
+    /// Finds the authority of a swap or cross-margin account along with its bump.
+    pub fn find_authority_address(program_id: &Pubkey, my_info: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::AUTHORITY_SEED, &my_info.to_bytes()], program_id)
+    }
+
+    /// Finds one side's margin account of a swap along with its bump.
+    pub fn find_margin_address(program_id: &Pubkey, swap: &Pubkey, is_long: bool) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[Self::MARGIN_SEED, &swap.to_bytes(), Self::side_seed(is_long)],
+            program_id,
+        )
+    }
+
+    /// Finds the fee vault of a swap along with its bump.
+    pub fn find_fee_vault_address(program_id: &Pubkey, swap: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::FEE_VAULT_SEED, &swap.to_bytes()], program_id)
+    }
+
+    /// Finds the referral link of a wallet along with its bump.
+    pub fn find_referral_link_address(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::REFERRAL_SEED, &user.to_bytes()], program_id)
+    }
+
+    /// What an instruction does to a market, along with the index of the swap's account, or `None`
+    /// for setup, admin and read-only instructions, which any status allows.
+    fn market_action(instruction: &PerpetualSwapInstruction) -> Option<(MarketAction, usize)> {
+        use PerpetualSwapInstruction::*;
+        match instruction {
+            InitializeSide { .. } | OpenWithVamm { .. } => Some((MarketAction::Open, 0)),
+            DepositToMargin { .. } | DepositCollateral { .. } => Some((MarketAction::Deposit, 0)),
+            AddCrossMarginMarket {} => Some((MarketAction::Deposit, 2)),
+            CrossMarginTopUp {} => Some((MarketAction::Deposit, 3)),
+            WithdrawFromMargin { .. }
+            | WithdrawCollateral { .. }
+            | RedeemSettled {}
+            | SweepFees {} => Some((MarketAction::Withdraw, 0)),
+            TransferPosition { .. }
+            | SplitPosition { .. }
+            | TryToLiquidate { .. }
+            | ClosePosition {} => Some((MarketAction::Close, 0)),
+            TransferFunds {}
+            | UpdatePrices { .. }
+            | OracleUpdateIndex {}
+            | UpdateCollateralPrices {} => Some((MarketAction::Crank, 0)),
+            // A cross-margin account spans several markets, so its handler checks each of them.
+            // Referral rebates sit in the referrer's own vault, apart from any swap, so there is
+            // no market to check.
+            _ => None,
+        }
+    }
+
+    /// Checks that the swap's status allows `action`.
+    pub fn check_market_status(
+        program_id: &Pubkey,
+        perpetual_swap_info: &AccountInfo,
+        action: MarketAction,
+    ) -> ProgramResult {
+        if perpetual_swap_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
+        if !perpetual_swap.status.allows(action) {
+            msg!("{:?} is not allowed while the market is {:?}", action, perpetual_swap.status);
+            return Err(PerpetualSwapError::InvalidMarketStatus.into());
+        }
+        Ok(())
+    }
+
+    /// Applies `params` right away, or queues them behind the swap's timelock if they are stricter
+    /// than the params in effect. Either way, any change already queued is replaced.
+    fn update_market_params(
+        perpetual_swap: &mut PerpetualSwap,
+        swap_pubkey: &Pubkey,
+        params: MarketParams,
+        now: u128,
+    ) -> ProgramResult {
+        if !math::validate_market_params(&params) {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+        if math::is_stricter(&perpetual_swap.market_params(), &params) {
+            let effective_time = now.saturating_add(perpetual_swap.params_timelock);
+            perpetual_swap.has_pending_params = true;
+            perpetual_swap.pending_params = params;
+            perpetual_swap.pending_params_time = effective_time;
+            PerpetualSwapEvent::MarketParamsQueued {
+                swap: *swap_pubkey,
+                effective_time,
+            }
+            .emit();
+        } else {
+            perpetual_swap.set_market_params(&params);
+            perpetual_swap.has_pending_params = false;
+            PerpetualSwapEvent::MarketParamsUpdated { swap: *swap_pubkey }.emit();
+        }
+        Ok(())
+    }
+
     /// Issue a spl_token `MintTo` instruction.
     pub fn token_mint_to<'a>(
         swap: &Pubkey,
@@ -63,11 +215,11 @@ impl Processor {
         mint: AccountInfo<'a>,
         destination: AccountInfo<'a>,
         authority: AccountInfo<'a>,
-        nonce: u8,
+        bump: u8,
         amount: u64,
     ) -> Result<(), ProgramError> {
         let swap_bytes = swap.to_bytes();
-        let authority_signature_seeds = [&swap_bytes[..32], &[nonce]];
+        let authority_signature_seeds = [Self::AUTHORITY_SEED, &swap_bytes[..], &[bump]];
         let signers = &[&authority_signature_seeds[..]];
         let ix = spl_token::instruction::mint_to(
             token_program.key,
@@ -81,34 +233,450 @@ impl Processor {
         invoke_signed(&ix, &[mint, destination, authority, token_program], signers)
     }
 
-    /// Issue a spl_token `Transfer` instruction.
-    pub fn token_transfer<'a>(
+    /// Issue a spl_token `TransferChecked` instruction, which fails unless `mint` and `decimals`
+    /// match the source account.
+    #[allow(clippy::too_many_arguments)]
+    pub fn token_transfer_checked<'a>(
         swap: &Pubkey,
         token_program: AccountInfo<'a>,
         source: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
         destination: AccountInfo<'a>,
         authority: AccountInfo<'a>,
-        nonce: u8,
+        bump: u8,
         amount: u64,
+        decimals: u8,
     ) -> Result<(), ProgramError> {
         let swap_bytes = swap.to_bytes();
-        let authority_signature_seeds = [&swap_bytes[..32], &[nonce]];
+        let authority_signature_seeds = [Self::AUTHORITY_SEED, &swap_bytes[..], &[bump]];
         let signers = &[&authority_signature_seeds[..]];
-        let ix = spl_token::instruction::transfer(
+        let ix = spl_token::instruction::transfer_checked(
             token_program.key,
             source.key,
+            mint.key,
             destination.key,
             authority.key,
             &[],
             amount,
+            decimals,
         )?;
         invoke_signed(
             &ix,
-            &[source, destination, authority, token_program],
+            &[source, mint, destination, authority, token_program],
             signers,
         )
     }
 
+    /// Issue a spl_token `CloseAccount` instruction, sending the rent to `destination`.
+    pub fn token_close_account<'a>(
+        swap: &Pubkey,
+        token_program: AccountInfo<'a>,
+        account: AccountInfo<'a>,
+        destination: AccountInfo<'a>,
+        authority: AccountInfo<'a>,
+        bump: u8,
+    ) -> Result<(), ProgramError> {
+        let swap_bytes = swap.to_bytes();
+        let authority_signature_seeds = [Self::AUTHORITY_SEED, &swap_bytes[..], &[bump]];
+        let signers = &[&authority_signature_seeds[..]];
+        let ix = spl_token::instruction::close_account(
+            token_program.key,
+            account.key,
+            destination.key,
+            authority.key,
+            &[],
+        )?;
+        invoke_signed(&ix, &[account, destination, authority, token_program], signers)
+    }
+
+    /// Transfers the whole `balance` of a token account the swap authority owns to `destination`,
+    /// then closes it and sends its rent to `rent_receiver`. The transfer is checked against
+    /// `mint` and its decimals.
+    #[allow(clippy::too_many_arguments)]
+    fn token_sweep_and_close<'a>(
+        swap: &Pubkey,
+        token_program: AccountInfo<'a>,
+        account: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        decimals: u8,
+        destination: AccountInfo<'a>,
+        rent_receiver: AccountInfo<'a>,
+        authority: AccountInfo<'a>,
+        bump: u8,
+        balance: u64,
+    ) -> ProgramResult {
+        if balance > 0 {
+            Self::token_transfer_checked(
+                swap,
+                token_program.clone(),
+                account.clone(),
+                mint,
+                destination,
+                authority.clone(),
+                bump,
+                balance,
+                decimals,
+            )?;
+        }
+        Self::token_close_account(swap, token_program, account, rent_receiver, authority, bump)
+    }
+
+    /// Moves a program-owned account's lamports to `destination` and zeroes its data, so the
+    /// runtime reclaims it at the end of the transaction.
+    fn close_program_account(account_info: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        let lamports = account_info.lamports();
+        **destination.lamports.borrow_mut() = destination
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(PerpetualSwapError::CalculationFailure)?;
+        **account_info.lamports.borrow_mut() = 0;
+        for byte in account_info.data.borrow_mut().iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    /// Realizes the PnL of both sides at the mark price: the losing side's margin pays the winner
+    /// what it is owed, and the insurance fund covers whatever a bankrupt loser can't. Each
+    /// account comes with its balance. Returns whether the long lost, what the loser's margin paid
+    /// and what the insurance fund paid.
+    #[allow(clippy::too_many_arguments)]
+    fn realize_pnl<'a>(
+        swap: &Pubkey,
+        perpetual_swap: &PerpetualSwap,
+        long_margin: (&AccountInfo<'a>, u64),
+        short_margin: (&AccountInfo<'a>, u64),
+        insurance_fund: (&AccountInfo<'a>, u64),
+        token_program_info: &AccountInfo<'a>,
+        mint_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
+    ) -> Result<(bool, u64, u64), ProgramError> {
+        let (insurance_fund_info, insurance_balance) = insurance_fund;
+        let is_long_losing = math::unrealized_pnl(perpetual_swap, true) < 0.0;
+        let ((loser_margin_info, loser_margin), (winner_margin_info, _), winner_account) =
+            if is_long_losing {
+                (long_margin, short_margin, perpetual_swap.short_account_pubkey)
+            } else {
+                (short_margin, long_margin, perpetual_swap.long_account_pubkey)
+            };
+        // This is subject to some rounding error
+        let owed = math::unrealized_pnl(perpetual_swap, !is_long_losing) as u64;
+        let paid = owed.min(loser_margin);
+        if paid > 0 {
+            Self::token_transfer_checked(
+                swap,
+                token_program_info.clone(),
+                loser_margin_info.clone(),
+                mint_info.clone(),
+                winner_margin_info.clone(),
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
+                paid,
+                perpetual_swap.collateral_decimals,
+            )?;
+        }
+        let deficit = owed - paid;
+        let mut insurance_payout = 0;
+        if deficit > 0 {
+            insurance_payout = deficit.min(insurance_balance);
+            if insurance_payout > 0 {
+                Self::token_transfer_checked(
+                    swap,
+                    token_program_info.clone(),
+                    insurance_fund_info.clone(),
+                    mint_info.clone(),
+                    winner_margin_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    insurance_payout,
+                    perpetual_swap.collateral_decimals,
+                )?;
+            }
+            PerpetualSwapEvent::NegativeEquitySettled {
+                swap: *swap,
+                account: winner_account,
+                is_long: !is_long_losing,
+                bankruptcy_price: math::bankruptcy_price(
+                    perpetual_swap,
+                    is_long_losing,
+                    loser_margin,
+                ),
+                deficit,
+                insurance_payout,
+            }
+            .emit();
+            if insurance_payout < deficit {
+                PerpetualSwapEvent::SocializedLoss {
+                    swap: *swap,
+                    account: winner_account,
+                    is_long: !is_long_losing,
+                    amount: deficit - insurance_payout,
+                }
+                .emit();
+            }
+        }
+        Ok((is_long_losing, paid, insurance_payout))
+    }
+
+    /// Records a new index price, first folding the one it replaces into the index TWAP, and into
+    /// the settlement window of a dated future.
+    fn update_index_price(perpetual_swap: &mut PerpetualSwap, index_price: f64, now: u128) {
+        if perpetual_swap.kind == MarketKind::DatedFuture {
+            let (sum, time) = math::settlement_index_sum(perpetual_swap, now);
+            perpetual_swap.settlement_index_sum = sum;
+            perpetual_swap.settlement_index_time = time;
+        }
+        if perpetual_swap.index_twap_time == 0 {
+            perpetual_swap.index_cumulative = 0.0;
+            perpetual_swap.index_twap_start_cumulative = 0.0;
+            perpetual_swap.index_twap_start_time = now;
+            perpetual_swap.index_twap_next_cumulative = 0.0;
+            perpetual_swap.index_twap_next_time = now;
+        } else {
+            let now = now.max(perpetual_swap.index_twap_time);
+            let cumulative = math::index_cumulative(perpetual_swap, now);
+            // Once the next start is a window old, the TWAP starts there instead
+            if now - perpetual_swap.index_twap_next_time >= PerpetualSwap::INDEX_TWAP_WINDOW {
+                perpetual_swap.index_twap_start_cumulative =
+                    perpetual_swap.index_twap_next_cumulative;
+                perpetual_swap.index_twap_start_time = perpetual_swap.index_twap_next_time;
+                perpetual_swap.index_twap_next_cumulative = cumulative;
+                perpetual_swap.index_twap_next_time = now;
+            }
+            perpetual_swap.index_cumulative = cumulative;
+        }
+        perpetual_swap.index_twap_time = now.max(perpetual_swap.index_twap_time);
+        perpetual_swap.index_price = index_price;
+    }
+
+    /// Creates one side's margin account at its program address, paid for by `payer`.
+    #[allow(clippy::too_many_arguments)]
+    fn create_margin_account<'a>(
+        swap: &Pubkey,
+        is_long: bool,
+        bump: u8,
+        margin: AccountInfo<'a>,
+        payer: AccountInfo<'a>,
+        system_program: AccountInfo<'a>,
+        rent: &Rent,
+        token_program_id: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        let swap_bytes = swap.to_bytes();
+        let margin_signature_seeds = [
+            Self::MARGIN_SEED,
+            &swap_bytes[..],
+            Self::side_seed(is_long),
+            &[bump],
+        ];
+        Self::create_token_account_at(
+            &margin_signature_seeds,
+            margin,
+            payer,
+            system_program,
+            rent,
+            token_program_id,
+        )
+    }
+
+    /// Creates a swap's fee vault at its program address, paid for by `payer`.
+    fn create_fee_vault_account<'a>(
+        swap: &Pubkey,
+        bump: u8,
+        fee_vault: AccountInfo<'a>,
+        payer: AccountInfo<'a>,
+        system_program: AccountInfo<'a>,
+        rent: &Rent,
+        token_program_id: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        let swap_bytes = swap.to_bytes();
+        let fee_vault_signature_seeds = [Self::FEE_VAULT_SEED, &swap_bytes[..], &[bump]];
+        Self::create_token_account_at(
+            &fee_vault_signature_seeds,
+            fee_vault,
+            payer,
+            system_program,
+            rent,
+            token_program_id,
+        )
+    }
+
+    /// Creates a token account at the program address of `signature_seeds`, left for the token
+    /// program to initialize.
+    fn create_token_account_at<'a>(
+        signature_seeds: &[&[u8]],
+        account: AccountInfo<'a>,
+        payer: AccountInfo<'a>,
+        system_program: AccountInfo<'a>,
+        rent: &Rent,
+        token_program_id: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        let ix = system_instruction::create_account(
+            payer.key,
+            account.key,
+            rent.minimum_balance(Account::LEN),
+            Account::LEN as u64,
+            token_program_id,
+        );
+        invoke_signed(&ix, &[payer, account, system_program], &[signature_seeds])
+    }
+
+    /// Charges a maker or taker fee into the swap's fee vault, to be swept by the admin.
+    #[allow(clippy::too_many_arguments)]
+    fn charge_trading_fee<'a>(
+        swap: &Pubkey,
+        perpetual_swap: &mut PerpetualSwap,
+        is_maker: bool,
+        amount: u64,
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        fee_vault: AccountInfo<'a>,
+        payer: AccountInfo<'a>,
+    ) -> Result<(), ProgramError> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let payer_key = *payer.key;
+        Self::token_transfer_checked(
+            swap,
+            token_program,
+            source,
+            mint,
+            fee_vault,
+            payer,
+            perpetual_swap.authority_bump,
+            amount,
+            perpetual_swap.collateral_decimals,
+        )?;
+        perpetual_swap.unswept_fees = perpetual_swap
+            .unswept_fees
+            .checked_add(amount)
+            .ok_or(PerpetualSwapError::CalculationFailure)?;
+        PerpetualSwapEvent::TradingFeeCharged {
+            swap: *swap,
+            payer: payer_key,
+            is_maker,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Marks a side held by the vAMM at the vAMM's price after its reserves or peg changed outside
+    /// a trade, and logs the change.
+    fn vamm_updated(swap: &Pubkey, perpetual_swap: &mut PerpetualSwap) {
+        let price = math::vamm_price(perpetual_swap);
+        if perpetual_swap.vamm_side != VammSide::None {
+            perpetual_swap.mark_price = price;
+        }
+        PerpetualSwapEvent::VammUpdated {
+            swap: *swap,
+            price,
+            base_reserve: perpetual_swap.vamm_base_reserve,
+        }
+        .emit();
+    }
+
+    /// Takes the vAMM out of the swap once the side it traded with has sold its position back to
+    /// it: the vAMM's margin, with the PnL already realized, goes back to the insurance fund and
+    /// its reserves take the trade back.
+    #[allow(clippy::too_many_arguments)]
+    fn close_vamm_side<'a>(
+        swap: &Pubkey,
+        perpetual_swap: &mut PerpetualSwap,
+        vamm_margin_info: &AccountInfo<'a>,
+        insurance_fund_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        mint_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let is_long = perpetual_swap.vamm_side == VammSide::Long;
+        let vamm_margin = Self::unpack_collateral_account(perpetual_swap, vamm_margin_info)?;
+        if vamm_margin.amount > 0 {
+            Self::token_transfer_checked(
+                swap,
+                token_program_info.clone(),
+                vamm_margin_info.clone(),
+                mint_info.clone(),
+                insurance_fund_info.clone(),
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
+                vamm_margin.amount,
+                perpetual_swap.collateral_decimals,
+            )?;
+        }
+        let (base_reserve, quote_reserve) =
+            math::vamm_reserves_after(perpetual_swap, is_long, perpetual_swap.position_size)
+                .ok_or(PerpetualSwapError::CalculationFailure)?;
+        perpetual_swap.vamm_base_reserve = base_reserve;
+        perpetual_swap.vamm_quote_reserve = quote_reserve;
+        perpetual_swap.mark_price = math::vamm_price(perpetual_swap);
+        perpetual_swap.entry_price = perpetual_swap.mark_price;
+        perpetual_swap.vamm_side = VammSide::None;
+        if is_long {
+            perpetual_swap.is_long_initialized = false;
+            perpetual_swap.long_owner = Pubkey::default();
+        } else {
+            perpetual_swap.is_short_initialized = false;
+            perpetual_swap.short_owner = Pubkey::default();
+        }
+        Ok(())
+    }
+
+    /// Pays a referred taker's referrer its share of the taker fee, straight into the referrer's
+    /// vault. Returns the rebate, which the fee vault doesn't get.
+    #[allow(clippy::too_many_arguments)]
+    fn pay_referral_rebate<'a>(
+        swap: &Pubkey,
+        perpetual_swap: &PerpetualSwap,
+        referral_infos: ReferralInfos<'_, 'a>,
+        taker_fee: u64,
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        payer: AccountInfo<'a>,
+    ) -> Result<u64, ProgramError> {
+        let ReferralInfos {
+            referrer_info,
+            mut referrer,
+            vault_info,
+        } = referral_infos;
+        let rebate = (taker_fee as u128 * perpetual_swap.referral_rebate_share_bps as u128
+            / 10_000) as u64;
+        if rebate == 0 {
+            return Ok(0);
+        }
+        let payer_key = *payer.key;
+        Self::token_transfer_checked(
+            swap,
+            token_program,
+            source,
+            mint,
+            vault_info.clone(),
+            payer,
+            perpetual_swap.authority_bump,
+            rebate,
+            perpetual_swap.collateral_decimals,
+        )?;
+        referrer.unclaimed_rebates = referrer
+            .unclaimed_rebates
+            .checked_add(rebate)
+            .ok_or(PerpetualSwapError::CalculationFailure)?;
+        referrer.total_rebates = referrer
+            .total_rebates
+            .checked_add(rebate)
+            .ok_or(PerpetualSwapError::CalculationFailure)?;
+        referrer.serialize(&mut *referrer_info.data.borrow_mut())?;
+        PerpetualSwapEvent::ReferralRebatePaid {
+            swap: *swap,
+            referrer: *referrer_info.key,
+            user: payer_key,
+            amount: rebate,
+        }
+        .emit();
+        Ok(rebate)
+    }
+
     pub fn initialize_account<'a>(
         account: AccountInfo<'a>,
         mint: AccountInfo<'a>,
@@ -131,23 +699,41 @@ impl Processor {
         instruction_data: &[u8],
     ) -> ProgramResult {
         let instruction = PerpetualSwapInstruction::unpack(instruction_data)?;
+        if let Some((action, swap_index)) = Self::market_action(&instruction) {
+            let perpetual_swap_info = accounts
+                .get(swap_index)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            Self::check_market_status(program_id, perpetual_swap_info, action)?;
+        }
 
         match instruction {
             PerpetualSwapInstruction::InitializePerpetualSwap {
-                nonce,
                 funding_rate,
                 minimum_margin,
                 liquidation_bounty,
                 minimum_funding_period,
+                liquidation_mode,
+                position_size,
+                kind,
+                expiry,
+                settlement_window,
+                contract_type,
+                quanto_multiplier,
             } => {
                 msg!("Instruction: InitializePerpetualSwap");
                 Self::process_initialize_perpetual_swap(
                     program_id,
-                    nonce,
                     funding_rate,
                     minimum_margin,
                     liquidation_bounty,
                     minimum_funding_period,
+                    liquidation_mode,
+                    position_size,
+                    kind,
+                    expiry,
+                    settlement_window,
+                    contract_type,
+                    quanto_multiplier,
                     accounts,
                 )
             }
@@ -163,13 +749,17 @@ impl Processor {
                 msg!("Instruction: WithdrawFromMargin");
                 Self::process_withdraw_from_margin(program_id, amount_to_withdraw, accounts)
             }
-            PerpetualSwapInstruction::TransferLong { amount } => {
-                msg!("Instruction: TransferLong");
-                Self::process_transfer_long(program_id, amount, accounts)
+            PerpetualSwapInstruction::TransferPosition {
+                is_long,
+                size,
+                price,
+            } => {
+                msg!("Instruction: TransferPosition");
+                Self::process_transfer_position(program_id, is_long, size, price, accounts)
             }
-            PerpetualSwapInstruction::TransferShort { amount } => {
-                msg!("Instruction: TransferShort");
-                Self::process_transfer_short(program_id, amount, accounts)
+            PerpetualSwapInstruction::SplitPosition { is_long, size } => {
+                msg!("Instruction: SplitPosition");
+                Self::process_split_position(program_id, is_long, size, accounts)
             }
             PerpetualSwapInstruction::TryToLiquidate { collateral } => {
                 msg!("Instruction: TryToLiquidate");
@@ -187,37 +777,307 @@ impl Processor {
             }
             PerpetualSwapInstruction::OracleUpdateIndex { } => {
                 msg!("Instruction: oracleUpdateindex");
-                Self::process_oracle_update_index(accounts)
+                Self::process_oracle_update_index(program_id, accounts)
+            }
+            PerpetualSwapInstruction::InitializeCrossMargin {} => {
+                msg!("Instruction: InitializeCrossMargin");
+                Self::process_initialize_cross_margin(program_id, accounts)
+            }
+            PerpetualSwapInstruction::DepositToCrossMargin { amount_to_deposit } => {
+                msg!("Instruction: DepositToCrossMargin");
+                Self::process_deposit_to_cross_margin(program_id, amount_to_deposit, accounts)
+            }
+            PerpetualSwapInstruction::WithdrawFromCrossMargin { amount_to_withdraw } => {
+                msg!("Instruction: WithdrawFromCrossMargin");
+                Self::process_withdraw_from_cross_margin(program_id, amount_to_withdraw, accounts)
+            }
+            PerpetualSwapInstruction::AddCrossMarginMarket {} => {
+                msg!("Instruction: AddCrossMarginMarket");
+                Self::process_add_cross_margin_market(program_id, accounts)
+            }
+            PerpetualSwapInstruction::RemoveCrossMarginMarket {} => {
+                msg!("Instruction: RemoveCrossMarginMarket");
+                Self::process_remove_cross_margin_market(program_id, accounts)
+            }
+            PerpetualSwapInstruction::CrossMarginTopUp {} => {
+                msg!("Instruction: CrossMarginTopUp");
+                Self::process_cross_margin_top_up(program_id, accounts)
+            }
+            PerpetualSwapInstruction::CrossMarginHealth {} => {
+                msg!("Instruction: CrossMarginHealth");
+                Self::process_cross_margin_health(program_id, accounts)
+            }
+            PerpetualSwapInstruction::AddCollateralAsset { haircut } => {
+                msg!("Instruction: AddCollateralAsset");
+                Self::process_add_collateral_asset(program_id, haircut, accounts)
+            }
+            PerpetualSwapInstruction::UpdateCollateralPrices {} => {
+                msg!("Instruction: UpdateCollateralPrices");
+                Self::process_update_collateral_prices(program_id, accounts)
+            }
+            PerpetualSwapInstruction::DepositCollateral { amount_to_deposit } => {
+                msg!("Instruction: DepositCollateral");
+                Self::process_deposit_collateral(program_id, amount_to_deposit, accounts)
+            }
+            PerpetualSwapInstruction::WithdrawCollateral {
+                asset_index,
+                amount_to_withdraw,
+            } => {
+                msg!("Instruction: WithdrawCollateral");
+                Self::process_withdraw_collateral(
+                    program_id,
+                    asset_index,
+                    amount_to_withdraw,
+                    accounts,
+                )
+            }
+            PerpetualSwapInstruction::UpdateMarginTiers { margin_tiers } => {
+                msg!("Instruction: UpdateMarginTiers");
+                Self::process_update_margin_tiers(program_id, &margin_tiers, accounts)
+            }
+            PerpetualSwapInstruction::GetHealth {} => {
+                msg!("Instruction: GetHealth");
+                Self::process_get_health(program_id, accounts)
+            }
+            PerpetualSwapInstruction::ConfigureCrankReward {
+                crank_reward,
+                crank_reward_cap,
+                crank_reward_interval,
+            } => {
+                msg!("Instruction: ConfigureCrankReward");
+                Self::process_configure_crank_reward(
+                    program_id,
+                    crank_reward,
+                    crank_reward_cap,
+                    crank_reward_interval,
+                    accounts,
+                )
+            }
+            PerpetualSwapInstruction::UpdateMarketParams {
+                funding_rate,
+                minimum_margin,
+                liquidation_bounty,
+                minimum_funding_period,
+                params_timelock,
+                mark_price_band,
+            } => {
+                msg!("Instruction: UpdateMarketParams");
+                Self::process_update_market_params(
+                    program_id,
+                    funding_rate,
+                    minimum_margin,
+                    liquidation_bounty,
+                    minimum_funding_period,
+                    params_timelock,
+                    mark_price_band,
+                    accounts,
+                )
+            }
+            PerpetualSwapInstruction::ApplyMarketParams {} => {
+                msg!("Instruction: ApplyMarketParams");
+                Self::process_apply_market_params(program_id, accounts)
+            }
+            PerpetualSwapInstruction::ProposeAdmin {} => {
+                msg!("Instruction: ProposeAdmin");
+                Self::process_propose_admin(program_id, accounts)
+            }
+            PerpetualSwapInstruction::AcceptAdmin {} => {
+                msg!("Instruction: AcceptAdmin");
+                Self::process_accept_admin(program_id, accounts)
+            }
+            PerpetualSwapInstruction::SetMarketStatus { status } => {
+                msg!("Instruction: SetMarketStatus");
+                Self::process_set_market_status(program_id, status, accounts)
+            }
+            PerpetualSwapInstruction::SettleMarket {} => {
+                msg!("Instruction: SettleMarket");
+                Self::process_settle_market(program_id, accounts)
+            }
+            PerpetualSwapInstruction::RedeemSettled {} => {
+                msg!("Instruction: RedeemSettled");
+                Self::process_redeem_settled(program_id, accounts)
+            }
+            PerpetualSwapInstruction::ClosePosition {} => {
+                msg!("Instruction: ClosePosition");
+                Self::process_close_position(program_id, accounts)
+            }
+            PerpetualSwapInstruction::CloseSwap {} => {
+                msg!("Instruction: CloseSwap");
+                Self::process_close_swap(program_id, accounts)
+            }
+            PerpetualSwapInstruction::ConfigureFees {
+                maker_fee_bps,
+                taker_fee_bps,
+                insurance_fund_fee_share_bps,
+                referral_rebate_share_bps,
+            } => {
+                msg!("Instruction: ConfigureFees");
+                Self::process_configure_fees(
+                    program_id,
+                    maker_fee_bps,
+                    taker_fee_bps,
+                    insurance_fund_fee_share_bps,
+                    referral_rebate_share_bps,
+                    accounts,
+                )
+            }
+            PerpetualSwapInstruction::SweepFees {} => {
+                msg!("Instruction: SweepFees");
+                Self::process_sweep_fees(program_id, accounts)
+            }
+            PerpetualSwapInstruction::InitializeReferrer {} => {
+                msg!("Instruction: InitializeReferrer");
+                Self::process_initialize_referrer(program_id, accounts)
+            }
+            PerpetualSwapInstruction::LinkReferrer {} => {
+                msg!("Instruction: LinkReferrer");
+                Self::process_link_referrer(program_id, accounts)
+            }
+            PerpetualSwapInstruction::ClaimReferralRebates {} => {
+                msg!("Instruction: ClaimReferralRebates");
+                Self::process_claim_referral_rebates(program_id, accounts)
+            }
+            PerpetualSwapInstruction::InitializeVamm {
+                base_reserve,
+                peg_multiplier,
+            } => {
+                msg!("Instruction: InitializeVamm");
+                Self::process_initialize_vamm(program_id, base_reserve, peg_multiplier, accounts)
+            }
+            PerpetualSwapInstruction::RepegVamm { peg_multiplier } => {
+                msg!("Instruction: RepegVamm");
+                Self::process_repeg_vamm(program_id, peg_multiplier, accounts)
+            }
+            PerpetualSwapInstruction::AdjustVammDepth { depth_multiplier } => {
+                msg!("Instruction: AdjustVammDepth");
+                Self::process_adjust_vamm_depth(program_id, depth_multiplier, accounts)
+            }
+            PerpetualSwapInstruction::OpenWithVamm {
+                is_long,
+                amount_to_deposit,
+                limit_price,
+            } => {
+                msg!("Instruction: OpenWithVamm");
+                Self::process_open_with_vamm(
+                    program_id,
+                    is_long,
+                    amount_to_deposit,
+                    limit_price,
+                    accounts,
+                )
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn process_initialize_perpetual_swap(
         program_id: &Pubkey,
-        nonce: u8,
         funding_rate: f64,
         minimum_margin: f64,
         liquidation_bounty: f64,
         minimum_funding_period: u128,
+        liquidation_mode: LiquidationMode,
+        position_size: f64,
+        kind: MarketKind,
+        expiry: u128,
+        settlement_window: u128,
+        contract_type: ContractType,
+        quanto_multiplier: f64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let margin_long_info = next_account_info(account_info_iter)?;
-        let margin_short_info = next_account_info(account_info_iter)?;
-        let pool_mint_info = next_account_info(account_info_iter)?;
-        let rent_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
+        let InitializePerpetualSwapAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            authority_bump,
+            long_margin_info: margin_long_info,
+            long_margin_bump,
+            short_margin_info: margin_short_info,
+            short_margin_bump,
+            insurance_fund_info,
+            mint_info: pool_mint_info,
+            rent_info,
+            token_program_info,
+            admin_info,
+            system_program_info,
+            pyth_product_info,
+            pyth_price_info,
+            clock_info,
+            fx_oracle_infos,
+        } = InitializePerpetualSwapAccounts::load(program_id, kind, accounts)?;
 
         let token_program_id = *token_program_info.key;
+        let collateral_mint = Self::unpack_mint(pool_mint_info, &token_program_id)?;
 
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
         // Check if the perpetual swap is already initialized
         if perpetual_swap.is_initialized() {
             return Err(PerpetualSwapError::AlreadyInUse.into());
         }
 
+        // A single tier holding every position to `minimum_margin` until tiers are configured
+        let mut margin_tiers = [MarginTier::default(); PerpetualSwap::MAX_MARGIN_TIERS];
+        margin_tiers[0] = MarginTier {
+            max_notional: f64::MAX,
+            initial_margin_ratio: minimum_margin,
+            maintenance_margin_ratio: minimum_margin,
+            max_leverage: 1.0 / minimum_margin,
+        };
+        let params = MarketParams {
+            funding_rate,
+            minimum_margin,
+            liquidation_bounty,
+            minimum_funding_period,
+            params_timelock: PerpetualSwap::DEFAULT_PARAMS_TIMELOCK,
+            num_margin_tiers: 1,
+            margin_tiers,
+            mark_price_band: PerpetualSwap::DEFAULT_MARK_PRICE_BAND,
+            maker_fee_bps: 0,
+            taker_fee_bps: 0,
+            insurance_fund_fee_share_bps: 0,
+            referral_rebate_share_bps: 0,
+        };
+        if !(position_size > 0.0 && math::validate_market_params(&params)) {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+        let is_valid_kind = match kind {
+            MarketKind::Perpetual => {
+                expiry == 0 && settlement_window == 0 && quanto_multiplier == 0.0
+            }
+            // A future that has already expired could never be traded
+            MarketKind::DatedFuture => {
+                settlement_window > 0
+                    && settlement_window <= PerpetualSwap::MAX_SETTLEMENT_WINDOW
+                    && expiry > settlement_window
+                    && expiry > Self::unix_timestamp_ms(clock_info)?
+                    && quanto_multiplier == 0.0
+            }
+            MarketKind::Quanto => {
+                expiry == 0
+                    && settlement_window == 0
+                    && quanto_multiplier > 0.0
+                    && quanto_multiplier.is_finite()
+                    && contract_type == ContractType::Linear
+            }
+        };
+        if !is_valid_kind {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+
+        // The index is only ever read from the oracle accounts bound here
+        if Self::pyth_price_pubkey(pyth_product_info)? != *pyth_price_info.key {
+            msg!("Pyth product price account does not match the Pyth price provided");
+            return Err(ProgramError::InvalidArgument);
+        }
+        // And a quanto market's FX rate only from the two bound here
+        if let Some((fx_product_info, fx_price_info)) = fx_oracle_infos {
+            if Self::pyth_price_pubkey(fx_product_info)? != *fx_price_info.key {
+                msg!("FX product price account does not match the FX price provided");
+                return Err(ProgramError::InvalidArgument);
+            }
+            perpetual_swap.fx_product_pubkey = *fx_product_info.key;
+            perpetual_swap.fx_price_pubkey = *fx_price_info.key;
+        }
+
         // Check if pool account is rent-exempt
         let rent = &Rent::from_account_info(rent_info)?;
         if !rent.is_exempt(
@@ -227,58 +1087,73 @@ impl Processor {
             return Err(PerpetualSwapError::NotRentExempt.into());
         }
 
-        // Check if the long margin account is already initialized
-        let long_margin_account = Account::unpack_unchecked(&margin_long_info.data.borrow())?;
-        if long_margin_account.is_initialized() {
+        // The margin accounts are created here, so they must not exist yet
+        if !margin_long_info.data_is_empty() || !margin_short_info.data_is_empty() {
             return Err(PerpetualSwapError::AlreadyInUse.into());
         }
 
-        // Check if the short margin account is already initialized
-        let short_margin_account = Account::unpack_unchecked(&margin_short_info.data.borrow())?;
-        if short_margin_account.is_initialized() {
+        // Check if the insurance fund is already initialized
+        let insurance_fund = Account::unpack_unchecked(&insurance_fund_info.data.borrow())?;
+        if insurance_fund.is_initialized() {
             return Err(PerpetualSwapError::AlreadyInUse.into());
         }
 
-        let authority_pubkey = Self::authority_id(program_id, perpetual_swap_info.key, nonce)?;
-
-        if *authority_info.key != authority_pubkey {
-            return Err(PerpetualSwapError::InvalidAuthorityAccount.into());
-        }
-
-        Self::initialize_account(
-            perpetual_swap_info.clone(),
-            pool_mint_info.clone(),
-            authority_info.clone(),
-            rent_info.clone(),
-            token_program_info.clone(),
-        )?;
-
         Self::initialize_account(
-            margin_long_info.clone(),
+            insurance_fund_info.clone(),
             pool_mint_info.clone(),
             authority_info.clone(),
             rent_info.clone(),
             token_program_info.clone(),
         )?;
 
-        Self::initialize_account(
-            margin_short_info.clone(),
-            pool_mint_info.clone(),
-            authority_info.clone(),
-            rent_info.clone(),
-            token_program_info.clone(),
-        )?;
+        for (is_long, margin_info, bump) in [
+            (true, margin_long_info, long_margin_bump),
+            (false, margin_short_info, short_margin_bump),
+        ]
+        .iter()
+        {
+            Self::create_margin_account(
+                perpetual_swap_info.key,
+                *is_long,
+                *bump,
+                (*margin_info).clone(),
+                admin_info.clone(),
+                system_program_info.clone(),
+                rent,
+                &token_program_id,
+            )?;
+            Self::initialize_account(
+                (*margin_info).clone(),
+                pool_mint_info.clone(),
+                authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+        }
 
         perpetual_swap.is_long_initialized = false;
         perpetual_swap.is_short_initialized = false;
-        perpetual_swap.nonce = nonce;
+        perpetual_swap.authority_bump = authority_bump;
+        perpetual_swap.long_margin_bump = long_margin_bump;
+        perpetual_swap.short_margin_bump = short_margin_bump;
         perpetual_swap.token_program_id = token_program_id;
         perpetual_swap.long_margin_pubkey = *margin_long_info.key;
         perpetual_swap.short_margin_pubkey = *margin_short_info.key;
-        perpetual_swap.minimum_margin = minimum_margin;
-        perpetual_swap.liquidation_bounty = liquidation_bounty;
-        perpetual_swap.funding_rate = funding_rate;
-        perpetual_swap.minimum_funding_period = minimum_funding_period;
+        perpetual_swap.insurance_fund_pubkey = *insurance_fund_info.key;
+        perpetual_swap.collateral_mint = *pool_mint_info.key;
+        perpetual_swap.collateral_decimals = collateral_mint.decimals;
+        perpetual_swap.set_market_params(&params);
+        perpetual_swap.liquidation_mode = liquidation_mode;
+        perpetual_swap.position_size = position_size;
+        perpetual_swap.kind = kind;
+        perpetual_swap.expiry = expiry;
+        perpetual_swap.settlement_window = settlement_window;
+        perpetual_swap.contract_type = contract_type;
+        perpetual_swap.quanto_multiplier = quanto_multiplier;
+        perpetual_swap.admin = *admin_info.key;
+        perpetual_swap.status = MarketStatus::Active;
+        perpetual_swap.oracle_product_pubkey = *pyth_product_info.key;
+        perpetual_swap.oracle_price_pubkey = *pyth_price_info.key;
         perpetual_swap
             .serialize(&mut *perpetual_swap_info.data.borrow_mut())
             .map_err(|e| e.into())
@@ -289,74 +1164,151 @@ impl Processor {
         amount_to_deposit: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let source_info = next_account_info(account_info_iter)?;
-        let margin_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
+        let InitializeSideAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            owner_info: user_transfer_authority_info,
+            source_info,
+            margin_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+            position_token_infos,
+            is_long,
+        } = InitializeSideAccounts::load(program_id, accounts)?;
+        let source_account = Self::unpack_collateral_account(&perpetual_swap, source_info)?;
 
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
-        let source_account =
-            Self::unpack_token_account(margin_info, &perpetual_swap.token_program_id)?;
-        // TODO Add all the data checks
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
+        if (is_long && perpetual_swap.is_long_initialized)
+            || (!is_long && perpetual_swap.is_short_initialized)
+        {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
         }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
+        if perpetual_swap.kind == MarketKind::DatedFuture
+            && perpetual_swap.is_expired(Self::unix_timestamp_ms(clock_info)?)
         {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
+            return Err(PerpetualSwapError::MarketExpired.into());
         }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        if source_account.owner != *user_transfer_authority_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
         }
 
-        let is_long = *margin_info.key == perpetual_swap.long_margin_pubkey;
-        let is_short = *margin_info.key == perpetual_swap.short_margin_pubkey;
-
-        if !is_long && !is_short {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
-        }
-
-        if 1.0 - ((amount_to_deposit as f64) / perpetual_swap.mark_price)
-            < perpetual_swap.minimum_margin
-        {
+        if (amount_to_deposit as f64) < math::initial_margin(&perpetual_swap) {
             return Err(PerpetualSwapError::WouldBeLiquidated.into());
         }
 
-        if source_account.amount < amount_to_deposit {
+        // The side completing the swap takes the first side's order
+        let is_maker = !(perpetual_swap.is_long_initialized || perpetual_swap.is_short_initialized);
+        let fee_bps = if is_maker {
+            perpetual_swap.maker_fee_bps
+        } else {
+            perpetual_swap.taker_fee_bps
+        };
+        let fee = math::trading_fee(
+            &perpetual_swap,
+            perpetual_swap.position_size,
+            perpetual_swap.mark_price,
+            fee_bps,
+        );
+        if source_account.amount < amount_to_deposit.saturating_add(fee) {
             return Err(PerpetualSwapError::InsufficientFunds.into());
         }
 
-        Self::token_transfer(
+        Self::token_transfer_checked(
             perpetual_swap_info.key,
             token_program_info.clone(),
             source_info.clone(),
+            mint_info.clone(),
             margin_info.clone(),
             user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
+            perpetual_swap.authority_bump,
             amount_to_deposit,
+            perpetual_swap.collateral_decimals,
         )?;
+        if let Some(fee_vault_info) = fee_vault_info {
+            let rebate = match referral_infos {
+                Some(referral_infos) => Self::pay_referral_rebate(
+                    perpetual_swap_info.key,
+                    &perpetual_swap,
+                    referral_infos,
+                    fee,
+                    token_program_info.clone(),
+                    source_info.clone(),
+                    mint_info.clone(),
+                    user_transfer_authority_info.clone(),
+                )?,
+                None => 0,
+            };
+            Self::charge_trading_fee(
+                perpetual_swap_info.key,
+                &mut perpetual_swap,
+                is_maker,
+                fee - rebate,
+                token_program_info.clone(),
+                source_info.clone(),
+                mint_info.clone(),
+                fee_vault_info.clone(),
+                user_transfer_authority_info.clone(),
+            )?;
+        }
+
+        // The side's only position token. The swap authority is the mint authority, so no more
+        // can be minted and whoever holds this one owns the side.
+        let mut position_mint = Pubkey::default();
+        if let Some((position_mint_info, position_token_info)) = position_token_infos {
+            let mint = Self::unpack_mint(position_mint_info, &perpetual_swap.token_program_id)?;
+            if mint.mint_authority != COption::Some(*authority_info.key) {
+                return Err(PerpetualSwapError::InvalidAuthorityAccount.into());
+            }
+            if mint.freeze_authority.is_some() {
+                return Err(PerpetualSwapError::InvalidFreezeAuthority.into());
+            }
+            if mint.supply != 0 {
+                return Err(PerpetualSwapError::InvalidSupply.into());
+            }
+            if mint.decimals != 0 {
+                return Err(PerpetualSwapError::InvalidMints.into());
+            }
+            let position_token =
+                Self::unpack_token_account(position_token_info, &perpetual_swap.token_program_id)?;
+            if position_token.mint != *position_mint_info.key {
+                return Err(PerpetualSwapError::InvalidMints.into());
+            }
+            Self::token_mint_to(
+                perpetual_swap_info.key,
+                token_program_info.clone(),
+                position_mint_info.clone(),
+                position_token_info.clone(),
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
+                1,
+            )?;
+            position_mint = *position_mint_info.key;
+        }
 
         if is_long {
             perpetual_swap.long_account_pubkey = *source_info.key;
+            perpetual_swap.long_owner = *user_transfer_authority_info.key;
+            perpetual_swap.long_position_mint = position_mint;
             perpetual_swap.is_long_initialized = true;
         } else {
             perpetual_swap.short_account_pubkey = *source_info.key;
+            perpetual_swap.short_owner = *user_transfer_authority_info.key;
+            perpetual_swap.short_position_mint = position_mint;
             perpetual_swap.is_short_initialized = true;
         }
 
         // Start the funding rate interval only when both parties have been set
         if perpetual_swap.is_initialized() {
             // This is number of milliseconds since the epoch
-            perpetual_swap.reference_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
+            perpetual_swap.reference_time = Self::unix_timestamp_ms(clock_info)?;
+            perpetual_swap.entry_price = perpetual_swap.mark_price;
         }
-        Ok(())
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
     pub fn process_deposit_to_margin(
@@ -364,51 +1316,36 @@ impl Processor {
         amount_to_deposit: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let source_info = next_account_info(account_info_iter)?;
-        let margin_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
-
-        let perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
-        let source_account =
-            Self::unpack_token_account(margin_info, &perpetual_swap.token_program_id)?;
-        // TODO Add all the data checks
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
-        }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
-        }
-
-        let is_long = *margin_info.key == perpetual_swap.long_margin_pubkey
-            && *source_info.key == perpetual_swap.long_account_pubkey;
-        let is_short = *margin_info.key == perpetual_swap.short_margin_pubkey
-            && *source_info.key == perpetual_swap.short_account_pubkey;
-
-        if !is_long && !is_short {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        let DepositAccounts {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info: _,
+            owner_info: user_transfer_authority_info,
+            source_info,
+            margin_info,
+            token_program_info,
+            mint_info,
+            is_long: _,
+        } = DepositAccounts::load(program_id, accounts)?;
+        let source_account = Self::unpack_collateral_account(&perpetual_swap, source_info)?;
+        if source_account.owner != *user_transfer_authority_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
         }
 
         if source_account.amount < amount_to_deposit {
             return Err(PerpetualSwapError::InsufficientFunds.into());
         }
 
-        Self::token_transfer(
+        Self::token_transfer_checked(
             perpetual_swap_info.key,
             token_program_info.clone(),
             source_info.clone(),
+            mint_info.clone(),
             margin_info.clone(),
             user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
+            perpetual_swap.authority_bump,
             amount_to_deposit,
+            perpetual_swap.collateral_decimals,
         )?;
 
         Ok(())
@@ -419,223 +1356,507 @@ impl Processor {
         amount_to_withdraw: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let margin_info = next_account_info(account_info_iter)?;
-        let dest_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
-
-        let perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
-        let source_account =
-            Self::unpack_token_account(margin_info, &perpetual_swap.token_program_id)?;
-
-        // TODO add all the data checks
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
-        }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        let WithdrawAccounts {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info,
+            owner_info: user_transfer_authority_info,
+            margin_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            collateral_infos,
+            is_long,
+        } = WithdrawAccounts::load(program_id, accounts)?;
+        // An expired future's PnL is fixed by its settlement, not by the mark price
+        if perpetual_swap.is_expired(Self::unix_timestamp_ms(clock_info)?) {
+            return Err(PerpetualSwapError::MarketExpired.into());
         }
+        let source_account = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
 
-        let is_long = *margin_info.key == perpetual_swap.long_margin_pubkey
-            && *dest_info.key == perpetual_swap.long_account_pubkey;
-        let is_short = *margin_info.key == perpetual_swap.short_margin_pubkey
-            && *dest_info.key == perpetual_swap.short_account_pubkey;
-
-        if !is_long && !is_short {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        // Withdrawals only go to a token account the owner holds
+        let dest_account = Self::unpack_collateral_account(&perpetual_swap, dest_info)?;
+        if dest_account.owner != *user_transfer_authority_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
         }
 
-        if 1.0 - (((source_account.amount - amount_to_withdraw) as f64) / perpetual_swap.mark_price)
-            < perpetual_swap.minimum_margin
+        let remaining_margin = source_account
+            .amount
+            .checked_sub(amount_to_withdraw)
+            .ok_or(PerpetualSwapError::InsufficientFunds)?;
+        let remaining_value = Self::margin_value(
+            program_id,
+            &perpetual_swap,
+            is_long,
+            remaining_margin,
+            collateral_infos,
+            0,
+            1,
+        )?;
+        if math::equity(&perpetual_swap, is_long, remaining_value)
+            < math::initial_margin(&perpetual_swap)
         {
             return Err(PerpetualSwapError::WouldBeLiquidated.into());
         }
 
-        Self::token_transfer(
+        Self::token_transfer_checked(
             perpetual_swap_info.key,
             token_program_info.clone(),
             margin_info.clone(),
+            mint_info.clone(),
             dest_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
+            authority_info.clone(),
+            perpetual_swap.authority_bump,
             amount_to_withdraw,
+            perpetual_swap.collateral_decimals,
         )?;
 
         Ok(())
     }
 
-    pub fn process_transfer_long(
+    pub fn process_transfer_position(
         program_id: &Pubkey,
-        margin_amount: u64,
+        is_long: bool,
+        size: f64,
+        price: f64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let long_margin_info = next_account_info(account_info_iter)?;
-        let long_account_info = next_account_info(account_info_iter)?;
-        let new_account_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
+        let TransferPositionAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            margin_info,
+            seller_info,
+            seller_account_info,
+            buyer_info,
+            buyer_account_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+            collateral_infos,
+        } = TransferPositionAccounts::load(program_id, is_long, accounts)?;
 
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
-        let long_margin =
-            Self::unpack_token_account(long_margin_info, &perpetual_swap.token_program_id)?;
-        let long_account =
-            Self::unpack_token_account(long_account_info, &perpetual_swap.token_program_id)?;
-        let new_account =
-            Self::unpack_token_account(new_account_info, &perpetual_swap.token_program_id)?;
-
-        // TODO add more checks
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
+        if perpetual_swap.is_expired(Self::unix_timestamp_ms(clock_info)?) {
+            return Err(PerpetualSwapError::MarketExpired.into());
         }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
+        let is_initialized = if is_long {
+            perpetual_swap.is_long_initialized
+        } else {
+            perpetual_swap.is_short_initialized
+        };
+        if !is_initialized {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
         }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        // A tokenized side changes hands with its position token
+        if perpetual_swap.is_tokenized(is_long) {
+            return Err(PerpetualSwapError::PositionTokenized.into());
         }
-        if perpetual_swap.long_margin_pubkey != *long_margin_info.key
-            || perpetual_swap.long_account_pubkey != *long_account_info.key
-        {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        if !(price > 0.0 && price.is_finite()) {
+            return Err(PerpetualSwapError::InvalidInstruction.into());
         }
-        if long_account.mint != new_account.mint {
-            return Err(PerpetualSwapError::InvalidMints.into());
+        // The whole side changes hands
+        if size != perpetual_swap.position_size {
+            return Err(PerpetualSwapError::InvalidPositionSize.into());
         }
-
-        if 1.0 - (margin_amount as f64 / perpetual_swap.mark_price) < perpetual_swap.minimum_margin
-        {
-            return Err(PerpetualSwapError::InsufficientMargin.into());
+        let seller_account = Self::unpack_collateral_account(&perpetual_swap, seller_account_info)?;
+        if seller_account.owner != *seller_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
         }
-
-        Self::token_transfer(
-            perpetual_swap_info.key,
-            token_program_info.clone(),
-            long_margin_info.clone(),
-            long_account_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
-            long_margin.amount,
-        )?;
-
-        Self::token_transfer(
-            perpetual_swap_info.key,
-            token_program_info.clone(),
-            new_account_info.clone(),
-            long_margin_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
-            margin_amount,
+        let buyer_account = Self::unpack_collateral_account(&perpetual_swap, buyer_account_info)?;
+        if buyer_account.owner != *buyer_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+        let margin_value = Self::margin_value(
+            program_id,
+            &perpetual_swap,
+            is_long,
+            margin.amount,
+            collateral_infos,
+            0,
+            1,
         )?;
+        if math::needs_liquidation(&perpetual_swap, is_long, margin_value) {
+            return Err(PerpetualSwapError::WouldBeLiquidated.into());
+        }
 
-        perpetual_swap.long_account_pubkey = *new_account_info.key;
+        // The margin and collateral stay where they are and the buyer takes the position over at
+        // its entry price, so the buyer pays the seller for the margin, the collateral at its
+        // haircut value and the PnL made up to `price`. A side worth less than nothing at `price`
+        // is paid for by the seller instead.
+        let realized_pnl = math::pnl_at_price(&perpetual_swap, is_long, size, price);
+        let payment = (margin_value as f64 + realized_pnl) as i64;
+        let (source_info, source_account, destination_info, owner_info) = if payment >= 0 {
+            (buyer_account_info, buyer_account, seller_account_info, buyer_info)
+        } else {
+            (seller_account_info, seller_account, buyer_account_info, seller_info)
+        };
+        let amount = payment.unsigned_abs();
+        if source_account.amount < amount {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+        // The seller made the market in the side, and each pays its fee after the payment
+        let maker_fee_bps = perpetual_swap.maker_fee_bps;
+        let taker_fee_bps = perpetual_swap.taker_fee_bps;
+        let maker_fee = math::trading_fee(&perpetual_swap, size, price, maker_fee_bps);
+        let taker_fee = math::trading_fee(&perpetual_swap, size, price, taker_fee_bps);
+        let seller_balance = seller_account.amount as i128 + payment as i128;
+        let buyer_balance = buyer_account.amount as i128 - payment as i128;
+        if seller_balance < maker_fee as i128 || buyer_balance < taker_fee as i128 {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+        if amount > 0 {
+            Self::token_transfer_checked(
+                perpetual_swap_info.key,
+                token_program_info.clone(),
+                source_info.clone(),
+                mint_info.clone(),
+                destination_info.clone(),
+                owner_info.clone(),
+                perpetual_swap.authority_bump,
+                amount,
+                perpetual_swap.collateral_decimals,
+            )?;
+        }
+        if let Some(fee_vault_info) = fee_vault_info {
+            let rebate = match referral_infos {
+                Some(referral_infos) => Self::pay_referral_rebate(
+                    perpetual_swap_info.key,
+                    &perpetual_swap,
+                    referral_infos,
+                    taker_fee,
+                    token_program_info.clone(),
+                    buyer_account_info.clone(),
+                    mint_info.clone(),
+                    buyer_info.clone(),
+                )?,
+                None => 0,
+            };
+            for (is_maker, fee, account_info, owner_info) in [
+                (true, maker_fee, seller_account_info, seller_info),
+                (false, taker_fee - rebate, buyer_account_info, buyer_info),
+            ]
+            .iter()
+            {
+                Self::charge_trading_fee(
+                    perpetual_swap_info.key,
+                    &mut perpetual_swap,
+                    *is_maker,
+                    *fee,
+                    token_program_info.clone(),
+                    (*account_info).clone(),
+                    mint_info.clone(),
+                    fee_vault_info.clone(),
+                    (*owner_info).clone(),
+                )?;
+            }
+        }
 
-        Ok(())
+        PerpetualSwapEvent::PositionTransferred {
+            swap: *perpetual_swap_info.key,
+            seller: *seller_info.key,
+            buyer: *buyer_info.key,
+            is_long,
+            size,
+            price,
+            realized_pnl,
+        }
+        .emit();
+        if is_long {
+            perpetual_swap.long_account_pubkey = *buyer_account_info.key;
+            perpetual_swap.long_owner = *buyer_info.key;
+        } else {
+            perpetual_swap.short_account_pubkey = *buyer_account_info.key;
+            perpetual_swap.short_owner = *buyer_info.key;
+        }
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
-    pub fn process_transfer_short(
+    pub fn process_split_position(
         program_id: &Pubkey,
-        margin_amount: u64,
+        is_long: bool,
+        size: f64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let short_margin_info = next_account_info(account_info_iter)?;
-        let short_account_info = next_account_info(account_info_iter)?;
-        let new_account_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
+        let SplitPositionAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            new_swap_info,
+            authority_info,
+            new_authority_info,
+            long_margin_info,
+            short_margin_info,
+            new_long_margin_info,
+            new_short_margin_info,
+            new_insurance_fund_info,
+            owner_info,
+            counterparty_info: _,
+            token_program_info,
+            mint_info,
+            rent_info,
+            system_program_info,
+            new_bumps: (new_authority_bump, new_long_margin_bump, new_short_margin_bump),
+            new_fee_vault,
+        } = SplitPositionAccounts::load(program_id, is_long, accounts)?;
 
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
-        let short_margin_account =
-            Self::unpack_token_account(short_margin_info, &perpetual_swap.token_program_id)?;
+        // Both sides are split, so both have to be open
+        if !perpetual_swap.is_initialized() {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
+        // The collateral vaults belong to the swap's collateral config, which isn't split, and a
+        // position token can't stand for the sides of two swaps
+        if perpetual_swap.has_collateral_config() {
+            return Err(PerpetualSwapError::CollateralNotSplittable.into());
+        }
+        if perpetual_swap.is_tokenized(true) || perpetual_swap.is_tokenized(false) {
+            return Err(PerpetualSwapError::PositionTokenized.into());
+        }
+        if perpetual_swap.vamm_side != VammSide::None {
+            return Err(PerpetualSwapError::HeldByVamm.into());
+        }
+        if !(size > 0.0 && size < perpetual_swap.position_size) {
+            return Err(PerpetualSwapError::InvalidPositionSize.into());
+        }
 
-        // TODO add all the checks
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
+        if PerpetualSwap::try_from_slice(&new_swap_info.data.borrow())?.is_initialized() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
         }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
+        let rent = &Rent::from_account_info(rent_info)?;
+        if !rent.is_exempt(new_swap_info.lamports(), new_swap_info.data_len()) {
+            return Err(PerpetualSwapError::NotRentExempt.into());
         }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        if !new_long_margin_info.data_is_empty() || !new_short_margin_info.data_is_empty() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
         }
-        if perpetual_swap.short_margin_pubkey != *short_margin_info.key
-            || perpetual_swap.short_account_pubkey != *short_account_info.key
-        {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        let insurance_fund = Account::unpack_unchecked(&new_insurance_fund_info.data.borrow())?;
+        if insurance_fund.is_initialized() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        if let Some((new_fee_vault_info, _)) = new_fee_vault {
+            if !new_fee_vault_info.data_is_empty() {
+                return Err(PerpetualSwapError::AlreadyInUse.into());
+            }
         }
 
-        Self::token_transfer(
-            perpetual_swap_info.key,
-            token_program_info.clone(),
-            short_margin_info.clone(),
-            short_account_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
-            short_margin_account.amount,
-        )?;
+        // Each side keeps the same share of its margin as of its position, so neither side's
+        // leverage changes. The rounding stays in the old swap.
+        let share = size / perpetual_swap.position_size;
+        let long_margin = Self::unpack_collateral_account(&perpetual_swap, long_margin_info)?;
+        let short_margin = Self::unpack_collateral_account(&perpetual_swap, short_margin_info)?;
+        let new_long_amount = (long_margin.amount as f64 * share) as u64;
+        let new_short_amount = (short_margin.amount as f64 * share) as u64;
 
-        Self::token_transfer(
-            perpetual_swap_info.key,
+        // The new swap trades like the old one: same sides, prices, params, oracles and status.
+        // It has accounts of its own, and starts without a vAMM, crank rewards or anything the
+        // admin queued on the old swap, which the admin can queue again.
+        let p = &perpetual_swap;
+        let new_swap = PerpetualSwap {
+            is_long_initialized: true,
+            is_short_initialized: true,
+            authority_bump: new_authority_bump,
+            token_program_id: p.token_program_id,
+            long_margin_pubkey: *new_long_margin_info.key,
+            long_account_pubkey: p.long_account_pubkey,
+            short_margin_pubkey: *new_short_margin_info.key,
+            short_account_pubkey: p.short_account_pubkey,
+            insurance_fund_pubkey: *new_insurance_fund_info.key,
+            reference_time: p.reference_time,
+            minimum_funding_period: p.minimum_funding_period,
+            index_price: p.index_price,
+            mark_price: p.mark_price,
+            entry_price: p.entry_price,
+            position_size: size,
+            minimum_margin: p.minimum_margin,
+            liquidation_bounty: p.liquidation_bounty,
+            funding_rate: p.funding_rate,
+            liquidation_mode: p.liquidation_mode,
+            collateral_config_pubkey: Pubkey::default(),
+            num_margin_tiers: p.num_margin_tiers,
+            margin_tiers: p.margin_tiers,
+            // A swap that charges fees keeps charging them, into a fee vault of its own
+            fee_vault_pubkey: new_fee_vault.map_or_else(Pubkey::default, |(info, _)| *info.key),
+            crank_reward: 0,
+            crank_reward_cap: 0,
+            crank_reward_interval: 0,
+            crank_reward_interval_start: 0,
+            crank_rewards_paid: 0,
+            long_owner: p.long_owner,
+            short_owner: p.short_owner,
+            admin: p.admin,
+            pending_admin: Pubkey::default(),
+            params_timelock: p.params_timelock,
+            has_pending_params: false,
+            pending_params: MarketParams::default(),
+            pending_params_time: 0,
+            status: p.status,
+            index_cumulative: p.index_cumulative,
+            index_twap_time: p.index_twap_time,
+            index_twap_start_cumulative: p.index_twap_start_cumulative,
+            index_twap_start_time: p.index_twap_start_time,
+            index_twap_next_cumulative: p.index_twap_next_cumulative,
+            index_twap_next_time: p.index_twap_next_time,
+            settlement_price: p.settlement_price,
+            long_margin_bump: new_long_margin_bump,
+            short_margin_bump: new_short_margin_bump,
+            collateral_mint: p.collateral_mint,
+            collateral_decimals: p.collateral_decimals,
+            kind: p.kind,
+            expiry: p.expiry,
+            settlement_window: p.settlement_window,
+            settlement_index_sum: p.settlement_index_sum,
+            settlement_index_time: p.settlement_index_time,
+            long_position_mint: Pubkey::default(),
+            short_position_mint: Pubkey::default(),
+            contract_type: p.contract_type,
+            quanto_multiplier: p.quanto_multiplier,
+            fx_rate: p.fx_rate,
+            maker_fee_bps: p.maker_fee_bps,
+            taker_fee_bps: p.taker_fee_bps,
+            insurance_fund_fee_share_bps: p.insurance_fund_fee_share_bps,
+            unswept_fees: 0,
+            referral_rebate_share_bps: p.referral_rebate_share_bps,
+            vamm_base_reserve: 0.0,
+            vamm_quote_reserve: 0.0,
+            vamm_peg_multiplier: 0.0,
+            vamm_side: VammSide::None,
+            oracle_product_pubkey: p.oracle_product_pubkey,
+            oracle_price_pubkey: p.oracle_price_pubkey,
+            mark_price_band: p.mark_price_band,
+            fx_product_pubkey: p.fx_product_pubkey,
+            fx_price_pubkey: p.fx_price_pubkey,
+        };
+        perpetual_swap.position_size -= size;
+
+        // The new swap starts with an empty insurance fund, so each half has to be as well
+        // margined as a freshly opened side
+        for (is_long, margin, new_amount) in [
+            (true, long_margin.amount, new_long_amount),
+            (false, short_margin.amount, new_short_amount),
+        ]
+        .iter()
+        {
+            if math::equity(&perpetual_swap, *is_long, margin - new_amount)
+                < math::initial_margin(&perpetual_swap)
+                || math::equity(&new_swap, *is_long, *new_amount) < math::initial_margin(&new_swap)
+            {
+                return Err(PerpetualSwapError::WouldBeLiquidated.into());
+            }
+        }
+
+        Self::initialize_account(
+            new_insurance_fund_info.clone(),
+            mint_info.clone(),
+            new_authority_info.clone(),
+            rent_info.clone(),
             token_program_info.clone(),
-            new_account_info.clone(),
-            short_margin_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
-            margin_amount,
         )?;
+        if let Some((new_fee_vault_info, new_fee_vault_bump)) = new_fee_vault {
+            Self::create_fee_vault_account(
+                new_swap_info.key,
+                new_fee_vault_bump,
+                new_fee_vault_info.clone(),
+                owner_info.clone(),
+                system_program_info.clone(),
+                rent,
+                &perpetual_swap.token_program_id,
+            )?;
+            Self::initialize_account(
+                new_fee_vault_info.clone(),
+                mint_info.clone(),
+                new_authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+        }
+        for (is_long, margin_info, new_margin_info, bump, amount) in [
+            (
+                true,
+                long_margin_info,
+                new_long_margin_info,
+                new_long_margin_bump,
+                new_long_amount,
+            ),
+            (
+                false,
+                short_margin_info,
+                new_short_margin_info,
+                new_short_margin_bump,
+                new_short_amount,
+            ),
+        ]
+        .iter()
+        {
+            Self::create_margin_account(
+                new_swap_info.key,
+                *is_long,
+                *bump,
+                (*new_margin_info).clone(),
+                owner_info.clone(),
+                system_program_info.clone(),
+                rent,
+                &perpetual_swap.token_program_id,
+            )?;
+            Self::initialize_account(
+                (*new_margin_info).clone(),
+                mint_info.clone(),
+                new_authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+            if *amount > 0 {
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    (*margin_info).clone(),
+                    mint_info.clone(),
+                    (*new_margin_info).clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    *amount,
+                    perpetual_swap.collateral_decimals,
+                )?;
+            }
+        }
 
-        perpetual_swap.short_account_pubkey = *new_account_info.key;
-        Ok(())
+        PerpetualSwapEvent::PositionSplit {
+            swap: *perpetual_swap_info.key,
+            new_swap: *new_swap_info.key,
+            is_long,
+            size,
+        }
+        .emit();
+        new_swap.serialize(&mut *new_swap_info.data.borrow_mut())?;
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
     pub fn process_transfer_funds(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let long_margin_info = next_account_info(account_info_iter)?;
-        let short_margin_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
-
-        let mut perpetual_swap: PerpetualSwap =
-            PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
+        let TransferFundsAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            crank_reward_infos,
+        } = TransferFundsAccounts::load(program_id, accounts)?;
 
         if !perpetual_swap.is_initialized() {
             return Err(PerpetualSwapError::AccountNotInitialized.into());
         }
-
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
-        }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        if !perpetual_swap.has_funding() {
+            return Err(PerpetualSwapError::NoFunding.into());
         }
 
-        let now = SystemTime::now().duration_since(UNIX_EPOCH);
         // This is number of milliseconds since the epoch
-        let transfer_time = now.unwrap().as_millis();
+        let transfer_time = Self::unix_timestamp_ms(clock_info)?;
         if perpetual_swap.reference_time > transfer_time {
             return Err(PerpetualSwapError::InvalidTransferTime.into());
         }
@@ -645,40 +1866,91 @@ impl Processor {
             return Err(PerpetualSwapError::InvalidTransferTime.into());
         }
 
-        // funding_rate = base_funding rate * (amount of time since last transfer) / (# of ms in 1 day)
-        let funding_interval = time_since_last_transfer as f64 / (24. * 60. * 60. * 1000.) as f64;
-        let funding_rate = perpetual_swap.funding_rate * funding_interval;
+        // Funding accrues over (amount of time since last transfer) / (# of ms in 1 day)
+        let funding_interval = time_since_last_transfer as f64 / (24. * 60. * 60. * 1000.);
+        let funding_payment = math::funding_payment(&perpetual_swap, funding_interval);
 
         // TODO check for liquidation
-        if perpetual_swap.mark_price - perpetual_swap.index_price > 0.0 {
+        if funding_payment > 0.0 {
             // This is subject to some rounding error
-            let funds_to_transfer =
-                ((perpetual_swap.mark_price - perpetual_swap.index_price) * funding_rate) as u64;
-            Self::token_transfer(
+            let funds_to_transfer = funding_payment as u64;
+            let long_margin = Self::unpack_collateral_account(&perpetual_swap, long_margin_info)?;
+            if long_margin.amount < funds_to_transfer {
+                return Err(PerpetualSwapError::InsufficientFunds.into());
+            }
+            Self::token_transfer_checked(
                 perpetual_swap_info.key,
                 token_program_info.clone(),
                 long_margin_info.clone(),
+                mint_info.clone(),
                 short_margin_info.clone(),
-                user_transfer_authority_info.clone(),
-                perpetual_swap.nonce,
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
                 funds_to_transfer,
+                perpetual_swap.collateral_decimals,
             )?;
         } else {
             // This is subject to some rounding error
-            let funds_to_transfer =
-                ((perpetual_swap.index_price - perpetual_swap.mark_price) * funding_rate) as u64;
-            Self::token_transfer(
+            let funds_to_transfer = (-funding_payment) as u64;
+            let short_margin = Self::unpack_collateral_account(&perpetual_swap, short_margin_info)?;
+            if short_margin.amount < funds_to_transfer {
+                return Err(PerpetualSwapError::InsufficientFunds.into());
+            }
+            Self::token_transfer_checked(
                 perpetual_swap_info.key,
                 token_program_info.clone(),
                 short_margin_info.clone(),
+                mint_info.clone(),
                 long_margin_info.clone(),
-                user_transfer_authority_info.clone(),
-                perpetual_swap.nonce,
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
                 funds_to_transfer,
+                perpetual_swap.collateral_decimals,
             )?;
         }
         perpetual_swap.reference_time = transfer_time;
-        Ok(())
+
+        // Funding pulls the vAMM's price toward the index along with the payment
+        if perpetual_swap.has_vamm() {
+            perpetual_swap.vamm_peg_multiplier =
+                math::vamm_funding_peg(&perpetual_swap, funding_interval);
+            Self::vamm_updated(perpetual_swap_info.key, &mut perpetual_swap);
+        }
+
+        // Whoever ran the crank is paid out of the fee vault
+        if let Some((fee_vault_info, reward_account_info)) = crank_reward_infos {
+            let fee_vault = Self::unpack_collateral_account(&perpetual_swap, fee_vault_info)?;
+            let reward = math::crank_reward(&perpetual_swap, transfer_time, fee_vault.amount);
+            if reward.amount > 0 {
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    fee_vault_info.clone(),
+                    mint_info.clone(),
+                    reward_account_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    reward.amount,
+                    perpetual_swap.collateral_decimals,
+                )?;
+                PerpetualSwapEvent::CrankRewardPaid {
+                    swap: *perpetual_swap_info.key,
+                    account: *reward_account_info.key,
+                    amount: reward.amount,
+                }
+                .emit();
+            }
+            // The reward comes out of whatever was put in the vault to pay rewards first, and only
+            // the rest out of the fees, so the sweep never counts on tokens that are gone
+            let reward_budget = fee_vault.amount.saturating_sub(perpetual_swap.unswept_fees);
+            perpetual_swap.unswept_fees -= reward.amount.saturating_sub(reward_budget);
+            perpetual_swap.crank_reward_interval_start = reward.interval_start;
+            perpetual_swap.crank_rewards_paid = reward.paid_in_interval;
+        }
+
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
     pub fn process_try_to_liquidate(
@@ -686,93 +1958,351 @@ impl Processor {
         collateral: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let user_transfer_authority_info = next_account_info(account_info_iter)?;
-        let liquidated_margin_info = next_account_info(account_info_iter)?;
-        let liquidator_account_info = next_account_info(account_info_iter)?;
-        let insurance_account_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
-
-        let perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
+        let TryToLiquidateAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            liquidator_info: user_transfer_authority_info,
+            liquidated_margin_info,
+            liquidator_account_info,
+            insurance_fund_info: insurance_account_info,
+            counterparty_margin_info,
+            counterparty_account_info,
+            token_program_info,
+            mint_info,
+            collateral_infos,
+            is_long,
+        } = TryToLiquidateAccounts::load(program_id, accounts)?;
 
         if !perpetual_swap.is_initialized() {
             return Err(PerpetualSwapError::AccountNotInitialized.into());
         }
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
-        }
+        let mut liquidated_margin =
+            Self::unpack_collateral_account(&perpetual_swap, liquidated_margin_info)?;
+        let mut liquidator_account =
+            Self::unpack_collateral_account(&perpetual_swap, liquidator_account_info)?;
 
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
+        // The insurance fund stands behind the vAMM's side, so there's nothing to take over
+        if perpetual_swap.vamm_holds(is_long) {
+            return Err(PerpetualSwapError::HeldByVamm.into());
         }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+
+        // The liquidator buys the side's non-primary collateral at its haircut value, so the rest
+        // of the liquidation only deals with the primary collateral
+        let account_info_iter = &mut collateral_infos.iter();
+        let mut collateral_config = None;
+        if perpetual_swap.has_collateral_config() {
+            let collateral_config_info = next_account_info(account_info_iter)?;
+            let config =
+                Self::unpack_collateral_config(program_id, &perpetual_swap, collateral_config_info)?;
+            for asset in config.assets() {
+                let vault_info = next_account_info(account_info_iter)?;
+                let liquidator_collateral_info = next_account_info(account_info_iter)?;
+                let asset_mint_info = next_account_info(account_info_iter)?;
+                if *vault_info.key != asset.vault_pubkey(is_long)
+                    || *asset_mint_info.key != asset.mint
+                {
+                    return Err(PerpetualSwapError::InvalidAccountKeys.into());
+                }
+                let vault = Self::unpack_token_account(vault_info, &perpetual_swap.token_program_id)?;
+                if vault.amount == 0 {
+                    continue;
+                }
+                let value = math::haircut_value(vault.amount, asset.price, asset.haircut) as u64;
+                liquidator_account.amount = liquidator_account
+                    .amount
+                    .checked_sub(value)
+                    .ok_or(PerpetualSwapError::InsufficientFunds)?;
+                liquidated_margin.amount = liquidated_margin
+                    .amount
+                    .checked_add(value)
+                    .ok_or(PerpetualSwapError::CalculationFailure)?;
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    vault_info.clone(),
+                    asset_mint_info.clone(),
+                    liquidator_collateral_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    vault.amount,
+                    asset.decimals,
+                )?;
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    liquidator_account_info.clone(),
+                    mint_info.clone(),
+                    liquidated_margin_info.clone(),
+                    user_transfer_authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    value,
+                    perpetual_swap.collateral_decimals,
+                )?;
+            }
+            collateral_config = Some(config);
         }
 
-        let liquidated_margin =
-            Self::unpack_token_account(liquidated_margin_info, &perpetual_swap.token_program_id)?;
-        let liquidator_account =
-            Self::unpack_token_account(liquidator_account_info, &perpetual_swap.token_program_id)?;
+        let deficit = math::negative_equity_deficit(&perpetual_swap, is_long, liquidated_margin.amount);
+        if deficit > 0 {
+            let insurance_fund =
+                Self::unpack_collateral_account(&perpetual_swap, insurance_account_info)?;
+            let bankruptcy_price =
+                math::bankruptcy_price(&perpetual_swap, is_long, liquidated_margin.amount);
+            // A tokenized counterparty has no account to be paid out to, and force-closing the
+            // vAMM would leave its reserves skewed, so their loss is socialized instead
+            if insurance_fund.amount < deficit
+                && perpetual_swap.liquidation_mode == LiquidationMode::AutoDeleverage
+                && !perpetual_swap.is_tokenized(!is_long)
+                && !perpetual_swap.vamm_holds(!is_long)
+            {
+                // Force-close the profitable counterparty at the bankruptcy price, where it
+                // realizes exactly the bankrupt margin, and return its margin
+                let counterparty_margin =
+                    Self::unpack_collateral_account(&perpetual_swap, counterparty_margin_info)?;
+                // Profit times leverage, the order the counterparty would be deleveraged in among
+                // several positions
+                let pnl = math::unrealized_pnl(&perpetual_swap, !is_long);
+                let equity = math::equity(&perpetual_swap, !is_long, counterparty_margin.amount);
+                let priority = if pnl > 0.0 && equity > 0.0 {
+                    pnl * math::notional(&perpetual_swap) / equity
+                } else {
+                    0.0
+                };
+                let counterparty_balance = counterparty_margin
+                    .amount
+                    .checked_add(liquidated_margin.amount)
+                    .ok_or(PerpetualSwapError::CalculationFailure)?;
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    liquidated_margin_info.clone(),
+                    mint_info.clone(),
+                    counterparty_margin_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    liquidated_margin.amount,
+                    perpetual_swap.collateral_decimals,
+                )?;
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    counterparty_margin_info.clone(),
+                    mint_info.clone(),
+                    counterparty_account_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    counterparty_balance,
+                    perpetual_swap.collateral_decimals,
+                )?;
+                // Its non-primary collateral goes back to its owner too, as `ClosePosition` would
+                // hand it back, or `CloseSwap` could never close its vaults
+                if let Some(collateral_config) = &collateral_config {
+                    for asset in collateral_config.assets() {
+                        let vault_info = next_account_info(account_info_iter)?;
+                        let collateral_dest_info = next_account_info(account_info_iter)?;
+                        let asset_mint_info = next_account_info(account_info_iter)?;
+                        if *vault_info.key != asset.vault_pubkey(!is_long)
+                            || *asset_mint_info.key != asset.mint
+                        {
+                            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+                        }
+                        let collateral_dest = Self::unpack_token_account(
+                            collateral_dest_info,
+                            &perpetual_swap.token_program_id,
+                        )?;
+                        if collateral_dest.owner != *perpetual_swap.owner(!is_long) {
+                            return Err(PerpetualSwapError::InvalidOwner.into());
+                        }
+                        let vault = Self::unpack_token_account(
+                            vault_info,
+                            &perpetual_swap.token_program_id,
+                        )?;
+                        if vault.amount > 0 {
+                            Self::token_transfer_checked(
+                                perpetual_swap_info.key,
+                                token_program_info.clone(),
+                                vault_info.clone(),
+                                asset_mint_info.clone(),
+                                collateral_dest_info.clone(),
+                                authority_info.clone(),
+                                perpetual_swap.authority_bump,
+                                vault.amount,
+                                asset.decimals,
+                            )?;
+                        }
+                    }
+                }
+                PerpetualSwapEvent::AutoDeleveraged {
+                    swap: *perpetual_swap_info.key,
+                    account: *counterparty_account_info.key,
+                    is_long: !is_long,
+                    bankruptcy_price,
+                    mark_price: perpetual_swap.mark_price,
+                    realized_pnl: liquidated_margin.amount,
+                    priority,
+                }
+                .emit();
 
-        if 1.0 - ((liquidated_margin.amount as f64) / perpetual_swap.mark_price)
-            > perpetual_swap.minimum_margin
-        {
-            return Err(PerpetualSwapError::DoesNotNeedLiquidation.into());
+                perpetual_swap.is_long_initialized = false;
+                perpetual_swap.is_short_initialized = false;
+                perpetual_swap.entry_price = 0.0;
+            } else {
+                // Settle the negative equity: the counterparty realizes its profit out of the
+                // bankrupt margin and the deficit out of the insurance fund. Whatever the fund
+                // can't pay is written off against the counterparty's profit.
+                if liquidator_account.amount < collateral {
+                    return Err(PerpetualSwapError::InsufficientFunds.into());
+                }
+                // The liquidator takes over a position that restarts from the mark price
+                if (collateral as f64) < math::initial_margin(&perpetual_swap) {
+                    return Err(PerpetualSwapError::InsufficientMargin.into());
+                }
+                let insurance_payout = deficit.min(insurance_fund.amount);
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    liquidated_margin_info.clone(),
+                    mint_info.clone(),
+                    counterparty_margin_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    liquidated_margin.amount,
+                    perpetual_swap.collateral_decimals,
+                )?;
+                if insurance_payout > 0 {
+                    Self::token_transfer_checked(
+                        perpetual_swap_info.key,
+                        token_program_info.clone(),
+                        insurance_account_info.clone(),
+                        mint_info.clone(),
+                        counterparty_margin_info.clone(),
+                        authority_info.clone(),
+                        perpetual_swap.authority_bump,
+                        insurance_payout,
+                        perpetual_swap.collateral_decimals,
+                    )?;
+                }
+                PerpetualSwapEvent::NegativeEquitySettled {
+                    swap: *perpetual_swap_info.key,
+                    account: *counterparty_account_info.key,
+                    is_long: !is_long,
+                    bankruptcy_price,
+                    deficit,
+                    insurance_payout,
+                }
+                .emit();
+                if insurance_payout < deficit {
+                    PerpetualSwapEvent::SocializedLoss {
+                        swap: *perpetual_swap_info.key,
+                        account: *counterparty_account_info.key,
+                        is_long: !is_long,
+                        amount: deficit - insurance_payout,
+                    }
+                    .emit();
+                }
+
+                // Both sides restart from the mark price and the liquidator takes over the
+                // busted position
+                perpetual_swap.entry_price = perpetual_swap.mark_price;
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    liquidator_account_info.clone(),
+                    mint_info.clone(),
+                    liquidated_margin_info.clone(),
+                    user_transfer_authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    collateral,
+                    perpetual_swap.collateral_decimals,
+                )?;
+                if is_long {
+                    perpetual_swap.long_account_pubkey = *liquidator_account_info.key;
+                    perpetual_swap.long_owner = liquidator_account.owner;
+                    perpetual_swap.long_position_mint = Pubkey::default();
+                } else {
+                    perpetual_swap.short_account_pubkey = *liquidator_account_info.key;
+                    perpetual_swap.short_owner = liquidator_account.owner;
+                    perpetual_swap.short_position_mint = Pubkey::default();
+                }
+            }
+            return perpetual_swap
+                .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+                .map_err(|e| e.into());
         }
 
-        if !(*liquidated_margin_info.key == perpetual_swap.long_margin_pubkey
-            || *liquidated_margin_info.key == perpetual_swap.short_margin_pubkey)
+        if math::equity(&perpetual_swap, is_long, liquidated_margin.amount)
+            >= math::maintenance_margin(&perpetual_swap)
         {
-            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            return Err(PerpetualSwapError::DoesNotNeedLiquidation.into());
         }
 
         let bounty = (perpetual_swap.liquidation_bounty * liquidated_margin.amount as f64) as u64;
-        if (1.0 - ((liquidator_account.amount + bounty) as f64) / perpetual_swap.mark_price)
-            < perpetual_swap.minimum_margin
-        {
+        if liquidator_account.amount < collateral {
             return Err(PerpetualSwapError::InsufficientFunds.into());
         }
+        // The liquidator takes over the position as it stands, unrealized PnL included
+        if math::equity(&perpetual_swap, is_long, collateral) < math::initial_margin(&perpetual_swap)
+        {
+            return Err(PerpetualSwapError::InsufficientMargin.into());
+        }
 
-        let remaining_balance = liquidated_margin.amount - bounty;
+        let remaining_balance = liquidated_margin
+            .amount
+            .checked_sub(bounty)
+            .ok_or(PerpetualSwapError::InsufficientFunds)?;
         // Liquidate the user who is past margin
-        Self::token_transfer(
+        Self::token_transfer_checked(
             perpetual_swap_info.key,
             token_program_info.clone(),
             liquidated_margin_info.clone(),
+            mint_info.clone(),
             liquidator_account_info.clone(),
-            user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
+            authority_info.clone(),
+            perpetual_swap.authority_bump,
             bounty,
+            perpetual_swap.collateral_decimals,
         )?;
         // Pay a liquidation fee to the insurance account
         if remaining_balance > 0 {
-            Self::token_transfer(
+            Self::token_transfer_checked(
                 perpetual_swap_info.key,
                 token_program_info.clone(),
                 liquidated_margin_info.clone(),
+                mint_info.clone(),
                 insurance_account_info.clone(),
-                user_transfer_authority_info.clone(),
-                perpetual_swap.nonce,
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
                 remaining_balance,
+                perpetual_swap.collateral_decimals,
             )?;
         }
 
         // Liquidator takes on the busted account position
-        Self::token_transfer(
+        Self::token_transfer_checked(
             perpetual_swap_info.key,
             token_program_info.clone(),
             liquidator_account_info.clone(),
+            mint_info.clone(),
             liquidated_margin_info.clone(),
             user_transfer_authority_info.clone(),
-            perpetual_swap.nonce,
+            perpetual_swap.authority_bump,
             collateral,
+            perpetual_swap.collateral_decimals,
         )?;
+        if is_long {
+            perpetual_swap.long_account_pubkey = *liquidator_account_info.key;
+            perpetual_swap.long_owner = liquidator_account.owner;
+            perpetual_swap.long_position_mint = Pubkey::default();
+        } else {
+            perpetual_swap.short_account_pubkey = *liquidator_account_info.key;
+            perpetual_swap.short_owner = liquidator_account.owner;
+            perpetual_swap.short_position_mint = Pubkey::default();
+        }
 
-        Ok(())
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
 
@@ -781,95 +2311,5731 @@ impl Processor {
         mark_price: f64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let token_program_info = next_account_info(account_info_iter)?;
-        let pyth_product_info = next_account_info(account_info_iter)?;
-        let pyth_price_info = next_account_info(account_info_iter)?;
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
+        let UpdatePricesAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+            pyth_product_info,
+            pyth_price_info,
+            clock_info,
+            fx_oracle_infos,
+        } = UpdatePricesAccounts::load(program_id, accounts)?;
 
         if !perpetual_swap.is_initialized() {
             return Err(PerpetualSwapError::AccountNotInitialized.into());
         }
-        if perpetual_swap_info.owner != program_id {
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        if *authority_info.key
-            != Self::authority_id(program_id, perpetual_swap_info.key, perpetual_swap.nonce)?
-        {
-            return Err(PerpetualSwapError::InvalidProgramAddress.into());
-        }
-        if *token_program_info.key != perpetual_swap.token_program_id {
-            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        // The mark of an expired future stays where it was until settlement, and only the index,
+        // through `OracleUpdateIndex`, keeps moving
+        let now = Self::unix_timestamp_ms(clock_info)?;
+        if perpetual_swap.is_expired(now) {
+            return Err(PerpetualSwapError::MarketExpired.into());
         }
 
-        let index_price = Self::pyth_handle_prices(
-            pyth_product_info,
-            pyth_price_info,
-        )?;
+        let index_price = Self::pyth_handle_prices(pyth_product_info, pyth_price_info)?;
 
-        perpetual_swap.mark_price = mark_price;
-        perpetual_swap.index_price = index_price;
-        Ok(())
+        // A side held by the vAMM is marked at the vAMM's price, which only trades, funding and
+        // the admin move
+        perpetual_swap.mark_price = if perpetual_swap.vamm_side == VammSide::None {
+            if !math::is_mark_within_band(&perpetual_swap, mark_price, index_price) {
+                msg!("Mark price is too far from the index price");
+                return Err(PerpetualSwapError::MarkPriceOutOfBand.into());
+            }
+            mark_price
+        } else {
+            math::vamm_price(&perpetual_swap)
+        };
+        Self::update_fx_rate(&mut perpetual_swap, fx_oracle_infos)?;
+        Self::update_index_price(&mut perpetual_swap, index_price, now);
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
     }
 
-    fn pyth_handle_prices(
-        pyth_product_info: &AccountInfo,
-        pyth_price_info: &AccountInfo,
-    ) -> Result<f64, ProgramError> {
+    /// The price account a Pyth product account lists, once the product is checked to be one.
+    fn pyth_price_pubkey(pyth_product_info: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        if *pyth_product_info.owner != crate::pyth::id() {
+            msg!("Pyth product account provided is not owned by the Pyth program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
         let pyth_product_data = &pyth_product_info.try_borrow_data()?;
+        if pyth_product_data.len() < std::mem::size_of::<pyth_client::Product>() {
+            msg!("Pyth product account provided is too small");
+            return Err(ProgramError::InvalidArgument);
+        }
         let pyth_product = pyth_client::cast::<pyth_client::Product>(pyth_product_data);
 
         if pyth_product.magic != pyth_client::MAGIC {
             msg!("Pyth product account provided is not a valid Pyth account");
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::InvalidArgument);
         }
         if pyth_product.atype != pyth_client::AccountType::Product as u32 {
             msg!("Pyth product account provided is not a valid Pyth product account");
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::InvalidArgument);
         }
         if pyth_product.ver != pyth_client::VERSION_1 {
             msg!("Pyth product account provided has a different version than the Pyth client");
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::InvalidArgument);
         }
         if !pyth_product.px_acc.is_valid() {
             msg!("Pyth product price account is invalid");
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::InvalidArgument);
         }
 
-        let pyth_price_pubkey = Pubkey::new(&pyth_product.px_acc.val);
-        if &pyth_price_pubkey != pyth_price_info.key {
+        Ok(Pubkey::new(&pyth_product.px_acc.val))
+    }
+
+    /// Reads the aggregate price of a Pyth product, scaled by its exponent. Only a price that is
+    /// currently trading is accepted.
+    fn pyth_handle_prices(
+        pyth_product_info: &AccountInfo,
+        pyth_price_info: &AccountInfo,
+    ) -> Result<f64, ProgramError> {
+        if Self::pyth_price_pubkey(pyth_product_info)? != *pyth_price_info.key {
             msg!("Pyth product price account does not match the Pyth price provided");
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::InvalidArgument);
+        }
+        if *pyth_price_info.owner != crate::pyth::id() {
+            msg!("Pyth price account provided is not owned by the Pyth program");
+            return Err(ProgramError::IncorrectProgramId);
         }
 
         let pyth_price_data = &pyth_price_info.try_borrow_data()?;
+        if pyth_price_data.len() < std::mem::size_of::<pyth_client::Price>() {
+            msg!("Pyth price account provided is too small");
+            return Err(ProgramError::InvalidArgument);
+        }
         let pyth_price = pyth_client::cast::<pyth_client::Price>(pyth_price_data);
-        let price = pyth_price.agg.price as f64;
-        Ok(price)
+        if pyth_price.magic != pyth_client::MAGIC
+            || pyth_price.atype != pyth_client::AccountType::Price as u32
+        {
+            msg!("Pyth price account provided is not a valid Pyth price account");
+            return Err(ProgramError::InvalidArgument);
+        }
+        match pyth_price.agg.status {
+            pyth_client::PriceStatus::Trading => {}
+            _ => return Err(PerpetualSwapError::OracleNotTrading.into()),
+        }
+        Ok(pyth_price.agg.price as f64 * 10f64.powi(pyth_price.expo))
+    }
 
+    /// Reads a quanto market's FX rate from its oracle along with the index.
+    fn update_fx_rate(
+        perpetual_swap: &mut PerpetualSwap,
+        fx_oracle_infos: Option<(&AccountInfo, &AccountInfo)>,
+    ) -> ProgramResult {
+        if let Some((fx_product_info, fx_price_info)) = fx_oracle_infos {
+            let fx_rate = Self::pyth_handle_prices(fx_product_info, fx_price_info)?;
+            if fx_rate <= 0.0 {
+                msg!("FX rate must be positive");
+                return Err(ProgramError::InvalidArgument);
+            }
+            perpetual_swap.fx_rate = fx_rate;
+        }
+        Ok(())
     }
 
     pub fn process_oracle_update_index(
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let perpetual_swap_info = next_account_info(account_info_iter)?;
-        let pyth_product_info = next_account_info(account_info_iter)?;
-        let pyth_price_info = next_account_info(account_info_iter)?;
-
-        let index_price = Self::pyth_handle_prices(
+        let OracleUpdateIndexAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
             pyth_product_info,
             pyth_price_info,
-        )?;
+            clock_info,
+            fx_oracle_infos,
+        } = OracleUpdateIndexAccounts::load(program_id, accounts)?;
 
+        if !perpetual_swap.is_initialized() {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
 
-        let mut perpetual_swap = PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?;
+        let index_price = Self::pyth_handle_prices(pyth_product_info, pyth_price_info)?;
 
-        perpetual_swap.index_price = index_price;
+        Self::update_fx_rate(&mut perpetual_swap, fx_oracle_infos)?;
+        Self::update_index_price(
+            &mut perpetual_swap,
+            index_price,
+            Self::unix_timestamp_ms(clock_info)?,
+        );
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
 
-        Ok(())
+    }
+
+    /// Computes the health of a cross-margin account from the variable list of
+    /// `(PerpetualSwap, margin account)` pairs, which must match its registered markets in order.
+    fn cross_margin_health(
+        program_id: &Pubkey,
+        cross_margin: &CrossMarginAccount,
+        vault_amount: u64,
+        market_infos: &[AccountInfo],
+    ) -> Result<math::CrossMarginHealth, ProgramError> {
+        let account_info_iter = &mut market_infos.iter();
+        let mut positions = Vec::with_capacity(cross_margin.markets().len());
+        for market in cross_margin.markets() {
+            let perpetual_swap_info = next_account_info(account_info_iter)?;
+            let margin_info = next_account_info(account_info_iter)?;
+            if *perpetual_swap_info.key != market.swap_pubkey {
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+            // A closed swap holds no position, so it neither backs nor burdens the account
+            let perpetual_swap =
+                match Self::unpack_registered_swap(program_id, perpetual_swap_info)? {
+                    Some(perpetual_swap) => perpetual_swap,
+                    None => continue,
+                };
+            let expected_margin_pubkey = if market.is_long {
+                perpetual_swap.long_margin_pubkey
+            } else {
+                perpetual_swap.short_margin_pubkey
+            };
+            if *margin_info.key != expected_margin_pubkey {
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+            // The position left the vault, e.g. when it was transferred or liquidated, so it no
+            // longer backs the account
+            if *perpetual_swap.user_account_pubkey(market.is_long) != cross_margin.vault_pubkey {
+                continue;
+            }
+            let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+            positions.push((perpetual_swap, market.is_long, margin.amount));
+        }
+        let positions: Vec<(&PerpetualSwap, bool, u64)> = positions
+            .iter()
+            .map(|(perpetual_swap, is_long, margin)| (perpetual_swap, *is_long, *margin))
+            .collect();
+        Ok(math::cross_margin_health(vault_amount, &positions))
+    }
+
+    /// The swap behind a registered cross-margin market, or `None` once `CloseSwap` has closed it
+    fn unpack_registered_swap(
+        program_id: &Pubkey,
+        perpetual_swap_info: &AccountInfo,
+    ) -> Result<Option<PerpetualSwap>, ProgramError> {
+        if perpetual_swap_info.owner != program_id
+            || perpetual_swap_info.lamports() == 0
+            || perpetual_swap_info.data.borrow().iter().all(|byte| *byte == 0)
+        {
+            return Ok(None);
+        }
+        Ok(Some(PerpetualSwap::try_from_slice(&perpetual_swap_info.data.borrow())?))
+    }
+
+    pub fn process_initialize_cross_margin(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let InitializeCrossMarginAccounts {
+            cross_margin_info,
+            mut cross_margin,
+            authority_info,
+            authority_bump,
+            vault_info,
+            mint_info,
+            owner_info,
+            rent_info,
+            token_program_info,
+        } = InitializeCrossMarginAccounts::load(program_id, accounts)?;
+
+        if cross_margin.is_initialized {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_info)?;
+        if !rent.is_exempt(cross_margin_info.lamports(), cross_margin_info.data_len()) {
+            return Err(PerpetualSwapError::NotRentExempt.into());
+        }
+
+        let vault = Account::unpack_unchecked(&vault_info.data.borrow())?;
+        if vault.is_initialized() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        let mint = Self::unpack_mint(mint_info, token_program_info.key)?;
+
+        Self::initialize_account(
+            vault_info.clone(),
+            mint_info.clone(),
+            authority_info.clone(),
+            rent_info.clone(),
+            token_program_info.clone(),
+        )?;
+
+        cross_margin.is_initialized = true;
+        cross_margin.authority_bump = authority_bump;
+        cross_margin.owner = *owner_info.key;
+        cross_margin.token_program_id = *token_program_info.key;
+        cross_margin.vault_pubkey = *vault_info.key;
+        cross_margin.mint = *mint_info.key;
+        cross_margin.decimals = mint.decimals;
+        cross_margin.num_markets = 0;
+        cross_margin
+            .serialize(&mut *cross_margin_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_deposit_to_cross_margin(
+        program_id: &Pubkey,
+        amount_to_deposit: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let DepositToCrossMarginAccounts {
+            cross_margin_info,
+            cross_margin,
+            authority_info: _,
+            owner_info: user_transfer_authority_info,
+            source_info,
+            vault_info,
+            token_program_info,
+            mint_info,
+        } = DepositToCrossMarginAccounts::load(program_id, accounts)?;
+
+        let source_account = Self::unpack_token_account(source_info, &cross_margin.token_program_id)?;
+        if source_account.owner != cross_margin.owner {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        if source_account.amount < amount_to_deposit {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+
+        Self::token_transfer_checked(
+            cross_margin_info.key,
+            token_program_info.clone(),
+            source_info.clone(),
+            mint_info.clone(),
+            vault_info.clone(),
+            user_transfer_authority_info.clone(),
+            cross_margin.authority_bump,
+            amount_to_deposit,
+            cross_margin.decimals,
+        )
+    }
+
+    pub fn process_withdraw_from_cross_margin(
+        program_id: &Pubkey,
+        amount_to_withdraw: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let WithdrawFromCrossMarginAccounts {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            owner_info: _,
+            vault_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            market_infos,
+        } = WithdrawFromCrossMarginAccounts::load(program_id, accounts)?;
+
+        let vault = Self::unpack_token_account(vault_info, &cross_margin.token_program_id)?;
+        let remaining_vault = vault
+            .amount
+            .checked_sub(amount_to_withdraw)
+            .ok_or(PerpetualSwapError::InsufficientFunds)?;
+        let health =
+            Self::cross_margin_health(program_id, &cross_margin, remaining_vault, market_infos)?;
+        if !health.is_healthy() {
+            return Err(PerpetualSwapError::WouldBeLiquidated.into());
+        }
+        // Every market the account is still in has to allow withdrawals
+        for perpetual_swap_info in market_infos.iter().step_by(2) {
+            if Self::unpack_registered_swap(program_id, perpetual_swap_info)?.is_none() {
+                continue;
+            }
+            Self::check_market_status(program_id, perpetual_swap_info, MarketAction::Withdraw)?;
+        }
+
+        Self::token_transfer_checked(
+            cross_margin_info.key,
+            token_program_info.clone(),
+            vault_info.clone(),
+            mint_info.clone(),
+            dest_info.clone(),
+            authority_info.clone(),
+            cross_margin.authority_bump,
+            amount_to_withdraw,
+            cross_margin.decimals,
+        )
+    }
+
+    pub fn process_add_cross_margin_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AddCrossMarginMarketAccounts {
+            cross_margin_info,
+            mut cross_margin,
+            owner_info: _,
+            perpetual_swap_info,
+            mut perpetual_swap,
+            position_account_info,
+        } = AddCrossMarginMarketAccounts::load(program_id, accounts)?;
+
+        if perpetual_swap.token_program_id != cross_margin.token_program_id {
+            return Err(PerpetualSwapError::IncorrectTokenProgramId.into());
+        }
+        if perpetual_swap.collateral_mint != cross_margin.mint {
+            return Err(PerpetualSwapError::InvalidMints.into());
+        }
+        if cross_margin
+            .markets()
+            .iter()
+            .any(|market| market.swap_pubkey == *perpetual_swap_info.key)
+        {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        if cross_margin.markets().len() >= CrossMarginAccount::MAX_MARKETS {
+            return Err(PerpetualSwapError::CrossMarginFull.into());
+        }
+
+        // Only the owner of the token account holding the position can move it
+        let position_account =
+            Self::unpack_collateral_account(&perpetual_swap, position_account_info)?;
+        if position_account.owner != cross_margin.owner {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        let is_long = if perpetual_swap.is_long_initialized
+            && perpetual_swap.long_account_pubkey == *position_account_info.key
+        {
+            perpetual_swap.long_account_pubkey = cross_margin.vault_pubkey;
+            true
+        } else if perpetual_swap.is_short_initialized
+            && perpetual_swap.short_account_pubkey == *position_account_info.key
+        {
+            perpetual_swap.short_account_pubkey = cross_margin.vault_pubkey;
+            false
+        } else {
+            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        };
+        if *perpetual_swap.owner(is_long) != cross_margin.owner {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        // The cross-margin account would own the side, not the holder of its position token
+        if perpetual_swap.is_tokenized(is_long) {
+            return Err(PerpetualSwapError::PositionTokenized.into());
+        }
+
+        cross_margin.markets[cross_margin.num_markets as usize] = CrossMarginMarket {
+            swap_pubkey: *perpetual_swap_info.key,
+            is_long,
+        };
+        cross_margin.num_markets += 1;
+
+        perpetual_swap.serialize(&mut *perpetual_swap_info.data.borrow_mut())?;
+        cross_margin
+            .serialize(&mut *cross_margin_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_remove_cross_margin_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let RemoveCrossMarginMarketAccounts {
+            cross_margin_info,
+            mut cross_margin,
+            owner_info: _,
+            perpetual_swap_info,
+        } = RemoveCrossMarginMarketAccounts::load(program_id, accounts)?;
+
+        let index = cross_margin
+            .markets()
+            .iter()
+            .position(|market| market.swap_pubkey == *perpetual_swap_info.key)
+            .ok_or(PerpetualSwapError::InvalidAccountKeys)?;
+        // A position the vault still backs has to stay in the account's health, or the vault
+        // could be withdrawn from under it
+        let registered_swap = Self::unpack_registered_swap(program_id, perpetual_swap_info)?;
+        if let Some(perpetual_swap) = registered_swap {
+            let is_long = cross_margin.markets[index].is_long;
+            let is_initialized = if is_long {
+                perpetual_swap.is_long_initialized
+            } else {
+                perpetual_swap.is_short_initialized
+            };
+            if is_initialized
+                && *perpetual_swap.user_account_pubkey(is_long) == cross_margin.vault_pubkey
+            {
+                return Err(PerpetualSwapError::PositionStillOpen.into());
+            }
+        }
+
+        let num_markets = cross_margin.num_markets as usize;
+        cross_margin.markets.copy_within(index + 1..num_markets, index);
+        cross_margin.markets[num_markets - 1] = CrossMarginMarket::default();
+        cross_margin.num_markets -= 1;
+
+        cross_margin
+            .serialize(&mut *cross_margin_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_cross_margin_top_up(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let CrossMarginTopUpAccounts {
+            cross_margin_info,
+            cross_margin,
+            authority_info,
+            vault_info,
+            perpetual_swap_info: _,
+            perpetual_swap,
+            margin_info,
+            token_program_info,
+            mint_info,
+            is_long,
+        } = CrossMarginTopUpAccounts::load(program_id, accounts)?;
+
+        let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+        let shortfall = math::maintenance_margin(&perpetual_swap)
+            - math::equity(&perpetual_swap, is_long, margin.amount);
+        if shortfall <= 0.0 {
+            return Err(PerpetualSwapError::DoesNotNeedLiquidation.into());
+        }
+        let vault = Self::unpack_collateral_account(&perpetual_swap, vault_info)?;
+        let top_up = (shortfall.ceil() as u64).min(vault.amount);
+
+        Self::token_transfer_checked(
+            cross_margin_info.key,
+            token_program_info.clone(),
+            vault_info.clone(),
+            mint_info.clone(),
+            margin_info.clone(),
+            authority_info.clone(),
+            cross_margin.authority_bump,
+            top_up,
+            perpetual_swap.collateral_decimals,
+        )
+    }
+
+    pub fn process_cross_margin_health(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let CrossMarginHealthAccounts {
+            cross_margin,
+            vault_info,
+            market_infos,
+        } = CrossMarginHealthAccounts::load(program_id, accounts)?;
+
+        let vault = Self::unpack_token_account(vault_info, &cross_margin.token_program_id)?;
+        let health =
+            Self::cross_margin_health(program_id, &cross_margin, vault.amount, market_infos)?;
+
+        msg!(
+            "Cross-margin health: equity {}, maintenance margin {}, free collateral {}",
+            health.equity,
+            health.maintenance_margin,
+            health.free_collateral()
+        );
+        Ok(())
+    }
+
+    /// Unpacks the `CollateralConfig` of a swap.
+    pub fn unpack_collateral_config(
+        program_id: &Pubkey,
+        perpetual_swap: &PerpetualSwap,
+        collateral_config_info: &AccountInfo,
+    ) -> Result<CollateralConfig, ProgramError> {
+        if collateral_config_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if *collateral_config_info.key != perpetual_swap.collateral_config_pubkey {
+            return Err(PerpetualSwapError::InvalidAccountKeys.into());
+        }
+        let collateral_config =
+            CollateralConfig::try_from_slice(&collateral_config_info.data.borrow())?;
+        if !collateral_config.is_initialized {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
+        Ok(collateral_config)
+    }
+
+    /// A side's margin plus its non-primary collateral at its haircut value, the way a liquidator
+    /// would buy it. `collateral_infos` starts with the collateral config, and the side's vault of
+    /// the first asset comes `offset` accounts after it, then every `stride` accounts.
+    fn margin_value(
+        program_id: &Pubkey,
+        perpetual_swap: &PerpetualSwap,
+        is_long: bool,
+        margin: u64,
+        collateral_infos: &[AccountInfo],
+        offset: usize,
+        stride: usize,
+    ) -> Result<u64, ProgramError> {
+        if !perpetual_swap.has_collateral_config() {
+            return Ok(margin);
+        }
+        let account_info_iter = &mut collateral_infos.iter();
+        let collateral_config_info = next_account_info(account_info_iter)?;
+        let collateral_config =
+            Self::unpack_collateral_config(program_id, perpetual_swap, collateral_config_info)?;
+        let vault_infos = &mut account_info_iter.skip(offset).step_by(stride);
+        let mut collateral = Vec::with_capacity(collateral_config.assets().len());
+        for asset in collateral_config.assets() {
+            let vault_info = next_account_info(vault_infos)?;
+            if *vault_info.key != asset.vault_pubkey(is_long) {
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+            let vault = Self::unpack_token_account(vault_info, &perpetual_swap.token_program_id)?;
+            collateral.push((vault.amount, asset.price, asset.haircut));
+        }
+        Ok(math::margin_value(margin, &collateral))
+    }
+
+    pub fn process_add_collateral_asset(
+        program_id: &Pubkey,
+        haircut: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AddCollateralAssetAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            collateral_config_info,
+            authority_info,
+            mint_info,
+            pyth_product_info,
+            pyth_price_info,
+            long_vault_info,
+            short_vault_info,
+            rent_info,
+            token_program_info,
+            admin_info: _,
+        } = AddCollateralAssetAccounts::load(program_id, accounts)?;
+
+        if !(haircut > 0.0 && haircut <= 1.0) {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+        let price = Self::collateral_price(pyth_product_info, pyth_price_info)?;
+
+        let mut collateral_config = if perpetual_swap.has_collateral_config() {
+            Self::unpack_collateral_config(program_id, &perpetual_swap, collateral_config_info)?
+        } else {
+            let mut collateral_config =
+                CollateralConfig::try_from_slice(&collateral_config_info.data.borrow())?;
+            if collateral_config.is_initialized {
+                return Err(PerpetualSwapError::AlreadyInUse.into());
+            }
+            let rent = &Rent::from_account_info(rent_info)?;
+            if !rent.is_exempt(
+                collateral_config_info.lamports(),
+                collateral_config_info.data_len(),
+            ) {
+                return Err(PerpetualSwapError::NotRentExempt.into());
+            }
+            collateral_config.is_initialized = true;
+            collateral_config.swap_pubkey = *perpetual_swap_info.key;
+            collateral_config.num_assets = 0;
+            perpetual_swap.collateral_config_pubkey = *collateral_config_info.key;
+            collateral_config
+        };
+        if collateral_config
+            .assets()
+            .iter()
+            .any(|asset| asset.mint == *mint_info.key)
+        {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        if collateral_config.assets().len() >= CollateralConfig::MAX_ASSETS {
+            return Err(PerpetualSwapError::CollateralConfigFull.into());
+        }
+        let mint = Self::unpack_mint(mint_info, &perpetual_swap.token_program_id)?;
+
+        for vault_info in [long_vault_info, short_vault_info].iter() {
+            let vault = Account::unpack_unchecked(&vault_info.data.borrow())?;
+            if vault.is_initialized() {
+                return Err(PerpetualSwapError::AlreadyInUse.into());
+            }
+            Self::initialize_account(
+                (*vault_info).clone(),
+                mint_info.clone(),
+                authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+        }
+
+        collateral_config.assets[collateral_config.num_assets as usize] = CollateralAsset {
+            mint: *mint_info.key,
+            decimals: mint.decimals,
+            pyth_product_pubkey: *pyth_product_info.key,
+            long_vault_pubkey: *long_vault_info.key,
+            short_vault_pubkey: *short_vault_info.key,
+            haircut,
+            price,
+        };
+        collateral_config.num_assets += 1;
+
+        collateral_config.serialize(&mut *collateral_config_info.data.borrow_mut())?;
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    /// Reads a non-primary collateral's price, in units of the primary collateral, from its oracle.
+    fn collateral_price(
+        pyth_product_info: &AccountInfo,
+        pyth_price_info: &AccountInfo,
+    ) -> Result<f64, ProgramError> {
+        let price = Self::pyth_handle_prices(pyth_product_info, pyth_price_info)?;
+        if price <= 0.0 {
+            msg!("Collateral price must be positive");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(price)
+    }
+
+    pub fn process_update_collateral_prices(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let UpdateCollateralPricesAccounts {
+            collateral_config_info,
+            mut collateral_config,
+            oracle_infos,
+        } = UpdateCollateralPricesAccounts::load(program_id, accounts)?;
+
+        let account_info_iter = &mut oracle_infos.iter();
+        for asset in collateral_config.assets[..collateral_config.num_assets as usize].iter_mut() {
+            let pyth_product_info = next_account_info(account_info_iter)?;
+            let pyth_price_info = next_account_info(account_info_iter)?;
+            if *pyth_product_info.key != asset.pyth_product_pubkey {
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+            asset.price = Self::collateral_price(pyth_product_info, pyth_price_info)?;
+        }
+
+        collateral_config
+            .serialize(&mut *collateral_config_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_deposit_collateral(
+        program_id: &Pubkey,
+        amount_to_deposit: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let DepositCollateralAccounts {
+            perpetual_swap_info,
+            perpetual_swap,
+            authority_info: _,
+            owner_info: user_transfer_authority_info,
+            source_info,
+            vault_info,
+            token_program_info,
+            mint_info,
+            asset,
+        } = DepositCollateralAccounts::load(program_id, accounts)?;
+
+        let source_account = Self::unpack_token_account(source_info, &perpetual_swap.token_program_id)?;
+        if source_account.owner != *user_transfer_authority_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        if source_account.amount < amount_to_deposit {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+
+        Self::token_transfer_checked(
+            perpetual_swap_info.key,
+            token_program_info.clone(),
+            source_info.clone(),
+            mint_info.clone(),
+            vault_info.clone(),
+            user_transfer_authority_info.clone(),
+            perpetual_swap.authority_bump,
+            amount_to_deposit,
+            asset.decimals,
+        )
+    }
+
+    pub fn process_withdraw_collateral(
+        program_id: &Pubkey,
+        asset_index: u8,
+        amount_to_withdraw: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let WithdrawCollateralAccounts {
+            perpetual_swap_info,
+            perpetual_swap,
+            collateral_config,
+            authority_info,
+            owner_info,
+            margin_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            vault_infos,
+            is_long,
+        } = WithdrawCollateralAccounts::load(program_id, accounts)?;
+
+        if perpetual_swap.is_expired(Self::unix_timestamp_ms(clock_info)?) {
+            return Err(PerpetualSwapError::MarketExpired.into());
+        }
+
+        let dest_account = Self::unpack_token_account(dest_info, &perpetual_swap.token_program_id)?;
+        if dest_account.owner != *owner_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+
+        let asset_index = asset_index as usize;
+        let withdrawn_asset = *collateral_config
+            .assets()
+            .get(asset_index)
+            .ok_or(PerpetualSwapError::InvalidInstruction)?;
+        if *mint_info.key != withdrawn_asset.mint {
+            return Err(PerpetualSwapError::InvalidMints.into());
+        }
+        let account_info_iter = &mut vault_infos.iter();
+        let mut collateral = Vec::with_capacity(collateral_config.assets().len());
+        let mut withdrawn_vault_info = None;
+        for (i, asset) in collateral_config.assets().iter().enumerate() {
+            let vault_info = next_account_info(account_info_iter)?;
+            if *vault_info.key != asset.vault_pubkey(is_long) {
+                return Err(PerpetualSwapError::InvalidAccountKeys.into());
+            }
+            let vault = Self::unpack_token_account(vault_info, &perpetual_swap.token_program_id)?;
+            let mut amount = vault.amount;
+            if i == asset_index {
+                amount = amount
+                    .checked_sub(amount_to_withdraw)
+                    .ok_or(PerpetualSwapError::InsufficientFunds)?;
+                withdrawn_vault_info = Some(vault_info);
+            }
+            collateral.push((amount, asset.price, asset.haircut));
+        }
+        let withdrawn_vault_info =
+            withdrawn_vault_info.ok_or(PerpetualSwapError::InvalidAccountKeys)?;
+
+        if perpetual_swap.is_initialized() {
+            let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+            let margin_value = math::margin_value(margin.amount, &collateral);
+            if math::equity(&perpetual_swap, is_long, margin_value)
+                < math::initial_margin(&perpetual_swap)
+            {
+                return Err(PerpetualSwapError::WouldBeLiquidated.into());
+            }
+        }
+
+        Self::token_transfer_checked(
+            perpetual_swap_info.key,
+            token_program_info.clone(),
+            withdrawn_vault_info.clone(),
+            mint_info.clone(),
+            dest_info.clone(),
+            authority_info.clone(),
+            perpetual_swap.authority_bump,
+            amount_to_withdraw,
+            withdrawn_asset.decimals,
+        )
+    }
+
+    pub fn process_update_margin_tiers(
+        program_id: &Pubkey,
+        margin_tiers: &[MarginTier],
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let UpdateMarketParamsAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+            clock_info,
+        } = UpdateMarketParamsAccounts::load(program_id, accounts)?;
+
+        if !math::validate_margin_tiers(margin_tiers) {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+        let mut params = perpetual_swap.next_market_params();
+        params.num_margin_tiers = margin_tiers.len() as u8;
+        params.margin_tiers = [MarginTier::default(); PerpetualSwap::MAX_MARGIN_TIERS];
+        params.margin_tiers[..margin_tiers.len()].copy_from_slice(margin_tiers);
+        Self::update_market_params(
+            &mut perpetual_swap,
+            perpetual_swap_info.key,
+            params,
+            Self::unix_timestamp_ms(clock_info)?,
+        )?;
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_get_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let GetHealthAccounts {
+            perpetual_swap,
+            long_margin_info,
+            short_margin_info,
+            collateral_infos,
+        } = GetHealthAccounts::load(program_id, accounts)?;
+
+        if !perpetual_swap.is_initialized() {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
+        let long_margin = Self::unpack_collateral_account(&perpetual_swap, long_margin_info)?;
+        let short_margin = Self::unpack_collateral_account(&perpetual_swap, short_margin_info)?;
+
+        // The vaults come in pairs, the long's first
+        let long_value = Self::margin_value(
+            program_id,
+            &perpetual_swap,
+            true,
+            long_margin.amount,
+            collateral_infos,
+            0,
+            2,
+        )?;
+        let short_value = Self::margin_value(
+            program_id,
+            &perpetual_swap,
+            false,
+            short_margin.amount,
+            collateral_infos,
+            1,
+            2,
+        )?;
+
+        let mut health = math::swap_health(&perpetual_swap, long_value, short_value);
+        // The collateral backs the margin, but only the margin itself can be withdrawn from it
+        health.long.max_withdrawable = health.long.max_withdrawable.min(long_margin.amount);
+        health.short.max_withdrawable = health.short.max_withdrawable.min(short_margin.amount);
+        for (side, side_health) in [("Long", health.long), ("Short", health.short)].iter() {
+            msg!(
+                "{} health: equity {}, margin ratio {}, free collateral {}, max withdrawable {}, liquidation price {}",
+                side,
+                side_health.equity,
+                side_health.margin_ratio,
+                side_health.free_collateral,
+                side_health.max_withdrawable,
+                side_health.liquidation_price
+            );
+        }
+        Ok(())
+    }
+
+    pub fn process_configure_crank_reward(
+        program_id: &Pubkey,
+        crank_reward: u64,
+        crank_reward_cap: u64,
+        crank_reward_interval: u128,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let ConfigureCrankRewardAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            fee_vault_info,
+            mint_info,
+            rent_info,
+            token_program_info,
+        } = ConfigureCrankRewardAccounts::load(program_id, accounts)?;
+
+        if crank_reward > crank_reward_cap {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+
+        if !perpetual_swap.has_fee_vault() {
+            let fee_vault = Account::unpack_unchecked(&fee_vault_info.data.borrow())?;
+            if fee_vault.is_initialized() {
+                return Err(PerpetualSwapError::AlreadyInUse.into());
+            }
+            Self::initialize_account(
+                fee_vault_info.clone(),
+                mint_info.clone(),
+                authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+            perpetual_swap.fee_vault_pubkey = *fee_vault_info.key;
+        }
+
+        perpetual_swap.crank_reward = crank_reward;
+        perpetual_swap.crank_reward_cap = crank_reward_cap;
+        perpetual_swap.crank_reward_interval = crank_reward_interval;
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_update_market_params(
+        program_id: &Pubkey,
+        funding_rate: f64,
+        minimum_margin: f64,
+        liquidation_bounty: f64,
+        minimum_funding_period: u128,
+        params_timelock: u128,
+        mark_price_band: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let UpdateMarketParamsAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+            clock_info,
+        } = UpdateMarketParamsAccounts::load(program_id, accounts)?;
+
+        let params = MarketParams {
+            funding_rate,
+            minimum_margin,
+            liquidation_bounty,
+            minimum_funding_period,
+            params_timelock,
+            mark_price_band,
+            ..perpetual_swap.next_market_params()
+        };
+        Self::update_market_params(
+            &mut perpetual_swap,
+            perpetual_swap_info.key,
+            params,
+            Self::unix_timestamp_ms(clock_info)?,
+        )?;
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_apply_market_params(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let ApplyMarketParamsAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            clock_info,
+        } = ApplyMarketParamsAccounts::load(program_id, accounts)?;
+
+        if !perpetual_swap.has_pending_params {
+            return Err(PerpetualSwapError::NoPendingChange.into());
+        }
+        if Self::unix_timestamp_ms(clock_info)? < perpetual_swap.pending_params_time {
+            return Err(PerpetualSwapError::TimelockNotElapsed.into());
+        }
+
+        let params = perpetual_swap.pending_params;
+        perpetual_swap.set_market_params(&params);
+        perpetual_swap.has_pending_params = false;
+        PerpetualSwapEvent::MarketParamsUpdated {
+            swap: *perpetual_swap_info.key,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_propose_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let ProposeAdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            new_admin_info,
+        } = ProposeAdminAccounts::load(program_id, accounts)?;
+
+        perpetual_swap.pending_admin = *new_admin_info.key;
+        PerpetualSwapEvent::AdminProposed {
+            swap: *perpetual_swap_info.key,
+            admin: *new_admin_info.key,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let AcceptAdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            new_admin_info: _,
+        } = AcceptAdminAccounts::load(program_id, accounts)?;
+
+        perpetual_swap.admin = perpetual_swap.pending_admin;
+        perpetual_swap.pending_admin = Pubkey::default();
+        PerpetualSwapEvent::AdminTransferred {
+            swap: *perpetual_swap_info.key,
+            admin: perpetual_swap.admin,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_set_market_status(
+        program_id: &Pubkey,
+        status: MarketStatus,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+        } = AdminAccounts::load(program_id, accounts)?;
+
+        // Settling is final, otherwise the frozen prices could be moved after the fact, and only
+        // `SettleMarket` records the price it happens at
+        if perpetual_swap.status == MarketStatus::Settled || status == MarketStatus::Settled {
+            return Err(PerpetualSwapError::InvalidMarketStatus.into());
+        }
+
+        perpetual_swap.status = status;
+        PerpetualSwapEvent::MarketStatusChanged {
+            swap: *perpetual_swap_info.key,
+            status,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_settle_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let SettleMarketAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+        } = SettleMarketAccounts::load(program_id, accounts)?;
+
+        let now = Self::unix_timestamp_ms(clock_info)?;
+        // Anyone can settle an expired future, but the admin still gets what's left over
+        if !perpetual_swap.is_expired(now) && !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if perpetual_swap.status == MarketStatus::Settled {
+            return Err(PerpetualSwapError::InvalidMarketStatus.into());
+        }
+        if perpetual_swap.index_twap_time == 0 {
+            return Err(PerpetualSwapError::NoIndexPrice.into());
+        }
+        let admin_token_account =
+            Self::unpack_collateral_account(&perpetual_swap, admin_token_account_info)?;
+        if admin_token_account.owner != perpetual_swap.admin {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        let long_margin = Self::unpack_collateral_account(&perpetual_swap, long_margin_info)?;
+        let short_margin = Self::unpack_collateral_account(&perpetual_swap, short_margin_info)?;
+        let insurance_fund = Self::unpack_collateral_account(&perpetual_swap, insurance_fund_info)?;
+
+        let settlement_price = if perpetual_swap.is_expired(now) {
+            math::settlement_twap(&perpetual_swap, now).ok_or(PerpetualSwapError::NoIndexPrice)?
+        } else {
+            math::index_twap(&perpetual_swap, now)
+        };
+        perpetual_swap.mark_price = settlement_price;
+        perpetual_swap.settlement_price = settlement_price;
+
+        // Realize the PnL between the margin accounts, so each side's margin is all it is owed
+        if perpetual_swap.is_initialized() {
+            Self::realize_pnl(
+                perpetual_swap_info.key,
+                &perpetual_swap,
+                (long_margin_info, long_margin.amount),
+                (short_margin_info, short_margin.amount),
+                (insurance_fund_info, insurance_fund.amount),
+                token_program_info,
+                mint_info,
+                authority_info,
+            )?;
+        }
+        // Nobody can redeem the vAMM's side, so its margin goes back to the insurance fund it
+        // came out of, and the vAMM leaves the swap
+        let vamm_held = (perpetual_swap.vamm_holds(true), perpetual_swap.vamm_holds(false));
+        if perpetual_swap.vamm_side != VammSide::None {
+            let vamm_margin_info = if vamm_held.0 {
+                long_margin_info
+            } else {
+                short_margin_info
+            };
+            Self::close_vamm_side(
+                perpetual_swap_info.key,
+                &mut perpetual_swap,
+                vamm_margin_info,
+                insurance_fund_info,
+                token_program_info,
+                mint_info,
+                authority_info,
+            )?;
+            perpetual_swap.mark_price = settlement_price;
+        }
+        perpetual_swap.entry_price = settlement_price;
+
+        // The insurance fund and fee vault stay with the swap until `CloseSwap`. The margin of a
+        // side nobody holds goes back to the admin, and the vAMM's is empty by now.
+        for (is_initialized, margin_info, amount) in [
+            (
+                perpetual_swap.is_long_initialized,
+                long_margin_info,
+                if vamm_held.0 { 0 } else { long_margin.amount },
+            ),
+            (
+                perpetual_swap.is_short_initialized,
+                short_margin_info,
+                if vamm_held.1 { 0 } else { short_margin.amount },
+            ),
+        ]
+        .iter()
+        {
+            if !is_initialized {
+                Self::token_sweep_and_close(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    (*margin_info).clone(),
+                    mint_info.clone(),
+                    perpetual_swap.collateral_decimals,
+                    admin_token_account_info.clone(),
+                    admin_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    *amount,
+                )?;
+            }
+        }
+
+        perpetual_swap.status = MarketStatus::Settled;
+        PerpetualSwapEvent::MarketSettled {
+            swap: *perpetual_swap_info.key,
+            settlement_price,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_redeem_settled(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let RedeemSettledAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            owner_info,
+            margin_info,
+            dest_info,
+            admin_info,
+            token_program_info,
+            mint_info,
+            collateral_infos,
+            is_long,
+        } = RedeemSettledAccounts::load(program_id, accounts)?;
+
+        if perpetual_swap.status != MarketStatus::Settled {
+            return Err(PerpetualSwapError::InvalidMarketStatus.into());
+        }
+        let is_initialized = if is_long {
+            perpetual_swap.is_long_initialized
+        } else {
+            perpetual_swap.is_short_initialized
+        };
+        if !is_initialized {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
+        let dest_account = Self::unpack_collateral_account(&perpetual_swap, dest_info)?;
+        if dest_account.owner != *owner_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+
+        // The margin already holds the final PnL, realized by `SettleMarket`
+        let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+        Self::token_sweep_and_close(
+            perpetual_swap_info.key,
+            token_program_info.clone(),
+            margin_info.clone(),
+            mint_info.clone(),
+            perpetual_swap.collateral_decimals,
+            dest_info.clone(),
+            admin_info.clone(),
+            authority_info.clone(),
+            perpetual_swap.authority_bump,
+            margin.amount,
+        )?;
+        if perpetual_swap.has_collateral_config() {
+            let account_info_iter = &mut collateral_infos.iter();
+            let config_info = next_account_info(account_info_iter)?;
+            let collateral_config =
+                Self::unpack_collateral_config(program_id, &perpetual_swap, config_info)?;
+            for asset in collateral_config.assets() {
+                let vault_info = next_account_info(account_info_iter)?;
+                let collateral_dest_info = next_account_info(account_info_iter)?;
+                let asset_mint_info = next_account_info(account_info_iter)?;
+                if *vault_info.key != asset.vault_pubkey(is_long)
+                    || *asset_mint_info.key != asset.mint
+                {
+                    return Err(PerpetualSwapError::InvalidAccountKeys.into());
+                }
+                let collateral_dest =
+                    Self::unpack_token_account(collateral_dest_info, &perpetual_swap.token_program_id)?;
+                if collateral_dest.owner != *owner_info.key {
+                    return Err(PerpetualSwapError::InvalidOwner.into());
+                }
+                let vault = Self::unpack_token_account(vault_info, &perpetual_swap.token_program_id)?;
+                Self::token_sweep_and_close(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    vault_info.clone(),
+                    asset_mint_info.clone(),
+                    asset.decimals,
+                    collateral_dest_info.clone(),
+                    admin_info.clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    vault.amount,
+                )?;
+            }
+        }
+
+        PerpetualSwapEvent::SettledPositionRedeemed {
+            swap: *perpetual_swap_info.key,
+            account: *owner_info.key,
+            is_long,
+            amount: margin.amount,
+        }
+        .emit();
+        if is_long {
+            perpetual_swap.is_long_initialized = false;
+        } else {
+            perpetual_swap.is_short_initialized = false;
+        }
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_close_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let ClosePositionAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            owner_info,
+            margin_info,
+            counterparty_margin_info,
+            insurance_fund_info,
+            dest_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            collateral_infos,
+            is_long,
+        } = ClosePositionAccounts::load(program_id, accounts)?;
+
+        // An expired future is closed out by settlement, at the settlement price
+        if perpetual_swap.is_expired(Self::unix_timestamp_ms(clock_info)?) {
+            return Err(PerpetualSwapError::MarketExpired.into());
+        }
+        let is_initialized = if is_long {
+            perpetual_swap.is_long_initialized
+        } else {
+            perpetual_swap.is_short_initialized
+        };
+        if !is_initialized {
+            return Err(PerpetualSwapError::AccountNotInitialized.into());
+        }
+        let dest_account = Self::unpack_collateral_account(&perpetual_swap, dest_info)?;
+        if dest_account.owner != *owner_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+        let margin = Self::unpack_collateral_account(&perpetual_swap, margin_info)?;
+        let counterparty_margin =
+            Self::unpack_collateral_account(&perpetual_swap, counterparty_margin_info)?;
+        let insurance_fund = Self::unpack_collateral_account(&perpetual_swap, insurance_fund_info)?;
+
+        // With the counterparty still in, the side leaves at the mark price. A side that can be
+        // liquidated has to be, so its loss isn't pushed onto the insurance fund for free.
+        let mut amount = margin.amount;
+        let is_vamm_counterparty = perpetual_swap.vamm_holds(!is_long);
+        if perpetual_swap.is_initialized() {
+            // Against the vAMM, the side trades its position back at the vAMM's price instead
+            if is_vamm_counterparty {
+                perpetual_swap.mark_price = math::vamm_execution_price(
+                    &perpetual_swap,
+                    !is_long,
+                    perpetual_swap.position_size,
+                )
+                .ok_or(PerpetualSwapError::CalculationFailure)?;
+            }
+            // Each asset comes as the side's vault, the owner's token account and the mint
+            let margin_value = Self::margin_value(
+                program_id,
+                &perpetual_swap,
+                is_long,
+                margin.amount,
+                collateral_infos,
+                0,
+                3,
+            )?;
+            if math::needs_liquidation(&perpetual_swap, is_long, margin_value) {
+                return Err(PerpetualSwapError::WouldBeLiquidated.into());
+            }
+            // The loss is realized out of the margin alone, and the collateral goes back to the
+            // owner, so the margin has to cover it
+            if math::negative_equity_deficit(&perpetual_swap, is_long, margin.amount) > 0 {
+                return Err(PerpetualSwapError::InsufficientFunds.into());
+            }
+            let side_margin = (margin_info, margin.amount);
+            let counterparty_margin = (counterparty_margin_info, counterparty_margin.amount);
+            let (long_margin, short_margin) = if is_long {
+                (side_margin, counterparty_margin)
+            } else {
+                (counterparty_margin, side_margin)
+            };
+            let (is_long_losing, paid, insurance_payout) = Self::realize_pnl(
+                perpetual_swap_info.key,
+                &perpetual_swap,
+                long_margin,
+                short_margin,
+                (insurance_fund_info, insurance_fund.amount),
+                token_program_info,
+                mint_info,
+                authority_info,
+            )?;
+            amount = if is_long_losing == is_long {
+                amount - paid
+            } else {
+                amount + paid + insurance_payout
+            };
+            // The counterparty's PnL was realized too, so it has nothing left to gain or lose
+            perpetual_swap.entry_price = perpetual_swap.mark_price;
+        }
+        let price = perpetual_swap.mark_price;
+        if is_vamm_counterparty {
+            Self::close_vamm_side(
+                perpetual_swap_info.key,
+                &mut perpetual_swap,
+                counterparty_margin_info,
+                insurance_fund_info,
+                token_program_info,
+                mint_info,
+                authority_info,
+            )?;
+        }
+        if amount > 0 {
+            Self::token_transfer_checked(
+                perpetual_swap_info.key,
+                token_program_info.clone(),
+                margin_info.clone(),
+                mint_info.clone(),
+                dest_info.clone(),
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
+                amount,
+                perpetual_swap.collateral_decimals,
+            )?;
+        }
+        if perpetual_swap.has_collateral_config() {
+            let account_info_iter = &mut collateral_infos.iter();
+            let config_info = next_account_info(account_info_iter)?;
+            let collateral_config =
+                Self::unpack_collateral_config(program_id, &perpetual_swap, config_info)?;
+            for asset in collateral_config.assets() {
+                let vault_info = next_account_info(account_info_iter)?;
+                let collateral_dest_info = next_account_info(account_info_iter)?;
+                let asset_mint_info = next_account_info(account_info_iter)?;
+                if *vault_info.key != asset.vault_pubkey(is_long)
+                    || *asset_mint_info.key != asset.mint
+                {
+                    return Err(PerpetualSwapError::InvalidAccountKeys.into());
+                }
+                let collateral_dest =
+                    Self::unpack_token_account(collateral_dest_info, &perpetual_swap.token_program_id)?;
+                if collateral_dest.owner != *owner_info.key {
+                    return Err(PerpetualSwapError::InvalidOwner.into());
+                }
+                let vault = Self::unpack_token_account(vault_info, &perpetual_swap.token_program_id)?;
+                if vault.amount > 0 {
+                    Self::token_transfer_checked(
+                        perpetual_swap_info.key,
+                        token_program_info.clone(),
+                        vault_info.clone(),
+                        asset_mint_info.clone(),
+                        collateral_dest_info.clone(),
+                        authority_info.clone(),
+                        perpetual_swap.authority_bump,
+                        vault.amount,
+                        asset.decimals,
+                    )?;
+                }
+            }
+        }
+
+        PerpetualSwapEvent::PositionClosed {
+            swap: *perpetual_swap_info.key,
+            account: *owner_info.key,
+            is_long,
+            price,
+            amount,
+        }
+        .emit();
+        // The side is free for a new owner to open with `InitializeSide`, and its position token
+        // is worthless
+        if is_long {
+            perpetual_swap.is_long_initialized = false;
+            perpetual_swap.long_owner = Pubkey::default();
+            perpetual_swap.long_position_mint = Pubkey::default();
+        } else {
+            perpetual_swap.is_short_initialized = false;
+            perpetual_swap.short_owner = Pubkey::default();
+            perpetual_swap.short_position_mint = Pubkey::default();
+        }
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_close_swap(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let CloseSwapAccounts {
+            perpetual_swap_info,
+            perpetual_swap,
+            admin_info: _,
+            authority_info,
+            long_margin_info,
+            short_margin_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            receiver_info,
+            token_program_info,
+            mint_info,
+            fee_vault_info,
+            collateral_infos,
+        } = CloseSwapAccounts::load(program_id, accounts)?;
+
+        if perpetual_swap.is_long_initialized || perpetual_swap.is_short_initialized {
+            return Err(PerpetualSwapError::PositionStillOpen.into());
+        }
+        let admin_token_account =
+            Self::unpack_collateral_account(&perpetual_swap, admin_token_account_info)?;
+        if admin_token_account.owner != perpetual_swap.admin {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+
+        // Whatever is left in the swap's token accounts goes to the admin, so nobody can keep the
+        // swap open by sending it dust. Settling the market and redeeming its sides already
+        // closed some of them.
+        let mut token_accounts = vec![long_margin_info, short_margin_info, insurance_fund_info];
+        token_accounts.extend(fee_vault_info);
+        for account_info in token_accounts {
+            if account_info.data_is_empty() {
+                continue;
+            }
+            let account = Self::unpack_collateral_account(&perpetual_swap, account_info)?;
+            Self::token_sweep_and_close(
+                perpetual_swap_info.key,
+                token_program_info.clone(),
+                account_info.clone(),
+                mint_info.clone(),
+                perpetual_swap.collateral_decimals,
+                admin_token_account_info.clone(),
+                receiver_info.clone(),
+                authority_info.clone(),
+                perpetual_swap.authority_bump,
+                account.amount,
+            )?;
+        }
+        // The non-primary collateral was handed back by `ClosePosition` or `RedeemSettled`, so its
+        // vaults are empty, and `RedeemSettled` closed its side's
+        if perpetual_swap.has_collateral_config() {
+            let account_info_iter = &mut collateral_infos.iter();
+            let config_info = next_account_info(account_info_iter)?;
+            let collateral_config =
+                Self::unpack_collateral_config(program_id, &perpetual_swap, config_info)?;
+            for asset in collateral_config.assets() {
+                for is_long in [true, false].iter() {
+                    let vault_info = next_account_info(account_info_iter)?;
+                    if *vault_info.key != asset.vault_pubkey(*is_long) {
+                        return Err(PerpetualSwapError::InvalidAccountKeys.into());
+                    }
+                    if vault_info.data_is_empty() {
+                        continue;
+                    }
+                    Self::token_close_account(
+                        perpetual_swap_info.key,
+                        token_program_info.clone(),
+                        vault_info.clone(),
+                        receiver_info.clone(),
+                        authority_info.clone(),
+                        perpetual_swap.authority_bump,
+                    )?;
+                }
+            }
+            Self::close_program_account(config_info, receiver_info)?;
+        }
+
+        PerpetualSwapEvent::SwapClosed {
+            swap: *perpetual_swap_info.key,
+        }
+        .emit();
+        Self::close_program_account(perpetual_swap_info, receiver_info)
+    }
+
+    pub fn process_configure_fees(
+        program_id: &Pubkey,
+        maker_fee_bps: u16,
+        taker_fee_bps: u16,
+        insurance_fund_fee_share_bps: u16,
+        referral_rebate_share_bps: u16,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let ConfigureFeesAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            fee_vault_info,
+            mint_info,
+            rent_info,
+            token_program_info,
+            admin_info,
+            system_program_info,
+            clock_info,
+            new_fee_vault_bump,
+        } = ConfigureFeesAccounts::load(program_id, accounts)?;
+
+        // Fees are market params, so raising them waits out the timelock like a margin increase
+        let params = MarketParams {
+            maker_fee_bps,
+            taker_fee_bps,
+            insurance_fund_fee_share_bps,
+            referral_rebate_share_bps,
+            ..perpetual_swap.next_market_params()
+        };
+        Self::update_market_params(
+            &mut perpetual_swap,
+            perpetual_swap_info.key,
+            params,
+            Self::unix_timestamp_ms(clock_info)?,
+        )?;
+
+        if let Some(fee_vault_bump) = new_fee_vault_bump {
+            if !fee_vault_info.data_is_empty() {
+                return Err(PerpetualSwapError::AlreadyInUse.into());
+            }
+            let rent = &Rent::from_account_info(rent_info)?;
+            Self::create_fee_vault_account(
+                perpetual_swap_info.key,
+                fee_vault_bump,
+                fee_vault_info.clone(),
+                admin_info.clone(),
+                system_program_info.clone(),
+                rent,
+                &perpetual_swap.token_program_id,
+            )?;
+            Self::initialize_account(
+                fee_vault_info.clone(),
+                mint_info.clone(),
+                authority_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            )?;
+            perpetual_swap.fee_vault_pubkey = *fee_vault_info.key;
+        }
+
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_sweep_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let SweepFeesAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+            authority_info,
+            fee_vault_info,
+            insurance_fund_info,
+            admin_token_account_info,
+            token_program_info,
+            mint_info,
+        } = SweepFeesAccounts::load(program_id, accounts)?;
+        Self::unpack_collateral_account(&perpetual_swap, admin_token_account_info)?;
+
+        // Crank rewards paid out of the fees were taken off the unswept fees, and anything else in
+        // the vault was put there to pay them
+        let fee_vault = Self::unpack_collateral_account(&perpetual_swap, fee_vault_info)?;
+        let amount = perpetual_swap.unswept_fees.min(fee_vault.amount);
+        let insurance_fund_amount = (amount as u128
+            * perpetual_swap.insurance_fund_fee_share_bps as u128
+            / 10_000) as u64;
+        for (destination_info, amount) in [
+            (insurance_fund_info, insurance_fund_amount),
+            (admin_token_account_info, amount - insurance_fund_amount),
+        ]
+        .iter()
+        {
+            if *amount > 0 {
+                Self::token_transfer_checked(
+                    perpetual_swap_info.key,
+                    token_program_info.clone(),
+                    fee_vault_info.clone(),
+                    mint_info.clone(),
+                    (*destination_info).clone(),
+                    authority_info.clone(),
+                    perpetual_swap.authority_bump,
+                    *amount,
+                    perpetual_swap.collateral_decimals,
+                )?;
+            }
+        }
+        perpetual_swap.unswept_fees = 0;
+
+        PerpetualSwapEvent::FeesSwept {
+            swap: *perpetual_swap_info.key,
+            amount,
+            insurance_fund_amount,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_initialize_referrer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let InitializeReferrerAccounts {
+            referrer_info,
+            referrer,
+            authority_info,
+            authority_bump,
+            vault_info,
+            mint_info,
+            owner_info,
+            rent_info,
+            token_program_info,
+        } = InitializeReferrerAccounts::load(program_id, accounts)?;
+        if referrer.is_initialized {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_info)?;
+        if !rent.is_exempt(referrer_info.lamports(), referrer_info.data_len()) {
+            return Err(PerpetualSwapError::NotRentExempt.into());
+        }
+        let vault = Account::unpack_unchecked(&vault_info.data.borrow())?;
+        if vault.is_initialized() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+
+        Self::initialize_account(
+            vault_info.clone(),
+            mint_info.clone(),
+            authority_info.clone(),
+            rent_info.clone(),
+            token_program_info.clone(),
+        )?;
+
+        let referrer = Referrer {
+            is_initialized: true,
+            authority_bump,
+            owner: *owner_info.key,
+            token_program_id: *token_program_info.key,
+            vault_pubkey: *vault_info.key,
+            mint: *mint_info.key,
+            unclaimed_rebates: 0,
+            total_rebates: 0,
+        };
+        referrer
+            .serialize(&mut *referrer_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_link_referrer(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let LinkReferrerAccounts {
+            referral_link_info,
+            referral_link_bump,
+            referrer_info,
+            user_info,
+            rent_info,
+            system_program_info,
+        } = LinkReferrerAccounts::load(program_id, accounts)?;
+        // The link lives at the user's program address, so it can only be created once
+        if !referral_link_info.data_is_empty() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_info)?;
+        let user_bytes = user_info.key.to_bytes();
+        let referral_link_signature_seeds =
+            [Self::REFERRAL_SEED, &user_bytes[..], &[referral_link_bump]];
+        let ix = system_instruction::create_account(
+            user_info.key,
+            referral_link_info.key,
+            rent.minimum_balance(ReferralLink::LEN),
+            ReferralLink::LEN as u64,
+            program_id,
+        );
+        invoke_signed(
+            &ix,
+            &[
+                user_info.clone(),
+                referral_link_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&referral_link_signature_seeds],
+        )?;
+
+        let referral_link = ReferralLink {
+            is_initialized: true,
+            user: *user_info.key,
+            referrer: *referrer_info.key,
+        };
+        referral_link.serialize(&mut *referral_link_info.data.borrow_mut())?;
+        PerpetualSwapEvent::ReferrerLinked {
+            user: *user_info.key,
+            referrer: *referrer_info.key,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub fn process_claim_referral_rebates(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let ClaimReferralRebatesAccounts {
+            referrer_info,
+            mut referrer,
+            authority_info,
+            owner_info: _,
+            vault_info,
+            destination_info,
+            token_program_info,
+            mint_info,
+        } = ClaimReferralRebatesAccounts::load(program_id, accounts)?;
+        let vault = Self::unpack_token_account(vault_info, &referrer.token_program_id)?;
+        let mint = Self::unpack_mint(mint_info, &referrer.token_program_id)?;
+
+        let amount = referrer.unclaimed_rebates.min(vault.amount);
+        if amount > 0 {
+            Self::token_transfer_checked(
+                referrer_info.key,
+                token_program_info.clone(),
+                vault_info.clone(),
+                mint_info.clone(),
+                destination_info.clone(),
+                authority_info.clone(),
+                referrer.authority_bump,
+                amount,
+                mint.decimals,
+            )?;
+        }
+        referrer.unclaimed_rebates -= amount;
+
+        PerpetualSwapEvent::ReferralRebatesClaimed {
+            referrer: *referrer_info.key,
+            amount,
+        }
+        .emit();
+        referrer
+            .serialize(&mut *referrer_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_initialize_vamm(
+        program_id: &Pubkey,
+        base_reserve: f64,
+        peg_multiplier: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+        } = AdminAccounts::load(program_id, accounts)?;
+
+        if perpetual_swap.has_vamm() {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        // The vAMM has to be able to sell a whole position
+        if !(base_reserve.is_finite()
+            && base_reserve > perpetual_swap.position_size
+            && peg_multiplier.is_finite()
+            && peg_multiplier > 0.0)
+        {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+
+        perpetual_swap.vamm_base_reserve = base_reserve;
+        perpetual_swap.vamm_quote_reserve = base_reserve;
+        perpetual_swap.vamm_peg_multiplier = peg_multiplier;
+        Self::vamm_updated(perpetual_swap_info.key, &mut perpetual_swap);
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_repeg_vamm(
+        program_id: &Pubkey,
+        peg_multiplier: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+        } = AdminAccounts::load(program_id, accounts)?;
+
+        if !perpetual_swap.has_vamm() {
+            return Err(PerpetualSwapError::NoVamm.into());
+        }
+        // It would move the mark of the position open against the vAMM at once
+        if perpetual_swap.vamm_side != VammSide::None {
+            return Err(PerpetualSwapError::HeldByVamm.into());
+        }
+        if !(peg_multiplier.is_finite() && peg_multiplier > 0.0) {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+
+        perpetual_swap.vamm_peg_multiplier = peg_multiplier;
+        Self::vamm_updated(perpetual_swap_info.key, &mut perpetual_swap);
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_adjust_vamm_depth(
+        program_id: &Pubkey,
+        depth_multiplier: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let AdminAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            admin_info: _,
+        } = AdminAccounts::load(program_id, accounts)?;
+
+        if !perpetual_swap.has_vamm() {
+            return Err(PerpetualSwapError::NoVamm.into());
+        }
+        // It would change the price the position open against the vAMM closes at
+        if perpetual_swap.vamm_side != VammSide::None {
+            return Err(PerpetualSwapError::HeldByVamm.into());
+        }
+        let base_reserve = perpetual_swap.vamm_base_reserve * depth_multiplier;
+        let quote_reserve = perpetual_swap.vamm_quote_reserve * depth_multiplier;
+        // However shallow it gets, the vAMM still has to be able to sell a whole position
+        if !(depth_multiplier.is_finite()
+            && quote_reserve.is_finite()
+            && base_reserve > perpetual_swap.position_size)
+        {
+            return Err(PerpetualSwapError::InvalidMarketParameter.into());
+        }
+
+        perpetual_swap.vamm_base_reserve = base_reserve;
+        perpetual_swap.vamm_quote_reserve = quote_reserve;
+        Self::vamm_updated(perpetual_swap_info.key, &mut perpetual_swap);
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+
+    pub fn process_open_with_vamm(
+        program_id: &Pubkey,
+        is_long: bool,
+        amount_to_deposit: u64,
+        limit_price: f64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let OpenWithVammAccounts {
+            perpetual_swap_info,
+            mut perpetual_swap,
+            authority_info,
+            owner_info,
+            source_info,
+            margin_info,
+            vamm_margin_info,
+            insurance_fund_info,
+            token_program_info,
+            mint_info,
+            clock_info,
+            fee_vault_info,
+            referral_infos,
+        } = OpenWithVammAccounts::load(program_id, accounts, is_long)?;
+        let source_account = Self::unpack_collateral_account(&perpetual_swap, source_info)?;
+        let insurance_fund = Self::unpack_collateral_account(&perpetual_swap, insurance_fund_info)?;
+
+        if !perpetual_swap.has_vamm() {
+            return Err(PerpetualSwapError::NoVamm.into());
+        }
+        if perpetual_swap.is_long_initialized || perpetual_swap.is_short_initialized {
+            return Err(PerpetualSwapError::AlreadyInUse.into());
+        }
+        let now = Self::unix_timestamp_ms(clock_info)?;
+        if perpetual_swap.kind == MarketKind::DatedFuture && perpetual_swap.is_expired(now) {
+            return Err(PerpetualSwapError::MarketExpired.into());
+        }
+        if source_account.owner != *owner_info.key {
+            return Err(PerpetualSwapError::InvalidOwner.into());
+        }
+
+        // A long buys the position from the vAMM and a short sells it to the vAMM
+        let size = perpetual_swap.position_size;
+        let price = math::vamm_execution_price(&perpetual_swap, is_long, size)
+            .ok_or(PerpetualSwapError::CalculationFailure)?;
+        if (is_long && price > limit_price) || (!is_long && price < limit_price) {
+            return Err(PerpetualSwapError::PriceLimitExceeded.into());
+        }
+        let (base_reserve, quote_reserve) =
+            math::vamm_reserves_after(&perpetual_swap, is_long, size)
+                .ok_or(PerpetualSwapError::CalculationFailure)?;
+        perpetual_swap.vamm_base_reserve = base_reserve;
+        perpetual_swap.vamm_quote_reserve = quote_reserve;
+        perpetual_swap.entry_price = price;
+        perpetual_swap.mark_price = math::vamm_price(&perpetual_swap);
+
+        let initial_margin = math::initial_margin(&perpetual_swap);
+        if (amount_to_deposit as f64) < initial_margin {
+            return Err(PerpetualSwapError::WouldBeLiquidated.into());
+        }
+        let vamm_margin = initial_margin.ceil() as u64;
+        if insurance_fund.amount < vamm_margin {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+        let fee = math::trading_fee(&perpetual_swap, size, price, perpetual_swap.taker_fee_bps);
+        if source_account.amount < amount_to_deposit.saturating_add(fee) {
+            return Err(PerpetualSwapError::InsufficientFunds.into());
+        }
+
+        Self::token_transfer_checked(
+            perpetual_swap_info.key,
+            token_program_info.clone(),
+            source_info.clone(),
+            mint_info.clone(),
+            margin_info.clone(),
+            owner_info.clone(),
+            perpetual_swap.authority_bump,
+            amount_to_deposit,
+            perpetual_swap.collateral_decimals,
+        )?;
+        Self::token_transfer_checked(
+            perpetual_swap_info.key,
+            token_program_info.clone(),
+            insurance_fund_info.clone(),
+            mint_info.clone(),
+            vamm_margin_info.clone(),
+            authority_info.clone(),
+            perpetual_swap.authority_bump,
+            vamm_margin,
+            perpetual_swap.collateral_decimals,
+        )?;
+        if let Some(fee_vault_info) = fee_vault_info {
+            let rebate = match referral_infos {
+                Some(referral_infos) => Self::pay_referral_rebate(
+                    perpetual_swap_info.key,
+                    &perpetual_swap,
+                    referral_infos,
+                    fee,
+                    token_program_info.clone(),
+                    source_info.clone(),
+                    mint_info.clone(),
+                    owner_info.clone(),
+                )?,
+                None => 0,
+            };
+            Self::charge_trading_fee(
+                perpetual_swap_info.key,
+                &mut perpetual_swap,
+                false,
+                fee - rebate,
+                token_program_info.clone(),
+                source_info.clone(),
+                mint_info.clone(),
+                fee_vault_info.clone(),
+                owner_info.clone(),
+            )?;
+        }
+
+        // The vAMM's side belongs to the swap authority, and its user account is the insurance
+        // fund, which it pays back into when the side closes
+        if is_long {
+            perpetual_swap.long_account_pubkey = *source_info.key;
+            perpetual_swap.long_owner = *owner_info.key;
+            perpetual_swap.short_account_pubkey = *insurance_fund_info.key;
+            perpetual_swap.short_owner = *authority_info.key;
+            perpetual_swap.vamm_side = VammSide::Short;
+        } else {
+            perpetual_swap.short_account_pubkey = *source_info.key;
+            perpetual_swap.short_owner = *owner_info.key;
+            perpetual_swap.long_account_pubkey = *insurance_fund_info.key;
+            perpetual_swap.long_owner = *authority_info.key;
+            perpetual_swap.vamm_side = VammSide::Long;
+        }
+        perpetual_swap.long_position_mint = Pubkey::default();
+        perpetual_swap.short_position_mint = Pubkey::default();
+        perpetual_swap.is_long_initialized = true;
+        perpetual_swap.is_short_initialized = true;
+        // Funding starts with the position, as when a second side completes a swap
+        perpetual_swap.reference_time = now;
+
+        PerpetualSwapEvent::VammPositionOpened {
+            swap: *perpetual_swap_info.key,
+            account: *owner_info.key,
+            is_long,
+            size,
+            price,
+        }
+        .emit();
+        perpetual_swap
+            .serialize(&mut *perpetual_swap_info.data.borrow_mut())
+            .map_err(|e| e.into())
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+    #[cfg(test)]
+    use solana_program::{clock::Epoch, instruction::Instruction, program_stubs, system_program};
+    #[cfg(test)]
+    use spl_token::instruction::TokenInstruction;
+    #[cfg(test)]
+    use spl_token::state::{AccountState, Mint};
+    #[cfg(test)]
+    use std::{cell::RefCell, sync::Once};
+
+    #[cfg(test)]
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        is_signer: bool,
+    }
+
+    #[cfg(test)]
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            Self {
+                key,
+                owner,
+                lamports: 1_000_000_000,
+                data,
+                is_signer: false,
+            }
+        }
+
+        fn wallet() -> Self {
+            Self::new(Pubkey::new_unique(), system_program::id(), vec![])
+        }
+
+        fn signer() -> Self {
+            let mut wallet = Self::wallet();
+            wallet.is_signer = true;
+            wallet
+        }
+
+        fn token_account(key: Pubkey, mint: &Pubkey, owner: &Pubkey) -> Self {
+            let mut data = vec![0; Account::LEN];
+            let account = Account {
+                mint: *mint,
+                owner: *owner,
+                amount: 1_000,
+                state: AccountState::Initialized,
+                ..Account::default()
+            };
+            Account::pack(account, &mut data).unwrap();
+            Self::new(key, spl_token::id(), data)
+        }
+
+        fn mint(key: Pubkey) -> Self {
+            let mut data = vec![0; Mint::LEN];
+            let mint = Mint {
+                decimals: 6,
+                is_initialized: true,
+                ..Mint::default()
+            };
+            Mint::pack(mint, &mut data).unwrap();
+            Self::new(key, spl_token::id(), data)
+        }
+
+        fn clock(unix_timestamp: i64) -> Self {
+            let mut account = Self::new(
+                solana_program::sysvar::clock::id(),
+                solana_program::sysvar::id(),
+                vec![0; Clock::size_of()],
+            );
+            let clock = Clock {
+                unix_timestamp,
+                ..Clock::default()
+            };
+            clock.to_account_info(&mut account.info()).unwrap();
+            account
+        }
+
+        /// A Pyth product account and the price account it lists, trading at `price` * 10^`expo`.
+        fn pyth_oracle(price: i64, expo: i32) -> (Self, Self) {
+            let price_key = Pubkey::new_unique();
+            let header = |atype: pyth_client::AccountType| {
+                [pyth_client::MAGIC, pyth_client::VERSION_1, atype as u32]
+                    .iter()
+                    .flat_map(|field| field.to_le_bytes().to_vec())
+                    .collect::<Vec<u8>>()
+            };
+
+            let mut product = vec![0; std::mem::size_of::<pyth_client::Product>()];
+            product[..12].copy_from_slice(&header(pyth_client::AccountType::Product));
+            product[16..48].copy_from_slice(price_key.as_ref());
+
+            let mut price_data = vec![0; std::mem::size_of::<pyth_client::Price>()];
+            price_data[..12].copy_from_slice(&header(pyth_client::AccountType::Price));
+            price_data[20..24].copy_from_slice(&expo.to_le_bytes());
+            price_data[144..152].copy_from_slice(&price.to_le_bytes());
+            price_data[160..164]
+                .copy_from_slice(&(pyth_client::PriceStatus::Trading as u32).to_le_bytes());
+
+            (
+                Self::new(Pubkey::new_unique(), crate::pyth::id(), product),
+                Self::new(price_key, crate::pyth::id(), price_data),
+            )
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                true,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                Epoch::default(),
+            )
+        }
+    }
+
+    /// A swap with both sides open, each held by its own wallet
+    #[cfg(test)]
+    struct TestSwap {
+        program_id: Pubkey,
+        mint: Pubkey,
+        collateral_mint: TestAccount,
+        swap: TestAccount,
+        authority: TestAccount,
+        admin: TestAccount,
+        long_owner: TestAccount,
+        short_owner: TestAccount,
+        long_account: TestAccount,
+        short_account: TestAccount,
+        long_margin: TestAccount,
+        short_margin: TestAccount,
+        token_program: TestAccount,
+        pyth_product: TestAccount,
+        pyth_price: TestAccount,
+    }
+
+    #[cfg(test)]
+    impl TestSwap {
+        fn new() -> Self {
+            let program_id = Pubkey::new_unique();
+            let swap_key = Pubkey::new_unique();
+            let (authority_key, authority_bump) =
+                Processor::find_authority_address(&program_id, &swap_key);
+            let (long_margin_key, long_margin_bump) =
+                Processor::find_margin_address(&program_id, &swap_key, true);
+            let (short_margin_key, short_margin_bump) =
+                Processor::find_margin_address(&program_id, &swap_key, false);
+            let mint = Pubkey::new_unique();
+            let admin = TestAccount::signer();
+            let long_owner = TestAccount::wallet();
+            let short_owner = TestAccount::wallet();
+            let long_account =
+                TestAccount::token_account(Pubkey::new_unique(), &mint, &long_owner.key);
+            let short_account =
+                TestAccount::token_account(Pubkey::new_unique(), &mint, &short_owner.key);
+            let long_margin = TestAccount::token_account(long_margin_key, &mint, &authority_key);
+            let short_margin = TestAccount::token_account(short_margin_key, &mint, &authority_key);
+            let (pyth_product, pyth_price) = TestAccount::pyth_oracle(100_000_000, -6);
+
+            let mut p = PerpetualSwap::try_from_slice(&[0; PerpetualSwap::LEN]).unwrap();
+            p.is_long_initialized = true;
+            p.is_short_initialized = true;
+            p.authority_bump = authority_bump;
+            p.long_margin_bump = long_margin_bump;
+            p.short_margin_bump = short_margin_bump;
+            p.collateral_mint = mint;
+            p.collateral_decimals = 6;
+            p.token_program_id = spl_token::id();
+            p.long_margin_pubkey = long_margin.key;
+            p.long_account_pubkey = long_account.key;
+            p.long_owner = long_owner.key;
+            p.short_margin_pubkey = short_margin.key;
+            p.short_account_pubkey = short_account.key;
+            p.short_owner = short_owner.key;
+            p.index_price = 100.0;
+            p.mark_price = 100.0;
+            p.entry_price = 100.0;
+            p.position_size = 1.0;
+            p.funding_rate = 0.01;
+            p.minimum_margin = 0.1;
+            p.liquidation_bounty = 0.05;
+            p.minimum_funding_period = 3_600_000;
+            p.num_margin_tiers = 1;
+            p.margin_tiers[0] = MarginTier {
+                max_notional: f64::MAX,
+                initial_margin_ratio: 0.1,
+                maintenance_margin_ratio: 0.1,
+                max_leverage: 10.0,
+            };
+            p.admin = admin.key;
+            p.params_timelock = 60_000;
+            p.oracle_product_pubkey = pyth_product.key;
+            p.oracle_price_pubkey = pyth_price.key;
+            p.mark_price_band = 0.1;
+
+            Self {
+                program_id,
+                mint,
+                collateral_mint: TestAccount::mint(mint),
+                swap: TestAccount::new(swap_key, program_id, p.try_to_vec().unwrap()),
+                authority: TestAccount::new(authority_key, program_id, vec![]),
+                admin,
+                long_owner,
+                short_owner,
+                long_account,
+                short_account,
+                long_margin,
+                short_margin,
+                token_program: TestAccount::new(spl_token::id(), Pubkey::default(), vec![]),
+                pyth_product,
+                pyth_price,
+            }
+        }
+
+        fn perpetual_swap(&self) -> PerpetualSwap {
+            PerpetualSwap::try_from_slice(&self.swap.data).unwrap()
+        }
+
+        fn set_perpetual_swap(&mut self, perpetual_swap: &PerpetualSwap) {
+            self.swap.data = perpetual_swap.try_to_vec().unwrap();
+        }
+
+        /// Registers a collateral asset on the swap and returns the config and both vaults.
+        fn add_collateral(&mut self) -> (TestAccount, TestAccount, TestAccount) {
+            let collateral_mint = Pubkey::new_unique();
+            let long_vault = TestAccount::token_account(
+                Pubkey::new_unique(),
+                &collateral_mint,
+                &self.authority.key,
+            );
+            let short_vault = TestAccount::token_account(
+                Pubkey::new_unique(),
+                &collateral_mint,
+                &self.authority.key,
+            );
+            let mut collateral_config =
+                CollateralConfig::try_from_slice(&[0; CollateralConfig::LEN]).unwrap();
+            collateral_config.is_initialized = true;
+            collateral_config.swap_pubkey = self.swap.key;
+            collateral_config.num_assets = 1;
+            collateral_config.assets[0] = CollateralAsset {
+                mint: collateral_mint,
+                decimals: 6,
+                pyth_product_pubkey: Pubkey::new_unique(),
+                long_vault_pubkey: long_vault.key,
+                short_vault_pubkey: short_vault.key,
+                haircut: 0.5,
+                price: 1.0,
+            };
+            let collateral_config = TestAccount::new(
+                Pubkey::new_unique(),
+                self.program_id,
+                collateral_config.try_to_vec().unwrap(),
+            );
+
+            let mut p = self.perpetual_swap();
+            p.collateral_config_pubkey = collateral_config.key;
+            self.set_perpetual_swap(&p);
+            (collateral_config, long_vault, short_vault)
+        }
+    }
+
+    #[cfg(test)]
+    thread_local! {
+        static INVOKED: RefCell<Vec<Instruction>> = const { RefCell::new(Vec::new()) };
+        static LOGGED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records every instruction a handler invokes instead of running it, and every message it
+    /// logs
+    #[cfg(test)]
+    struct RecordingStubs;
+
+    #[cfg(test)]
+    impl program_stubs::SyscallStubs for RecordingStubs {
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            _account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+            Ok(())
+        }
+
+        fn sol_log(&self, message: &str) {
+            LOGGED.with(|logged| logged.borrow_mut().push(message.to_string()));
+        }
+    }
+
+    #[cfg(test)]
+    fn process(
+        accounts: &mut [&mut TestAccount],
+        handler: impl FnOnce(&[AccountInfo]) -> ProgramResult,
+    ) -> ProgramResult {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(RecordingStubs));
+        });
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+        let account_infos: Vec<AccountInfo> =
+            accounts.iter_mut().map(|account| account.info()).collect();
+        handler(&account_infos)
+    }
+
+    /// The source, destination and amount of every token transfer the last `process` invoked.
+    /// Every transfer has to be checked against its mint.
+    #[cfg(test)]
+    fn token_transfers() -> Vec<(Pubkey, Pubkey, u64)> {
+        INVOKED.with(|invoked| {
+            invoked
+                .borrow()
+                .iter()
+                .filter_map(|instruction| {
+                    match TokenInstruction::unpack(&instruction.data).ok()? {
+                        TokenInstruction::Transfer { .. } => panic!("unchecked token transfer"),
+                        TokenInstruction::TransferChecked { amount, .. } => Some((
+                            instruction.accounts[0].pubkey,
+                            instruction.accounts[2].pubkey,
+                            amount,
+                        )),
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Whether the last `process` emitted `event`
+    #[cfg(test)]
+    fn emitted(event: &PerpetualSwapEvent) -> bool {
+        let message = format!("Event: {:?}", event);
+        LOGGED.with(|logged| logged.borrow().contains(&message))
+    }
+
+    /// The authority signing each token transfer the last `process` invoked
+    #[cfg(test)]
+    fn token_transfer_authorities() -> Vec<Pubkey> {
+        INVOKED.with(|invoked| {
+            invoked
+                .borrow()
+                .iter()
+                .filter_map(|instruction| {
+                    match TokenInstruction::unpack(&instruction.data).ok()? {
+                        TokenInstruction::Transfer { .. } => panic!("unchecked token transfer"),
+                        TokenInstruction::TransferChecked { .. } => {
+                            Some(instruction.accounts[3].pubkey)
+                        }
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// The mint and decimals each token transfer the last `process` invoked was checked against
+    #[cfg(test)]
+    fn token_transfer_mints() -> Vec<(Pubkey, u8)> {
+        INVOKED.with(|invoked| {
+            invoked
+                .borrow()
+                .iter()
+                .filter_map(|instruction| {
+                    match TokenInstruction::unpack(&instruction.data).ok()? {
+                        TokenInstruction::TransferChecked { decimals, .. } => {
+                            Some((instruction.accounts[1].pubkey, decimals))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    pub fn test_initialize_side_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.is_long_initialized = false;
+        p.is_short_initialized = false;
+        t.set_perpetual_swap(&p);
+        let mut clock = TestAccount::wallet();
+        let mut initialize_long = |t: &mut TestSwap, owner: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    &mut t.long_account,
+                    &mut t.long_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_initialize_side(&program_id, 100, accounts),
+            )
+        };
+
+        // The wallet holding the source account didn't sign
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            initialize_long(&mut t, &mut long_owner),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        // Someone else signed for the source account
+        assert_eq!(
+            initialize_long(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+
+        long_owner.is_signer = true;
+        assert_eq!(initialize_long(&mut t, &mut long_owner), Ok(()));
+        assert_eq!(t.perpetual_swap().long_owner, long_owner.key);
+
+        // The side can't be taken over once it's open
+        assert_eq!(
+            initialize_long(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::AlreadyInUse.into())
+        );
+    }
+
+    #[test]
+    pub fn test_deposit_to_margin_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let deposit = |t: &mut TestSwap, owner: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    &mut t.short_account,
+                    &mut t.short_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_deposit_to_margin(&program_id, 100, accounts),
+            )
+        };
+
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            deposit(&mut t, &mut short_owner),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            deposit(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        short_owner.is_signer = true;
+        assert_eq!(deposit(&mut t, &mut short_owner), Ok(()));
+    }
+
+    #[test]
+    pub fn test_withdraw_from_margin_checks_collateral_mint() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut withdraw = |t: &mut TestSwap, dest: &mut TestAccount, mint: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut short_owner,
+                    &mut t.short_margin,
+                    dest,
+                    &mut t.token_program,
+                    mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, 100, accounts),
+            )
+        };
+
+        // A destination of another mint
+        let other_mint = Pubkey::new_unique();
+        let mut other_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &other_mint, &t.short_owner.key);
+        let mut collateral_mint = TestAccount::mint(t.mint);
+        assert_eq!(
+            withdraw(&mut t, &mut other_dest, &mut collateral_mint),
+            Err(PerpetualSwapError::InvalidMints.into())
+        );
+        // The right accounts, but another mint to check the transfer against
+        let mut dest =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.short_owner.key);
+        assert_eq!(
+            withdraw(&mut t, &mut dest, &mut TestAccount::mint(other_mint)),
+            Err(PerpetualSwapError::InvalidMints.into())
+        );
+        assert_eq!(withdraw(&mut t, &mut dest, &mut collateral_mint), Ok(()));
+    }
+
+    #[test]
+    pub fn test_withdraw_from_margin_rejects_duplicate_accounts() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        // The margin account passed again as the destination
+        let mut dest = TestAccount::token_account(t.short_margin.key, &t.mint, &t.authority.key);
+        let mut mint = TestAccount::mint(t.mint);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut short_owner,
+                    &mut t.short_margin,
+                    &mut dest,
+                    &mut t.token_program,
+                    &mut mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, 100, accounts),
+            ),
+            Err(PerpetualSwapError::DuplicateAccount.into())
+        );
+    }
+
+    #[test]
+    pub fn test_withdraw_from_margin_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let withdraw = |t: &mut TestSwap, owner: &mut TestAccount, dest: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    &mut t.long_margin,
+                    dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, 100, accounts),
+            )
+        };
+
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        let mut long_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &long_owner.key);
+        assert_eq!(
+            withdraw(&mut t, &mut long_owner, &mut long_dest),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        let mut other = TestAccount::signer();
+        let mut other_dest = TestAccount::token_account(Pubkey::new_unique(), &t.mint, &other.key);
+        assert_eq!(
+            withdraw(&mut t, &mut other, &mut other_dest),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+
+        // The owner can't send the margin to a token account they don't hold
+        long_owner.is_signer = true;
+        assert_eq!(
+            withdraw(&mut t, &mut long_owner, &mut other_dest),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(withdraw(&mut t, &mut long_owner, &mut long_dest), Ok(()));
+    }
+
+    #[test]
+    pub fn test_margin_transfers_are_signed_by_swap_authority() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut long_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &long_owner.key);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut long_dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, 100, accounts),
+            ),
+            Ok(())
+        );
+        // The owner signs the instruction, but only the swap authority can move the margin
+        assert_eq!(
+            token_transfers(),
+            vec![(t.long_margin.key, long_dest.key, 100)]
+        );
+        assert_eq!(token_transfer_authorities(), vec![t.authority.key]);
+
+        // Funding moves between the margin accounts without any owner signing
+        let mut p = t.perpetual_swap();
+        p.position_size = 1_000.0;
+        p.mark_price = 110.0;
+        t.set_perpetual_swap(&p);
+        let mut clock = TestAccount::clock(3_600);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_transfer_funds(&program_id, accounts),
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            token_transfers(),
+            vec![(t.long_margin.key, t.short_margin.key, 4)]
+        );
+        assert_eq!(token_transfer_authorities(), vec![t.authority.key]);
+    }
+
+    #[test]
+    pub fn test_transfer_position_requires_both_signatures() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut buyer_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &Pubkey::default());
+        let mut transfer = |t: &mut TestSwap, seller: &mut TestAccount, buyer: &mut TestAccount| {
+            Account::pack(
+                Account {
+                    owner: buyer.key,
+                    ..Account::unpack(&buyer_account.data).unwrap()
+                },
+                &mut buyer_account.data,
+            )
+            .unwrap();
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    seller,
+                    &mut t.long_account,
+                    buyer,
+                    &mut buyer_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| {
+                    Processor::process_transfer_position(&program_id, true, 1.0, 100.0, accounts)
+                },
+            )
+        };
+
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        let mut buyer = TestAccount::signer();
+        assert_eq!(
+            transfer(&mut t, &mut long_owner, &mut buyer),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            transfer(&mut t, &mut TestAccount::signer(), &mut buyer),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        long_owner.is_signer = true;
+        buyer.is_signer = false;
+        assert_eq!(
+            transfer(&mut t, &mut long_owner, &mut buyer),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        // The buyer's wallet owns the position afterwards
+        buyer.is_signer = true;
+        assert_eq!(transfer(&mut t, &mut long_owner, &mut buyer), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.long_owner, buyer.key);
+        assert_eq!(p.long_account_pubkey, buyer_account.key);
+    }
+
+    #[test]
+    pub fn test_transfer_position_payment() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut buyer = TestAccount::signer();
+        let buyer_key = buyer.key;
+        let mut buyer_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &buyer.key);
+        let buyer_account_key = buyer_account.key;
+        let mut transfer = |t: &mut TestSwap, size: f64, price: f64| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.short_margin,
+                    &mut short_owner,
+                    &mut t.short_account,
+                    &mut buyer,
+                    &mut buyer_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| {
+                    Processor::process_transfer_position(&program_id, false, size, price, accounts)
+                },
+            )
+        };
+
+        assert_eq!(
+            transfer(&mut t, 0.5, 100.0),
+            Err(PerpetualSwapError::InvalidPositionSize.into())
+        );
+        assert_eq!(
+            transfer(&mut t, 1.0, 0.0),
+            Err(PerpetualSwapError::InvalidInstruction.into())
+        );
+        // The short made 50 at a price of 50, so the buyer owes the 1000 of margin plus 50, more
+        // than it holds
+        assert_eq!(
+            transfer(&mut t, 1.0, 50.0),
+            Err(PerpetualSwapError::InsufficientFunds.into())
+        );
+        assert_eq!(transfer(&mut t, 1.0, 150.0), Ok(()));
+        // The buyer pays the margin less the short's loss of 50 out of its own account, and signs
+        // for it
+        assert_eq!(
+            token_transfers(),
+            vec![(buyer_account_key, t.short_account.key, 950)]
+        );
+        assert_eq!(token_transfer_authorities(), vec![buyer_key]);
+        // The counterparty's position is untouched
+        let p = t.perpetual_swap();
+        assert_eq!(p.entry_price, 100.0);
+        assert_eq!(p.long_owner, t.long_owner.key);
+    }
+
+    #[test]
+    pub fn test_split_position() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut new_swap = TestAccount::new(
+            Pubkey::new_unique(),
+            program_id,
+            vec![0; PerpetualSwap::LEN],
+        );
+        let (new_authority_key, new_authority_bump) =
+            Processor::find_authority_address(&program_id, &new_swap.key);
+        let mut new_authority = TestAccount::new(new_authority_key, program_id, vec![]);
+        let mut new_long_margin = TestAccount::new(
+            Processor::find_margin_address(&program_id, &new_swap.key, true).0,
+            system_program::id(),
+            vec![],
+        );
+        let mut new_short_margin = TestAccount::new(
+            Processor::find_margin_address(&program_id, &new_swap.key, false).0,
+            system_program::id(),
+            vec![],
+        );
+        let mut new_insurance_fund =
+            TestAccount::new(Pubkey::new_unique(), spl_token::id(), vec![0; Account::LEN]);
+        let mut rent = TestAccount::new(
+            solana_program::sysvar::rent::id(),
+            solana_program::sysvar::id(),
+            vec![0; Rent::size_of()],
+        );
+        Rent::default().to_account_info(&mut rent.info()).unwrap();
+        let mut system = TestAccount::new(system_program::id(), Pubkey::default(), vec![]);
+        let new_keys = (
+            new_long_margin.key,
+            new_short_margin.key,
+            new_insurance_fund.key,
+        );
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        let mut split = |t: &mut TestSwap,
+                         long_owner: &mut TestAccount,
+                         short_owner: &mut TestAccount,
+                         size: f64| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut new_swap,
+                    &mut t.authority,
+                    &mut new_authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut new_long_margin,
+                    &mut new_short_margin,
+                    &mut new_insurance_fund,
+                    long_owner,
+                    short_owner,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut rent,
+                    &mut system,
+                ],
+                |accounts| Processor::process_split_position(&program_id, true, size, accounts),
+            )
+        };
+
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        long_owner.is_signer = true;
+        // Part of the short side's margin moves, so its owner has to sign as well
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        short_owner.is_signer = true;
+        // Splitting off nothing or the whole side isn't a split
+        for size in [0.0, 1.0, f64::NAN].iter() {
+            assert_eq!(
+                split(&mut t, &mut long_owner, &mut short_owner, *size),
+                Err(PerpetualSwapError::InvalidPositionSize.into())
+            );
+        }
+
+        // A side that's liquidatable before the split still is after it
+        let p = t.perpetual_swap();
+        let mut underwater = p.clone();
+        underwater.position_size = 100.0;
+        underwater.mark_price = 99.0;
+        t.set_perpetual_swap(&underwater);
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 50.0),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+
+        // Clear of liquidation isn't enough, each half has to meet initial margin
+        let mut thin = p.clone();
+        thin.margin_tiers[0].initial_margin_ratio = 20.0;
+        t.set_perpetual_swap(&thin);
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+
+        t.set_perpetual_swap(&p);
+        t.add_collateral();
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Err(PerpetualSwapError::CollateralNotSplittable.into())
+        );
+
+        // A vAMM not holding a side doesn't stop the split, but it stays with the old swap, and
+        // so does whatever the admin queued there
+        let mut p = p;
+        p.vamm_base_reserve = 1_000.0;
+        p.vamm_quote_reserve = 1_000.0;
+        p.vamm_peg_multiplier = 100.0;
+        p.pending_admin = Pubkey::new_unique();
+        p.has_pending_params = true;
+        p.pending_params = p.market_params();
+        p.pending_params_time = 60_000;
+        p.status = MarketStatus::ReduceOnly;
+        t.set_perpetual_swap(&p);
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Ok(())
+        );
+        // The new swap can't be split into again
+        assert_eq!(
+            split(&mut t, &mut long_owner, &mut short_owner, 0.25),
+            Err(PerpetualSwapError::AlreadyInUse.into())
+        );
+
+        let old = t.perpetual_swap();
+        assert_eq!(old.position_size, 0.75);
+        let new = PerpetualSwap::try_from_slice(&new_swap.data).unwrap();
+        assert_eq!(new.position_size, 0.25);
+        assert_eq!(new.authority_bump, new_authority_bump);
+        assert_eq!(
+            (
+                new.long_margin_pubkey,
+                new.short_margin_pubkey,
+                new.insurance_fund_pubkey
+            ),
+            new_keys
+        );
+        assert_eq!(new.long_owner, old.long_owner);
+        assert_eq!(new.short_account_pubkey, old.short_account_pubkey);
+        assert_eq!(new.entry_price, old.entry_price);
+        assert!(new.is_initialized());
+        assert_eq!(
+            (
+                new.vamm_base_reserve,
+                new.vamm_quote_reserve,
+                new.vamm_peg_multiplier
+            ),
+            (0.0, 0.0, 0.0)
+        );
+        assert_eq!(new.pending_admin, Pubkey::default());
+        assert!(!new.has_pending_params);
+        assert_eq!(new.pending_params, MarketParams::default());
+        assert_eq!(new.status, MarketStatus::ReduceOnly);
+        assert_eq!(old.vamm_base_reserve, 1_000.0);
+        assert_eq!(old.pending_admin, p.pending_admin);
+    }
+
+    #[test]
+    pub fn test_tokenized_position() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.is_long_initialized = false;
+        t.set_perpetual_swap(&p);
+        let mut position_mint =
+            TestAccount::new(Pubkey::new_unique(), spl_token::id(), vec![0; Mint::LEN]);
+        let authority_key = t.authority.key;
+        let set_supply = |position_mint: &mut TestAccount, supply: u64| {
+            let mint = Mint {
+                mint_authority: COption::Some(authority_key),
+                supply,
+                is_initialized: true,
+                ..Mint::default()
+            };
+            Mint::pack(mint, &mut position_mint.data).unwrap();
+        };
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut first_position_token =
+            TestAccount::token_account(Pubkey::new_unique(), &position_mint.key, &long_owner.key);
+        let mut clock = TestAccount::clock(0);
+        let mut initialize_long = |t: &mut TestSwap, position_mint: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_account,
+                    &mut t.long_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                    position_mint,
+                    &mut first_position_token,
+                ],
+                |accounts| Processor::process_initialize_side(&program_id, 100, accounts),
+            )
+        };
+
+        // A position token that was already minted could have been handed out twice
+        set_supply(&mut position_mint, 1);
+        assert_eq!(
+            initialize_long(&mut t, &mut position_mint),
+            Err(PerpetualSwapError::InvalidSupply.into())
+        );
+        set_supply(&mut position_mint, 0);
+        assert_eq!(initialize_long(&mut t, &mut position_mint), Ok(()));
+        assert_eq!(t.perpetual_swap().long_position_mint, position_mint.key);
+
+        // The token was sold, so its holder owns the side and the wallet that opened it doesn't
+        let mut holder = TestAccount::signer();
+        let mut position_token =
+            TestAccount::token_account(Pubkey::new_unique(), &position_mint.key, &holder.key);
+        let mut old_position_token =
+            TestAccount::token_account(Pubkey::new_unique(), &position_mint.key, &t.long_owner.key);
+        Account::pack(
+            Account {
+                amount: 0,
+                ..Account::unpack(&old_position_token.data).unwrap()
+            },
+            &mut old_position_token.data,
+        )
+        .unwrap();
+        let mut old_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        old_owner.is_signer = true;
+        let withdraw = |t: &mut TestSwap,
+                        owner: &mut TestAccount,
+                        position_token: &mut TestAccount| {
+            let mut dest = TestAccount::token_account(Pubkey::new_unique(), &t.mint, &owner.key);
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    &mut t.long_margin,
+                    &mut dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                    position_token,
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, 1, accounts),
+            )
+        };
+        assert_eq!(
+            withdraw(&mut t, &mut old_owner, &mut old_position_token),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(
+            withdraw(&mut t, &mut holder, &mut old_position_token),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        let mut collateral_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &holder.key);
+        assert_eq!(
+            withdraw(&mut t, &mut holder, &mut collateral_account),
+            Err(PerpetualSwapError::InvalidMints.into())
+        );
+        assert_eq!(withdraw(&mut t, &mut holder, &mut position_token), Ok(()));
+
+        // It changes hands with the token, not with `TransferPosition`
+        let mut buyer = TestAccount::signer();
+        let mut buyer_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &buyer.key);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    &mut old_owner,
+                    &mut t.long_account,
+                    &mut buyer,
+                    &mut buyer_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| {
+                    Processor::process_transfer_position(&program_id, true, 1.0, 100.0, accounts)
+                },
+            ),
+            Err(PerpetualSwapError::PositionTokenized.into())
+        );
+    }
+
+    #[test]
+    pub fn test_add_collateral_asset_reads_oracle_price() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut collateral_config = TestAccount::new(
+            Pubkey::new_unique(),
+            program_id,
+            vec![0; CollateralConfig::LEN],
+        );
+        let mut mint = TestAccount::mint(Pubkey::new_unique());
+        // 2_500_000 * 10^-6
+        let (mut pyth_product, mut pyth_price) = TestAccount::pyth_oracle(2_500_000, -6);
+        let vault =
+            || TestAccount::new(Pubkey::new_unique(), spl_token::id(), vec![0; Account::LEN]);
+        let (mut long_vault, mut short_vault) = (vault(), vault());
+        let mut rent = TestAccount::new(
+            solana_program::sysvar::rent::id(),
+            solana_program::sysvar::id(),
+            vec![0; Rent::size_of()],
+        );
+        Rent::default().to_account_info(&mut rent.info()).unwrap();
+        let mut add_asset = |t: &mut TestSwap, pyth_price: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut collateral_config,
+                    &mut t.authority,
+                    &mut mint,
+                    &mut pyth_product,
+                    pyth_price,
+                    &mut long_vault,
+                    &mut short_vault,
+                    &mut rent,
+                    &mut t.token_program,
+                    &mut t.admin,
+                ],
+                |accounts| Processor::process_add_collateral_asset(&program_id, 0.5, accounts),
+            )
+        };
+
+        // The price account has to be the one the product lists
+        let (_, mut other_price) = TestAccount::pyth_oracle(2_500_000, -6);
+        assert_eq!(
+            add_asset(&mut t, &mut other_price),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(add_asset(&mut t, &mut pyth_price), Ok(()));
+        let p = t.perpetual_swap();
+        let config = CollateralConfig::try_from_slice(&collateral_config.data).unwrap();
+        assert_eq!(p.collateral_config_pubkey, collateral_config.key);
+        assert_eq!(config.assets().len(), 1);
+        assert!((config.assets()[0].price - 2.5).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn test_deposit_collateral_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut collateral_config, mut long_vault, _short_vault) = t.add_collateral();
+        let collateral_mint = CollateralConfig::try_from_slice(&collateral_config.data)
+            .unwrap()
+            .assets[0]
+            .mint;
+        let mut mint = TestAccount::mint(collateral_mint);
+        let mut deposit = |t: &mut TestSwap, owner: &mut TestAccount, mint: &mut TestAccount| {
+            let mut source =
+                TestAccount::token_account(Pubkey::new_unique(), &collateral_mint, &owner.key);
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut collateral_config,
+                    &mut t.authority,
+                    owner,
+                    &mut source,
+                    &mut long_vault,
+                    &mut t.token_program,
+                    mint,
+                ],
+                |accounts| Processor::process_deposit_collateral(&program_id, 100, accounts),
+            )
+        };
+
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            deposit(&mut t, &mut long_owner, &mut mint),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        // Not even the other side's owner can deposit into the long's vault
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        assert_eq!(
+            deposit(&mut t, &mut short_owner, &mut mint),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        long_owner.is_signer = true;
+        // The transfer is checked against the mint of the vault's asset
+        let mut primary_mint = TestAccount::mint(t.mint);
+        assert_eq!(
+            deposit(&mut t, &mut long_owner, &mut primary_mint),
+            Err(PerpetualSwapError::InvalidMints.into())
+        );
+        assert_eq!(deposit(&mut t, &mut long_owner, &mut mint), Ok(()));
+        assert_eq!(token_transfer_mints(), vec![(collateral_mint, 6)]);
+    }
+
+    #[test]
+    pub fn test_withdraw_collateral_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut collateral_config, _long_vault, mut short_vault) = t.add_collateral();
+        let collateral_mint = CollateralConfig::try_from_slice(&collateral_config.data)
+            .unwrap()
+            .assets[0]
+            .mint;
+        let mut mint = TestAccount::mint(collateral_mint);
+        let mut withdraw = |t: &mut TestSwap, owner: &mut TestAccount| {
+            let mut dest =
+                TestAccount::token_account(Pubkey::new_unique(), &collateral_mint, &owner.key);
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut collateral_config,
+                    &mut t.authority,
+                    owner,
+                    &mut t.short_account,
+                    &mut t.short_margin,
+                    &mut dest,
+                    &mut t.token_program,
+                    &mut mint,
+                    &mut TestAccount::clock(0),
+                    &mut short_vault,
+                ],
+                |accounts| Processor::process_withdraw_collateral(&program_id, 0, 100, accounts),
+            )
+        };
+
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            withdraw(&mut t, &mut short_owner),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            withdraw(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        short_owner.is_signer = true;
+        assert_eq!(withdraw(&mut t, &mut short_owner), Ok(()));
+    }
+
+    #[test]
+    pub fn test_non_primary_collateral_counts_toward_margin() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut collateral_config, mut long_vault, mut short_vault) = t.add_collateral();
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.position_size = 100.0;
+        t.set_perpetual_swap(&p);
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut long_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &long_owner.key);
+
+        // The long's 1,000 of margin just meets its initial margin of 1,000, and its 1,000 units
+        // of collateral are worth 500 after the haircut
+        let mut withdraw = |t: &mut TestSwap, amount: u64| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut long_dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                    &mut collateral_config,
+                    &mut long_vault,
+                ],
+                |accounts| Processor::process_withdraw_from_margin(&program_id, amount, accounts),
+            )
+        };
+        assert_eq!(withdraw(&mut t, 500), Ok(()));
+        assert_eq!(
+            withdraw(&mut t, 501),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+
+        // `GetHealth` counts it too, but only the margin itself is withdrawable
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut collateral_config,
+                    &mut long_vault,
+                    &mut short_vault,
+                ],
+                |accounts| Processor::process_get_health(&program_id, accounts),
+            ),
+            Ok(())
+        );
+        LOGGED.with(|logged| {
+            assert!(logged.borrow()[0].starts_with("Long health: equity 1500,"));
+        });
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut collateral_config,
+                    &mut short_vault,
+                    &mut long_vault,
+                ],
+                |accounts| Processor::process_get_health(&program_id, accounts),
+            ),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+
+        // Down 500 at 95, the long is under its maintenance margin of 950 without the
+        // collateral, but can still close with it
+        let mut p = t.perpetual_swap();
+        p.mark_price = 95.0;
+        t.set_perpetual_swap(&p);
+        let mut asset_mint = TestAccount::mint(
+            CollateralConfig::try_from_slice(&collateral_config.data)
+                .unwrap()
+                .assets[0]
+                .mint,
+        );
+        let mut collateral_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &asset_mint.key, &long_owner.key);
+        let mut close = |t: &mut TestSwap, long_vault: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut long_dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                    &mut collateral_config,
+                    long_vault,
+                    &mut collateral_dest,
+                    &mut asset_mint,
+                ],
+                |accounts| Processor::process_close_position(&program_id, accounts),
+            )
+        };
+        assert_eq!(close(&mut t, &mut long_vault), Ok(()));
+
+        // The loss is realized out of the margin, so the collateral can't cover a loss beyond it
+        p.mark_price = 89.0;
+        t.set_perpetual_swap(&p);
+        Account::pack(
+            Account {
+                amount: 40_000,
+                ..Account::unpack(&long_vault.data).unwrap()
+            },
+            &mut long_vault.data,
+        )
+        .unwrap();
+        assert_eq!(
+            close(&mut t, &mut long_vault),
+            Err(PerpetualSwapError::InsufficientFunds.into())
+        );
+    }
+
+    /// A cross-margin account owned by `owner` with an empty, funded vault
+    #[cfg(test)]
+    fn test_cross_margin(
+        program_id: &Pubkey,
+        mint: &Pubkey,
+        owner: &Pubkey,
+    ) -> (TestAccount, TestAccount, TestAccount) {
+        let cross_margin_key = Pubkey::new_unique();
+        let (authority_key, authority_bump) =
+            Processor::find_authority_address(program_id, &cross_margin_key);
+        let vault = TestAccount::token_account(Pubkey::new_unique(), mint, &authority_key);
+        let mut cross_margin =
+            CrossMarginAccount::try_from_slice(&[0; CrossMarginAccount::LEN]).unwrap();
+        cross_margin.is_initialized = true;
+        cross_margin.authority_bump = authority_bump;
+        cross_margin.owner = *owner;
+        cross_margin.token_program_id = spl_token::id();
+        cross_margin.vault_pubkey = vault.key;
+        cross_margin.mint = *mint;
+        cross_margin.decimals = 6;
+        (
+            TestAccount::new(
+                cross_margin_key,
+                *program_id,
+                cross_margin.try_to_vec().unwrap(),
+            ),
+            TestAccount::new(authority_key, *program_id, vec![]),
+            vault,
+        )
+    }
+
+    #[test]
+    pub fn test_deposit_to_cross_margin_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut cross_margin, mut authority, mut vault) =
+            test_cross_margin(&program_id, &t.mint, &t.long_owner.key);
+        let mut token_program = TestAccount::new(spl_token::id(), Pubkey::default(), vec![]);
+        let mut mint = TestAccount::mint(t.mint);
+        let mut deposit = |owner: &mut TestAccount, source: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut cross_margin,
+                    &mut authority,
+                    owner,
+                    source,
+                    &mut vault,
+                    &mut token_program,
+                    &mut mint,
+                ],
+                |accounts| Processor::process_deposit_to_cross_margin(&program_id, 100, accounts),
+            )
+        };
+
+        let mut owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            deposit(&mut owner, &mut t.long_account),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            deposit(&mut TestAccount::signer(), &mut t.long_account),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        owner.is_signer = true;
+        assert_eq!(
+            deposit(&mut owner, &mut t.short_account),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(deposit(&mut owner, &mut t.long_account), Ok(()));
+        assert_eq!(token_transfer_mints(), vec![(t.mint, 6)]);
+    }
+
+    #[test]
+    pub fn test_withdraw_from_cross_margin_requires_owner_signature() {
+        let t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut cross_margin, mut authority, mut vault) =
+            test_cross_margin(&program_id, &t.mint, &t.long_owner.key);
+        let mut dest = TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.long_owner.key);
+        let mut token_program = TestAccount::new(spl_token::id(), Pubkey::default(), vec![]);
+        let mut mint = TestAccount::mint(t.mint);
+        let mut withdraw = |owner: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut cross_margin,
+                    &mut authority,
+                    owner,
+                    &mut vault,
+                    &mut dest,
+                    &mut token_program,
+                    &mut mint,
+                ],
+                |accounts| {
+                    Processor::process_withdraw_from_cross_margin(&program_id, 100, accounts)
+                },
+            )
+        };
+
+        let mut owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            withdraw(&mut owner),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            withdraw(&mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        owner.is_signer = true;
+        assert_eq!(withdraw(&mut owner), Ok(()));
+    }
+
+    #[test]
+    pub fn test_cross_margin_health_skips_positions_the_vault_no_longer_holds() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut cross_margin, mut authority, mut vault) =
+            test_cross_margin(&program_id, &t.mint, &t.long_owner.key);
+        let mut account = CrossMarginAccount::try_from_slice(&cross_margin.data).unwrap();
+        account.num_markets = 2;
+        account.markets[0] = CrossMarginMarket {
+            swap_pubkey: t.swap.key,
+            is_long: true,
+        };
+
+        // The vault's long in `t` is 2,000 up but has since been transferred away, while its short
+        // in `other` is 500 down
+        let mut p = t.perpetual_swap();
+        p.position_size = 100.0;
+        p.mark_price = 120.0;
+        t.set_perpetual_swap(&p);
+        let mut other = TestSwap::new();
+        other.swap.owner = program_id;
+        let mut p = other.perpetual_swap();
+        p.short_account_pubkey = vault.key;
+        p.position_size = 100.0;
+        p.mark_price = 105.0;
+        other.set_perpetual_swap(&p);
+        account.markets[1] = CrossMarginMarket {
+            swap_pubkey: other.swap.key,
+            is_long: false,
+        };
+        cross_margin.data = account.try_to_vec().unwrap();
+
+        let mut owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        owner.is_signer = true;
+        let mut dest = TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.long_owner.key);
+        let mut withdraw = |t: &mut TestSwap, other: &mut TestSwap, amount: u64| {
+            process(
+                &mut [
+                    &mut cross_margin,
+                    &mut authority,
+                    &mut owner,
+                    &mut vault,
+                    &mut dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    &mut other.swap,
+                    &mut other.short_margin,
+                ],
+                |accounts| {
+                    Processor::process_withdraw_from_cross_margin(&program_id, amount, accounts)
+                },
+            )
+        };
+
+        // Only the short backs the account: 400 left in the vault plus its 500 of equity is less
+        // than its 1,050 maintenance margin
+        assert_eq!(
+            withdraw(&mut t, &mut other, 600),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+        assert_eq!(withdraw(&mut t, &mut other, 400), Ok(()));
+
+        // Once the vault holds the long again, its profit counts
+        let mut p = t.perpetual_swap();
+        p.long_account_pubkey = account.vault_pubkey;
+        t.set_perpetual_swap(&p);
+        assert_eq!(withdraw(&mut t, &mut other, 600), Ok(()));
+
+        // Nothing can be withdrawn while any of the account's markets is paused
+        let mut p = other.perpetual_swap();
+        p.status = MarketStatus::Paused;
+        other.set_perpetual_swap(&p);
+        assert_eq!(
+            withdraw(&mut t, &mut other, 600),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+
+        // A closed swap no longer counts, whether it was only zeroed or already reclaimed
+        other.swap.data = vec![0; PerpetualSwap::LEN];
+        other.swap.lamports = 0;
+        assert_eq!(withdraw(&mut t, &mut other, 1_000), Ok(()));
+        other.swap.owner = system_program::id();
+        other.swap.data = vec![];
+        assert_eq!(withdraw(&mut t, &mut other, 1_000), Ok(()));
+    }
+
+    #[test]
+    pub fn test_remove_cross_margin_market() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut cross_margin, _authority, vault) =
+            test_cross_margin(&program_id, &t.mint, &t.long_owner.key);
+        let mut account = CrossMarginAccount::try_from_slice(&cross_margin.data).unwrap();
+        let mut other = TestSwap::new();
+        other.swap.owner = program_id;
+        account.num_markets = 2;
+        account.markets[0] = CrossMarginMarket {
+            swap_pubkey: t.swap.key,
+            is_long: true,
+        };
+        account.markets[1] = CrossMarginMarket {
+            swap_pubkey: other.swap.key,
+            is_long: false,
+        };
+        cross_margin.data = account.try_to_vec().unwrap();
+        let mut p = t.perpetual_swap();
+        p.long_account_pubkey = vault.key;
+        t.set_perpetual_swap(&p);
+
+        let mut owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        let remove_market =
+            |cross_margin: &mut TestAccount, owner: &mut TestAccount, swap: &mut TestAccount| {
+                process(&mut [cross_margin, owner, swap], |accounts| {
+                    Processor::process_remove_cross_margin_market(&program_id, accounts)
+                })
+            };
+
+        assert_eq!(
+            remove_market(&mut cross_margin, &mut owner, &mut t.swap),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        owner.is_signer = true;
+        // The vault still backs the long
+        assert_eq!(
+            remove_market(&mut cross_margin, &mut owner, &mut t.swap),
+            Err(PerpetualSwapError::PositionStillOpen.into())
+        );
+        assert_eq!(
+            remove_market(
+                &mut cross_margin,
+                &mut owner,
+                &mut TestAccount::new(Pubkey::new_unique(), program_id, vec![])
+            ),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+
+        // Once the long is closed, its slot is freed and the other market moves up
+        p.is_long_initialized = false;
+        t.set_perpetual_swap(&p);
+        assert_eq!(
+            remove_market(&mut cross_margin, &mut owner, &mut t.swap),
+            Ok(())
+        );
+        let account = CrossMarginAccount::try_from_slice(&cross_margin.data).unwrap();
+        assert_eq!(account.num_markets, 1);
+        assert_eq!(account.markets[0].swap_pubkey, other.swap.key);
+        assert_eq!(account.markets[1], CrossMarginMarket::default());
+
+        // The short in `other` was never the vault's, and a closed swap can always be dropped
+        other.swap.data = vec![0; PerpetualSwap::LEN];
+        other.swap.lamports = 0;
+        assert_eq!(
+            remove_market(&mut cross_margin, &mut owner, &mut other.swap),
+            Ok(())
+        );
+        let account = CrossMarginAccount::try_from_slice(&cross_margin.data).unwrap();
+        assert_eq!(account.num_markets, 0);
+    }
+
+    #[test]
+    pub fn test_add_cross_margin_market_requires_owner_signature() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut cross_margin, _authority, _vault) =
+            test_cross_margin(&program_id, &t.mint, &t.long_owner.key);
+        let mut add_market = |t: &mut TestSwap, owner: &mut TestAccount| {
+            process(
+                &mut [&mut cross_margin, owner, &mut t.swap, &mut t.long_account],
+                |accounts| Processor::process_add_cross_margin_market(&program_id, accounts),
+            )
+        };
+
+        let mut owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        assert_eq!(
+            add_market(&mut t, &mut owner),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            add_market(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+
+        // Holding the token account isn't enough once the position belongs to another wallet
+        let mut p = t.perpetual_swap();
+        p.long_owner = Pubkey::new_unique();
+        t.set_perpetual_swap(&p);
+        owner.is_signer = true;
+        assert_eq!(
+            add_market(&mut t, &mut owner),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+
+        // Nor can a position margined in another mint than the account's vault
+        p.long_owner = t.long_owner.key;
+        p.collateral_mint = Pubkey::new_unique();
+        t.set_perpetual_swap(&p);
+        assert_eq!(
+            add_market(&mut t, &mut owner),
+            Err(PerpetualSwapError::InvalidMints.into())
+        );
+    }
+
+    #[cfg(test)]
+    fn update_market_params(
+        t: &mut TestSwap,
+        admin: &mut TestAccount,
+        minimum_margin: f64,
+        now: i64,
+    ) -> ProgramResult {
+        let program_id = t.program_id;
+        let mut clock = TestAccount::clock(now);
+        process(&mut [&mut t.swap, admin, &mut clock], |accounts| {
+            Processor::process_update_market_params(
+                &program_id,
+                0.01,
+                minimum_margin,
+                0.05,
+                3_600_000,
+                60_000,
+                0.1,
+                accounts,
+            )
+        })
+    }
+
+    #[cfg(test)]
+    fn apply_market_params(t: &mut TestSwap, now: i64) -> ProgramResult {
+        let program_id = t.program_id;
+        let mut clock = TestAccount::clock(now);
+        process(&mut [&mut t.swap, &mut clock], |accounts| {
+            Processor::process_apply_market_params(&program_id, accounts)
+        })
+    }
+
+    #[test]
+    pub fn test_negative_equity_settled_from_insurance_fund() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.position_size = 100.0;
+        p.mark_price = 85.0;
+        t.set_perpetual_swap(&p);
+        let mut liquidator = TestAccount::signer();
+        let mut liquidator_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &liquidator.key);
+        let liquidator_account_key = liquidator_account.key;
+        let mut liquidate = |t: &mut TestSwap, insurance_fund: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut liquidator,
+                    &mut t.long_margin,
+                    &mut liquidator_account,
+                    insurance_fund,
+                    &mut t.short_margin,
+                    &mut t.short_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_try_to_liquidate(&program_id, 900, accounts),
+            )
+        };
+
+        // The long's 1,000 of margin is down 1,500, so it is 500 short of paying the short. The
+        // insurance fund makes up the difference before the liquidator takes over the long.
+        assert_eq!(liquidate(&mut t, &mut insurance_fund), Ok(()));
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.long_margin.key, t.short_margin.key, 1_000),
+                (insurance_fund.key, t.short_margin.key, 500),
+                (liquidator_account_key, t.long_margin.key, 900),
+            ]
+        );
+        let p = t.perpetual_swap();
+        assert_eq!(p.entry_price, 85.0);
+        assert_eq!(p.long_account_pubkey, liquidator_account_key);
+
+        // A fund that can't cover the whole deficit pays what it has, and the rest of the short's
+        // profit is written off
+        let mut p = t.perpetual_swap();
+        p.entry_price = 100.0;
+        p.liquidation_mode = LiquidationMode::SocializedLoss;
+        t.set_perpetual_swap(&p);
+        Account::pack(
+            Account {
+                amount: 200,
+                ..Account::unpack(&insurance_fund.data).unwrap()
+            },
+            &mut insurance_fund.data,
+        )
+        .unwrap();
+        assert_eq!(liquidate(&mut t, &mut insurance_fund), Ok(()));
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.long_margin.key, t.short_margin.key, 1_000),
+                (insurance_fund.key, t.short_margin.key, 200),
+                (liquidator_account_key, t.long_margin.key, 900),
+            ]
+        );
+
+        // The vAMM is never force-closed, since that would leave its reserves skewed, so its
+        // loss is socialized even under auto-deleveraging
+        let mut p = t.perpetual_swap();
+        p.entry_price = 100.0;
+        p.liquidation_mode = LiquidationMode::AutoDeleverage;
+        p.vamm_base_reserve = 1_100.0;
+        p.vamm_quote_reserve = 1_000_000.0 / 1_100.0;
+        p.vamm_peg_multiplier = 100.0;
+        p.vamm_side = VammSide::Short;
+        t.set_perpetual_swap(&p);
+        assert_eq!(liquidate(&mut t, &mut insurance_fund), Ok(()));
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.long_margin.key, t.short_margin.key, 1_000),
+                (insurance_fund.key, t.short_margin.key, 200),
+                (liquidator_account_key, t.long_margin.key, 900),
+            ]
+        );
+        let p = t.perpetual_swap();
+        assert_eq!(p.vamm_side, VammSide::Short);
+        assert!(p.is_short_initialized);
+        assert_eq!(p.vamm_base_reserve, 1_100.0);
+    }
+
+    #[test]
+    pub fn test_auto_deleverage_when_insurance_fund_is_short() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let (mut collateral_config, mut long_vault, mut short_vault) = t.add_collateral();
+        let asset_mint = CollateralConfig::try_from_slice(&collateral_config.data)
+            .unwrap()
+            .assets[0]
+            .mint;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        Account::pack(
+            Account {
+                amount: 200,
+                ..Account::unpack(&insurance_fund.data).unwrap()
+            },
+            &mut insurance_fund.data,
+        )
+        .unwrap();
+        // The long has no non-primary collateral left for the liquidator to buy
+        Account::pack(
+            Account {
+                amount: 0,
+                ..Account::unpack(&long_vault.data).unwrap()
+            },
+            &mut long_vault.data,
+        )
+        .unwrap();
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.liquidation_mode = LiquidationMode::AutoDeleverage;
+        p.position_size = 100.0;
+        p.mark_price = 85.0;
+        t.set_perpetual_swap(&p);
+        let mut liquidator = TestAccount::signer();
+        let mut liquidator_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &liquidator.key);
+        let mut liquidator_collateral =
+            TestAccount::token_account(Pubkey::new_unique(), &asset_mint, &liquidator.key);
+        let mut counterparty_collateral =
+            TestAccount::token_account(Pubkey::new_unique(), &asset_mint, &t.short_owner.key);
+        let mut asset_mint_account = TestAccount::mint(asset_mint);
+        let mut asset_mint_copy = TestAccount::mint(asset_mint);
+
+        // The long's 1,000 of margin is down 1,500 and the fund only holds 200, so the short is
+        // force-closed at the long's bankruptcy price of 90 instead. It gets the long's margin
+        // on top of its own, and its non-primary collateral back.
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut liquidator,
+                    &mut t.long_margin,
+                    &mut liquidator_account,
+                    &mut insurance_fund,
+                    &mut t.short_margin,
+                    &mut t.short_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut collateral_config,
+                    &mut long_vault,
+                    &mut liquidator_collateral,
+                    &mut asset_mint_account,
+                    &mut short_vault,
+                    &mut counterparty_collateral,
+                    &mut asset_mint_copy,
+                ],
+                |accounts| Processor::process_try_to_liquidate(&program_id, 900, accounts),
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.long_margin.key, t.short_margin.key, 1_000),
+                (t.short_margin.key, t.short_account.key, 2_000),
+                (short_vault.key, counterparty_collateral.key, 1_000),
+            ]
+        );
+        // The short made 1,500 on 2,500 of equity and 8,500 of notional
+        assert!(emitted(&PerpetualSwapEvent::AutoDeleveraged {
+            swap: t.swap.key,
+            account: t.short_account.key,
+            is_long: false,
+            bankruptcy_price: 90.0,
+            mark_price: 85.0,
+            realized_pnl: 1_000,
+            priority: 5_100.0,
+        }));
+        let p = t.perpetual_swap();
+        assert!(!p.is_long_initialized && !p.is_short_initialized);
+        assert_eq!(p.entry_price, 0.0);
+    }
+
+    #[test]
+    pub fn test_liquidation_bounty() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.position_size = 100.0;
+        p.mark_price = 95.0;
+        t.set_perpetual_swap(&p);
+        let mut liquidator = TestAccount::signer();
+        let mut liquidator_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &liquidator.key);
+        Account::pack(
+            Account {
+                amount: 2_000,
+                ..Account::unpack(&liquidator_account.data).unwrap()
+            },
+            &mut liquidator_account.data,
+        )
+        .unwrap();
+
+        // The long's equity of 500 is under its maintenance margin of 950. The liquidator is paid
+        // 5% of the 1,000 of margin and the rest goes to the insurance fund.
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut liquidator,
+                    &mut t.long_margin,
+                    &mut liquidator_account,
+                    &mut insurance_fund,
+                    &mut t.short_margin,
+                    &mut t.short_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_try_to_liquidate(&program_id, 1_500, accounts),
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.long_margin.key, liquidator_account.key, 50),
+                (t.long_margin.key, insurance_fund.key, 950),
+                (liquidator_account.key, t.long_margin.key, 1_500),
+            ]
+        );
+        assert_eq!(
+            t.perpetual_swap().long_account_pubkey,
+            liquidator_account.key
+        );
+    }
+
+    #[test]
+    pub fn test_crank_reward_capped_per_interval() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut fee_vault =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut cranker_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &Pubkey::new_unique());
+        let mut p = t.perpetual_swap();
+        p.fee_vault_pubkey = fee_vault.key;
+        p.crank_reward = 30;
+        p.crank_reward_cap = 50;
+        p.crank_reward_interval = 10_800_000;
+        t.set_perpetual_swap(&p);
+        let fee_vault_key = fee_vault.key;
+        let mut crank = |t: &mut TestSwap,
+                         fee_vault: &mut TestAccount,
+                         now: i64|
+         -> Result<Vec<u64>, ProgramError> {
+            let mut clock = TestAccount::clock(now);
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                    fee_vault,
+                    &mut cranker_account,
+                ],
+                |accounts| Processor::process_transfer_funds(&program_id, accounts),
+            )?;
+            Ok(token_transfers()
+                .into_iter()
+                .filter(|(source, _, _)| *source == fee_vault_key)
+                .map(|(_, _, amount)| amount)
+                .collect())
+        };
+
+        // The second crank in the interval is paid only what's left under the cap
+        assert_eq!(crank(&mut t, &mut fee_vault, 3_600), Ok(vec![30]));
+        assert_eq!(crank(&mut t, &mut fee_vault, 7_200), Ok(vec![20]));
+        let p = t.perpetual_swap();
+        assert_eq!(p.crank_rewards_paid, 50);
+        assert_eq!(p.crank_reward_interval_start, 0);
+
+        // A new interval resets the cap, but the reward never exceeds the fee vault's balance
+        Account::pack(
+            Account {
+                amount: 10,
+                ..Account::unpack(&fee_vault.data).unwrap()
+            },
+            &mut fee_vault.data,
+        )
+        .unwrap();
+        assert_eq!(crank(&mut t, &mut fee_vault, 10_800), Ok(vec![10]));
+        let p = t.perpetual_swap();
+        assert_eq!(p.crank_rewards_paid, 10);
+        assert_eq!(p.crank_reward_interval_start, 10_800_000);
+
+        // Of 1,000 in the vault, 990 are fees, so only 10 of the reward come out of the rest and
+        // the other 20 are taken off the fees the admin can sweep
+        Account::pack(
+            Account {
+                amount: 1_000,
+                ..Account::unpack(&fee_vault.data).unwrap()
+            },
+            &mut fee_vault.data,
+        )
+        .unwrap();
+        let mut p = t.perpetual_swap();
+        p.unswept_fees = 990;
+        t.set_perpetual_swap(&p);
+        assert_eq!(crank(&mut t, &mut fee_vault, 21_600), Ok(vec![30]));
+        assert_eq!(t.perpetual_swap().unswept_fees, 970);
+    }
+
+    #[test]
+    pub fn test_update_market_params_timelock() {
+        let mut t = TestSwap::new();
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        assert_eq!(
+            update_market_params(&mut t, &mut admin, 0.05, 1_000),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            update_market_params(&mut t, &mut TestAccount::signer(), 0.05, 1_000),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        admin.is_signer = true;
+        assert_eq!(
+            update_market_params(&mut t, &mut admin, 1.5, 1_000),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        // A margin floor of 1 would leave a long with no liquidation price
+        assert_eq!(
+            update_market_params(&mut t, &mut admin, 1.0, 1_000),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+
+        // Lowering the margin floor applies right away
+        assert_eq!(
+            update_market_params(&mut t, &mut admin, 0.05, 1_000),
+            Ok(())
+        );
+        let p = t.perpetual_swap();
+        assert_eq!(p.minimum_margin, 0.05);
+        assert_eq!(p.funding_rate, 0.01);
+        assert!(!p.has_pending_params);
+
+        // Raising it waits out the timelock, which anyone can then apply
+        assert_eq!(update_market_params(&mut t, &mut admin, 0.2, 1_000), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.minimum_margin, 0.05);
+        assert!(p.has_pending_params);
+        assert_eq!(p.pending_params_time, 1_000_000 + 60_000);
+        assert_eq!(
+            apply_market_params(&mut t, 1_059),
+            Err(PerpetualSwapError::TimelockNotElapsed.into())
+        );
+        assert_eq!(apply_market_params(&mut t, 1_060), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.minimum_margin, 0.2);
+        assert!(!p.has_pending_params);
+        assert_eq!(
+            apply_market_params(&mut t, 1_060),
+            Err(PerpetualSwapError::NoPendingChange.into())
+        );
+
+        // Re-sending the params in effect cancels a queued change
+        assert_eq!(update_market_params(&mut t, &mut admin, 0.3, 2_000), Ok(()));
+        assert_eq!(update_market_params(&mut t, &mut admin, 0.2, 2_000), Ok(()));
+        assert!(!t.perpetual_swap().has_pending_params);
+    }
+
+    #[test]
+    pub fn test_stricter_margin_tiers_are_timelocked() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let update_margin_tiers = |t: &mut TestSwap, maintenance_margin_ratio: f64| {
+            let margin_tiers = [MarginTier {
+                max_notional: f64::MAX,
+                initial_margin_ratio: 0.1,
+                maintenance_margin_ratio,
+                max_leverage: 10.0,
+            }];
+            let mut clock = TestAccount::clock(1_000);
+            process(&mut [&mut t.swap, &mut t.admin, &mut clock], |accounts| {
+                Processor::process_update_margin_tiers(&program_id, &margin_tiers, accounts)
+            })
+        };
+
+        let mut p = t.perpetual_swap();
+        p.minimum_margin = 0.01;
+        t.set_perpetual_swap(&p);
+        assert_eq!(update_margin_tiers(&mut t, 0.05), Ok(()));
+        assert_eq!(
+            t.perpetual_swap().margin_tiers[0].maintenance_margin_ratio,
+            0.05
+        );
+        assert_eq!(update_margin_tiers(&mut t, 0.08), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.margin_tiers[0].maintenance_margin_ratio, 0.05);
+        assert_eq!(
+            p.pending_params.margin_tiers[0].maintenance_margin_ratio,
+            0.08
+        );
+    }
+
+    #[test]
+    pub fn test_admin_transfer() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut new_admin = TestAccount::wallet();
+        let propose = |t: &mut TestSwap, admin: &mut TestAccount, new_admin: &mut TestAccount| {
+            process(&mut [&mut t.swap, admin, new_admin], |accounts| {
+                Processor::process_propose_admin(&program_id, accounts)
+            })
+        };
+        let accept = |t: &mut TestSwap, new_admin: &mut TestAccount| {
+            process(&mut [&mut t.swap, new_admin], |accounts| {
+                Processor::process_accept_admin(&program_id, accounts)
+            })
+        };
+
+        assert_eq!(
+            accept(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::NoPendingChange.into())
+        );
+        assert_eq!(
+            propose(&mut t, &mut TestAccount::signer(), &mut new_admin),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        assert_eq!(propose(&mut t, &mut admin, &mut new_admin), Ok(()));
+        // Proposing alone hands nothing over
+        assert_eq!(t.perpetual_swap().admin, admin.key);
+
+        assert_eq!(
+            accept(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        assert_eq!(
+            accept(&mut t, &mut new_admin),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        new_admin.is_signer = true;
+        assert_eq!(accept(&mut t, &mut new_admin), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.admin, new_admin.key);
+        assert_eq!(p.pending_admin, Pubkey::default());
+
+        assert_eq!(
+            update_market_params(&mut t, &mut admin, 0.05, 1_000),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        assert_eq!(
+            update_market_params(&mut t, &mut new_admin, 0.05, 1_000),
+            Ok(())
+        );
+    }
+
+    #[cfg(test)]
+    fn set_market_status(
+        t: &mut TestSwap,
+        admin: &mut TestAccount,
+        status: MarketStatus,
+    ) -> ProgramResult {
+        let program_id = t.program_id;
+        process(&mut [&mut t.swap, admin], |accounts| {
+            Processor::process_set_market_status(&program_id, status, accounts)
+        })
+    }
+
+    #[test]
+    pub fn test_market_status() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        // Instructions go through `Processor::process` so the status check runs first
+        let mut deposit = |t: &mut TestSwap| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut short_owner,
+                    &mut t.short_account,
+                    &mut t.short_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| {
+                    Processor::process(&program_id, accounts, &[2, 100, 0, 0, 0, 0, 0, 0, 0])
+                },
+            )
+        };
+        let initialize_side = |t: &mut TestSwap| {
+            process(&mut [&mut t.swap], |accounts| {
+                Processor::process(&program_id, accounts, &[1, 100, 0, 0, 0, 0, 0, 0, 0])
+            })
+        };
+        let withdraw = |t: &mut TestSwap| {
+            process(&mut [&mut t.swap], |accounts| {
+                Processor::process(&program_id, accounts, &[3, 100, 0, 0, 0, 0, 0, 0, 0])
+            })
+        };
+        let sweep_fees = |t: &mut TestSwap| {
+            process(&mut [&mut t.swap], |accounts| {
+                Processor::process(&program_id, accounts, &[33])
+            })
+        };
+
+        assert_eq!(
+            set_market_status(&mut t, &mut TestAccount::signer(), MarketStatus::Paused),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+
+        assert_eq!(
+            set_market_status(&mut t, &mut admin, MarketStatus::ReduceOnly),
+            Ok(())
+        );
+        assert_eq!(
+            initialize_side(&mut t),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        assert_eq!(deposit(&mut t), Ok(()));
+
+        assert_eq!(
+            set_market_status(&mut t, &mut admin, MarketStatus::Paused),
+            Ok(())
+        );
+        assert_eq!(deposit(&mut t), Ok(()));
+        assert_eq!(
+            withdraw(&mut t),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        assert_eq!(
+            sweep_fees(&mut t),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+
+        // Only `SettleMarket` can settle, since it records the settlement price
+        assert_eq!(
+            set_market_status(&mut t, &mut admin, MarketStatus::Settled),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        let mut p = t.perpetual_swap();
+        p.status = MarketStatus::Settled;
+        t.set_perpetual_swap(&p);
+        assert_eq!(
+            deposit(&mut t),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        // Withdrawals get past the status check and fail on the missing accounts instead
+        assert_eq!(withdraw(&mut t), Err(ProgramError::NotEnoughAccountKeys));
+        assert_eq!(sweep_fees(&mut t), Err(ProgramError::NotEnoughAccountKeys));
+        // Settling can't be undone
+        assert_eq!(
+            set_market_status(&mut t, &mut admin, MarketStatus::Active),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        assert_eq!(t.perpetual_swap().status, MarketStatus::Settled);
+    }
+
+    #[test]
+    pub fn test_settle_market_and_redeem() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let window = PerpetualSwap::INDEX_TWAP_WINDOW as i64;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut admin_token_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.position_size = 10.0;
+        p.mark_price = 120.0;
+        // The index is 100 for half a window, then 110 for the next half
+        Processor::update_index_price(&mut p, 100.0, 1_000_000);
+        Processor::update_index_price(&mut p, 110.0, 1_000_000 + window as u128 / 2);
+        t.set_perpetual_swap(&p);
+        let mut clock = TestAccount::clock((1_000_000 + window) / 1000);
+        let mut settle = |t: &mut TestSwap, admin: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_settle_market(&program_id, accounts),
+            )
+        };
+        let redeem = |t: &mut TestSwap, is_long: bool, owner: &mut TestAccount| {
+            let (margin, dest) = if is_long {
+                (&mut t.long_margin, &mut t.long_account)
+            } else {
+                (&mut t.short_margin, &mut t.short_account)
+            };
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    margin,
+                    dest,
+                    &mut t.admin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_redeem_settled(&program_id, accounts),
+            )
+        };
+        let set_amount = |account: &mut TestAccount, amount: u64| {
+            Account::pack(
+                Account {
+                    amount,
+                    ..Account::unpack(&account.data).unwrap()
+                },
+                &mut account.data,
+            )
+            .unwrap();
+        };
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+
+        assert_eq!(
+            redeem(&mut t, true, &mut long_owner),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+        assert_eq!(
+            settle(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        assert_eq!(settle(&mut t, &mut admin), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.status, MarketStatus::Settled);
+        // The settlement price is the index averaged over time, not the mark or the last index
+        assert_eq!(p.settlement_price, 105.0);
+        assert_eq!(p.mark_price, 105.0);
+        // The long's PnL of 50 is realized into the margin accounts, and the insurance fund stays
+        assert_eq!(p.entry_price, 105.0);
+        assert_eq!(
+            token_transfers(),
+            vec![(t.short_margin.key, t.long_margin.key, 50)]
+        );
+        set_amount(&mut t.long_margin, 1_050);
+        set_amount(&mut t.short_margin, 950);
+        assert_eq!(
+            settle(&mut t, &mut admin),
+            Err(PerpetualSwapError::InvalidMarketStatus.into())
+        );
+
+        assert_eq!(
+            redeem(&mut t, true, &mut short_owner),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(redeem(&mut t, true, &mut long_owner), Ok(()));
+        assert_eq!(
+            token_transfers(),
+            vec![(t.long_margin.key, t.long_account.key, 1_050)]
+        );
+        assert!(!t.perpetual_swap().is_long_initialized);
+        assert_eq!(
+            redeem(&mut t, true, &mut long_owner),
+            Err(PerpetualSwapError::AccountNotInitialized.into())
+        );
+        assert_eq!(redeem(&mut t, false, &mut short_owner), Ok(()));
+        assert_eq!(
+            token_transfers(),
+            vec![(t.short_margin.key, t.short_account.key, 950)]
+        );
+        let p = t.perpetual_swap();
+        assert!(!p.is_long_initialized && !p.is_short_initialized);
+
+        // The admin then closes the swap, skipping the margin accounts the sides closed, and gets
+        // what's left in the insurance fund
+        t.long_margin.data = vec![];
+        t.short_margin.data = vec![];
+        let mut receiver = TestAccount::new(Pubkey::new_unique(), system_program::id(), vec![]);
+        let receiver_lamports = receiver.lamports;
+        let swap_lamports = t.swap.lamports;
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut receiver,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_close_swap(&program_id, accounts),
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            token_transfers(),
+            vec![(insurance_fund.key, admin_token_account.key, 1_000)]
+        );
+        assert_eq!(t.swap.lamports, 0);
+        assert_eq!(receiver.lamports, receiver_lamports + swap_lamports);
+        assert!(t.swap.data.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    pub fn test_settle_market_with_vamm() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let window = PerpetualSwap::INDEX_TWAP_WINDOW as i64;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut admin_token_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        // The long bought 10 of the vAMM's 1,000 units, so the vAMM holds the short
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.position_size = 10.0;
+        p.vamm_base_reserve = 990.0;
+        p.vamm_quote_reserve = 1_000_000.0 / 990.0;
+        p.vamm_peg_multiplier = 100.0;
+        p.vamm_side = VammSide::Short;
+        p.short_owner = t.authority.key;
+        p.short_account_pubkey = insurance_fund.key;
+        Processor::update_index_price(&mut p, 100.0, 1_000_000);
+        Processor::update_index_price(&mut p, 110.0, 1_000_000 + window as u128 / 2);
+        t.set_perpetual_swap(&p);
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock((1_000_000 + window) / 1000),
+                ],
+                |accounts| Processor::process_settle_market(&program_id, accounts),
+            ),
+            Ok(())
+        );
+
+        // The long's PnL of 50 is realized, then the vAMM's margin goes back to the insurance
+        // fund and its reserves take the long's trade back
+        assert_eq!(
+            token_transfers(),
+            vec![
+                (t.short_margin.key, t.long_margin.key, 50),
+                (t.short_margin.key, insurance_fund.key, 1_000),
+            ]
+        );
+        let p = t.perpetual_swap();
+        assert_eq!(p.vamm_side, VammSide::None);
+        assert!(p.is_long_initialized && !p.is_short_initialized);
+        assert_eq!(p.short_owner, Pubkey::default());
+        assert_eq!(p.vamm_base_reserve, 1_000.0);
+        assert_eq!((p.mark_price, p.entry_price), (105.0, 105.0));
+
+        // So the long redeems, and nothing keeps the admin from closing the swap
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut t.long_account,
+                    &mut t.admin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_redeem_settled(&program_id, accounts),
+            ),
+            Ok(())
+        );
+        t.long_margin.data = vec![];
+        t.short_margin.data = vec![];
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut TestAccount::new(Pubkey::new_unique(), system_program::id(), vec![]),
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_close_swap(&program_id, accounts),
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    pub fn test_dated_future_settles_at_expiry() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut admin_token_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.kind = MarketKind::DatedFuture;
+        p.expiry = 10_000_000;
+        p.settlement_window = 4_000_000;
+        p.mark_price = 130.0;
+        // The index is 100 for the first half of the settlement window and 120 for the second
+        Processor::update_index_price(&mut p, 100.0, 1_000_000);
+        Processor::update_index_price(&mut p, 120.0, 8_000_000);
+        t.set_perpetual_swap(&p);
+
+        let mut clock = TestAccount::clock(9_000);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_transfer_funds(&program_id, accounts),
+            ),
+            Err(PerpetualSwapError::NoFunding.into())
+        );
+
+        let mut settle = |t: &mut TestSwap, clock: &mut TestAccount| {
+            // The admin doesn't sign
+            let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    clock,
+                ],
+                |accounts| Processor::process_settle_market(&program_id, accounts),
+            )
+        };
+        // Only the admin can settle before expiry
+        assert_eq!(
+            settle(&mut t, &mut clock),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        let mut clock = TestAccount::clock(10_500);
+        p.is_long_initialized = false;
+        t.set_perpetual_swap(&p);
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_account,
+                    &mut t.long_margin,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_initialize_side(&program_id, 100, accounts),
+            ),
+            Err(PerpetualSwapError::MarketExpired.into())
+        );
+        p.is_long_initialized = true;
+        t.set_perpetual_swap(&p);
+
+        assert_eq!(settle(&mut t, &mut clock), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.status, MarketStatus::Settled);
+        assert_eq!(p.settlement_price, 110.0);
+    }
+
+    #[test]
+    pub fn test_expired_future_waits_for_settlement() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.kind = MarketKind::DatedFuture;
+        p.expiry = 10_000_000;
+        p.settlement_window = 4_000_000;
+        t.set_perpetual_swap(&p);
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut long_dest =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &long_owner.key);
+        let mut buyer = TestAccount::signer();
+        let mut buyer_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &buyer.key);
+        let mut insurance_fund =
+            TestAccount::token_account(p.insurance_fund_pubkey, &t.mint, &t.authority.key);
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+
+        for (secs, expected) in [
+            (9_000, Ok(())),
+            (10_000, Err(PerpetualSwapError::MarketExpired.into())),
+        ]
+        .iter()
+        {
+            let mut clock = TestAccount::clock(*secs);
+            assert_eq!(
+                process(
+                    &mut [
+                        &mut t.swap,
+                        &mut t.authority,
+                        &mut long_owner,
+                        &mut t.long_margin,
+                        &mut long_dest,
+                        &mut t.token_program,
+                        &mut t.collateral_mint,
+                        &mut clock,
+                    ],
+                    |accounts| Processor::process_withdraw_from_margin(&program_id, 100, accounts),
+                ),
+                *expected
+            );
+            assert_eq!(
+                process(
+                    &mut [
+                        &mut t.swap,
+                        &mut admin,
+                        &mut t.authority,
+                        &mut t.token_program,
+                        &mut t.pyth_product,
+                        &mut t.pyth_price,
+                        &mut clock,
+                    ],
+                    |accounts| Processor::process_update_prices(&program_id, 100.0, accounts),
+                ),
+                *expected
+            );
+        }
+
+        // Past expiry, the sides can't trade or close at the mark price either
+        let mut clock = TestAccount::clock(10_000);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.long_margin,
+                    &mut long_owner,
+                    &mut t.long_account,
+                    &mut buyer,
+                    &mut buyer_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| {
+                    Processor::process_transfer_position(&program_id, true, 1.0, 100.0, accounts)
+                },
+            ),
+            Err(PerpetualSwapError::MarketExpired.into())
+        );
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut long_dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_close_position(&program_id, accounts),
+            ),
+            Err(PerpetualSwapError::MarketExpired.into())
+        );
+    }
+
+    #[test]
+    pub fn test_settle_market_requires_index() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        t.set_perpetual_swap(&p);
+        let mut admin_token_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        let mut clock = TestAccount::clock(1_000);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.admin,
+                    &mut t.authority,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut admin_token_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_settle_market(&program_id, accounts),
+            ),
+            Err(PerpetualSwapError::NoIndexPrice.into())
+        );
+    }
+
+    #[test]
+    pub fn test_quanto_index_update_requires_fx_oracle() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.kind = MarketKind::Quanto;
+        p.quanto_multiplier = 0.01;
+        t.set_perpetual_swap(&p);
+        let mut clock = TestAccount::clock(1_000);
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.pyth_product,
+                    &mut t.pyth_price,
+                    &mut clock
+                ],
+                |accounts| Processor::process_oracle_update_index(&program_id, accounts),
+            ),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    pub fn test_quanto_fx_rate_from_bound_oracle() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        // 1.25 collateral per unit of the quote currency, at Pyth's usual exponent
+        let (mut fx_product, mut fx_price) = TestAccount::pyth_oracle(1_250_000_000, -9);
+        let mut p = t.perpetual_swap();
+        p.kind = MarketKind::Quanto;
+        p.quanto_multiplier = 0.5;
+        p.fx_product_pubkey = fx_product.key;
+        p.fx_price_pubkey = fx_price.key;
+        t.set_perpetual_swap(&p);
+        let mut clock = TestAccount::clock(1_000);
+        let mut update_index =
+            |t: &mut TestSwap, fx_product: &mut TestAccount, fx_price: &mut TestAccount| {
+                process(
+                    &mut [
+                        &mut t.swap,
+                        &mut t.pyth_product,
+                        &mut t.pyth_price,
+                        &mut clock,
+                        fx_product,
+                        fx_price,
+                    ],
+                    |accounts| Processor::process_oracle_update_index(&program_id, accounts),
+                )
+            };
+
+        // Any other feed, even a well-formed one, is refused
+        let (mut other_product, mut other_price) = TestAccount::pyth_oracle(1_000_000_000, -9);
+        assert_eq!(
+            update_index(&mut t, &mut other_product, &mut other_price),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+        assert_eq!(t.perpetual_swap().fx_rate, 0.0);
+
+        assert_eq!(update_index(&mut t, &mut fx_product, &mut fx_price), Ok(()));
+        let mut p = t.perpetual_swap();
+        assert!((p.fx_rate - 1.25).abs() < 1e-12);
+
+        // PnL is paid at the fixed multiplier, while margin is sized at the live rate
+        p.mark_price = 110.0;
+        assert!((math::unrealized_pnl(&p, true) - 5.0).abs() < 1e-9);
+        assert!((math::unrealized_pnl(&p, false) + 5.0).abs() < 1e-9);
+        assert!((math::notional(&p) - 137.5).abs() < 1e-9);
+        assert!((math::initial_margin(&p) - 13.75).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn test_update_prices_mark_price() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut clock = TestAccount::clock(1_000);
+        let mut update_prices = |t: &mut TestSwap, admin: &mut TestAccount, mark_price: f64| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    admin,
+                    &mut t.authority,
+                    &mut t.token_program,
+                    &mut t.pyth_product,
+                    &mut t.pyth_price,
+                    &mut clock,
+                ],
+                |accounts| Processor::process_update_prices(&program_id, mark_price, accounts),
+            )
+        };
+
+        // Only the admin sets the mark
+        assert_eq!(
+            update_prices(&mut t, &mut TestAccount::signer(), 105.0),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        assert_eq!(
+            update_prices(&mut t, &mut admin, 105.0),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        // The oracle reads 100, so the band allows 90 to 110
+        admin.is_signer = true;
+        assert_eq!(
+            update_prices(&mut t, &mut admin, 111.0),
+            Err(PerpetualSwapError::MarkPriceOutOfBand.into())
+        );
+        assert_eq!(
+            update_prices(&mut t, &mut admin, 89.0),
+            Err(PerpetualSwapError::MarkPriceOutOfBand.into())
+        );
+        assert_eq!(
+            update_prices(&mut t, &mut admin, f64::NAN),
+            Err(PerpetualSwapError::MarkPriceOutOfBand.into())
+        );
+        assert_eq!(update_prices(&mut t, &mut admin, 110.0), Ok(()));
+        assert_eq!(t.perpetual_swap().mark_price, 110.0);
+    }
+
+    #[test]
+    pub fn test_update_index_reads_bound_oracle() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut clock = TestAccount::clock(1_000);
+        let mut update_index =
+            |swap: &mut TestAccount, product: &mut TestAccount, price: &mut TestAccount| {
+                process(&mut [swap, product, price, &mut clock], |accounts| {
+                    Processor::process_oracle_update_index(&program_id, accounts)
+                })
+            };
+
+        // Another Pyth product than the one bound at init
+        let (mut other_product, mut other_price) = TestAccount::pyth_oracle(1, 0);
+        assert_eq!(
+            update_index(&mut t.swap, &mut other_product, &mut other_price),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+        // The bound keys, but not owned by Pyth
+        t.pyth_price.owner = Pubkey::new_unique();
+        assert_eq!(
+            update_index(&mut t.swap, &mut t.pyth_product, &mut t.pyth_price),
+            Err(ProgramError::IncorrectProgramId)
+        );
+        t.pyth_price.owner = crate::pyth::id();
+
+        // A price that isn't trading can't be used
+        t.pyth_price.data[160..164]
+            .copy_from_slice(&(pyth_client::PriceStatus::Halted as u32).to_le_bytes());
+        assert_eq!(
+            update_index(&mut t.swap, &mut t.pyth_product, &mut t.pyth_price),
+            Err(PerpetualSwapError::OracleNotTrading.into())
+        );
+
+        // 12_345_000 * 10^-5
+        let (_, mut pyth_price) = TestAccount::pyth_oracle(12_345_000, -5);
+        pyth_price.key = t.pyth_price.key;
+        assert_eq!(
+            update_index(&mut t.swap, &mut t.pyth_product, &mut pyth_price),
+            Ok(())
+        );
+        assert!((t.perpetual_swap().index_price - 123.45).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn test_close_position_and_close_swap() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.insurance_fund_pubkey = insurance_fund.key;
+        p.mark_price = 1_100.0;
+        t.set_perpetual_swap(&p);
+        let mut admin_token_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        let mut receiver = TestAccount::wallet();
+        let close_position = |t: &mut TestSwap,
+                              insurance_fund: &mut TestAccount,
+                              is_long: bool,
+                              owner: &mut TestAccount| {
+            let (margin, counterparty_margin, dest) = if is_long {
+                (&mut t.long_margin, &mut t.short_margin, &mut t.long_account)
+            } else {
+                (
+                    &mut t.short_margin,
+                    &mut t.long_margin,
+                    &mut t.short_account,
+                )
+            };
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    owner,
+                    margin,
+                    counterparty_margin,
+                    insurance_fund,
+                    dest,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_close_position(&program_id, accounts),
+            )
+        };
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+
+        // The short lost its whole margin, so it has to be liquidated instead
+        assert_eq!(
+            close_position(&mut t, &mut insurance_fund, false, &mut short_owner),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+        p.mark_price = 120.0;
+        t.set_perpetual_swap(&p);
+        assert_eq!(
+            close_position(&mut t, &mut insurance_fund, false, &mut long_owner),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(
+            close_position(&mut t, &mut insurance_fund, false, &mut short_owner),
+            Ok(())
+        );
+        let p = t.perpetual_swap();
+        assert!(!p.is_short_initialized);
+        assert_eq!(p.short_owner, Pubkey::default());
+        // The long's PnL was realized along with the short's
+        assert_eq!(p.entry_price, 120.0);
+
+        let mut close_swap =
+            |t: &mut TestSwap, insurance_fund: &mut TestAccount, admin: &mut TestAccount| {
+                process(
+                    &mut [
+                        &mut t.swap,
+                        admin,
+                        &mut t.authority,
+                        &mut t.long_margin,
+                        &mut t.short_margin,
+                        insurance_fund,
+                        &mut admin_token_account,
+                        &mut receiver,
+                        &mut t.token_program,
+                        &mut t.collateral_mint,
+                    ],
+                    |accounts| Processor::process_close_swap(&program_id, accounts),
+                )
+            };
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        assert_eq!(
+            close_swap(&mut t, &mut insurance_fund, &mut admin),
+            Err(PerpetualSwapError::PositionStillOpen.into())
+        );
+
+        assert_eq!(
+            close_position(&mut t, &mut insurance_fund, true, &mut long_owner),
+            Ok(())
+        );
+        assert!(!t.perpetual_swap().is_long_initialized);
+        assert_eq!(
+            close_swap(&mut t, &mut insurance_fund, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        let swap_lamports = t.swap.lamports;
+        assert_eq!(close_swap(&mut t, &mut insurance_fund, &mut admin), Ok(()));
+        assert_eq!(t.swap.lamports, 0);
+        assert_eq!(receiver.lamports, 1_000_000_000 + swap_lamports);
+        assert!(t.swap.data.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    pub fn test_initialize_perpetual_swap_margin_addresses() {
+        let program_id = Pubkey::new_unique();
+        let mut swap = TestAccount::new(
+            Pubkey::new_unique(),
+            program_id,
+            vec![0; PerpetualSwap::LEN],
+        );
+        let (authority_key, authority_bump) =
+            Processor::find_authority_address(&program_id, &swap.key);
+        let (long_margin_key, long_margin_bump) =
+            Processor::find_margin_address(&program_id, &swap.key, true);
+        let (short_margin_key, short_margin_bump) =
+            Processor::find_margin_address(&program_id, &swap.key, false);
+        let mut authority = TestAccount::new(authority_key, program_id, vec![]);
+        let mut insurance_fund =
+            TestAccount::new(Pubkey::new_unique(), spl_token::id(), vec![0; Account::LEN]);
+        let mut mint = TestAccount::mint(Pubkey::new_unique());
+        let mut rent = TestAccount::new(
+            solana_program::sysvar::rent::id(),
+            solana_program::sysvar::id(),
+            vec![0; Rent::size_of()],
+        );
+        Rent::default().to_account_info(&mut rent.info()).unwrap();
+        let mut token_program = TestAccount::new(spl_token::id(), Pubkey::default(), vec![]);
+        let mut admin = TestAccount::signer();
+        let mut system = TestAccount::new(system_program::id(), Pubkey::default(), vec![]);
+        let (mut pyth_product, mut pyth_price) = TestAccount::pyth_oracle(100_000_000, -6);
+        // Only a quanto market reads the FX oracle, the others ignore the trailing accounts
+        let (mut fx_product, mut fx_price) = TestAccount::pyth_oracle(1_250_000_000, -9);
+        let (mint_key, pyth_product_key) = (mint.key, pyth_product.key);
+        let (fx_product_key, fx_price_key) = (fx_product.key, fx_price.key);
+        let mut clock = TestAccount::clock(20_000);
+        let mut initialize = |swap: &mut TestAccount,
+                              long_margin_key: Pubkey,
+                              kind: MarketKind,
+                              quanto_multiplier: f64,
+                              pyth_price: &mut TestAccount| {
+            process(
+                &mut [
+                    swap,
+                    &mut authority,
+                    &mut TestAccount::new(long_margin_key, system_program::id(), vec![]),
+                    &mut TestAccount::new(short_margin_key, system_program::id(), vec![]),
+                    &mut insurance_fund,
+                    &mut mint,
+                    &mut rent,
+                    &mut token_program,
+                    &mut admin,
+                    &mut system,
+                    &mut pyth_product,
+                    pyth_price,
+                    &mut clock,
+                    &mut fx_product,
+                    &mut fx_price,
+                ],
+                |accounts| {
+                    // A dated future expiring right now
+                    let (expiry, settlement_window) = if kind == MarketKind::DatedFuture {
+                        (20_000_000, 3_600_000)
+                    } else {
+                        (0, 0)
+                    };
+                    Processor::process_initialize_perpetual_swap(
+                        &program_id,
+                        0.01,
+                        0.1,
+                        0.05,
+                        3_600_000,
+                        LiquidationMode::AutoDeleverage,
+                        1.0,
+                        kind,
+                        expiry,
+                        settlement_window,
+                        ContractType::Linear,
+                        quanto_multiplier,
+                        accounts,
+                    )
+                },
+            )
+        };
+
+        // A margin account anywhere but its program address is refused
+        assert_eq!(
+            initialize(
+                &mut swap,
+                Pubkey::new_unique(),
+                MarketKind::Perpetual,
+                0.0,
+                &mut pyth_price
+            ),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+        // Only a quanto market takes a quanto multiplier, and it must have one
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::Perpetual,
+                0.5,
+                &mut pyth_price
+            ),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::Quanto,
+                0.0,
+                &mut pyth_price
+            ),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        // A future that has already expired
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::DatedFuture,
+                0.0,
+                &mut pyth_price
+            ),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+
+        // A price account the product doesn't list
+        let (_, mut other_price) = TestAccount::pyth_oracle(100_000_000, -6);
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::Perpetual,
+                0.0,
+                &mut other_price
+            ),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::Perpetual,
+                0.0,
+                &mut pyth_price
+            ),
+            Ok(())
+        );
+        let p = PerpetualSwap::try_from_slice(&swap.data).unwrap();
+        assert_eq!(p.authority_bump, authority_bump);
+        assert_eq!(p.long_margin_pubkey, long_margin_key);
+        assert_eq!(p.long_margin_bump, long_margin_bump);
+        assert_eq!(p.short_margin_pubkey, short_margin_key);
+        assert_eq!(p.short_margin_bump, short_margin_bump);
+        assert_eq!(p.collateral_mint, mint_key);
+        assert_eq!(p.collateral_decimals, 6);
+        assert_eq!(p.oracle_product_pubkey, pyth_product_key);
+        assert_eq!(p.oracle_price_pubkey, pyth_price.key);
+        assert_eq!(p.fx_product_pubkey, Pubkey::default());
+
+        // A quanto market binds its FX oracle along with the index's
+        swap.data = vec![0; PerpetualSwap::LEN];
+        assert_eq!(
+            initialize(
+                &mut swap,
+                long_margin_key,
+                MarketKind::Quanto,
+                0.5,
+                &mut pyth_price
+            ),
+            Ok(())
+        );
+        let p = PerpetualSwap::try_from_slice(&swap.data).unwrap();
+        assert_eq!(p.fx_product_pubkey, fx_product_key);
+        assert_eq!(p.fx_price_pubkey, fx_price_key);
+    }
+
+    #[test]
+    pub fn test_trading_fees() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.is_short_initialized = false;
+        p.position_size = 100.0;
+        p.margin_tiers[0].initial_margin_ratio = 0.05;
+        p.margin_tiers[0].maintenance_margin_ratio = 0.05;
+        p.minimum_margin = 0.05;
+        p.margin_tiers[0].max_leverage = 20.0;
+        p.insurance_fund_pubkey = Pubkey::new_unique();
+        t.set_perpetual_swap(&p);
+        let (fee_vault_key, _) = Processor::find_fee_vault_address(&program_id, &t.swap.key);
+        let mut fee_vault = TestAccount::new(fee_vault_key, system_program::id(), vec![]);
+        let mut rent = TestAccount::new(
+            solana_program::sysvar::rent::id(),
+            solana_program::sysvar::id(),
+            vec![0; Rent::size_of()],
+        );
+        Rent::default().to_account_info(&mut rent.info()).unwrap();
+        let mut system = TestAccount::new(system_program::id(), Pubkey::default(), vec![]);
+        let mut configure = |t: &mut TestSwap, fee_vault: &mut TestAccount, taker_fee_bps: u16| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    fee_vault,
+                    &mut t.collateral_mint,
+                    &mut rent,
+                    &mut t.token_program,
+                    &mut t.admin,
+                    &mut system,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| {
+                    Processor::process_configure_fees(
+                        &program_id,
+                        2,
+                        taker_fee_bps,
+                        5_000,
+                        0,
+                        accounts,
+                    )
+                },
+            )
+        };
+
+        assert_eq!(
+            configure(&mut t, &mut fee_vault, PerpetualSwap::MAX_FEE_BPS + 1),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        // The fee vault has to be the swap's PDA
+        assert_eq!(
+            configure(&mut t, &mut TestAccount::wallet(), 5),
+            Err(PerpetualSwapError::InvalidProgramAddress.into())
+        );
+        assert_eq!(configure(&mut t, &mut fee_vault, 5), Ok(()));
+        // The vault is there right away, but traders get the timelock's notice of the fees
+        let p = t.perpetual_swap();
+        assert_eq!(p.fee_vault_pubkey, fee_vault_key);
+        assert!(!p.charges_fees());
+        assert!(p.has_pending_params);
+        assert_eq!(
+            apply_market_params(&mut t, 59),
+            Err(PerpetualSwapError::TimelockNotElapsed.into())
+        );
+        assert_eq!(apply_market_params(&mut t, 60), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!((p.maker_fee_bps, p.taker_fee_bps), (2, 5));
+        assert_eq!(p.insurance_fund_fee_share_bps, 5_000);
+        assert!(p.charges_fees());
+
+        // Lowering a fee takes effect at once
+        let mut vault = TestAccount::new(fee_vault_key, spl_token::id(), vec![]);
+        assert_eq!(configure(&mut t, &mut vault, 4), Ok(()));
+        let p = t.perpetual_swap();
+        assert!(!p.has_pending_params);
+        assert_eq!(p.taker_fee_bps, 4);
+        assert_eq!(configure(&mut t, &mut vault, 5), Ok(()));
+        assert_eq!(apply_market_params(&mut t, 60), Ok(()));
+
+        // Opening the second side is the taker: 5 bps of 10,000 notional on top of the deposit
+        let mut fee_vault = TestAccount::token_account(fee_vault_key, &t.mint, &t.authority.key);
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut clock = TestAccount::clock(0);
+        let mut initialize_short =
+            |t: &mut TestSwap, fee_vault: &mut TestAccount, amount_to_deposit: u64| {
+                process(
+                    &mut [
+                        &mut t.swap,
+                        &mut t.authority,
+                        &mut short_owner,
+                        &mut t.short_account,
+                        &mut t.short_margin,
+                        &mut t.token_program,
+                        &mut t.collateral_mint,
+                        &mut clock,
+                        fee_vault,
+                    ],
+                    |accounts| {
+                        Processor::process_initialize_side(&program_id, amount_to_deposit, accounts)
+                    },
+                )
+            };
+        assert_eq!(
+            initialize_short(&mut t, &mut TestAccount::wallet(), 900),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+        assert_eq!(
+            initialize_short(&mut t, &mut fee_vault, 1_000),
+            Err(PerpetualSwapError::InsufficientFunds.into())
+        );
+        assert_eq!(initialize_short(&mut t, &mut fee_vault, 900), Ok(()));
+        assert_eq!(t.perpetual_swap().unswept_fees, 5);
+
+        // Only the admin can sweep
+        let mut insurance_fund = TestAccount::token_account(
+            t.perpetual_swap().insurance_fund_pubkey,
+            &t.mint,
+            &t.authority.key,
+        );
+        let mut admin_account =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.admin.key);
+        let mut sweep = |t: &mut TestSwap, admin: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    admin,
+                    &mut t.authority,
+                    &mut fee_vault,
+                    &mut insurance_fund,
+                    &mut admin_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_sweep_fees(&program_id, accounts),
+            )
+        };
+        assert_eq!(
+            sweep(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        assert_eq!(sweep(&mut t, &mut admin), Ok(()));
+        assert_eq!(t.perpetual_swap().unswept_fees, 0);
+    }
+
+    #[test]
+    pub fn test_referral_rebates() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut p = t.perpetual_swap();
+        p.is_short_initialized = false;
+        p.position_size = 100.0;
+        p.minimum_margin = 0.05;
+        p.margin_tiers[0].initial_margin_ratio = 0.05;
+        p.margin_tiers[0].maintenance_margin_ratio = 0.05;
+        p.margin_tiers[0].max_leverage = 20.0;
+        p.fee_vault_pubkey = Pubkey::new_unique();
+        p.taker_fee_bps = 5;
+        p.referral_rebate_share_bps = 2_000;
+        t.set_perpetual_swap(&p);
+        let mut fee_vault =
+            TestAccount::token_account(p.fee_vault_pubkey, &t.mint, &t.authority.key);
+
+        let referrer_key = Pubkey::new_unique();
+        let (referrer_authority_key, referrer_authority_bump) =
+            Processor::find_authority_address(&program_id, &referrer_key);
+        let mut referrer_authority = TestAccount::new(referrer_authority_key, program_id, vec![]);
+        let mut referrer_vault =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &referrer_authority_key);
+        let mut referrer_owner = TestAccount::signer();
+        let referrer = Referrer {
+            is_initialized: true,
+            authority_bump: referrer_authority_bump,
+            owner: referrer_owner.key,
+            token_program_id: spl_token::id(),
+            vault_pubkey: referrer_vault.key,
+            mint: t.mint,
+            unclaimed_rebates: 0,
+            total_rebates: 0,
+        };
+        let mut referrer =
+            TestAccount::new(referrer_key, program_id, referrer.try_to_vec().unwrap());
+        let referral_link = ReferralLink {
+            is_initialized: true,
+            user: t.short_owner.key,
+            referrer: referrer_key,
+        };
+        let mut referral_link = TestAccount::new(
+            Processor::find_referral_link_address(&program_id, &t.short_owner.key).0,
+            program_id,
+            referral_link.try_to_vec().unwrap(),
+        );
+
+        // The referral link can't be left out to dodge the rebate
+        let mut short_owner = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        short_owner.is_signer = true;
+        let mut clock = TestAccount::clock(0);
+        let mut initialize_short = |t: &mut TestSwap, referral: &mut [&mut TestAccount]| {
+            let mut accounts: Vec<&mut TestAccount> = vec![
+                &mut t.swap,
+                &mut t.authority,
+                &mut short_owner,
+                &mut t.short_account,
+                &mut t.short_margin,
+                &mut t.token_program,
+                &mut t.collateral_mint,
+                &mut clock,
+                &mut fee_vault,
+            ];
+            accounts.extend(referral.iter_mut().map(|account| &mut **account));
+            process(&mut accounts, |accounts| {
+                Processor::process_initialize_side(&program_id, 900, accounts)
+            })
+        };
+        assert_eq!(
+            initialize_short(&mut t, &mut []),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        assert_eq!(
+            initialize_short(&mut t, &mut [&mut TestAccount::wallet()]),
+            Err(PerpetualSwapError::InvalidProgramAddress.into())
+        );
+        assert_eq!(
+            initialize_short(&mut t, &mut [&mut referral_link]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        assert_eq!(
+            initialize_short(
+                &mut t,
+                &mut [
+                    &mut referral_link,
+                    &mut TestAccount::wallet(),
+                    &mut referrer_vault
+                ]
+            ),
+            Err(PerpetualSwapError::InvalidAccountKeys.into())
+        );
+        assert_eq!(
+            initialize_short(
+                &mut t,
+                &mut [&mut referral_link, &mut referrer, &mut referrer_vault]
+            ),
+            Ok(())
+        );
+        // 20% of the 5 taker fee goes to the referrer
+        assert_eq!(t.perpetual_swap().unswept_fees, 4);
+        let r = Referrer::try_from_slice(&referrer.data).unwrap();
+        assert_eq!((r.unclaimed_rebates, r.total_rebates), (1, 1));
+
+        // A wallet links once
+        let mut user = TestAccount::new(t.short_owner.key, system_program::id(), vec![]);
+        user.is_signer = true;
+        let mut rent = TestAccount::new(
+            solana_program::sysvar::rent::id(),
+            solana_program::sysvar::id(),
+            vec![0; Rent::size_of()],
+        );
+        Rent::default().to_account_info(&mut rent.info()).unwrap();
+        let mut system = TestAccount::new(system_program::id(), Pubkey::default(), vec![]);
+        let mut link =
+            |link: &mut TestAccount, referrer: &mut TestAccount, user: &mut TestAccount| {
+                process(
+                    &mut [link, referrer, user, &mut rent, &mut system],
+                    |accounts| Processor::process_link_referrer(&program_id, accounts),
+                )
+            };
+        assert_eq!(
+            link(&mut referral_link, &mut referrer, &mut user),
+            Err(PerpetualSwapError::AlreadyInUse.into())
+        );
+        let mut other_user = TestAccount::signer();
+        assert_eq!(
+            link(&mut referral_link, &mut referrer, &mut other_user),
+            Err(PerpetualSwapError::InvalidProgramAddress.into())
+        );
+        let mut other_link = TestAccount::new(
+            Processor::find_referral_link_address(&program_id, &other_user.key).0,
+            system_program::id(),
+            vec![],
+        );
+        assert_eq!(
+            link(&mut other_link, &mut TestAccount::wallet(), &mut other_user),
+            Err(ProgramError::IncorrectProgramId)
+        );
+        other_user.is_signer = false;
+        assert_eq!(
+            link(&mut other_link, &mut referrer, &mut other_user),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        // Only the referrer's owner claims
+        let mut destination =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &referrer_owner.key);
+        let mut claim = |t: &mut TestSwap, owner: &mut TestAccount| {
+            process(
+                &mut [
+                    &mut referrer,
+                    &mut referrer_authority,
+                    owner,
+                    &mut referrer_vault,
+                    &mut destination,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                ],
+                |accounts| Processor::process_claim_referral_rebates(&program_id, accounts),
+            )
+        };
+        assert_eq!(
+            claim(&mut t, &mut TestAccount::signer()),
+            Err(PerpetualSwapError::InvalidOwner.into())
+        );
+        assert_eq!(claim(&mut t, &mut referrer_owner), Ok(()));
+        let r = Referrer::try_from_slice(&referrer.data).unwrap();
+        assert_eq!((r.unclaimed_rebates, r.total_rebates), (0, 1));
+    }
+
+    #[test]
+    pub fn test_vamm_counterparty() {
+        let mut t = TestSwap::new();
+        let program_id = t.program_id;
+        let mut insurance_fund =
+            TestAccount::token_account(Pubkey::new_unique(), &t.mint, &t.authority.key);
+        let mut p = t.perpetual_swap();
+        p.is_long_initialized = false;
+        p.is_short_initialized = false;
+        p.position_size = 10.0;
+        p.insurance_fund_pubkey = insurance_fund.key;
+        t.set_perpetual_swap(&p);
+        let mut admin = TestAccount::new(t.admin.key, system_program::id(), vec![]);
+        admin.is_signer = true;
+        let mut long_owner = TestAccount::new(t.long_owner.key, system_program::id(), vec![]);
+        long_owner.is_signer = true;
+        let mut clock = TestAccount::clock(0);
+        let mut open_long = |t: &mut TestSwap,
+                             insurance_fund: &mut TestAccount,
+                             amount_to_deposit: u64,
+                             limit_price: f64| {
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_account,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    insurance_fund,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut clock,
+                ],
+                |accounts| {
+                    Processor::process_open_with_vamm(
+                        &program_id,
+                        true,
+                        amount_to_deposit,
+                        limit_price,
+                        accounts,
+                    )
+                },
+            )
+        };
+        assert_eq!(
+            open_long(&mut t, &mut insurance_fund, 200, 200.0),
+            Err(PerpetualSwapError::NoVamm.into())
+        );
+
+        let initialize_vamm = |t: &mut TestSwap, admin: &mut TestAccount, base_reserve: f64| {
+            process(&mut [&mut t.swap, admin], |accounts| {
+                Processor::process_initialize_vamm(&program_id, base_reserve, 100.0, accounts)
+            })
+        };
+        assert_eq!(
+            initialize_vamm(&mut t, &mut TestAccount::signer(), 1_000.0),
+            Err(PerpetualSwapError::InvalidAdmin.into())
+        );
+        // It couldn't sell a whole position
+        assert_eq!(
+            initialize_vamm(&mut t, &mut admin, 10.0),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        assert_eq!(initialize_vamm(&mut t, &mut admin, 1_000.0), Ok(()));
+        assert_eq!(math::vamm_price(&t.perpetual_swap()), 100.0);
+        assert_eq!(
+            initialize_vamm(&mut t, &mut admin, 1_000.0),
+            Err(PerpetualSwapError::AlreadyInUse.into())
+        );
+
+        // Buying 10 out of 1,000 costs 1,010.10.. of quote, about 101.01 a unit
+        assert_eq!(
+            open_long(&mut t, &mut insurance_fund, 200, 101.0),
+            Err(PerpetualSwapError::PriceLimitExceeded.into())
+        );
+        assert_eq!(
+            open_long(&mut t, &mut insurance_fund, 50, 102.0),
+            Err(PerpetualSwapError::WouldBeLiquidated.into())
+        );
+        assert_eq!(open_long(&mut t, &mut insurance_fund, 200, 102.0), Ok(()));
+        let p = t.perpetual_swap();
+        assert!((p.entry_price - 100_000.0 / 990.0).abs() < 1e-9);
+        assert_eq!(p.mark_price, math::vamm_price(&p));
+        assert!(p.mark_price > p.entry_price);
+        assert_eq!(p.vamm_side, VammSide::Short);
+        assert!(p.is_initialized());
+        assert_eq!(p.long_owner, t.long_owner.key);
+        assert_eq!(p.short_owner, t.authority.key);
+        assert_eq!(p.short_account_pubkey, insurance_fund.key);
+        assert_eq!(
+            open_long(&mut t, &mut insurance_fund, 200, 102.0),
+            Err(PerpetualSwapError::AlreadyInUse.into())
+        );
+
+        // Neither repegging nor changing the depth can move the price of the open position
+        let repeg_vamm = |t: &mut TestSwap, admin: &mut TestAccount, peg_multiplier: f64| {
+            process(&mut [&mut t.swap, admin], |accounts| {
+                Processor::process_repeg_vamm(&program_id, peg_multiplier, accounts)
+            })
+        };
+        assert_eq!(
+            repeg_vamm(&mut t, &mut admin, 99.0),
+            Err(PerpetualSwapError::HeldByVamm.into())
+        );
+        let adjust_vamm_depth =
+            |t: &mut TestSwap, admin: &mut TestAccount, depth_multiplier: f64| {
+                process(&mut [&mut t.swap, admin], |accounts| {
+                    Processor::process_adjust_vamm_depth(&program_id, depth_multiplier, accounts)
+                })
+            };
+        assert_eq!(
+            adjust_vamm_depth(&mut t, &mut admin, 2.0),
+            Err(PerpetualSwapError::HeldByVamm.into())
+        );
+
+        // Closing sells the position back to the vAMM, which leaves the swap with it
+        assert_eq!(
+            process(
+                &mut [
+                    &mut t.swap,
+                    &mut t.authority,
+                    &mut long_owner,
+                    &mut t.long_margin,
+                    &mut t.short_margin,
+                    &mut insurance_fund,
+                    &mut t.long_account,
+                    &mut t.token_program,
+                    &mut t.collateral_mint,
+                    &mut TestAccount::clock(0),
+                ],
+                |accounts| Processor::process_close_position(&program_id, accounts),
+            ),
+            Ok(())
+        );
+        let p = t.perpetual_swap();
+        assert!(!p.is_long_initialized && !p.is_short_initialized);
+        assert_eq!(p.vamm_side, VammSide::None);
+        assert_eq!(p.short_owner, Pubkey::default());
+        assert_eq!(p.vamm_base_reserve, 1_000.0);
+        assert_eq!(p.mark_price, math::vamm_price(&p));
 
+        // Once nobody trades against it, repegging moves its price and changing the depth doesn't
+        assert_eq!(
+            repeg_vamm(&mut t, &mut admin, -1.0),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        assert_eq!(repeg_vamm(&mut t, &mut admin, 99.0), Ok(()));
+        let vamm_price = math::vamm_price(&t.perpetual_swap());
+        assert!((vamm_price - 99.0).abs() < 1e-9);
+        assert_eq!(
+            adjust_vamm_depth(&mut t, &mut admin, 0.0),
+            Err(PerpetualSwapError::InvalidMarketParameter.into())
+        );
+        assert_eq!(adjust_vamm_depth(&mut t, &mut admin, 2.0), Ok(()));
+        let p = t.perpetual_swap();
+        assert_eq!(p.vamm_base_reserve, 2_000.0);
+        assert!((math::vamm_price(&p) - vamm_price).abs() < 1e-9);
     }
 }
@@ -2,32 +2,439 @@ use solana_program::pubkey::Pubkey;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
+/// What happens to a bankrupt position's shortfall once the insurance fund is exhausted
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum LiquidationMode {
+    /// The profitable counterparty is force-closed at the bankruptcy price
+    AutoDeleverage,
+    /// The counterparty keeps its position and absorbs the shortfall out of its profit
+    SocializedLoss,
+}
+
+/// What a market lets its users do. New swaps start out active.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum MarketStatus {
+    Active,
+    /// No new positions, but margin can still be added and positions closed
+    ReduceOnly,
+    /// Only deposits are accepted
+    Paused,
+    /// Prices are frozen for good and only withdrawals are accepted
+    Settled,
+}
+
+/// The kinds of instruction a market's status decides on
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MarketAction {
+    /// Opens a position, adding risk to the market
+    Open,
+    /// Adds margin to a side
+    Deposit,
+    /// Takes margin out of a side
+    Withdraw,
+    /// Hands a position over or liquidates it
+    Close,
+    /// Updates prices or moves funding
+    Crank,
+}
+
+impl MarketStatus {
+    pub fn allows(self, action: MarketAction) -> bool {
+        match self {
+            MarketStatus::Active => true,
+            MarketStatus::ReduceOnly => action != MarketAction::Open,
+            MarketStatus::Paused => action == MarketAction::Deposit,
+            MarketStatus::Settled => action == MarketAction::Withdraw,
+        }
+    }
+}
+
+/// How a position's PnL follows the price. Both types share the funding, margin and liquidation
+/// engine.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum ContractType {
+    /// Margined in the quote asset, with `position_size` units of the base asset
+    Linear,
+    /// Margined in the base asset, with `position_size` units of the quote asset, so PnL and
+    /// notional go with 1/price
+    Inverse,
+}
+
+/// The side of a swap the vAMM has taken as counterparty, if any
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum VammSide {
+    None,
+    Long,
+    Short,
+}
+
+/// What a market trades. All kinds share the margin and liquidation engine.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum MarketKind {
+    /// Never expires, and funding keeps the mark price close to the index
+    Perpetual,
+    /// Expires at `expiry` without funding, and settles in cash at the index TWAP over the
+    /// settlement window that ends there
+    DatedFuture,
+    /// A perpetual on an asset priced in another currency than the collateral. PnL and funding
+    /// are paid at the fixed `quanto_multiplier`, and margin is sized at the `fx_rate`
+    Quanto,
+}
+
+/// Margin requirements for positions up to a notional size
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct MarginTier {
+    pub max_notional: f64,
+    pub initial_margin_ratio: f64,
+    pub maintenance_margin_ratio: f64,
+    pub max_leverage: f64,
+}
+
+impl MarginTier {
+    /// The tier with both ratios raised to at least `minimum_margin`.
+    pub fn with_minimum_margin(self, minimum_margin: f64) -> Self {
+        Self {
+            initial_margin_ratio: self.initial_margin_ratio.max(minimum_margin),
+            maintenance_margin_ratio: self.maintenance_margin_ratio.max(minimum_margin),
+            ..self
+        }
+    }
+}
+
+/// Parameters the admin can change once the swap is live
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct MarketParams {
+    pub funding_rate: f64,
+    pub minimum_margin: f64, // Floor on every margin tier's ratios
+    pub liquidation_bounty: f64,
+    pub minimum_funding_period: u128,
+    pub params_timelock: u128, // Milliseconds a stricter change waits before it can be applied
+    pub num_margin_tiers: u8,
+    pub margin_tiers: [MarginTier; PerpetualSwap::MAX_MARGIN_TIERS],
+    pub mark_price_band: f64, // Furthest the mark can be set from the index, as a share of it
+    pub maker_fee_bps: u16,   // Set through ConfigureFees, which shares the timelock
+    pub taker_fee_bps: u16,
+    pub insurance_fund_fee_share_bps: u16,
+    pub referral_rebate_share_bps: u16,
+}
+
+impl MarketParams {
+    pub const LEN: usize = 201;
+
+    pub fn margin_tiers(&self) -> &[MarginTier] {
+        &self.margin_tiers[..self.num_margin_tiers as usize]
+    }
+}
+
 #[repr(C)]
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
 pub struct PerpetualSwap {
     pub is_long_initialized: bool,
     pub is_short_initialized: bool,
-    pub nonce: u8,
+    pub authority_bump: u8, // Bump of the swap authority, derived from ("authority", swap)
     pub token_program_id: Pubkey,
     pub long_margin_pubkey: Pubkey,
     pub long_account_pubkey: Pubkey,
     pub short_margin_pubkey: Pubkey,
     pub short_account_pubkey: Pubkey,
+    pub insurance_fund_pubkey: Pubkey,
     pub reference_time: u128,
     pub minimum_funding_period: u128,
     pub index_price: f64,
     pub mark_price: f64,
+    pub entry_price: f64, // Mark price at which the current positions were opened
+    pub position_size: f64, // Units of the underlying each side is exposed to
     pub minimum_margin: f64,
     pub liquidation_bounty: f64, // Percentage of the minimum margin that the liquidator receives
     pub funding_rate: f64,
+    pub liquidation_mode: LiquidationMode,
+    pub collateral_config_pubkey: Pubkey, // Default when the swap only takes the primary collateral
+    pub num_margin_tiers: u8,
+    pub margin_tiers: [MarginTier; PerpetualSwap::MAX_MARGIN_TIERS], // Sorted by max_notional
+    pub fee_vault_pubkey: Pubkey, // Default until crank rewards or fees are configured
+    pub crank_reward: u64,        // Paid out of the fee vault for every successful funding crank
+    pub crank_reward_cap: u64,    // Most paid out within one reward interval
+    pub crank_reward_interval: u128,
+    pub crank_reward_interval_start: u128,
+    pub crank_rewards_paid: u64, // Paid out since crank_reward_interval_start
+    pub long_owner: Pubkey,      // Wallet that must sign for anything moving the long's value
+    pub short_owner: Pubkey,
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey, // Default unless an admin transfer waits to be accepted
+    pub params_timelock: u128,
+    pub has_pending_params: bool,
+    pub pending_params: MarketParams,
+    pub pending_params_time: u128, // When the pending params can be applied
+    pub status: MarketStatus,
+    pub index_cumulative: f64, // Index weighted by the milliseconds it held for, up to the last update
+    pub index_twap_time: u128, // When the index was last updated, 0 before any index update
+    pub index_twap_start_cumulative: f64, // index_cumulative at the start of what the TWAP averages
+    pub index_twap_start_time: u128,
+    pub index_twap_next_cumulative: f64, // Becomes the start once it is INDEX_TWAP_WINDOW old
+    pub index_twap_next_time: u128,
+    pub settlement_price: f64, // Final price once the market is settled
+    pub long_margin_bump: u8,  // Bump of the long margin account, derived from ("margin", swap, "long")
+    pub short_margin_bump: u8,
+    pub collateral_mint: Pubkey, // Mint of the margin, insurance fund and fee vault
+    pub collateral_decimals: u8,
+    pub kind: MarketKind,
+    pub expiry: u128,            // Dated futures only, like the three fields below
+    pub settlement_window: u128, // Length of the window before expiry the settlement price averages
+    pub settlement_index_sum: f64, // Index weighted by the milliseconds of the window it held for
+    pub settlement_index_time: u128, // Milliseconds of the window settlement_index_sum covers
+    pub long_position_mint: Pubkey,  // Default unless the long is tokenized
+    pub short_position_mint: Pubkey,
+    pub contract_type: ContractType,
+    pub quanto_multiplier: f64, // Quanto only: collateral paid per point of price per unit of size
+    pub fx_rate: f64,           // Quanto only: collateral per unit of the quote currency
+    pub maker_fee_bps: u16,     // Charged on the notional of opens and transfers
+    pub taker_fee_bps: u16,
+    pub insurance_fund_fee_share_bps: u16, // Share of swept fees sent to the insurance fund
+    pub unswept_fees: u64,                 // Fees in the fee vault the admin hasn't swept yet
+    pub referral_rebate_share_bps: u16,    // Share of a referred taker's fee paid to the referrer
+    pub vamm_base_reserve: f64,            // 0 unless the swap has a vAMM to trade against
+    pub vamm_quote_reserve: f64,
+    pub vamm_peg_multiplier: f64, // Scales the vAMM's reserve ratio into a price
+    pub vamm_side: VammSide,
+    pub oracle_product_pubkey: Pubkey, // Pyth product account of the index, bound at init
+    pub oracle_price_pubkey: Pubkey,
+    pub mark_price_band: f64,
+    pub fx_product_pubkey: Pubkey, // Quanto only: Pyth product account of the FX rate, bound at init
+    pub fx_price_pubkey: Pubkey,
 }
 
 impl PerpetualSwap {
-    pub const LEN: usize = 234;
+    pub const MAX_MARGIN_TIERS: usize = 4;
+    pub const LEN: usize = 1326;
+    pub const MAX_FUNDING_RATE: f64 = 1.0;
+    pub const MAX_MINIMUM_FUNDING_PERIOD: u128 = 7 * 24 * 60 * 60 * 1000;
+    pub const DEFAULT_PARAMS_TIMELOCK: u128 = 24 * 60 * 60 * 1000;
+    pub const MAX_PARAMS_TIMELOCK: u128 = 30 * 24 * 60 * 60 * 1000;
+    pub const INDEX_TWAP_WINDOW: u128 = 60 * 60 * 1000;
+    pub const MAX_SETTLEMENT_WINDOW: u128 = 24 * 60 * 60 * 1000;
+    pub const MAX_FEE_BPS: u16 = 1_000;
+    pub const DEFAULT_MARK_PRICE_BAND: f64 = 0.1;
+    pub const MAX_MARK_PRICE_BAND: f64 = 1.0;
 
     pub fn is_initialized(&self) -> bool {
         self.is_long_initialized && self.is_short_initialized
     }
+
+    pub fn has_collateral_config(&self) -> bool {
+        self.collateral_config_pubkey != Pubkey::default()
+    }
+
+    pub fn margin_tiers(&self) -> &[MarginTier] {
+        &self.margin_tiers[..self.num_margin_tiers as usize]
+    }
+
+    pub fn has_fee_vault(&self) -> bool {
+        self.fee_vault_pubkey != Pubkey::default()
+    }
+
+    pub fn charges_fees(&self) -> bool {
+        self.maker_fee_bps > 0 || self.taker_fee_bps > 0
+    }
+
+    /// Whether referrers get a share of the taker fees, so takers pass their referral link.
+    pub fn pays_referral_rebates(&self) -> bool {
+        self.taker_fee_bps > 0 && self.referral_rebate_share_bps > 0
+    }
+
+    pub fn has_vamm(&self) -> bool {
+        self.vamm_base_reserve > 0.0
+    }
+
+    /// Whether the vAMM is the counterparty holding the long (`is_long`) or short side.
+    pub fn vamm_holds(&self, is_long: bool) -> bool {
+        self.vamm_side == if is_long { VammSide::Long } else { VammSide::Short }
+    }
+
+    pub fn has_funding(&self) -> bool {
+        self.kind != MarketKind::DatedFuture
+    }
+
+    /// Whether the market is a dated future at or past its expiry at `now`.
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.kind == MarketKind::DatedFuture && now >= self.expiry
+    }
+
+    pub fn market_params(&self) -> MarketParams {
+        MarketParams {
+            funding_rate: self.funding_rate,
+            minimum_margin: self.minimum_margin,
+            liquidation_bounty: self.liquidation_bounty,
+            minimum_funding_period: self.minimum_funding_period,
+            params_timelock: self.params_timelock,
+            num_margin_tiers: self.num_margin_tiers,
+            margin_tiers: self.margin_tiers,
+            mark_price_band: self.mark_price_band,
+            maker_fee_bps: self.maker_fee_bps,
+            taker_fee_bps: self.taker_fee_bps,
+            insurance_fund_fee_share_bps: self.insurance_fund_fee_share_bps,
+            referral_rebate_share_bps: self.referral_rebate_share_bps,
+        }
+    }
+
+    pub fn set_market_params(&mut self, params: &MarketParams) {
+        self.funding_rate = params.funding_rate;
+        self.minimum_margin = params.minimum_margin;
+        self.liquidation_bounty = params.liquidation_bounty;
+        self.minimum_funding_period = params.minimum_funding_period;
+        self.params_timelock = params.params_timelock;
+        self.num_margin_tiers = params.num_margin_tiers;
+        self.margin_tiers = params.margin_tiers;
+        self.mark_price_band = params.mark_price_band;
+        self.maker_fee_bps = params.maker_fee_bps;
+        self.taker_fee_bps = params.taker_fee_bps;
+        self.insurance_fund_fee_share_bps = params.insurance_fund_fee_share_bps;
+        self.referral_rebate_share_bps = params.referral_rebate_share_bps;
+    }
+
+    /// The params a new change builds on: the queued ones if there are any, so a change doesn't
+    /// silently drop one that is still waiting on the timelock.
+    pub fn next_market_params(&self) -> MarketParams {
+        if self.has_pending_params {
+            self.pending_params
+        } else {
+            self.market_params()
+        }
+    }
+
+    pub fn owner(&self, is_long: bool) -> &Pubkey {
+        if is_long {
+            &self.long_owner
+        } else {
+            &self.short_owner
+        }
+    }
+
+    pub fn user_account_pubkey(&self, is_long: bool) -> &Pubkey {
+        if is_long {
+            &self.long_account_pubkey
+        } else {
+            &self.short_account_pubkey
+        }
+    }
+
+    pub fn position_mint(&self, is_long: bool) -> &Pubkey {
+        if is_long {
+            &self.long_position_mint
+        } else {
+            &self.short_position_mint
+        }
+    }
+
+    /// Whether the side is owned by whoever holds its position token rather than by `owner`.
+    pub fn is_tokenized(&self, is_long: bool) -> bool {
+        *self.position_mint(is_long) != Pubkey::default()
+    }
+}
+
+/// A non-primary collateral mint accepted by a swap
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct CollateralAsset {
+    pub mint: Pubkey,
+    pub decimals: u8, // Of the mint, for checked transfers in and out of the vaults
+    pub pyth_product_pubkey: Pubkey,
+    pub long_vault_pubkey: Pubkey,
+    pub short_vault_pubkey: Pubkey,
+    pub haircut: f64, // Weight in (0, 1] applied to the asset's value
+    pub price: f64,   // Last oracle price, in units of the primary collateral
+}
+
+impl CollateralAsset {
+    pub fn vault_pubkey(&self, is_long: bool) -> Pubkey {
+        if is_long {
+            self.long_vault_pubkey
+        } else {
+            self.short_vault_pubkey
+        }
+    }
+}
+
+/// The non-primary collateral mints a swap accepts as margin
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct CollateralConfig {
+    pub is_initialized: bool,
+    pub swap_pubkey: Pubkey,
+    pub num_assets: u8,
+    pub assets: [CollateralAsset; CollateralConfig::MAX_ASSETS],
+}
+
+impl CollateralConfig {
+    pub const MAX_ASSETS: usize = 4;
+    pub const LEN: usize = 614;
+
+    pub fn assets(&self) -> &[CollateralAsset] {
+        &self.assets[..self.num_assets as usize]
+    }
+}
+
+/// A position held by a cross-margin account in one swap
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct CrossMarginMarket {
+    pub swap_pubkey: Pubkey,
+    pub is_long: bool,
+}
+
+/// One collateral vault backing positions in several swaps. Each of those positions uses the
+/// vault as its user account, so margin released by a swap flows back into the vault.
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct CrossMarginAccount {
+    pub is_initialized: bool,
+    pub authority_bump: u8, // Bump of the account's authority, derived from ("authority", account)
+    pub owner: Pubkey,
+    pub token_program_id: Pubkey,
+    pub vault_pubkey: Pubkey,
+    pub mint: Pubkey, // Mint of the vault, and of every market's collateral
+    pub decimals: u8,
+    pub num_markets: u8,
+    pub markets: [CrossMarginMarket; CrossMarginAccount::MAX_MARKETS],
+}
+
+impl CrossMarginAccount {
+    pub const MAX_MARKETS: usize = 8;
+    pub const LEN: usize = 396;
+
+    pub fn markets(&self) -> &[CrossMarginMarket] {
+        &self.markets[..self.num_markets as usize]
+    }
+}
+
+/// A partner earning a share of the taker fees paid by the wallets linked to it. The rebates are
+/// paid into its vault as the fees are charged, and its owner claims them from there.
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Referrer {
+    pub is_initialized: bool,
+    pub authority_bump: u8, // Bump of the account's authority, derived from ("authority", account)
+    pub owner: Pubkey,
+    pub token_program_id: Pubkey,
+    pub vault_pubkey: Pubkey,
+    pub mint: Pubkey, // Only swaps with this collateral mint pay the referrer rebates
+    pub unclaimed_rebates: u64,
+    pub total_rebates: u64, // Paid since the referrer was created, claimed or not
+}
+
+impl Referrer {
+    pub const LEN: usize = 146;
+}
+
+/// The referrer a wallet signed up through. It lives at the program address derived from
+/// ("referral", wallet), so a wallet can only link once.
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct ReferralLink {
+    pub is_initialized: bool,
+    pub user: Pubkey,
+    pub referrer: Pubkey,
+}
+
+impl ReferralLink {
+    pub const LEN: usize = 65;
 }
 
 mod test {
@@ -39,23 +446,208 @@ mod test {
         let p = PerpetualSwap {
             is_long_initialized: true,
             is_short_initialized: true,
-            nonce: 122,
+            authority_bump: 122,
             token_program_id: Pubkey::new_unique(),
             long_margin_pubkey: Pubkey::new_unique(),
             long_account_pubkey: Pubkey::new_unique(),
             short_margin_pubkey: Pubkey::new_unique(),
             short_account_pubkey: Pubkey::new_unique(),
+            insurance_fund_pubkey: Pubkey::new_unique(),
             reference_time: 123456900,
             minimum_funding_period: 28800000,
             index_price: 100.0,
             mark_price: 100.2,
+            entry_price: 99.5,
+            position_size: 2.0,
             minimum_margin: 10.0,
             liquidation_bounty: 6.0,
             funding_rate: 0.8,
+            liquidation_mode: LiquidationMode::SocializedLoss,
+            collateral_config_pubkey: Pubkey::new_unique(),
+            num_margin_tiers: 1,
+            margin_tiers: [MarginTier {
+                max_notional: f64::MAX,
+                initial_margin_ratio: 0.1,
+                maintenance_margin_ratio: 0.05,
+                max_leverage: 10.0,
+            }; PerpetualSwap::MAX_MARGIN_TIERS],
+            fee_vault_pubkey: Pubkey::new_unique(),
+            crank_reward: 1_000,
+            crank_reward_cap: 10_000,
+            crank_reward_interval: 86_400_000,
+            crank_reward_interval_start: 123456900,
+            crank_rewards_paid: 2_000,
+            long_owner: Pubkey::new_unique(),
+            short_owner: Pubkey::new_unique(),
+            admin: Pubkey::new_unique(),
+            pending_admin: Pubkey::new_unique(),
+            params_timelock: 86_400_000,
+            has_pending_params: true,
+            pending_params: MarketParams {
+                funding_rate: 0.5,
+                minimum_margin: 0.2,
+                liquidation_bounty: 0.1,
+                minimum_funding_period: 3_600_000,
+                params_timelock: 3_600_000,
+                num_margin_tiers: 1,
+                margin_tiers: [MarginTier::default(); PerpetualSwap::MAX_MARGIN_TIERS],
+                mark_price_band: 0.2,
+                maker_fee_bps: 3,
+                taker_fee_bps: 6,
+                insurance_fund_fee_share_bps: 2_500,
+                referral_rebate_share_bps: 2_000,
+            },
+            pending_params_time: 123456900,
+            status: MarketStatus::ReduceOnly,
+            index_cumulative: 360_360_000.0,
+            index_twap_time: 123456900,
+            index_twap_start_cumulative: 0.0,
+            index_twap_start_time: 119856900,
+            index_twap_next_cumulative: 180_000_000.0,
+            index_twap_next_time: 121656900,
+            settlement_price: 100.3,
+            long_margin_bump: 253,
+            short_margin_bump: 251,
+            collateral_mint: Pubkey::new_unique(),
+            collateral_decimals: 6,
+            kind: MarketKind::DatedFuture,
+            expiry: 1_700_000_000_000,
+            settlement_window: 3_600_000,
+            settlement_index_sum: 360_000_000.0,
+            settlement_index_time: 3_600_000,
+            long_position_mint: Pubkey::new_unique(),
+            short_position_mint: Pubkey::default(),
+            contract_type: ContractType::Inverse,
+            quanto_multiplier: 0.000_01,
+            fx_rate: 0.000_012,
+            maker_fee_bps: 2,
+            taker_fee_bps: 5,
+            insurance_fund_fee_share_bps: 2_500,
+            unswept_fees: 12_345,
+            referral_rebate_share_bps: 2_000,
+            vamm_base_reserve: 1_000.0,
+            vamm_quote_reserve: 1_010.0,
+            vamm_peg_multiplier: 100.0,
+            vamm_side: VammSide::Short,
+            oracle_product_pubkey: Pubkey::new_unique(),
+            oracle_price_pubkey: Pubkey::new_unique(),
+            mark_price_band: 0.1,
+            fx_product_pubkey: Pubkey::new_unique(),
+            fx_price_pubkey: Pubkey::new_unique(),
         };
         let packed = p.try_to_vec().unwrap();
+        assert_eq!(packed.len(), PerpetualSwap::LEN);
         let unpacked = PerpetualSwap::try_from_slice(packed.as_slice()).unwrap();
 
         assert_eq!(p, unpacked);
+        assert_eq!(p.pending_params.try_to_vec().unwrap().len(), MarketParams::LEN);
+    }
+
+    #[test]
+    pub fn test_market_status_allows() {
+        let actions = [
+            MarketAction::Open,
+            MarketAction::Deposit,
+            MarketAction::Withdraw,
+            MarketAction::Close,
+            MarketAction::Crank,
+        ];
+        let allowed = |status: MarketStatus| -> Vec<MarketAction> {
+            actions
+                .iter()
+                .copied()
+                .filter(|action| status.allows(*action))
+                .collect()
+        };
+        assert_eq!(allowed(MarketStatus::Active), actions.to_vec());
+        assert_eq!(
+            allowed(MarketStatus::ReduceOnly),
+            vec![
+                MarketAction::Deposit,
+                MarketAction::Withdraw,
+                MarketAction::Close,
+                MarketAction::Crank
+            ]
+        );
+        assert_eq!(allowed(MarketStatus::Paused), vec![MarketAction::Deposit]);
+        assert_eq!(allowed(MarketStatus::Settled), vec![MarketAction::Withdraw]);
+    }
+
+    #[test]
+    pub fn test_cross_margin_account_unpack() {
+        let mut markets = [CrossMarginMarket::default(); CrossMarginAccount::MAX_MARKETS];
+        markets[0] = CrossMarginMarket {
+            swap_pubkey: Pubkey::new_unique(),
+            is_long: true,
+        };
+        let c = CrossMarginAccount {
+            is_initialized: true,
+            authority_bump: 254,
+            owner: Pubkey::new_unique(),
+            token_program_id: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            decimals: 6,
+            num_markets: 1,
+            markets,
+        };
+        let packed = c.try_to_vec().unwrap();
+        assert_eq!(packed.len(), CrossMarginAccount::LEN);
+        let unpacked = CrossMarginAccount::try_from_slice(packed.as_slice()).unwrap();
+
+        assert_eq!(c, unpacked);
+        assert_eq!(unpacked.markets().len(), 1);
+    }
+
+    #[test]
+    pub fn test_referrer_unpack() {
+        let r = Referrer {
+            is_initialized: true,
+            authority_bump: 253,
+            owner: Pubkey::new_unique(),
+            token_program_id: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            unclaimed_rebates: 1_500,
+            total_rebates: 4_000,
+        };
+        let packed = r.try_to_vec().unwrap();
+        assert_eq!(packed.len(), Referrer::LEN);
+        assert_eq!(r, Referrer::try_from_slice(packed.as_slice()).unwrap());
+
+        let l = ReferralLink {
+            is_initialized: true,
+            user: Pubkey::new_unique(),
+            referrer: Pubkey::new_unique(),
+        };
+        let packed = l.try_to_vec().unwrap();
+        assert_eq!(packed.len(), ReferralLink::LEN);
+        assert_eq!(l, ReferralLink::try_from_slice(packed.as_slice()).unwrap());
+    }
+
+    #[test]
+    pub fn test_collateral_config_unpack() {
+        let mut assets = [CollateralAsset::default(); CollateralConfig::MAX_ASSETS];
+        assets[0] = CollateralAsset {
+            mint: Pubkey::new_unique(),
+            decimals: 9,
+            pyth_product_pubkey: Pubkey::new_unique(),
+            long_vault_pubkey: Pubkey::new_unique(),
+            short_vault_pubkey: Pubkey::new_unique(),
+            haircut: 0.8,
+            price: 35.5,
+        };
+        let c = CollateralConfig {
+            is_initialized: true,
+            swap_pubkey: Pubkey::new_unique(),
+            num_assets: 1,
+            assets,
+        };
+        let packed = c.try_to_vec().unwrap();
+        assert_eq!(packed.len(), CollateralConfig::LEN);
+        let unpacked = CollateralConfig::try_from_slice(packed.as_slice()).unwrap();
+
+        assert_eq!(c, unpacked);
+        assert_eq!(unpacked.assets()[0].vault_pubkey(false), assets[0].short_vault_pubkey);
     }
 }
@@ -11,7 +11,7 @@ impl Unpackable for u64 {
     }
 
     fn from_le_bytes(bytes: &[u8]) -> Self{
-        return u64::from_le_bytes(bytes.try_into().unwrap());
+        u64::from_le_bytes(bytes.try_into().unwrap())
     }
 }
 
@@ -21,7 +21,7 @@ impl Unpackable for f64 {
     }
 
     fn from_le_bytes(bytes: &[u8]) -> Self{
-        return f64::from_le_bytes(bytes.try_into().unwrap());
+        f64::from_le_bytes(bytes.try_into().unwrap())
     }
 }
 
@@ -31,6 +31,16 @@ impl Unpackable for u128 {
     }
 
     fn from_le_bytes(bytes: &[u8]) -> Self{
-        return u128::from_le_bytes(bytes.try_into().unwrap());
+        u128::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Unpackable for u16 {
+    fn get_bytes() -> usize {
+        2
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self{
+        u16::from_le_bytes(bytes.try_into().unwrap())
     }
 }
\ No newline at end of file
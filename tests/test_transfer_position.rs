@@ -14,17 +14,7 @@ use {
 use spl_perpetual::{error::*, instruction::*, processor::*, state::*};
 
 #[tokio::test]
-async fn test_transfer_long() {
-    let mut test = ProgramTest::new(
-        "spl_perpetual",
-        spl_perpetual::id(),
-        processor!(Processor::process),
-    );
-    // TODO Testing is quite tough...
-}
-
-#[tokio::test]
-async fn test_transfer_short() {
+async fn test_transfer_position() {
     let mut test = ProgramTest::new(
         "spl_perpetual",
         spl_perpetual::id(),